@@ -1,6 +1,7 @@
-use super::rsdt::SDT;
-use super::GenericAddressStructure;
+use super::rsdt::{SDTBuilder, SDT};
+use super::{AccessSize, AddressSpaceID, GenericAddressStructure};
 use crate::error::Result;
+use arrayvec::{Array, ArrayVec};
 use byteorder::{ByteOrder, NativeEndian};
 use core::convert::TryFrom;
 use core::fmt;
@@ -14,6 +15,7 @@ mod offsets {
     pub const HPET_NUMBER: usize = 16;
     pub const MIN_CLOCK_TICK: Range<usize> = 17..19;
     pub const PAGE_PROTECTION: usize = 19;
+    pub const HPET_SIZE: usize = 20;
 }
 
 /// Page Protection for HPET register access.
@@ -104,6 +106,95 @@ impl<'a> fmt::Debug for HPET<'a> {
     }
 }
 
+/// Builder for a HPET SDT
+pub struct HPETBuilder {
+    hardware_rev_id: u8,
+    comparator_count: u8,
+    counter_cap: bool,
+    legacy_replacement: bool,
+    pci_vendor_id: u16,
+    address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: PageProtection,
+}
+
+impl HPETBuilder {
+    /// Create a new builder for a HPET SDT describing a 64 bit timer block
+    /// at the given address with the given number of comparators.
+    pub fn new(address: u64, comparator_count: u8) -> HPETBuilder {
+        HPETBuilder {
+            hardware_rev_id: 1,
+            comparator_count,
+            counter_cap: true,
+            legacy_replacement: true,
+            pci_vendor_id: 0x8086,
+            address,
+            hpet_number: 0,
+            minimum_tick: 0,
+            page_protection: PageProtection::NoProtection,
+        }
+    }
+
+    /// Set the minimum number of ticks that should be used in periodic mode.
+    pub fn set_minimum_tick(&mut self, minimum_tick: u16) {
+        self.minimum_tick = minimum_tick;
+    }
+
+    /// Set the type of page protection for HPET register access.
+    pub fn set_page_protection(&mut self, page_protection: PageProtection) {
+        self.page_protection = page_protection;
+    }
+}
+
+impl SDTBuilder for HPETBuilder {
+    const SIGNATURE: [u8; 4] = [b'H', b'P', b'E', b'T'];
+
+    fn revision(&self) -> u8 {
+        1
+    }
+
+    fn encode_table<T: Array<Item = u8>>(
+        &mut self,
+        buffer: &mut ArrayVec<T>,
+    ) -> Result<()> {
+        // The last comparator index is stored, not the count
+        let event_timer_block_id = self.hardware_rev_id as u32
+            | ((self.comparator_count.saturating_sub(1) as u32 & 0x1F) << 8)
+            | ((self.counter_cap as u32) << 13)
+            | ((self.legacy_replacement as u32) << 15)
+            | ((self.pci_vendor_id as u32) << 16);
+
+        let mut tmp_buf = [0u8; offsets::HPET_SIZE];
+        NativeEndian::write_u32(
+            &mut tmp_buf[offsets::EVENT_TIMER_BLOCK_ID],
+            event_timer_block_id,
+        );
+        buffer
+            .try_extend_from_slice(&tmp_buf[offsets::EVENT_TIMER_BLOCK_ID])?;
+
+        GenericAddressStructure {
+            address_space: AddressSpaceID::SystemMemory,
+            bit_width: 64,
+            bit_offset: 0,
+            access_size: AccessSize::Undefined,
+            address: self.address,
+        }
+        .encode(buffer)?;
+
+        tmp_buf[offsets::HPET_NUMBER] = self.hpet_number;
+        NativeEndian::write_u16(
+            &mut tmp_buf[offsets::MIN_CLOCK_TICK],
+            self.minimum_tick,
+        );
+        tmp_buf[offsets::PAGE_PROTECTION] = self.page_protection as u8;
+        buffer.try_extend_from_slice(
+            &tmp_buf[offsets::HPET_NUMBER..offsets::HPET_SIZE],
+        )?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -138,4 +229,27 @@ mod test {
         assert_eq!(hpet.minimum_tick, 0x37ee);
         assert_eq!(hpet.page_protection, PageProtection::NoProtection);
     }
+
+    #[test]
+    fn test_hpet_build() {
+        let mut builder = HPETBuilder::new(0xfed00000, 3);
+        builder.set_minimum_tick(0x80);
+
+        let mut buffer = ArrayVec::<[u8; 64]>::new();
+        let size = builder.encode_sdt(&mut buffer).unwrap();
+        assert_eq!(size, 0x38);
+
+        let hpet_sdt = unsafe { SDT::new(buffer.as_ptr()).unwrap() };
+        let hpet = HPET::new(&hpet_sdt).unwrap();
+
+        assert_eq!(hpet.hardware_rev_id, 1);
+        assert_eq!(hpet.comparator_count, 2);
+        assert_eq!(hpet.counter_cap, true);
+        assert_eq!(hpet.legacy_replacement, true);
+        assert_eq!(hpet.pci_vendor_id, 0x8086);
+        assert_eq!(hpet.address.address_space, AddressSpaceID::SystemMemory);
+        assert_eq!(hpet.address.address, 0xfed00000);
+        assert_eq!(hpet.minimum_tick, 0x80);
+        assert_eq!(hpet.page_protection, PageProtection::NoProtection);
+    }
 }
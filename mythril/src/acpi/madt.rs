@@ -293,6 +293,22 @@ impl Ics {
                 );
                 NativeEndian::write_u32(&mut tmp_buf[8..12], gsi_base);
             }
+            &Ics::InterruptSourceOverride { source, gsi, flags } => {
+                // Only the ISA bus (0) is supported
+                tmp_buf[2] = 0;
+                tmp_buf[3] = source;
+                NativeEndian::write_u32(&mut tmp_buf[4..8], gsi);
+                NativeEndian::write_u16(&mut tmp_buf[8..10], flags.bits());
+            }
+            &Ics::LocalX2Apic {
+                x2apic_id,
+                flags,
+                apic_proc_uid,
+            } => {
+                NativeEndian::write_u32(&mut tmp_buf[4..8], x2apic_id);
+                NativeEndian::write_u32(&mut tmp_buf[8..12], flags.bits());
+                NativeEndian::write_u32(&mut tmp_buf[12..16], apic_proc_uid);
+            }
             _ => {
                 return Err(Error::NotImplemented(format!(
                     "The ICS Type {:?} has not been implemented",
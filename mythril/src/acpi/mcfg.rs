@@ -0,0 +1,213 @@
+use super::rsdt::{SDTBuilder, SDT};
+use crate::error::{Error, Result};
+use arrayvec::{Array, ArrayVec};
+use byteorder::{ByteOrder, NativeEndian};
+use core::fmt;
+use core::ops::Range;
+
+mod offsets {
+    use super::*;
+    pub const RESERVED: Range<usize> = 0..8;
+    pub const ALLOCATIONS: usize = 8;
+
+    // Offsets within each allocation structure
+    pub const BASE_ADDRESS: Range<usize> = 0..8;
+    pub const SEGMENT_GROUP: Range<usize> = 8..10;
+    pub const START_BUS: usize = 10;
+    pub const END_BUS: usize = 11;
+    pub const ALLOCATION_SIZE: usize = 16;
+}
+
+/// A memory mapped (ECAM) configuration space base address allocation.
+///
+/// See Table 4-3 of the PCI Firmware specification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct McfgAllocation {
+    /// The base address of the configuration space of the segment group.
+    pub base_address: u64,
+    /// The PCI segment group number.
+    pub segment_group: u16,
+    /// The first bus decoded by this allocation.
+    pub start_bus: u8,
+    /// The last bus decoded by this allocation.
+    pub end_bus: u8,
+}
+
+impl McfgAllocation {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            base_address: NativeEndian::read_u64(&bytes[offsets::BASE_ADDRESS]),
+            segment_group: NativeEndian::read_u16(
+                &bytes[offsets::SEGMENT_GROUP],
+            ),
+            start_bus: bytes[offsets::START_BUS],
+            end_bus: bytes[offsets::END_BUS],
+        }
+    }
+
+    fn encode<T: Array<Item = u8>>(
+        &self,
+        buffer: &mut ArrayVec<T>,
+    ) -> Result<()> {
+        let mut tmp_buf = [0u8; offsets::ALLOCATION_SIZE];
+        NativeEndian::write_u64(
+            &mut tmp_buf[offsets::BASE_ADDRESS],
+            self.base_address,
+        );
+        NativeEndian::write_u16(
+            &mut tmp_buf[offsets::SEGMENT_GROUP],
+            self.segment_group,
+        );
+        tmp_buf[offsets::START_BUS] = self.start_bus;
+        tmp_buf[offsets::END_BUS] = self.end_bus;
+        buffer.try_extend_from_slice(&tmp_buf[..])?;
+        Ok(())
+    }
+}
+
+/// PCI Express memory mapped configuration space base address
+/// Description Table (MCFG).
+///
+/// See `PCI Firmware § 4.1.2`.
+pub struct MCFG<'a> {
+    /// System Descriptor Table Header for this structure.
+    sdt: &'a SDT<'a>,
+    /// The raw configuration space base address allocation structures.
+    allocations: &'a [u8],
+}
+
+impl<'a> MCFG<'a> {
+    /// Create a new MCFG given a SDT.
+    pub fn new(sdt: &'a SDT<'a>) -> Result<MCFG<'a>> {
+        let allocations =
+            sdt.table.get(offsets::ALLOCATIONS..).ok_or_else(|| {
+                Error::InvalidValue("MCFG table is too short".into())
+            })?;
+        if allocations.len() % offsets::ALLOCATION_SIZE != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid MCFG allocations length: {}",
+                allocations.len()
+            )));
+        }
+        Ok(MCFG { sdt, allocations })
+    }
+
+    /// The configuration space base address allocations.
+    pub fn allocations(&self) -> impl Iterator<Item = McfgAllocation> + 'a {
+        self.allocations
+            .chunks(offsets::ALLOCATION_SIZE)
+            .map(McfgAllocation::parse)
+    }
+}
+
+impl<'a> fmt::Debug for MCFG<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.sdt)?;
+        for allocation in self.allocations() {
+            write!(
+                f,
+                " segment={} buses={}-{} address=0x{:x}",
+                allocation.segment_group,
+                allocation.start_bus,
+                allocation.end_bus,
+                allocation.base_address
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for a MCFG SDT
+pub struct MCFGBuilder<T: Array> {
+    allocations: ArrayVec<T>,
+}
+
+impl<T: Array<Item = McfgAllocation>> MCFGBuilder<T> {
+    /// Create a new builder for the MCFG SDT.
+    pub fn new() -> MCFGBuilder<T> {
+        MCFGBuilder {
+            allocations: ArrayVec::<T>::new(),
+        }
+    }
+
+    /// Add a configuration space base address allocation to the table.
+    pub fn add_allocation(&mut self, allocation: McfgAllocation) -> Result<()> {
+        self.allocations.try_push(allocation)?;
+        Ok(())
+    }
+}
+
+impl<U> SDTBuilder for MCFGBuilder<U>
+where
+    U: Array<Item = McfgAllocation>,
+{
+    const SIGNATURE: [u8; 4] = [b'M', b'C', b'F', b'G'];
+
+    fn revision(&self) -> u8 {
+        1
+    }
+
+    fn encode_table<T: Array<Item = u8>>(
+        &mut self,
+        buffer: &mut ArrayVec<T>,
+    ) -> Result<()> {
+        buffer.try_extend_from_slice(&[0u8; offsets::RESERVED.end])?;
+        for allocation in self.allocations.iter() {
+            allocation.encode(buffer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mcfg_parse() {
+        // sample MCFG ACPI entry (like the one from QEMU's q35 machine)
+        let buf = [
+            0x4d, 0x43, 0x46, 0x47, 0x3c, 0x00, 0x00, 0x00, 0x01, 0xef, 0x42,
+            0x4f, 0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x4d, 0x43,
+            0x46, 0x47, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let mcfg_sdt = unsafe { SDT::new(buf.as_ptr()).unwrap() };
+        let mcfg = MCFG::new(&mcfg_sdt).unwrap();
+        let allocations: ArrayVec<[_; 4]> = mcfg.allocations().collect();
+
+        assert_eq!(
+            allocations.as_slice(),
+            &[McfgAllocation {
+                base_address: 0xb0000000,
+                segment_group: 0,
+                start_bus: 0,
+                end_bus: 0xff,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_mcfg_build() {
+        let allocation = McfgAllocation {
+            base_address: 0xb0000000,
+            segment_group: 0,
+            start_bus: 0,
+            end_bus: 0,
+        };
+        let mut builder = MCFGBuilder::<[_; 4]>::new();
+        builder.add_allocation(allocation).unwrap();
+
+        let mut buffer = ArrayVec::<[u8; 128]>::new();
+        let size = builder.encode_sdt(&mut buffer).unwrap();
+        assert_eq!(size, 0x3c);
+
+        let mcfg_sdt = unsafe { SDT::new(buffer.as_ptr()).unwrap() };
+        let mcfg = MCFG::new(&mcfg_sdt).unwrap();
+        let allocations: ArrayVec<[_; 4]> = mcfg.allocations().collect();
+        assert_eq!(allocations.as_slice(), &[allocation]);
+    }
+}
@@ -10,6 +10,7 @@
 //! [ACPI 6.3]: https://uefi.org/sites/default/files/resources/ACPI_6_3_May16.pdf
 
 use crate::error::{Error, Result};
+use arrayvec::{Array, ArrayVec};
 use byteorder::{ByteOrder, NativeEndian};
 use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
@@ -21,6 +22,8 @@ pub mod fadt;
 pub mod hpet;
 /// Support for the Multiple APIC Descriptor Table (MADT).
 pub mod madt;
+/// Support for the PCI Express memory mapped configuration space table (MCFG).
+pub mod mcfg;
 /// Support for the Root System Descriptor Pointer (RSDP).
 pub mod rsdp;
 /// Support for the Root System Descriptor Table (RSDT).
@@ -183,4 +186,18 @@ impl GenericAddressStructure {
             address,
         })
     }
+    /// Encode this GAS into the given buffer.
+    pub fn encode<T: Array<Item = u8>>(
+        &self,
+        buffer: &mut ArrayVec<T>,
+    ) -> Result<()> {
+        let mut bytes = [0u8; GAS_SIZE];
+        bytes[offsets::GAS_ADDRESS_SPACE] = self.address_space as u8;
+        bytes[offsets::GAS_BIT_WIDTH] = self.bit_width;
+        bytes[offsets::GAS_BIT_OFFSET] = self.bit_offset;
+        bytes[offsets::GAS_ACCESS_SIZE] = self.access_size as u8;
+        NativeEndian::write_u64(&mut bytes[offsets::GAS_ADDRESS], self.address);
+        buffer.try_extend_from_slice(&bytes[..])?;
+        Ok(())
+    }
 }
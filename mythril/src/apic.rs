@@ -1,8 +1,11 @@
 #![deny(missing_docs)]
 
 use crate::error::{Error, Result};
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::percore;
 use crate::time;
 use crate::{declare_per_core, get_per_core, get_per_core_mut};
+use alloc::vec::Vec;
 use num_enum::TryFromPrimitive;
 use raw_cpuid::CpuId;
 use x86::msr;
@@ -68,8 +71,8 @@ pub enum DstMode {
 pub enum DeliveryMode {
     /// Send interrupt vector to target
     Fixed = 0x00,
-    #[doc(hidden)]
-    _Reserved0 = 0x01,
+    /// Send interrupt vector to the lowest priority target
+    LowestPriority = 0x01,
     /// Send SMI interrupt to target
     SMI = 0x02,
     #[doc(hidden)]
@@ -88,6 +91,20 @@ declare_per_core! {
     static mut LOCAL_APIC: Option<LocalApic> = None;
 }
 
+static HOST_APIC_IDS: RoAfterInit<Vec<ApicId>> = RoAfterInit::uninitialized();
+
+/// Record the APIC ids of the host cores (indexed by core id)
+///
+/// This may only be called by the BSP.
+pub unsafe fn init_host_apic_ids(apic_ids: Vec<ApicId>) {
+    RoAfterInit::init(&HOST_APIC_IDS, apic_ids);
+}
+
+/// Returns the APIC id of the given host core
+pub fn host_apic_id(core: percore::CoreId) -> Option<ApicId> {
+    HOST_APIC_IDS.get(core.raw as usize).copied()
+}
+
 /// Obtain a reference to the current core's LocalApic
 pub fn get_local_apic() -> &'static LocalApic {
     get_per_core!(LOCAL_APIC)
@@ -33,7 +33,8 @@ pub struct BootModule {
 }
 
 impl BootModule {
-    pub fn data(&self) -> &[u8] {
+    // Boot modules are never freed, so their data is always available
+    pub fn data(&self) -> &'static [u8] {
         unsafe {
             core::slice::from_raw_parts(
                 self.address.as_u64() as *const u8,
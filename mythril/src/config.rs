@@ -1,6 +1,9 @@
 #![deny(missing_docs)]
 
+use crate::boot_info::BootInfo;
+use crate::error::{Error, Result};
 use crate::percore;
+use crate::switch;
 
 use alloc::string::String;
 use core::fmt;
@@ -25,6 +28,159 @@ pub struct UserVmConfig {
 
     /// A list of core ID's (starting from 0) used by this machine
     pub cpus: Vec<percore::CoreId>,
+
+    /// The multiboot identifier for an image used to initialize the CMOS
+    /// NVRAM of this virtual machine (if any)
+    #[serde(default)]
+    pub nvram: Option<String>,
+
+    /// Whether this virtual machine receives input from the physical
+    /// PS2 keyboard (only one virtual machine may use it)
+    #[serde(default)]
+    pub ps2_keyboard: bool,
+
+    /// The action taken when the guest resets the machine
+    #[serde(default = "PowerAction::restart")]
+    pub on_reboot: PowerAction,
+
+    /// The action taken when the guest powers off the machine
+    #[serde(default = "PowerAction::destroy")]
+    pub on_poweroff: PowerAction,
+
+    /// The action taken when the guest crashes (e.g., triple faults)
+    #[serde(default = "PowerAction::destroy")]
+    pub on_crash: PowerAction,
+
+    /// The number of ports of the virtio console of this virtual machine.
+    /// The first port is the guest console (hvc0). No virtio console is
+    /// created if this is zero.
+    #[serde(default)]
+    pub virtio_console_ports: u32,
+
+    /// The virtio block devices of this virtual machine
+    #[serde(default)]
+    pub disks: Vec<UserDiskConfig>,
+
+    /// The virtio network devices of this virtual machine
+    #[serde(default)]
+    pub nics: Vec<UserNicConfig>,
+
+    /// Whether this virtual machine has a virtio entropy device
+    #[serde(default)]
+    pub virtio_rng: bool,
+
+    /// How the guest may use the RDRAND and RDSEED instructions
+    #[serde(default = "RandomInstructionPolicy::passthrough")]
+    pub random_instructions: RandomInstructionPolicy,
+}
+
+/// A virtio block device backed by a multiboot module
+#[derive(Deserialize, Debug)]
+pub struct UserDiskConfig {
+    /// The multiboot identifier for the disk image
+    pub image: String,
+
+    /// How guest writes to the disk are handled
+    #[serde(default = "DiskMode::read_only")]
+    pub mode: DiskMode,
+}
+
+/// The handling of guest writes to a disk
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskMode {
+    /// The guest can not write to the disk
+    ReadOnly,
+
+    /// Guest writes are kept in memory, and the image itself is never
+    /// modified (so writes are lost when mythril restarts)
+    CopyOnWrite,
+}
+
+impl DiskMode {
+    fn read_only() -> Self {
+        DiskMode::ReadOnly
+    }
+}
+
+/// The handling of guest RDRAND and RDSEED instructions
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RandomInstructionPolicy {
+    /// The guest executes the instructions directly (if the host processor
+    /// supports them)
+    Passthrough,
+
+    /// The instructions cause a VM exit, and are emulated with the same
+    /// host instruction (failing if it does). Instructions the host
+    /// processor can not exit on are hidden from CPUID.
+    Emulate,
+
+    /// The instructions are hidden from CPUID, and raise #UD
+    Disabled,
+}
+
+impl RandomInstructionPolicy {
+    fn passthrough() -> Self {
+        RandomInstructionPolicy::Passthrough
+    }
+}
+
+/// The action taken by the hypervisor in response to a guest power event
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    /// Stop the virtual machine (it will not run again)
+    Destroy,
+
+    /// Return the virtual machine to its initial configured state and
+    /// start it again
+    Restart,
+}
+
+impl PowerAction {
+    fn destroy() -> Self {
+        PowerAction::Destroy
+    }
+
+    fn restart() -> Self {
+        PowerAction::Restart
+    }
+}
+
+/// A virtio network device connected to an inter-VM switch
+#[derive(Deserialize, Debug)]
+pub struct UserNicConfig {
+    /// The MAC address of the device (e.g., "52:54:00:12:34:56")
+    pub mac: switch::MacAddress,
+
+    /// The name of the switch the device is connected to. Only devices
+    /// connected to the same switch can communicate.
+    pub switch: String,
+}
+
+/// The configuration of the guest console multiplexer
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct UserConsoleConfig {
+    /// The byte that introduces a console command (Ctrl-a by default)
+    pub escape: u8,
+
+    /// The number of bytes of output retained for each virtual machine
+    pub scrollback: usize,
+
+    /// The virtual machine initially attached to the console (if any)
+    pub attach: Option<u32>,
+}
+
+impl Default for UserConsoleConfig {
+    fn default() -> Self {
+        Self {
+            escape: 0x01,
+            scrollback: 16 * 1024,
+            attach: Some(0),
+        }
+    }
 }
 
 /// The top level Mythril configuration
@@ -35,6 +191,66 @@ pub struct UserConfig {
 
     /// A list of virtual machine configurations
     pub vms: Vec<UserVmConfig>,
+
+    /// The guest console configuration
+    #[serde(default)]
+    pub console: UserConsoleConfig,
+
+    /// The memory regions shared between virtual machines
+    #[serde(default)]
+    pub shared_memory: Vec<UserSharedMemoryConfig>,
+}
+
+impl UserConfig {
+    /// Check that every multiboot module named by the virtual machine
+    /// configurations was provided by the bootloader
+    pub fn validate_modules(&self, info: &BootInfo) -> Result<()> {
+        for (vm_id, vm) in self.vms.iter().enumerate() {
+            let mut modules =
+                vec![("kernel", &vm.kernel), ("initramfs", &vm.initramfs)];
+            modules.extend(vm.nvram.iter().map(|image| ("NVRAM image", image)));
+            modules.extend(
+                vm.disks.iter().map(|disk| ("disk image", &disk.image)),
+            );
+            for (kind, name) in modules {
+                if info.find_module(name).is_none() {
+                    return Err(Error::InvalidValue(format!(
+                        "Virtual machine {}: no such {} '{}'",
+                        vm_id, kind, name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A named region of memory shared between virtual machines
+#[derive(Deserialize, Debug)]
+pub struct UserSharedMemoryConfig {
+    /// The name of the region
+    pub name: String,
+
+    /// The size of the region in bytes (a power of two of at least 4KB)
+    pub size: u64,
+
+    /// The virtual machines the region is mapped in to
+    pub vms: Vec<UserSharedMemoryMapping>,
+}
+
+/// The mapping of a shared memory region in to a virtual machine
+#[derive(Deserialize, Debug)]
+pub struct UserSharedMemoryMapping {
+    /// The id of the virtual machine
+    pub vm: u32,
+
+    /// The guest physical address of the region. This must be within the
+    /// PCI memory window and aligned to the size of the region.
+    pub address: u64,
+
+    /// Whether the virtual machine can only read the region
+    #[serde(default)]
+    pub readonly: bool,
 }
 
 struct CoreIdVisitor;
@@ -64,3 +280,81 @@ impl<'de> Deserialize<'de> for percore::CoreId {
         deserializer.deserialize_u64(CoreIdVisitor)
     }
 }
+
+struct MacAddressVisitor;
+
+impl<'de> Visitor<'de> for MacAddressVisitor {
+    type Value = switch::MacAddress;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a MAC address")
+    }
+
+    fn visit_str<E>(self, value: &str) -> core::result::Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        switch::MacAddress::parse(value)
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for switch::MacAddress {
+    fn deserialize<D>(
+        deserializer: D,
+    ) -> core::result::Result<switch::MacAddress, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(MacAddressVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::boot_info::BootModule;
+    use crate::memory::HostPhysAddr;
+
+    fn boot_info(modules: &[&str]) -> BootInfo {
+        BootInfo {
+            modules: modules
+                .iter()
+                .map(|name| BootModule {
+                    identifier: Some(String::from(*name)),
+                    address: HostPhysAddr::new(0),
+                    size: 0,
+                })
+                .collect(),
+            ..BootInfo::default()
+        }
+    }
+
+    fn test_config(vm: &str) -> UserConfig {
+        serde_json::from_str(&format!(
+            r#"{{"version": 1, "vms": [{{"memory": 256, "kernel": "kernel",
+                "initramfs": "initramfs", "cmdline": "", "cpus": [] {}}}]}}"#,
+            vm
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_modules() {
+        let info = boot_info(&["kernel", "initramfs", "nvram", "disk"]);
+        let config = test_config(
+            r#", "nvram": "nvram",
+            "disks": [{"image": "disk"}]"#,
+        );
+        assert!(config.validate_modules(&info).is_ok());
+
+        let config = test_config(r#", "disks": [{"image": "missing"}]"#);
+        assert!(config.validate_modules(&info).is_err());
+
+        let config = test_config(r#", "nvram": "missing""#);
+        assert!(config.validate_modules(&info).is_err());
+
+        let info = boot_info(&["kernel"]);
+        assert!(test_config("").validate_modules(&info).is_err());
+    }
+}
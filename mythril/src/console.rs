@@ -0,0 +1,469 @@
+#![deny(missing_docs)]
+
+//! # Guest Console Multiplexing
+//!
+//! Every byte transmitted by a guest UART (or virtio console) is tagged with
+//! the id of the VM that produced it and stored in a per-VM scrollback
+//! buffer. At most one VM is 'attached' to the physical serial console at a
+//! time. Output from the attached VM is written to the console and input
+//! from the console is forwarded to the attached VM.
+//!
+//! Input that follows the configured escape byte (Ctrl-a by default) is
+//! interpreted as a command to the multiplexer:
+//!
+//! * `0`-`9` - attach to the VM with the given id
+//! * `n` - attach to the next VM
+//! * `d` - detach from the current VM
+//! * `l` - list the VMs and their state
+//! * `p` - press the power button of the attached VM
+//! * `h` or `?` - print the supported commands
+//!
+//! Sending the escape byte twice forwards a single escape byte to the
+//! attached VM.
+
+use crate::config::UserConsoleConfig;
+use crate::error::{Error, Result};
+use crate::interrupt;
+use crate::ioapic;
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::logger;
+use crate::physdev;
+use crate::virtdev::Port;
+use crate::vm;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// The port of the guest UART that is connected to the console
+pub const GUEST_CONSOLE_PORT: Port = 0x3f8;
+
+static CONSOLE: RoAfterInit<Mutex<HostConsole>> = RoAfterInit::uninitialized();
+
+struct HostConsole {
+    serial: physdev::com::Uart8250,
+    mux: ConsoleMux,
+}
+
+/// The result of processing a byte of console input
+#[derive(Debug, PartialEq)]
+pub enum ConsoleAction {
+    /// The input was consumed by the multiplexer
+    None,
+
+    /// The byte should be delivered to the guest console of the given VM
+    Forward {
+        /// The id of the VM that should receive the input
+        vm_id: u32,
+
+        /// The received byte
+        key: u8,
+    },
+
+    /// The console is now attached to the given VM
+    Attach(u32),
+
+    /// The power button of the given VM should be pressed
+    PowerButton(u32),
+}
+
+/// The state of the console multiplexer
+///
+/// The multiplexer does not write to the physical console directly. Instead,
+/// any output is appended to the `out` buffer passed to each method.
+pub struct ConsoleMux {
+    escape: u8,
+    scrollback_size: usize,
+    scrollback: BTreeMap<u32, VecDeque<u8>>,
+    attached: Option<u32>,
+    escape_pending: bool,
+}
+
+impl ConsoleMux {
+    /// Create a new multiplexer for `vm_count` virtual machines
+    pub fn new(config: &UserConsoleConfig, vm_count: u32) -> Result<Self> {
+        if let Some(vm_id) = config.attach {
+            if vm_id >= vm_count {
+                return Err(Error::InvalidValue(format!(
+                    "Console attached to unknown VM id '{}'",
+                    vm_id
+                )));
+            }
+        }
+
+        Ok(Self {
+            escape: config.escape,
+            scrollback_size: config.scrollback,
+            scrollback: (0..vm_count).map(|id| (id, VecDeque::new())).collect(),
+            attached: config.attach,
+            escape_pending: false,
+        })
+    }
+
+    /// The VM currently attached to the console (if any)
+    pub fn attached(&self) -> Option<u32> {
+        self.attached
+    }
+
+    /// The retained output of the given VM
+    pub fn scrollback(&self, vm_id: u32) -> Option<&VecDeque<u8>> {
+        self.scrollback.get(&vm_id)
+    }
+
+    /// Record a byte of output from the given VM
+    pub fn write_guest_output(
+        &mut self,
+        vm_id: u32,
+        byte: u8,
+        out: &mut Vec<u8>,
+    ) {
+        let scrollback_size = self.scrollback_size;
+        let buffer = match self.scrollback.get_mut(&vm_id) {
+            Some(buffer) => buffer,
+            None => {
+                warn!("Console output from unknown VM id '{}'", vm_id);
+                return;
+            }
+        };
+
+        if scrollback_size > 0 {
+            if buffer.len() == scrollback_size {
+                buffer.pop_front();
+            }
+            buffer.push_back(byte);
+        }
+
+        if self.attached == Some(vm_id) {
+            out.push(byte);
+        }
+    }
+
+    /// Process a byte of input from the physical console
+    pub fn process_input(
+        &mut self,
+        key: u8,
+        out: &mut Vec<u8>,
+    ) -> ConsoleAction {
+        if !self.escape_pending {
+            if key == self.escape {
+                self.escape_pending = true;
+                return ConsoleAction::None;
+            }
+            return self.forward(key);
+        }
+
+        self.escape_pending = false;
+
+        match key {
+            key if key == self.escape => self.forward(key),
+            b'0'..=b'9' => self.attach((key - b'0') as u32, out),
+            b'n' => {
+                let next = self
+                    .attached
+                    .map(|vm_id| (vm_id + 1) % self.vm_count())
+                    .unwrap_or(0);
+                self.attach(next, out)
+            }
+            b'd' => {
+                if let Some(vm_id) = self.attached.take() {
+                    Self::message(
+                        out,
+                        format!("detached from VM {}", vm_id).as_str(),
+                    );
+                }
+                ConsoleAction::None
+            }
+            b'l' => {
+                for vm_id in self.scrollback.keys() {
+                    let state = if self.attached == Some(*vm_id) {
+                        "attached"
+                    } else {
+                        "detached"
+                    };
+                    Self::message(
+                        out,
+                        format!("VM {}: {}", vm_id, state).as_str(),
+                    );
+                }
+                ConsoleAction::None
+            }
+            b'p' => match self.attached {
+                Some(vm_id) => {
+                    Self::message(
+                        out,
+                        format!("pressing power button of VM {}", vm_id)
+                            .as_str(),
+                    );
+                    ConsoleAction::PowerButton(vm_id)
+                }
+                None => ConsoleAction::None,
+            },
+            b'h' | b'?' => {
+                Self::message(
+                    out,
+                    "0-9: attach to VM, n: next VM, d: detach, l: list VMs, \
+                     p: power button",
+                );
+                ConsoleAction::None
+            }
+            _ => ConsoleAction::None,
+        }
+    }
+
+    fn vm_count(&self) -> u32 {
+        self.scrollback.len() as u32
+    }
+
+    fn message(out: &mut Vec<u8>, msg: &str) {
+        out.extend_from_slice(b"\r\n[mythril: ");
+        out.extend_from_slice(msg.as_bytes());
+        out.extend_from_slice(b"]\r\n");
+    }
+
+    fn forward(&self, key: u8) -> ConsoleAction {
+        match self.attached {
+            Some(vm_id) => ConsoleAction::Forward { vm_id, key },
+            None => ConsoleAction::None,
+        }
+    }
+
+    fn attach(&mut self, vm_id: u32, out: &mut Vec<u8>) -> ConsoleAction {
+        let buffer = match self.scrollback.get(&vm_id) {
+            Some(buffer) => buffer,
+            None => {
+                Self::message(out, format!("no VM {}", vm_id).as_str());
+                return ConsoleAction::None;
+            }
+        };
+
+        Self::message(out, format!("attached to VM {}", vm_id).as_str());
+
+        // Replay the retained output, so the operator has some context
+        out.extend(buffer.iter());
+
+        self.attached = Some(vm_id);
+        ConsoleAction::Attach(vm_id)
+    }
+}
+
+fn flush_output(out: &[u8]) {
+    if !out.is_empty() {
+        logger::write_console(String::from_utf8_lossy(out));
+    }
+}
+
+/// Initialize the global console multiplexer
+///
+/// This must be called after the global `VirtualMachineSet` has been
+/// initialized and may only be called by the BSP.
+pub unsafe fn init_console(
+    serial: physdev::com::Uart8250,
+    config: &UserConsoleConfig,
+) -> Result<()> {
+    let mux = ConsoleMux::new(config, vm::virtual_machines().count())?;
+    let attached = mux.attached();
+
+    RoAfterInit::init(&CONSOLE, Mutex::new(HostConsole { serial, mux }));
+
+    route_host_input(attached.unwrap_or(0))
+}
+
+/// Direct the physical console interrupt to the BSP of the given VM
+pub fn route_host_input(vm_id: u32) -> Result<()> {
+    let bsp = vm::virtual_machines()
+        .bsp_core_id(vm_id)
+        .ok_or_else(|| Error::NotFound)?;
+
+    ioapic::map_gsi_vector_to_core(
+        interrupt::gsi::UART,
+        interrupt::vector::UART,
+        bsp,
+    )
+    .map_err(|_| {
+        Error::DeviceError("Failed to update console GSI mapping".into())
+    })
+}
+
+/// Record a byte of output from the given VM, writing it to the physical
+/// console if the VM is attached.
+pub fn write_guest_output(vm_id: u32, byte: u8) {
+    write_guest_output_bytes(vm_id, &[byte]);
+}
+
+/// Record a buffer of output from the given VM, writing it to the physical
+/// console if the VM is attached.
+pub fn write_guest_output_bytes(vm_id: u32, bytes: &[u8]) {
+    if !RoAfterInit::is_initialized(&CONSOLE) {
+        return;
+    }
+
+    let mut out = vec![];
+    let mut console = CONSOLE.lock();
+    for byte in bytes {
+        console.mux.write_guest_output(vm_id, *byte, &mut out);
+    }
+    flush_output(&out);
+}
+
+/// Read and process a pending byte from the physical console
+pub fn handle_host_input() -> Result<ConsoleAction> {
+    if !RoAfterInit::is_initialized(&CONSOLE) {
+        return Ok(ConsoleAction::None);
+    }
+
+    let mut out = vec![];
+    let mut console = CONSOLE.lock();
+    let key = console.serial.read();
+    let action = console.mux.process_input(key, &mut out);
+    flush_output(&out);
+    drop(console);
+
+    match action {
+        ConsoleAction::Attach(vm_id) => route_host_input(vm_id)?,
+        ConsoleAction::PowerButton(vm_id) => {
+            vm::virtual_machines().press_power_button(vm_id)?
+        }
+        _ => (),
+    }
+    Ok(action)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> UserConsoleConfig {
+        UserConsoleConfig {
+            escape: 0x01,
+            scrollback: 8,
+            attach: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_output_tagged_by_vm() {
+        let mut mux = ConsoleMux::new(&test_config(), 2).unwrap();
+        let mut out = vec![];
+        mux.write_guest_output(0, b'a', &mut out);
+        mux.write_guest_output(1, b'b', &mut out);
+
+        assert_eq!(out, vec![b'a']);
+        assert!(mux.scrollback(0).unwrap().iter().eq([b'a'].iter()));
+        assert!(mux.scrollback(1).unwrap().iter().eq([b'b'].iter()));
+    }
+
+    #[test]
+    fn test_scrollback_wraps() {
+        let mut mux = ConsoleMux::new(&test_config(), 1).unwrap();
+        let mut out = vec![];
+        for i in 0..10 {
+            mux.write_guest_output(0, i, &mut out);
+        }
+        assert!(mux
+            .scrollback(0)
+            .unwrap()
+            .iter()
+            .eq((2..10).collect::<Vec<u8>>().iter()));
+    }
+
+    #[test]
+    fn test_input_forwarded_to_attached() {
+        let mut mux = ConsoleMux::new(&test_config(), 2).unwrap();
+        let mut out = vec![];
+        assert_eq!(
+            mux.process_input(b'x', &mut out),
+            ConsoleAction::Forward {
+                vm_id: 0,
+                key: b'x'
+            }
+        );
+    }
+
+    #[test]
+    fn test_attach_replays_scrollback() {
+        let mut mux = ConsoleMux::new(&test_config(), 2).unwrap();
+        let mut out = vec![];
+        mux.write_guest_output(1, b'z', &mut out);
+
+        assert_eq!(mux.process_input(0x01, &mut out), ConsoleAction::None);
+        assert_eq!(mux.process_input(b'1', &mut out), ConsoleAction::Attach(1));
+        assert_eq!(mux.attached(), Some(1));
+        assert_eq!(out.last(), Some(&b'z'));
+
+        out.clear();
+        mux.write_guest_output(1, b'y', &mut out);
+        assert_eq!(out, vec![b'y']);
+    }
+
+    #[test]
+    fn test_next_wraps() {
+        let mut mux = ConsoleMux::new(&test_config(), 2).unwrap();
+        let mut out = vec![];
+        mux.process_input(0x01, &mut out);
+        assert_eq!(mux.process_input(b'n', &mut out), ConsoleAction::Attach(1));
+        mux.process_input(0x01, &mut out);
+        assert_eq!(mux.process_input(b'n', &mut out), ConsoleAction::Attach(0));
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut mux = ConsoleMux::new(&test_config(), 1).unwrap();
+        let mut out = vec![];
+        mux.process_input(0x01, &mut out);
+        mux.process_input(b'd', &mut out);
+        assert_eq!(mux.attached(), None);
+        assert_eq!(mux.process_input(b'x', &mut out), ConsoleAction::None);
+
+        out.clear();
+        mux.write_guest_output(0, b'a', &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_power_button() {
+        let mut mux = ConsoleMux::new(&test_config(), 2).unwrap();
+        let mut out = vec![];
+        mux.process_input(0x01, &mut out);
+        assert_eq!(
+            mux.process_input(b'p', &mut out),
+            ConsoleAction::PowerButton(0)
+        );
+
+        // Nothing happens if no VM is attached
+        mux.process_input(0x01, &mut out);
+        mux.process_input(b'd', &mut out);
+        mux.process_input(0x01, &mut out);
+        assert_eq!(mux.process_input(b'p', &mut out), ConsoleAction::None);
+    }
+
+    #[test]
+    fn test_escape_escape_forwards_escape() {
+        let mut mux = ConsoleMux::new(&test_config(), 1).unwrap();
+        let mut out = vec![];
+        mux.process_input(0x01, &mut out);
+        assert_eq!(
+            mux.process_input(0x01, &mut out),
+            ConsoleAction::Forward {
+                vm_id: 0,
+                key: 0x01
+            }
+        );
+    }
+
+    #[test]
+    fn test_attach_unknown_vm() {
+        let mut mux = ConsoleMux::new(&test_config(), 1).unwrap();
+        let mut out = vec![];
+        mux.process_input(0x01, &mut out);
+        assert_eq!(mux.process_input(b'5', &mut out), ConsoleAction::None);
+        assert_eq!(mux.attached(), Some(0));
+    }
+
+    #[test]
+    fn test_invalid_initial_attach() {
+        let mut config = test_config();
+        config.attach = Some(3);
+        assert!(ConsoleMux::new(&config, 1).is_err());
+    }
+}
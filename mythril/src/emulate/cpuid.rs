@@ -1,8 +1,30 @@
+use crate::config::RandomInstructionPolicy;
 use crate::error::Result;
-use crate::{vcpu, vmexit};
+use crate::{vcpu, vmcs, vmexit};
+
+// Whether the guest should see RDRAND and RDSEED (which are reported in
+// leaves 1 and 7 respectively)
+fn random_instructions_visible(
+    vcpu: &vcpu::VCpu,
+    exiting: vmcs::SecondaryExecFlags,
+) -> Result<bool> {
+    Ok(match vcpu.vm.random_policy {
+        RandomInstructionPolicy::Passthrough => true,
+        RandomInstructionPolicy::Disabled => false,
+
+        // Emulation is only possible if the instruction causes a VMEXIT
+        RandomInstructionPolicy::Emulate => {
+            let controls = vmcs::SecondaryExecFlags::from_bits_truncate(
+                vcpu.vmcs
+                    .read_field(vmcs::VmcsField::SecondaryVmExecControl)?,
+            );
+            controls.contains(exiting)
+        }
+    })
+}
 
 pub fn emulate_cpuid(
-    _vcpu: &mut vcpu::VCpu,
+    vcpu: &mut vcpu::VCpu,
     guest_cpu: &mut vmexit::GuestCpuState,
 ) -> Result<()> {
     //FIXME: for now just use the actual cpuid
@@ -11,7 +33,18 @@ pub fn emulate_cpuid(
         guest_cpu.rcx as u32,
     );
 
+    // The guest sees its virtual APIC ids, with each core as a separate
+    // package-level core (i.e., no SMT)
+    let apic_id = vcpu.local_apic.apic_id();
+    let core_count = vcpu.vm.cpus.len() as u32;
+
     if guest_cpu.rax as u32 == 1 {
+        // Initial APIC id and the number of addressable logical processors
+        res.ebx = (res.ebx & 0x0000ffff)
+            | (apic_id << 24)
+            | ((core_count & 0xff) << 16);
+        res.edx |= 1 << 28;
+
         // Disable MTRR
         res.edx &= !(1 << 12);
 
@@ -21,10 +54,48 @@ pub fn emulate_cpuid(
         // Hide hypervisor feature
         res.ecx &= !(1 << 31);
 
-        // Hide TSC deadline timer
-        res.ecx &= !(1 << 24);
+        // Expose the TSC deadline timer (this is emulated by the virtual
+        // local apic, so it does not depend on host support)
+        res.ecx |= 1 << 24;
+
+        // Expose x2APIC mode (also emulated by the virtual local apic)
+        res.ecx |= 1 << 21;
+
+        if !random_instructions_visible(
+            vcpu,
+            vmcs::SecondaryExecFlags::RDRAND_EXITING,
+        )? {
+            res.ecx &= !(1 << 30);
+        }
+    } else if guest_cpu.rax as u32 == 0x07 && guest_cpu.rcx as u32 == 0 {
+        if !random_instructions_visible(
+            vcpu,
+            vmcs::SecondaryExecFlags::RDSEED_EXITING,
+        )? {
+            res.ebx &= !(1 << 18);
+        }
     } else if guest_cpu.rax as u32 == 0x0b {
-        res.edx = crate::percore::read_core_id().raw as u32;
+        let level = guest_cpu.rcx as u32 & 0xff;
+        match level {
+            // SMT level: one logical processor per core
+            0 => {
+                res.eax = 0;
+                res.ebx = 1;
+                res.ecx = (1 << 8) | level;
+            }
+            // Core level: all of the VM's cores
+            1 => {
+                res.eax = 32 - (core_count.max(1) - 1).leading_zeros();
+                res.ebx = core_count;
+                res.ecx = (2 << 8) | level;
+            }
+            _ => {
+                res.eax = 0;
+                res.ebx = 0;
+                res.ecx = level;
+            }
+        }
+        res.edx = apic_id;
     }
 
     guest_cpu.rax = res.eax as u64 | (guest_cpu.rax & 0xffffffff00000000);
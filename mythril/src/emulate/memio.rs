@@ -496,7 +496,7 @@ pub fn handle_apic_access(
     ) -> Result<()> {
         let offset = address_to_apic_offset(addr);
         let res = vcpu.local_apic.register_read(offset)?;
-        let mut bytes = res.to_be_bytes();
+        let mut bytes = res.to_le_bytes();
 
         match event {
             DeviceEvent::MemRead(_, mut req) => {
@@ -2,3 +2,4 @@ pub mod controlreg;
 pub mod cpuid;
 pub mod memio;
 pub mod portio;
+pub mod random;
@@ -0,0 +1,101 @@
+use crate::error::Result;
+use crate::{vcpu, vmcs, vmexit};
+
+// The arithmetic flags set by RDRAND and RDSEED (all but CF are cleared)
+const RFLAGS_CF: u64 = 1 << 0;
+const RFLAGS_ARITHMETIC: u64 =
+    RFLAGS_CF | 1 << 2 | 1 << 4 | 1 << 6 | 1 << 7 | 1 << 11;
+
+// The value of a destination register of the given size after `value` is
+// written to it. Like other instructions, 16 bit writes preserve the rest
+// of the register, and 32 bit writes clear the upper half.
+fn merge_register(old: u64, value: u64, size: u8) -> u64 {
+    match size {
+        2 => (old & !0xffff) | (value & 0xffff),
+        4 => value & 0xffffffff,
+        _ => value,
+    }
+}
+
+// The destination register and RFLAGS after the instruction completes
+// with the given value. If no value is available, the destination is
+// cleared and CF is clear, as with the hardware instructions.
+fn complete(old: u64, rflags: u64, value: Option<u64>, size: u8) -> (u64, u64) {
+    let rflags = rflags & !RFLAGS_ARITHMETIC;
+    match value {
+        Some(value) => (merge_register(old, value, size), rflags | RFLAGS_CF),
+        None => (merge_register(old, 0, size), rflags),
+    }
+}
+
+fn emulate(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+    info: vmexit::RandomInstructionInformation,
+    value: Option<u64>,
+) -> Result<()> {
+    let old = info.register.read(&vcpu.vmcs, guest_cpu)?;
+    let rflags = vcpu.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+    let (register, rflags) = complete(old, rflags, value, info.size);
+    info.register.write(register, &mut vcpu.vmcs, guest_cpu)?;
+    vcpu.vmcs
+        .write_field(vmcs::VmcsField::GuestRflags, rflags)?;
+    Ok(())
+}
+
+/// Emulate a RDRAND instruction with the host RDRAND instruction
+///
+/// If the host instruction fails, so does the emulated one (i.e., CF is
+/// cleared).
+pub fn emulate_rdrand(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+    info: vmexit::RandomInstructionInformation,
+) -> Result<()> {
+    let value = vcpu.entropy.rdrand_u64();
+    emulate(vcpu, guest_cpu, info, value)
+}
+
+/// Emulate a RDSEED instruction with the host RDSEED instruction
+///
+/// If the host instruction fails, so does the emulated one (i.e., CF is
+/// cleared). RDRAND is not used instead, as its output is not suitable
+/// for seeding.
+pub fn emulate_rdseed(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+    info: vmexit::RandomInstructionInformation,
+) -> Result<()> {
+    let value = vcpu.entropy.rdseed_u64();
+    emulate(vcpu, guest_cpu, info, value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_register() {
+        let old = 0x1122334455667788;
+        let value = 0xaabbccddeeff0011;
+        assert_eq!(merge_register(old, value, 2), 0x1122334455660011);
+        assert_eq!(merge_register(old, value, 4), 0x00000000eeff0011);
+        assert_eq!(merge_register(old, value, 8), value);
+    }
+
+    #[test]
+    fn test_complete() {
+        let old = 0x1122334455667788;
+        let rflags = 0x2 | 1 << 6 | 1 << 9;
+
+        let (register, flags) = complete(old, rflags, Some(0xabcd), 4);
+        assert_eq!(register, 0xabcd);
+        assert_eq!(flags, 0x2 | 1 << 9 | RFLAGS_CF);
+
+        // Failures clear the destination and CF
+        let (register, flags) = complete(old, rflags | RFLAGS_CF, None, 2);
+        assert_eq!(register, 0x1122334455660000);
+        assert_eq!(flags, 0x2 | 1 << 9);
+        assert_eq!(complete(old, rflags, None, 8).0, 0);
+    }
+}
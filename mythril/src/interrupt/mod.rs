@@ -1,6 +1,7 @@
 pub mod idt;
 
 pub mod vector {
+    pub const KEYBOARD: u8 = 35;
     pub const UART: u8 = 36;
     pub const TIMER: u8 = 48;
     pub const IPC: u8 = 49;
@@ -8,7 +9,11 @@ pub mod vector {
 
 pub mod gsi {
     pub const PIT: u32 = 0;
+    pub const KEYBOARD: u32 = 1;
     pub const UART: u32 = 4;
+    pub const RTC: u32 = 8;
+    pub const SCI: u32 = 9;
+    pub const MOUSE: u32 = 12;
 }
 
 pub unsafe fn enable_interrupts() {
@@ -13,8 +13,10 @@
 //! Structure entry in the Multiple APIC Descriptor Table.
 
 use crate::acpi::madt::{Ics, MADT};
+use crate::apic;
 use crate::error::{Error, Result};
 use crate::lock::ro_after_init::RoAfterInit;
+use crate::percore;
 use core::convert::TryFrom;
 use core::fmt;
 use core::ops::Range;
@@ -24,7 +26,8 @@ use arrayvec::ArrayVec;
 use spin::Mutex;
 
 const IOREDTBL_KNOWN_BITS_MASK: u64 = 0xff000000_0001ffff;
-const IOREDTBL_RW_MASK: u64 = 0xff000000_0001afff;
+/// The guest writable bits of an I/O Redirection Table Entry.
+pub(crate) const IOREDTBL_RW_MASK: u64 = 0xff000000_0001afff;
 const IOAPIC_VERSION: u8 = 0x11;
 const IOWIN_OFFSET: isize = 0x10;
 
@@ -83,6 +86,16 @@ pub fn map_gsi_vector(gsi: u32, vector: u8, apic_id: u8) -> Result<()> {
     }
 }
 
+/// Map a given GSI to an interrupt vector on the given host core
+pub fn map_gsi_vector_to_core(
+    gsi: u32,
+    vector: u8,
+    core: percore::CoreId,
+) -> Result<()> {
+    let apic_id = apic::host_apic_id(core).ok_or_else(|| Error::NotFound)?;
+    map_gsi_vector(gsi, vector, u8::try_from(apic_id.raw)?)
+}
+
 /// Initialize the system I/O APICS
 ///
 /// This function should only be called by the BSP
@@ -542,6 +555,46 @@ impl IoRedTblEntry {
         Ok(entry)
     }
 
+    /// The interrupt vector.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// The action the APIC should take on signal.
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+
+    /// The interpretation of the destination field.
+    pub fn destination_mode(&self) -> DestinationMode {
+        self.destination_mode
+    }
+
+    /// Polarity of the input signal.
+    pub fn pin_polarity(&self) -> PinPolarity {
+        self.pin_polarity
+    }
+
+    /// True if a level triggered interrupt is awaiting an EOI.
+    pub fn remote_irr(&self) -> bool {
+        self.remote_irr
+    }
+
+    /// Type of signal on the interrupt pin.
+    pub fn trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    /// True if the interrupt signal is masked.
+    pub fn is_masked(&self) -> bool {
+        self.interrupt_mask
+    }
+
+    /// The logical set of processors or APIC ID of the destination.
+    pub fn destination(&self) -> u8 {
+        self.destination
+    }
+
     /// Perform basic validity checks found in the table from section 3.2.4
     /// in the I/O APIC specification.
     fn validate(&self) -> Result<()> {
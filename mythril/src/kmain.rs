@@ -3,6 +3,8 @@ use crate::ap;
 use crate::apic;
 use crate::boot_info::BootInfo;
 use crate::config;
+use crate::console;
+use crate::error::{Error, Result};
 use crate::interrupt;
 use crate::ioapic;
 use crate::linux;
@@ -12,14 +14,18 @@ use crate::multiboot;
 use crate::multiboot2;
 use crate::percore;
 use crate::physdev;
+use crate::random;
+use crate::shmem;
+use crate::switch;
 use crate::time;
 use crate::vcpu;
 use crate::virtdev;
 use crate::vm;
 
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
-use log::{debug, info};
+use log::{debug, info, warn};
 use managed::ManagedMap;
 use spin::RwLock;
 
@@ -38,40 +44,59 @@ fn build_vm(
     vm_id: u32,
     cfg: &config::UserVmConfig,
     info: &BootInfo,
-    add_uart: bool,
+    ps2_keyboard: &mut Option<physdev::keyboard::Ps2Controller>,
 ) -> vm::VirtualMachine {
-    let physical_config = if add_uart == false {
-        vm::HostPhysicalDevices::default()
-    } else {
-        vm::HostPhysicalDevices {
-            serial: RwLock::new(Some(
-                physdev::com::Uart8250::new(0x3f8)
-                    .expect("Failed to create UART"),
-            )),
-            ps2_keyboard: RwLock::new(None),
+    let physical_config = vm::HostPhysicalDevices::default();
+    if cfg.ps2_keyboard {
+        match ps2_keyboard.take() {
+            Some(keyboard) => {
+                *physical_config.ps2_keyboard.write() = Some(keyboard)
+            }
+            None => warn!("The PS2 keyboard is unavailable for VM {}", vm_id),
         }
-    };
+    }
 
     let mut config =
         vm::VirtualMachineConfig::new(&cfg.cpus, cfg.memory, physical_config)
             .expect("Failed to create VirtualMachineConfig");
 
+    if let Some(nvram) = &cfg.nvram {
+        config.set_nvram_image(nvram.clone());
+    }
+
+    config.set_power_policy(vm::PowerPolicy {
+        on_reboot: cfg.on_reboot,
+        on_poweroff: cfg.on_poweroff,
+        on_crash: cfg.on_crash,
+    });
+    config.set_random_instruction_policy(cfg.random_instructions);
+
     let mut acpi = acpi::rsdp::RSDPBuilder::<[_; 1024]>::new(
         ManagedMap::Owned(BTreeMap::new()),
     );
 
-    let mut madt = acpi::madt::MADTBuilder::<[_; 8]>::new();
+    let mut madt = acpi::madt::MADTBuilder::<[_; 64]>::new();
     madt.set_ica(vm::GUEST_LOCAL_APIC_ADDR.as_u64() as u32);
 
-    for core in cfg.cpus.iter() {
-        madt.add_ics(acpi::madt::Ics::LocalApic {
-            // TODO(alschwalm): we should assign an actual APIC id here,
-            // instead of using the core id
-            apic_id: core.raw as u8,
-            apic_uid: 0,
-            flags: acpi::madt::LocalApicFlags::ENABLED,
-        })
-        .expect("Failed to add APIC to MADT");
+    // The virtual APIC id of each core is its index in the VM's core list
+    // (see `VirtualMachine::core_to_apic_id`)
+    for apic_id in 0..cfg.cpus.len() as u32 {
+        // APIC ids above 254 can only be described with an x2APIC
+        // structure (0xff is the xAPIC broadcast id)
+        let ics = if apic_id > 254 {
+            acpi::madt::Ics::LocalX2Apic {
+                x2apic_id: apic_id,
+                apic_proc_uid: apic_id,
+                flags: acpi::madt::LocalApicFlags::ENABLED,
+            }
+        } else {
+            acpi::madt::Ics::LocalApic {
+                apic_id: apic_id as u8,
+                apic_uid: apic_id as u8,
+                flags: acpi::madt::LocalApicFlags::ENABLED,
+            }
+        };
+        madt.add_ics(ics).expect("Failed to add APIC to MADT");
     }
     madt.add_ics(acpi::madt::Ics::IoApic {
         ioapic_id: 0,
@@ -80,8 +105,119 @@ fn build_vm(
     })
     .expect("Failed to add I/O APIC to MADT");
 
+    // The SCI is an active high, level triggered interrupt (unlike the
+    // other ISA interrupts)
+    madt.add_ics(acpi::madt::Ics::InterruptSourceOverride {
+        source: interrupt::gsi::SCI as u8,
+        gsi: interrupt::gsi::SCI,
+        flags: acpi::madt::MpsIntiFlags::ACTIVE_HIGH
+            | acpi::madt::MpsIntiFlags::LEVEL_TRIGGERED,
+    })
+    .expect("Failed to add SCI override to MADT");
+
     acpi.add_sdt(madt).unwrap();
 
+    let mut hpet = acpi::hpet::HPETBuilder::new(
+        virtdev::hpet::HPET_BASE,
+        virtdev::hpet::HPET_COMPARATOR_COUNT as u8,
+    );
+    hpet.set_minimum_tick(virtdev::hpet::HPET_MIN_TICK);
+    acpi.add_sdt(hpet).unwrap();
+
+    let mut mcfg = acpi::mcfg::MCFGBuilder::<[_; 1]>::new();
+    mcfg.add_allocation(acpi::mcfg::McfgAllocation {
+        base_address: virtdev::pci::PCI_ECAM_BASE,
+        segment_group: 0,
+        start_bus: 0,
+        end_bus: (virtdev::pci::PCI_ECAM_BUS_COUNT - 1) as u8,
+    })
+    .expect("Failed to add ECAM allocation to MCFG");
+    acpi.add_sdt(mcfg).unwrap();
+
+    // The first two PCI devices are part of the chipset
+    let mut pci_addresses =
+        (2..32).map(|device| virtdev::pci::PciBdf::new(0, device, 0));
+    if cfg.virtio_console_ports > 0 {
+        let console = virtdev::virtio::console::VirtioConsole::new(
+            vm_id,
+            cfg.virtio_console_ports,
+        )
+        .expect("Failed to make virtio console");
+        config.add_pci_device(
+            pci_addresses.next().expect("No free PCI device address"),
+            Box::new(
+                virtdev::virtio::VirtioPciDevice::new(Box::new(console))
+                    .expect("Failed to make virtio console PCI device"),
+            ),
+        );
+    }
+
+    for disk in &cfg.disks {
+        let image = info
+            .find_module(&disk.image)
+            .expect("Disk images are validated with the configuration")
+            .data();
+        let block = match disk.mode {
+            config::DiskMode::ReadOnly => {
+                virtdev::virtio::block::VirtioBlock::read_only(
+                    &disk.image,
+                    image,
+                )
+            }
+            config::DiskMode::CopyOnWrite => {
+                virtdev::virtio::block::VirtioBlock::copy_on_write(
+                    &disk.image,
+                    image,
+                )
+            }
+        };
+        config.add_pci_device(
+            pci_addresses.next().expect("No free PCI device address"),
+            Box::new(
+                virtdev::virtio::VirtioPciDevice::new(Box::new(block))
+                    .expect("Failed to make virtio block PCI device"),
+            ),
+        );
+    }
+
+    for (port, mac) in switch::vm_ports(vm_id) {
+        let net = virtdev::virtio::net::VirtioNet::new(port, mac);
+        config.add_pci_device(
+            pci_addresses.next().expect("No free PCI device address"),
+            Box::new(
+                virtdev::virtio::VirtioPciDevice::new(Box::new(net))
+                    .expect("Failed to make virtio net PCI device"),
+            ),
+        );
+    }
+
+    for mapping in shmem::vm_regions(vm_id) {
+        config.map_shared_memory(
+            mapping.address,
+            mapping.frames,
+            mapping.readonly,
+        );
+        config.add_pci_device(
+            pci_addresses.next().expect("No free PCI device address"),
+            Box::new(
+                virtdev::ivshmem::IvshmemDevice::new(&mapping)
+                    .expect("Failed to make ivshmem device"),
+            ),
+        );
+    }
+
+    if cfg.virtio_rng {
+        let rng =
+            virtdev::virtio::rng::VirtioRng::new(random::HostEntropy::new());
+        config.add_pci_device(
+            pci_addresses.next().expect("No free PCI device address"),
+            Box::new(
+                virtdev::virtio::VirtioPciDevice::new(Box::new(rng))
+                    .expect("Failed to make virtio rng PCI device"),
+            ),
+        );
+    }
+
     let virtual_devices = &mut config.virtual_devices;
 
     virtual_devices.push(RwLock::new(
@@ -92,7 +228,8 @@ fn build_vm(
     ));
 
     virtual_devices.push(RwLock::new(virtdev::DynamicVirtualDevice::Uart(
-        virtdev::com::Uart8250::new(0x3F8).expect("Failed to make Uart"),
+        virtdev::com::Uart8250::new(console::GUEST_CONSOLE_PORT)
+            .expect("Failed to make Uart"),
     )));
 
     let mut fw_cfg_builder = virtdev::qemu_fw_cfg::QemuFwCfgBuilder::new();
@@ -152,6 +289,25 @@ pub extern "C" fn ap_entry(ap_data: &ap::ApData) -> ! {
     vcpu::mp_entry_point()
 }
 
+// Direct the physical keyboard interrupt to the BSP of the VM that owns the
+// keyboard (if any)
+fn route_ps2_keyboard() -> Result<()> {
+    let vms = vm::virtual_machines();
+    for vm_id in 0..vms.count() {
+        let vm = vms.get_by_vm_id(vm_id).ok_or_else(|| Error::NotFound)?;
+        if vm.host_devices.ps2_keyboard.read().is_none() {
+            continue;
+        }
+
+        return ioapic::map_gsi_vector_to_core(
+            interrupt::gsi::KEYBOARD,
+            interrupt::vector::KEYBOARD,
+            vm.bsp_id(),
+        );
+    }
+    Ok(())
+}
+
 static LOGGER: logger::DirectLogger = logger::DirectLogger::new();
 
 #[no_mangle]
@@ -185,7 +341,8 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
     // Calibrate the global time source
     time::init_global_time().expect("Failed to init global timesource");
 
-    // physdev::keyboard::Ps2Controller::init().expect("Failed to init ps2 controller");
+    // Record the wall-clock time, which is used to seed the guest RTCs
+    physdev::rtc::init_boot_time();
 
     // If the boot method provided an RSDT, use that one. Otherwise, search the
     // BIOS areas for it.
@@ -214,14 +371,13 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
 
     percore::init_sections(apic_ids.len())
         .expect("Failed to initialize per-core sections");
+    apic::init_host_apic_ids(apic_ids.clone());
 
     // Initialize the BSP local APIC
     let local_apic =
         apic::LocalApic::init().expect("Failed to initialize local APIC");
 
     ioapic::init_ioapics(&madt).expect("Failed to initialize IOAPICs");
-    ioapic::map_gsi_vector(interrupt::gsi::UART, interrupt::vector::UART, 0)
-        .expect("Failed to map com0 gsi");
 
     let raw_cfg = boot_info
         .find_module("mythril.cfg")
@@ -232,17 +388,49 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
         .expect("Failed to parse 'mythril.cfg'");
 
     debug!("mythril.cfg: {:?}", mythril_cfg);
+    mythril_cfg
+        .validate_modules(&boot_info)
+        .expect("Invalid 'mythril.cfg'");
+
+    // Only take over the physical keyboard if a guest will use it
+    let mut ps2_keyboard = None;
+    if mythril_cfg.vms.iter().any(|vm| vm.ps2_keyboard) {
+        match physdev::keyboard::Ps2Controller::init() {
+            Ok(keyboard) => ps2_keyboard = Some(keyboard),
+            Err(e) => warn!("Failed to init ps2 controller: {:?}", e),
+        }
+    }
+
+    switch::init_switch(&mythril_cfg.vms)
+        .expect("Failed to initialize the inter-VM switches");
+    shmem::init_shared_memory(&mythril_cfg)
+        .expect("Failed to initialize the shared memory regions");
+
+    match random::EntropySource::detect() {
+        random::EntropySource::TscJitter => {
+            warn!("No hardware entropy source, using TSC jitter")
+        }
+        source => info!("Host entropy source: {:?}", source),
+    }
 
     let vms = mythril_cfg
         .vms
         .into_iter()
         .enumerate()
         .map(|(num, vm_cfg)| {
-            build_vm(num as u32, &vm_cfg, &boot_info, num == 0)
+            build_vm(num as u32, &vm_cfg, &boot_info, &mut ps2_keyboard)
         });
     vm::init_virtual_machines(vms)
         .expect("Failed to initialize early virtual machine state");
 
+    route_ps2_keyboard().expect("Failed to route the ps2 keyboard");
+
+    console::init_console(
+        physdev::com::Uart8250::new(0x3f8).expect("Failed to create UART"),
+        &mythril_cfg.console,
+    )
+    .expect("Failed to initialize console");
+
     debug!("AP_STARTUP address: 0x{:x}", AP_STARTUP_ADDR);
 
     for (idx, apic_id) in apic_ids.into_iter().enumerate() {
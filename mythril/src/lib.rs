@@ -32,6 +32,8 @@ pub mod apic;
 pub mod boot_info;
 /// User configuration format
 pub mod config;
+/// Multiplexing of guest serial consoles
+pub mod console;
 pub mod emulate;
 pub mod error;
 pub mod global_alloc;
@@ -46,7 +48,13 @@ pub mod multiboot;
 pub mod multiboot2;
 pub mod percore;
 pub mod physdev;
+/// Host entropy sources
+pub mod random;
 pub mod registers;
+/// Inter-VM shared memory
+pub mod shmem;
+/// Inter-VM ethernet switching
+pub mod switch;
 pub mod time;
 pub mod tsc;
 pub mod vcpu;
@@ -12,9 +12,26 @@ pub mod SerialOffset {
     pub const IER: u16 = 1;
     pub const DLH: u16 = 1;
     pub const IIR: u16 = 2;
+    pub const FCR: u16 = 2;
     pub const LCR: u16 = 3;
+    pub const MCR: u16 = 4;
     pub const LSR: u16 = 5;
     pub const MSR: u16 = 6;
+    pub const SCR: u16 = 7;
+}
+
+/// Interrupt identifiers reported in bits 0-3 of the IIR. Bits 6 and 7
+/// are set when the FIFOs are enabled.
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+pub mod InterruptId {
+    pub const MODEM_STATUS: u8 = 0b0000;
+    pub const NONE: u8 = 0b0001;
+    pub const THR_EMPTY: u8 = 0b0010;
+    pub const RECV_DATA_AVAIL: u8 = 0b0100;
+    pub const RECV_LINE_STATUS: u8 = 0b0110;
+    pub const CHAR_TIMEOUT: u8 = 0b1100;
+    pub const FIFOS_ENABLED: u8 = 0b1100_0000;
 }
 
 bitflags! {
@@ -43,6 +60,42 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct FcrFlags: u8 {
+        const ENABLE_FIFO = 1 << 0;
+        const CLEAR_RECEIVE_FIFO = 1 << 1;
+        const CLEAR_TRANSMIT_FIFO = 1 << 2;
+        const DMA_MODE = 1 << 3;
+        const RESERVED_1 = 1 << 4;
+        const RESERVED_2 = 1 << 5;
+        const TRIGGER_LEVEL_LOW = 1 << 6;
+        const TRIGGER_LEVEL_HIGH = 1 << 7;
+    }
+}
+
+bitflags! {
+    pub struct McrFlags: u8 {
+        const DATA_TERMINAL_READY = 1 << 0;
+        const REQUEST_TO_SEND = 1 << 1;
+        const AUX_OUTPUT_1 = 1 << 2;
+        const AUX_OUTPUT_2 = 1 << 3;
+        const LOOPBACK_MODE = 1 << 4;
+    }
+}
+
+bitflags! {
+    pub struct MsrFlags: u8 {
+        const DELTA_CLEAR_TO_SEND = 1 << 0;
+        const DELTA_DATA_SET_READY = 1 << 1;
+        const TRAILING_EDGE_RING_INDICATOR = 1 << 2;
+        const DELTA_DATA_CARRIER_DETECT = 1 << 3;
+        const CLEAR_TO_SEND = 1 << 4;
+        const DATA_SET_READY = 1 << 5;
+        const RING_INDICATOR = 1 << 6;
+        const DATA_CARRIER_DETECT = 1 << 7;
+    }
+}
+
 pub struct Uart8250 {
     base: u16,
 }
@@ -14,27 +14,45 @@ const PS2_STATUS_PORT: u16 = 0x64;
 const PS2_COMMAND_PORT: u16 = 0x64;
 
 bitflags! {
-    struct Ps2StatusFlags: u8 {
+    /// The bits of the PS2 controller status register
+    pub struct Ps2StatusFlags: u8 {
+        /// Data is available in the output buffer
         const OUTPUT_BUFFER_FULL = 1 << 0;
+        /// The controller has not yet consumed the input buffer
         const INPUT_BUFFER_FULL = 1 << 1;
+        /// The system flag (set once the controller passes its self test)
         const SELF_TEST_PASS = 1 << 2;
+        /// The last write was a command (rather than data)
         const INPUT_FOR_CONTROLLER = 1 << 3;
-        const RESERVED_1 = 1 << 4;
-        const RESERVED_2 = 1 << 5;
+        /// The keyboard is not inhibited by the keyboard lock
+        const KEYBOARD_UNLOCKED = 1 << 4;
+        /// The data in the output buffer is from the second port
+        const SECOND_PORT_OUTPUT_FULL = 1 << 5;
+        /// A timeout occurred communicating with a device
         const TIMEOUT_ERROR = 1 << 6;
+        /// A parity error occurred communicating with a device
         const PARITY_ERROR = 1 << 7;
     }
 }
 
 bitflags! {
-    struct Ps2ConfigurationFlags: u8 {
+    /// The bits of the PS2 controller configuration (command) byte
+    pub struct Ps2ConfigurationFlags: u8 {
+        /// Raise an interrupt when the first port provides data
         const FIRST_PORT_INTERRUPT = 1 << 0;
+        /// Raise an interrupt when the second port provides data
         const SECOND_PORT_INTERRUPT = 1 << 1;
+        /// The system flag (the system passed POST)
         const SYSTEM_POST = 1 << 2;
+        /// Reserved
         const RESERVED_1 = 1 << 3;
+        /// Disable the first port
         const FIRST_PORT_CLOCK_DISABLED = 1 << 4;
+        /// Disable the second port
         const SECOND_PORT_CLOCK_DISABLED = 1 << 5;
+        /// Translate the first port scancodes to scancode set 1
         const FIRST_PORT_TRANSLATION = 1 << 6;
+        /// Reserved
         const RESERVED_2 = 1 << 7;
     }
 }
@@ -136,6 +154,22 @@ impl Ps2Controller {
         unsafe { inb(PS2_DATA_PORT) }
     }
 
+    /// Read a pending byte from the first (keyboard) port, if there is one
+    ///
+    /// Any data from the second port is discarded.
+    pub fn try_read_keyboard(&mut self) -> Option<u8> {
+        loop {
+            let status = self.read_status_port();
+            if !status.contains(Ps2StatusFlags::OUTPUT_BUFFER_FULL) {
+                return None;
+            }
+            let data = unsafe { inb(PS2_DATA_PORT) };
+            if !status.contains(Ps2StatusFlags::SECOND_PORT_OUTPUT_FULL) {
+                return Some(data);
+            }
+        }
+    }
+
     fn write_data_port(&mut self, data: u8) {
         self.wait_write();
         unsafe {
@@ -0,0 +1,215 @@
+#![deny(missing_docs)]
+
+//! # Physical CMOS RTC Support
+//!
+//! This module provides support for reading the wall-clock time from
+//! the physical MC146818 compatible real time clock, and the calendar
+//! calculations needed to keep that time.
+
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::time;
+use x86::io::{inb, outb};
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+const RTC_CENTURY: u8 = 0x32;
+
+const STATUS_A_UIP: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+static BOOT_TIME: RoAfterInit<(DateTime, time::Instant)> =
+    RoAfterInit::uninitialized();
+
+/// A calendar date and time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    /// The full year (e.g., 2021)
+    pub year: u32,
+    /// The month (1-12)
+    pub month: u8,
+    /// The day of the month (1-31)
+    pub day: u8,
+    /// The hour (0-23)
+    pub hour: u8,
+    /// The minute (0-59)
+    pub minute: u8,
+    /// The second (0-59)
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Create a `DateTime` from the number of seconds since the unix epoch
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        // See http://howardhinnant.github.io/date_algorithms.html
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+            - day_of_era / 146096)
+            / 365;
+        let day_of_year = day_of_era
+            - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        let time = seconds % SECONDS_PER_DAY;
+        Self {
+            year: year as u32,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// The number of seconds since the unix epoch
+    pub fn unix_seconds(&self) -> u64 {
+        let month = self.month.max(1).min(12) as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * mp + 2) / 5 + self.day.max(1) as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4
+            - year_of_era / 100
+            + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        days.max(0) as u64 * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// The day of the week (1-7, with Sunday as 1)
+    pub fn day_of_week(&self) -> u8 {
+        // The unix epoch was a Thursday
+        ((self.unix_seconds() / SECONDS_PER_DAY + 4) % 7 + 1) as u8
+    }
+}
+
+// The high bit of the address (which masks NMIs) is left clear, so NMIs
+// remain enabled after the read
+unsafe fn read_register(register: u8) -> u8 {
+    outb(CMOS_ADDRESS_PORT, register);
+    inb(CMOS_DATA_PORT)
+}
+
+unsafe fn read_raw_time() -> [u8; 7] {
+    while read_register(RTC_STATUS_A) & STATUS_A_UIP != 0 {
+        crate::lock::relax_cpu();
+    }
+    [
+        read_register(RTC_SECONDS),
+        read_register(RTC_MINUTES),
+        read_register(RTC_HOURS),
+        read_register(RTC_DAY_OF_MONTH),
+        read_register(RTC_MONTH),
+        read_register(RTC_YEAR),
+        read_register(RTC_CENTURY),
+    ]
+}
+
+/// Read the current time from the physical RTC
+pub unsafe fn read_host_time() -> DateTime {
+    // Read until two consecutive reads agree, so an update cycle can't
+    // produce an inconsistent time.
+    let mut raw = read_raw_time();
+    loop {
+        let next = read_raw_time();
+        if next == raw {
+            break;
+        }
+        raw = next;
+    }
+
+    let status_b = read_register(RTC_STATUS_B);
+    let decode = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        }
+    };
+
+    let mut hour = decode(raw[2] & !HOUR_PM);
+    if status_b & STATUS_B_24H == 0 {
+        hour %= 12;
+        if raw[2] & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    // Not all platforms have a century register, so assume the 21st
+    // century if it isn't sensible.
+    let century = match decode(raw[6]) {
+        century @ 19..=21 => century as u32,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw[5]) as u32,
+        month: decode(raw[4]),
+        day: decode(raw[3]),
+        hour,
+        minute: decode(raw[1]),
+        second: decode(raw[0]),
+    }
+}
+
+/// Record the time of the physical RTC at boot
+///
+/// This must be called after the global system `TimeSource` has been
+/// initialized and may only be called by the BSP.
+pub unsafe fn init_boot_time() {
+    RoAfterInit::init(&BOOT_TIME, (read_host_time(), time::now()));
+}
+
+/// The time of the physical RTC at boot and the instant it was read (if
+/// it has been recorded)
+pub fn boot_time() -> Option<(DateTime, time::Instant)> {
+    if RoAfterInit::is_initialized(&BOOT_TIME) {
+        Some(*BOOT_TIME)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_calendar_conversion() {
+        let leap_day = DateTime {
+            year: 2000,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+        };
+        assert_eq!(leap_day.unix_seconds(), 951827696);
+        assert_eq!(DateTime::from_unix_seconds(951827696), leap_day);
+
+        // The unix epoch was a Thursday and 2000-02-29 was a Tuesday
+        assert_eq!(DateTime::from_unix_seconds(0).day_of_week(), 5);
+        assert_eq!(leap_day.day_of_week(), 3);
+    }
+}
@@ -0,0 +1,209 @@
+#![deny(missing_docs)]
+
+//! # Host Entropy
+//!
+//! Random numbers for guests (e.g., for a virtio entropy device) are taken
+//! from the RDSEED and RDRAND instructions when the host processor supports
+//! them. RDSEED is preferred, as its output is not expanded by a DRBG, but
+//! RDRAND is used if RDSEED is temporarily exhausted.
+//!
+//! Processors without either instruction fall back to sampling the jitter
+//! in the duration of short TSC measured operations. This is much slower,
+//! and is not a cryptographically strong source, but it ensures guests that
+//! wait for entropy can make progress.
+//!
+//! Emulated RDRAND and RDSEED instructions only use the corresponding host
+//! instruction (see `HostEntropy::rdrand_u64` and `rdseed_u64`), so guests
+//! are told when no value is available rather than being given output of a
+//! weaker source.
+
+use core::arch::x86_64::{_rdrand64_step, _rdseed64_step};
+use raw_cpuid::CpuId;
+
+// The number of attempts made to read RDRAND (as recommended by Intel) or
+// RDSEED before falling back to another source
+const HARDWARE_RETRIES: usize = 10;
+
+// The number of TSC deltas mixed in to each word of jitter output
+const JITTER_SAMPLES: usize = 64;
+
+/// The source used to generate random numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntropySource {
+    /// The RDSEED instruction (and RDRAND, if RDSEED is exhausted)
+    RdSeed,
+
+    /// The RDRAND instruction
+    RdRand,
+
+    /// The jitter of TSC measurements
+    TscJitter,
+}
+
+impl EntropySource {
+    /// Returns the best source supported by the host processor
+    pub fn detect() -> Self {
+        let cpuid = CpuId::new();
+        let rdseed = cpuid
+            .get_extended_feature_info()
+            .map_or(false, |info| info.has_rdseed());
+        let rdrand = cpuid
+            .get_feature_info()
+            .map_or(false, |info| info.has_rdrand());
+
+        if rdseed && rdrand {
+            EntropySource::RdSeed
+        } else if rdrand {
+            EntropySource::RdRand
+        } else {
+            EntropySource::TscJitter
+        }
+    }
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..HARDWARE_RETRIES {
+        if _rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..HARDWARE_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// The finalizer of the SplitMix64 generator, which spreads every bit of
+// the input over the output
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+/// A generator of random numbers from the host processor
+pub struct HostEntropy {
+    source: EntropySource,
+
+    // The pool the TSC jitter is accumulated in
+    jitter_state: u64,
+}
+
+impl HostEntropy {
+    /// Create a generator using the best source supported by the host
+    pub fn new() -> Self {
+        Self::with_source(EntropySource::detect())
+    }
+
+    /// Create a generator using the given source
+    ///
+    /// The source must be supported by the host processor.
+    pub fn with_source(source: EntropySource) -> Self {
+        Self {
+            source,
+            jitter_state: unsafe { x86::time::rdtsc() },
+        }
+    }
+
+    /// The source used by this generator
+    pub fn source(&self) -> EntropySource {
+        self.source
+    }
+
+    fn jitter_u64(&mut self) -> u64 {
+        for _ in 0..JITTER_SAMPLES {
+            let start = unsafe { x86::time::rdtsc() };
+
+            // The duration of the mixing varies with the state of the caches
+            // and pipeline, which is the entropy collected
+            self.jitter_state = mix(self.jitter_state ^ start);
+            let delta = unsafe { x86::time::rdtsc() }.wrapping_sub(start);
+            self.jitter_state =
+                self.jitter_state.rotate_left(7) ^ delta.wrapping_mul(31);
+        }
+        mix(self.jitter_state)
+    }
+
+    /// Returns a value from the host RDSEED instruction, or `None` if it is
+    /// not supported or is exhausted
+    pub fn rdseed_u64(&self) -> Option<u64> {
+        match self.source {
+            EntropySource::RdSeed => unsafe { rdseed() },
+            EntropySource::RdRand | EntropySource::TscJitter => None,
+        }
+    }
+
+    /// Returns a value from the host RDRAND instruction, or `None` if it is
+    /// not supported or fails
+    pub fn rdrand_u64(&self) -> Option<u64> {
+        match self.source {
+            EntropySource::RdSeed | EntropySource::RdRand => unsafe {
+                rdrand()
+            },
+            EntropySource::TscJitter => None,
+        }
+    }
+
+    /// Returns a random 64 bit value, falling back to TSC jitter if the
+    /// hardware sources fail
+    pub fn next_u64(&mut self) -> u64 {
+        match self.rdseed_u64().or_else(|| self.rdrand_u64()) {
+            Some(value) => value,
+            None => self.jitter_u64(),
+        }
+    }
+
+    /// Fill `data` with random bytes
+    pub fn fill_bytes(&mut self, data: &mut [u8]) {
+        for chunk in data.chunks_mut(8) {
+            let value = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
+
+impl Default for HostEntropy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_source(source: EntropySource) {
+        let mut entropy = HostEntropy::with_source(source);
+        let first = entropy.next_u64();
+        assert!((0..4).any(|_| entropy.next_u64() != first));
+
+        let mut data = [0u8; 13];
+        entropy.fill_bytes(&mut data);
+        assert!(data.iter().any(|byte| *byte != 0));
+    }
+
+    #[test]
+    fn test_detected_source() {
+        check_source(EntropySource::detect());
+    }
+
+    #[test]
+    fn test_tsc_jitter() {
+        check_source(EntropySource::TscJitter);
+
+        // Jitter is never reported as hardware output
+        let entropy = HostEntropy::with_source(EntropySource::TscJitter);
+        assert_eq!(entropy.rdseed_u64(), None);
+        assert_eq!(entropy.rdrand_u64(), None);
+    }
+}
@@ -0,0 +1,304 @@
+#![deny(missing_docs)]
+
+//! # Inter-VM Shared Memory
+//!
+//! Each named region declared in the configuration is a set of host frames
+//! that is mapped in to the guest physical address space of several VMs
+//! (the 'peers' of the region). Every peer is given an ivshmem compatible
+//! PCI device (see `virtdev::ivshmem`), which describes the region and has
+//! a doorbell register that interrupts another peer.
+//!
+//! The address of a region is fixed by the configuration for each peer. It
+//! must be within the PCI memory window (as it is decoded by a BAR of the
+//! ivshmem device) and aligned to the size of the region.
+
+use crate::config::UserConfig;
+use crate::error::{Error, Result};
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::memory::{GuestPhysAddr, HostPhysAddr, HostPhysFrame, Raw4kPage};
+use crate::virtdev::pci::{PCI_MMIO_END, PCI_MMIO_START};
+use crate::vm;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The identifier of a shared memory region
+pub type RegionId = usize;
+
+/// The identifier of a peer within a region (the ivshmem 'IVPosition')
+pub type PeerId = u16;
+
+static SHARED_MEMORY: RoAfterInit<Vec<Region>> = RoAfterInit::uninitialized();
+
+struct Peer {
+    vm_id: u32,
+    address: GuestPhysAddr,
+    readonly: bool,
+}
+
+struct Region {
+    name: String,
+    size: u64,
+    frames: Vec<HostPhysFrame>,
+    peers: Vec<Peer>,
+}
+
+/// A shared memory region, as seen by one of its peers
+pub struct SharedMemoryMapping {
+    /// The region that is mapped
+    pub region: RegionId,
+
+    /// The id of the peer within the region
+    pub peer: PeerId,
+
+    /// The guest physical address of the region
+    pub address: GuestPhysAddr,
+
+    /// The size of the region in bytes
+    pub size: u64,
+
+    /// Whether the peer can only read the region
+    pub readonly: bool,
+
+    /// The host frames of the region
+    pub frames: &'static [HostPhysFrame],
+}
+
+impl Region {
+    // Validate the configuration of the region, without allocating it
+    fn peers(config: &UserConfig, index: usize) -> Result<Vec<Peer>> {
+        let region = &config.shared_memory[index];
+        let invalid = |msg: &str| {
+            Error::InvalidValue(format!(
+                "Shared memory region '{}': {}",
+                region.name, msg
+            ))
+        };
+
+        if config.shared_memory[..index]
+            .iter()
+            .any(|other| other.name == region.name)
+        {
+            return Err(invalid("duplicate name"));
+        }
+        if !region.size.is_power_of_two()
+            || region.size < HostPhysFrame::SIZE as u64
+        {
+            return Err(invalid("size must be a power of two of at least 4KB"));
+        }
+        if region.vms.len() > PeerId::MAX as usize + 1 {
+            return Err(invalid("too many virtual machines"));
+        }
+
+        let mut peers: Vec<Peer> = vec![];
+        for mapping in region.vms.iter() {
+            let vm = config
+                .vms
+                .get(mapping.vm as usize)
+                .ok_or_else(|| invalid("no such virtual machine"))?;
+            if peers.iter().any(|peer| peer.vm_id == mapping.vm) {
+                return Err(invalid("mapped twice in the same machine"));
+            }
+
+            let start = mapping.address;
+            if start % region.size != 0 {
+                return Err(invalid("address is not aligned to the size"));
+            }
+            let in_window = match start.checked_add(region.size - 1) {
+                Some(end) => start >= PCI_MMIO_START && end <= PCI_MMIO_END,
+                None => false,
+            };
+            if !in_window {
+                return Err(invalid("not within the PCI memory window"));
+            }
+            if start < vm.memory << 20 {
+                return Err(invalid("overlaps guest memory"));
+            }
+
+            // Other regions of the same machine must not overlap
+            for other in config.shared_memory[..index].iter() {
+                for other_mapping in other.vms.iter() {
+                    if other_mapping.vm == mapping.vm
+                        && other_mapping.address < start + region.size
+                        && start < other_mapping.address + other.size
+                    {
+                        return Err(invalid("overlaps another region"));
+                    }
+                }
+            }
+
+            peers.push(Peer {
+                vm_id: mapping.vm,
+                address: GuestPhysAddr::new(start),
+                readonly: mapping.readonly,
+            });
+        }
+        Ok(peers)
+    }
+
+    fn new(config: &UserConfig, index: usize) -> Result<Self> {
+        let peers = Self::peers(config, index)?;
+        let region = &config.shared_memory[index];
+        let mut frames = vec![];
+        for _ in 0..region.size / HostPhysFrame::SIZE as u64 {
+            let page = Box::into_raw(Box::new(Raw4kPage::default()));
+            frames.push(HostPhysFrame::from_start_address(HostPhysAddr::new(
+                page as u64,
+            ))?);
+        }
+        Ok(Self {
+            name: region.name.clone(),
+            size: region.size,
+            frames,
+            peers,
+        })
+    }
+}
+
+/// Allocate the shared memory regions described by the configuration
+pub unsafe fn init_shared_memory(config: &UserConfig) -> Result<()> {
+    let regions = (0..config.shared_memory.len())
+        .map(|index| Region::new(config, index))
+        .collect::<Result<Vec<_>>>()?;
+    RoAfterInit::init(&SHARED_MEMORY, regions);
+    Ok(())
+}
+
+/// Returns the shared memory regions mapped in to the given VM
+pub fn vm_regions(vm_id: u32) -> impl Iterator<Item = SharedMemoryMapping> {
+    SHARED_MEMORY
+        .iter()
+        .enumerate()
+        .flat_map(move |(id, region)| {
+            region
+                .peers
+                .iter()
+                .enumerate()
+                .filter_map(move |(peer, info)| {
+                    if info.vm_id != vm_id {
+                        return None;
+                    }
+                    Some(SharedMemoryMapping {
+                        region: id,
+                        peer: peer as PeerId,
+                        address: info.address,
+                        size: region.size,
+                        readonly: info.readonly,
+                        frames: &region.frames,
+                    })
+                })
+        })
+}
+
+/// Interrupt the given peer of a region with the given (ivshmem) vector
+///
+/// Doorbells for peers that do not exist are ignored.
+pub fn ring_doorbell(region: RegionId, peer: PeerId, vector: u16) {
+    let info = match SHARED_MEMORY
+        .get(region)
+        .and_then(|region| region.peers.get(peer as usize))
+    {
+        Some(info) => info,
+        None => return,
+    };
+
+    let msg = vm::VirtualMachineMsg::SharedMemoryDoorbell { region, vector };
+    if let Err(err) = vm::virtual_machines().send_msg(msg, info.vm_id, true) {
+        warn!(
+            "Failed to ring doorbell of peer {} of shared memory '{}': {:?}",
+            peer, SHARED_MEMORY[region].name, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config(shared_memory: &str) -> UserConfig {
+        let vm = r#"{"memory": 256, "kernel": "", "initramfs": "",
+                     "cmdline": "", "cpus": []}"#;
+        serde_json::from_str(&format!(
+            r#"{{"version": 1, "vms": [{}, {}],
+                "shared_memory": {}}}"#,
+            vm, vm, shared_memory
+        ))
+        .unwrap()
+    }
+
+    fn validate(shared_memory: &str) -> Result<()> {
+        let config = test_config(shared_memory);
+        for index in 0..config.shared_memory.len() {
+            Region::peers(&config, index)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_regions() {
+        let config = test_config(
+            r#"[{"name": "a", "size": 4096, "vms": [
+                    {"vm": 0, "address": 3221225472},
+                    {"vm": 1, "address": 3758096384, "readonly": true}]},
+                {"name": "b", "size": 8192, "vms": [
+                    {"vm": 1, "address": 3221225472}]}]"#,
+        );
+        let peers = Region::peers(&config, 0).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[1].vm_id, 1);
+        assert_eq!(peers[1].address, GuestPhysAddr::new(0xe0000000));
+        assert!(peers[1].readonly);
+        assert!(Region::peers(&config, 1).is_ok());
+
+        let region = Region::new(&config, 1).unwrap();
+        assert_eq!(region.frames.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_regions() {
+        // Not a power of two
+        assert!(validate(
+            r#"[{"name": "a", "size": 12288, "vms": [
+                    {"vm": 0, "address": 3221225472}]}]"#
+        )
+        .is_err());
+
+        // Not aligned to the size
+        assert!(validate(
+            r#"[{"name": "a", "size": 8192, "vms": [
+                    {"vm": 0, "address": 3221229568}]}]"#
+        )
+        .is_err());
+
+        // Outside the PCI memory window
+        assert!(validate(
+            r#"[{"name": "a", "size": 4096, "vms": [
+                    {"vm": 0, "address": 1048576}]}]"#
+        )
+        .is_err());
+
+        // No such VM
+        assert!(validate(
+            r#"[{"name": "a", "size": 4096, "vms": [
+                    {"vm": 2, "address": 3221225472}]}]"#
+        )
+        .is_err());
+
+        // Overlapping regions in the same VM
+        assert!(validate(
+            r#"[{"name": "a", "size": 8192, "vms": [
+                    {"vm": 0, "address": 3221225472}]},
+                {"name": "b", "size": 4096, "vms": [
+                    {"vm": 0, "address": 3221229568}]}]"#
+        )
+        .is_err());
+
+        // Duplicate names
+        assert!(validate(
+            r#"[{"name": "a", "size": 4096, "vms": []},
+                {"name": "a", "size": 4096, "vms": []}]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,356 @@
+#![deny(missing_docs)]
+
+//! # Inter-VM Ethernet Switching
+//!
+//! Each virtual NIC declared in the configuration is a port on a named
+//! switch. Frames transmitted by a guest are forwarded to the ports of the
+//! same switch by destination MAC address (broadcast and multicast frames
+//! are sent to every other port). Frames for unknown addresses are dropped,
+//! as the address of every port is fixed by the configuration.
+//!
+//! Frames are delivered to the BSP of the receiving VM as a
+//! `VirtualMachineMsg::NetworkFrame`. The number of frames in flight to each
+//! port is limited, so one busy port can not starve the other ports of a VM.
+//! The total in flight to each VM (regardless of how many NICs it has) is
+//! also limited to part of the message queue of its BSP, so a busy network
+//! can not fill the queue and cause other messages, like interrupts, to be
+//! lost. Frames over either limit are dropped.
+
+use crate::config::UserVmConfig;
+use crate::error::{Error, Result};
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::vm;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The identifier of a switch port (unique across all switches)
+pub type PortId = usize;
+
+/// The length of an ethernet header
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// The largest frame forwarded by a switch (excluding the frame check
+/// sequence, but allowing for a VLAN tag)
+pub const MAX_FRAME_SIZE: usize = 1518;
+
+// The number of frames that may be waiting to be received by each port
+const MAX_PENDING_FRAMES: u32 = 32;
+
+// The number of messages in the queue of each core that are reserved for
+// messages other than frames
+const RESERVED_MESSAGES: usize = 36;
+
+// The number of frames that may be waiting to be received by each VM
+const MAX_PENDING_VM_FRAMES: u32 =
+    (vm::MAX_PENDING_MSG - RESERVED_MESSAGES) as u32;
+
+static SWITCH: RoAfterInit<Switch> = RoAfterInit::uninitialized();
+
+/// An ethernet MAC address
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// The broadcast address
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    /// Parse an address of the form `52:54:00:12:34:56`
+    pub fn parse(value: &str) -> Result<Self> {
+        let invalid =
+            || Error::InvalidValue(format!("Invalid MAC address: '{}'", value));
+
+        let mut address = [0u8; 6];
+        let mut parts = value.split(':');
+        for byte in address.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(MacAddress(address))
+    }
+
+    /// Returns whether this is a multicast (or broadcast) address
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+}
+
+impl fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
+        )
+    }
+}
+
+struct SwitchPort {
+    switch: String,
+    vm_id: u32,
+    mac: MacAddress,
+
+    // The number of frames sent to this port that have not been received
+    pending: AtomicU32,
+}
+
+struct Switch {
+    ports: Vec<SwitchPort>,
+
+    // The number of frames sent to each VM that have not been received
+    vm_pending: Vec<AtomicU32>,
+}
+
+// Increment `counter` if it is below `limit`, returning whether it was
+fn reserve(counter: &AtomicU32, limit: u32) -> bool {
+    if counter.fetch_add(1, Ordering::SeqCst) >= limit {
+        counter.fetch_sub(1, Ordering::SeqCst);
+        false
+    } else {
+        true
+    }
+}
+
+impl Switch {
+    fn new(vms: &[UserVmConfig]) -> Result<Self> {
+        let mut ports: Vec<SwitchPort> = vec![];
+        for (vm_id, vm) in vms.iter().enumerate() {
+            for nic in vm.nics.iter() {
+                if nic.mac.is_multicast() {
+                    return Err(Error::InvalidValue(format!(
+                        "Multicast MAC address {:?} assigned to a NIC",
+                        nic.mac
+                    )));
+                }
+                if ports.iter().any(|port| {
+                    port.switch == nic.switch && port.mac == nic.mac
+                }) {
+                    return Err(Error::InvalidValue(format!(
+                        "Duplicate MAC address {:?} on switch '{}'",
+                        nic.mac, nic.switch
+                    )));
+                }
+                ports.push(SwitchPort {
+                    switch: nic.switch.clone(),
+                    vm_id: vm_id as u32,
+                    mac: nic.mac,
+                    pending: AtomicU32::new(0),
+                });
+            }
+        }
+        let vm_pending = vms.iter().map(|_| AtomicU32::new(0)).collect();
+        Ok(Self { ports, vm_pending })
+    }
+
+    // The ports that should receive a frame sent to `dest` from `source`
+    fn destinations(
+        &self,
+        source: PortId,
+        dest: MacAddress,
+    ) -> impl Iterator<Item = PortId> + '_ {
+        let switch = &self.ports[source].switch;
+        self.ports
+            .iter()
+            .enumerate()
+            .filter(move |(id, port)| {
+                *id != source
+                    && port.switch == *switch
+                    && (dest.is_multicast() || port.mac == dest)
+            })
+            .map(|(id, _)| id)
+    }
+
+    // Reserve space for a frame sent to the given port
+    fn reserve(&self, dest: PortId) -> Result<()> {
+        let port = &self.ports[dest];
+        if !reserve(&port.pending, MAX_PENDING_FRAMES) {
+            return Err(Error::Exhausted);
+        }
+        if !reserve(
+            &self.vm_pending[port.vm_id as usize],
+            MAX_PENDING_VM_FRAMES,
+        ) {
+            port.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Exhausted);
+        }
+        Ok(())
+    }
+
+    // Release the space reserved for a frame sent to the given port
+    fn release(&self, dest: PortId) {
+        let port = &self.ports[dest];
+        port.pending.fetch_sub(1, Ordering::SeqCst);
+        self.vm_pending[port.vm_id as usize].fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn send(&self, dest: PortId, frame: &[u8]) -> Result<()> {
+        self.reserve(dest)?;
+
+        let msg = vm::VirtualMachineMsg::NetworkFrame {
+            port: dest,
+            frame: frame.to_vec(),
+        };
+        vm::virtual_machines()
+            .send_msg(msg, self.ports[dest].vm_id, true)
+            .map_err(|err| {
+                self.release(dest);
+                err
+            })
+    }
+}
+
+/// Initialize the switches with the NICs of the given virtual machines
+///
+/// The ports are numbered in the order of the VMs, then the order of the
+/// NICs of each VM.
+pub unsafe fn init_switch(vms: &[UserVmConfig]) -> Result<()> {
+    RoAfterInit::init(&SWITCH, Switch::new(vms)?);
+    Ok(())
+}
+
+/// Returns the id and MAC address of each port connected to the given VM
+pub fn vm_ports(vm_id: u32) -> impl Iterator<Item = (PortId, MacAddress)> {
+    SWITCH
+        .ports
+        .iter()
+        .enumerate()
+        .filter(move |(_, port)| port.vm_id == vm_id)
+        .map(|(id, port)| (id, port.mac))
+}
+
+/// Forward a frame transmitted by the NIC connected to the `source` port
+///
+/// Frames that can not be delivered (because they are malformed, or the
+/// receiver is too busy) are dropped.
+pub fn transmit(source: PortId, frame: &[u8]) {
+    if !RoAfterInit::is_initialized(&SWITCH) {
+        return;
+    }
+
+    if frame.len() < ETHERNET_HEADER_SIZE || frame.len() > MAX_FRAME_SIZE {
+        debug!(
+            "switch: dropping frame of length {} from port {}",
+            frame.len(),
+            source
+        );
+        return;
+    }
+
+    let mut dest = [0u8; 6];
+    dest.copy_from_slice(&frame[..6]);
+    for port in SWITCH.destinations(source, MacAddress(dest)) {
+        if let Err(err) = SWITCH.send(port, frame) {
+            debug!("switch: dropping frame for port {}: {:?}", port, err);
+        }
+    }
+}
+
+/// Record that a `NetworkFrame` message for the given port was received
+pub fn frame_received(port: PortId) {
+    SWITCH.release(port);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::UserNicConfig;
+
+    fn nic(switch: &str, last: u8) -> UserNicConfig {
+        UserNicConfig {
+            mac: MacAddress([0x52, 0x54, 0, 0, 0, last]),
+            switch: switch.into(),
+        }
+    }
+
+    fn vm(nics: Vec<UserNicConfig>) -> UserVmConfig {
+        UserVmConfig {
+            nics,
+            ..serde_json::from_str(
+                r#"{"memory": 1, "kernel": "", "initramfs": "",
+                    "cmdline": "", "cpus": []}"#,
+            )
+            .unwrap()
+        }
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        let mac = MacAddress::parse("52:54:00:ab:CD:01").unwrap();
+        assert_eq!(mac.0, [0x52, 0x54, 0x00, 0xab, 0xcd, 0x01]);
+        assert!(!mac.is_multicast());
+        assert!(MacAddress::BROADCAST.is_multicast());
+
+        assert!(MacAddress::parse("52:54:00:ab:cd").is_err());
+        assert!(MacAddress::parse("52:54:00:ab:cd:01:02").is_err());
+        assert!(MacAddress::parse("52:54:00:ab:cd:0g").is_err());
+        assert!(MacAddress::parse("52:54:00:ab:cd:1").is_err());
+    }
+
+    #[test]
+    fn test_destinations() {
+        let switch = Switch::new(&[
+            vm(vec![nic("lan", 1), nic("dmz", 1)]),
+            vm(vec![nic("lan", 2)]),
+            vm(vec![nic("lan", 3), nic("dmz", 2)]),
+        ])
+        .unwrap();
+
+        let to = |source, mac| {
+            switch.destinations(source, mac).collect::<Vec<PortId>>()
+        };
+        assert_eq!(to(0, switch.ports[2].mac), vec![2]);
+        assert_eq!(to(0, switch.ports[4].mac), vec![]);
+        assert_eq!(to(0, MacAddress::BROADCAST), vec![2, 3]);
+        assert_eq!(to(1, MacAddress::BROADCAST), vec![4]);
+        assert_eq!(to(2, switch.ports[2].mac), vec![]);
+    }
+
+    #[test]
+    fn test_pending_limits() {
+        let nics = (0..4).map(|last| nic("lan", last)).collect();
+        let switch = Switch::new(&[vm(nics), vm(vec![nic("lan", 9)])]).unwrap();
+
+        // Each port is limited, and so is the total for the VM
+        let mut reserved = 0;
+        for port in 0..4 {
+            while switch.reserve(port).is_ok() {
+                reserved += 1;
+            }
+        }
+        assert_eq!(reserved, MAX_PENDING_VM_FRAMES);
+
+        // Other VMs are unaffected
+        switch.reserve(4).unwrap();
+
+        switch.release(0);
+        switch.reserve(3).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Switch::new(&[
+            vm(vec![nic("lan", 1)]),
+            vm(vec![nic("lan", 1)])
+        ])
+        .is_err());
+
+        // The same address may be used on different switches
+        assert!(Switch::new(&[
+            vm(vec![nic("lan", 1)]),
+            vm(vec![nic("dmz", 1)])
+        ])
+        .is_ok());
+
+        let mut multicast = nic("lan", 1);
+        multicast.mac.0[0] = 0x01;
+        assert!(Switch::new(&[vm(vec![multicast])]).is_err());
+    }
+}
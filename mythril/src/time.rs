@@ -48,6 +48,11 @@ pub fn system_start_time() -> Instant {
     *START_TIME
 }
 
+/// Get the global system `TimeSource`.
+pub fn global_time_source() -> &'static dyn TimeSource {
+    *TIME_SRC
+}
+
 /// Returns whether the global system `TimeSource` has be initialized.
 pub fn is_global_time_ready() -> bool {
     RoAfterInit::is_initialized(&TIME_SRC)
@@ -145,13 +150,16 @@ pub struct ReadyTimer {
     duration: Duration,
     mode: TimerMode,
     kind: TimerInterruptType,
+
+    // The time from starting the timer until it first elapses
+    delay: Duration,
 }
 
 /// A started one-shot or periodic timer
 pub struct RunningTimer {
     duration: Duration,
     mode: TimerMode,
-    started: Instant,
+    deadline: Instant,
     kind: TimerInterruptType,
 }
 
@@ -162,15 +170,27 @@ impl ReadyTimer {
             duration,
             mode: TimerMode::OneShot,
             kind,
+            delay: duration,
         }
     }
 
     /// Create a new periodic timer.
     pub fn periodic(period: Duration, kind: TimerInterruptType) -> Self {
+        Self::periodic_after(period, period, kind)
+    }
+
+    /// Create a new periodic timer that first elapses after `delay`, and
+    /// then every `period` after that.
+    pub fn periodic_after(
+        delay: Duration,
+        period: Duration,
+        kind: TimerInterruptType,
+    ) -> Self {
         Self {
             duration: period,
             mode: TimerMode::Periodic,
             kind,
+            delay,
         }
     }
 
@@ -179,7 +199,7 @@ impl ReadyTimer {
         RunningTimer {
             duration: self.duration,
             mode: self.mode,
-            started: now(),
+            deadline: now() + self.delay,
             kind: self.kind,
         }
     }
@@ -200,6 +220,7 @@ impl RunningTimer {
             duration: self.duration,
             mode: self.mode,
             kind: self.kind,
+            delay: self.duration,
         }
     }
 
@@ -216,25 +237,26 @@ impl RunningTimer {
     /// Note that for a periodic timer, `reset` must still be called
     /// before `elapsed` will return false after having elapsed once.
     pub fn elapsed(&self) -> bool {
-        now() - self.started > self.duration
+        now() > self.deadline
     }
 
     /// Reset this timer
     ///
-    /// Set the effective start time for this timer to 'now' (as determined
-    /// by the global system timer) for one-shot timers. For periodic timers,
-    /// it sets the start time to the previous elapses_at time.
+    /// Set the next deadline for this timer to one duration from 'now' (as
+    /// determined by the global system timer) for one-shot timers. For
+    /// periodic timers, it sets the deadline to one period after the
+    /// previous elapses_at time.
     pub fn reset(&mut self) {
-        self.started = if self.is_periodic() {
-            self.elapses_at()
+        self.deadline = if self.is_periodic() {
+            self.deadline + self.duration
         } else {
-            now()
+            now() + self.duration
         };
     }
 
     /// Determine when the timer will next elapse
     pub fn elapses_at(&self) -> Instant {
-        self.started + self.duration
+        self.deadline
     }
 }
 
@@ -430,3 +452,54 @@ pub fn set_periodic_timer(
     let timer = ReadyTimer::periodic(interval, kind);
     wheel.register_timer(timer)
 }
+
+/// Set a periodic timer on this core that first elapses after `delay`
+pub fn set_periodic_timer_after(
+    delay: core::time::Duration,
+    interval: core::time::Duration,
+    kind: TimerInterruptType,
+) -> TimerId {
+    let wheel = unsafe { get_timer_wheel_mut() };
+    let timer = ReadyTimer::periodic_after(delay, interval, kind);
+    wheel.register_timer(timer)
+}
+
+/// Time sources for unit tests
+#[cfg(test)]
+pub mod mock {
+    use super::{Instant, TimeSource};
+    use alloc::boxed::Box;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// A `TimeSource` that only advances when told to
+    pub struct MockTimeSource {
+        ticks: AtomicU64,
+        frequency: u64,
+    }
+
+    impl MockTimeSource {
+        /// Create a new mock time source (starting at tick 0) with the
+        /// given frequency
+        pub fn new(frequency: u64) -> &'static Self {
+            Box::leak(Box::new(Self {
+                ticks: AtomicU64::new(0),
+                frequency,
+            }))
+        }
+
+        /// Advance the mock time source by the given number of ticks
+        pub fn advance(&self, ticks: u64) {
+            self.ticks.fetch_add(ticks, Ordering::SeqCst);
+        }
+    }
+
+    impl TimeSource for MockTimeSource {
+        fn now(&self) -> Instant {
+            Instant(self.ticks.load(Ordering::SeqCst))
+        }
+
+        fn frequency(&self) -> u64 {
+            self.frequency
+        }
+    }
+}
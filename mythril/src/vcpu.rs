@@ -1,11 +1,14 @@
 use crate::apic;
+use crate::config;
+use crate::console;
 use crate::emulate;
 use crate::error::{self, Error, Result};
 use crate::interrupt;
-use crate::ioapic;
-use crate::memory::Raw4kPage;
+use crate::memory::{GuestPhysAddr, Raw4kPage};
 use crate::percore;
+use crate::random;
 use crate::registers::{GdtrBase, IdtrBase};
+use crate::switch;
 use crate::time;
 use crate::vm::VirtualMachine;
 use crate::{declare_per_core, get_per_core_mut};
@@ -25,6 +28,17 @@ extern "C" {
 
 const PER_CORE_HOST_STACK_SIZE: usize = 1024 * 1024;
 
+// The vector of the invalid opcode exception (#UD)
+const UNDEFINED_OPCODE_VECTOR: u8 = 6;
+
+// The vector of the general protection exception (#GP)
+const GENERAL_PROTECTION_VECTOR: u8 = 13;
+
+// The 'deliver error code' bit of the VM-entry interruption information
+const DELIVER_ERROR_CODE: u64 = 1 << 11;
+
+const CR0_PROTECTION_ENABLE: u64 = 1 << 0;
+
 declare_per_core! {
     // NOTE: The per-core stack cannot be part of the VCpu type because an
     // instance of VCpu will (briefly) reside _on_ the stack
@@ -79,7 +93,7 @@ pub fn mp_entry_point() -> ! {
     vcpu.launch().expect("Failed to launch vm")
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum InjectedInterruptType {
     ExternalInterrupt = 0,
@@ -102,6 +116,7 @@ pub struct VCpu {
     pub vm: Pin<&'static VirtualMachine>,
     pub vmcs: vmcs::ActiveVmcs,
     pub local_apic: virtdev::lapic::LocalApic,
+    pub entropy: random::HostEntropy,
     pending_interrupts: BTreeMap<u8, InjectedInterruptType>,
     stack: &'static mut [u8; PER_CORE_HOST_STACK_SIZE],
 }
@@ -117,11 +132,15 @@ impl VCpu {
     ) -> Result<Pin<&'static mut Self>> {
         let vmx = vmx::Vmx::enable()?;
         let vmcs = vmcs::Vmcs::new()?.activate(vmx)?;
+        let apic_id = vm
+            .core_to_apic_id(percore::read_core_id())
+            .ok_or_else(|| Error::NotFound)?;
 
         let vcpu = Self {
             vm: vm,
             vmcs: vmcs,
-            local_apic: virtdev::lapic::LocalApic::new(),
+            local_apic: virtdev::lapic::LocalApic::new(apic_id),
+            entropy: random::HostEntropy::new(),
             stack: get_per_core_mut!(HOST_STACK),
             pending_interrupts: BTreeMap::new(),
         };
@@ -155,7 +174,7 @@ impl VCpu {
 
         Self::initialize_host_vmcs(&mut vcpu.vmcs, stack_base)?;
         Self::initialize_guest_vmcs(vcpu)?;
-        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs)?;
+        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs, vcpu.vm.random_policy)?;
 
         Ok(Pin::new(vcpu))
     }
@@ -178,39 +197,195 @@ impl VCpu {
 
     /// Block until a StartVcpu is received by this core
     ///
-    /// This routine will also prepare the VCpu with the information
-    /// received from the StartVcpu signal
+    /// Until then, the VCpu is in the wait-for-SIPI state: interrupts sent
+    /// to it are discarded and INIT signals are ignored. This routine will
+    /// also prepare the VCpu with the information received from the StartVcpu
+    /// signal.
     pub fn wait_for_init(&mut self) -> Result<()> {
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            vmcs::ActivityState::WaitForSipi as u64,
+        )?;
+
         loop {
             if let Some(msg) = vm::virtual_machines().recv_msg() {
                 match msg {
                     vm::VirtualMachineMsg::StartVcpu(addr) => {
-                        debug!(
-                            "Setting start address to 0x{:x}",
-                            addr.as_u64()
-                        );
-                        self.vmcs.write_field(
-                            vmcs::VmcsField::GuestCsSelector,
-                            addr.as_u64() >> 4,
-                        )?;
-                        self.vmcs.write_field(
-                            vmcs::VmcsField::GuestCsBase,
-                            0x0000,
-                        )?;
-                        self.vmcs.write_field(vmcs::VmcsField::GuestRip, 0)?;
-                        break;
+                        return self.start_at(addr);
+                    }
+                    vm::VirtualMachineMsg::CancelTimer(timer_id) => {
+                        time::cancel_timer(&timer_id)?;
+                    }
+                    vm::VirtualMachineMsg::PowerAction(
+                        config::PowerAction::Restart,
+                    ) => {
+                        // This core is already in its post-INIT state
+                        self.local_apic.reset(self.vm)?;
+                        self.vm.notify_reset();
+                    }
+                    vm::VirtualMachineMsg::PowerAction(
+                        config::PowerAction::Destroy,
+                    ) => self.stop(),
+                    vm::VirtualMachineMsg::NetworkFrame { port, .. } => {
+                        switch::frame_received(port);
                     }
                     _ => {
-                        warn!(
-                            "Ignoring non-startup signal on waiting guest AP"
+                        debug!(
+                            "Ignoring non-startup signal on AP waiting for SIPI"
                         );
                     }
                 }
             }
         }
+    }
+
+    fn is_waiting_for_sipi(&mut self) -> Result<bool> {
+        Ok(self.vmcs.read_field(vmcs::VmcsField::GuestActivityState)?
+            == vmcs::ActivityState::WaitForSipi as u64)
+    }
+
+    // Leave the wait-for-SIPI state, starting in real mode at the given
+    // (page aligned) address
+    fn start_at(&mut self, addr: GuestPhysAddr) -> Result<()> {
+        debug!("Setting start address to 0x{:x}", addr.as_u64());
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestCsSelector,
+            addr.as_u64() >> 4,
+        )?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::GuestCsBase, addr.as_u64())?;
+        self.vmcs.write_field(vmcs::VmcsField::GuestRip, 0)?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            vmcs::ActivityState::Active as u64,
+        )?;
         Ok(())
     }
 
+    /// Perform an architectural INIT of this VCpu
+    ///
+    /// The guest register state (and local apic) returns to its reset values.
+    /// The BSP restarts at the reset vector, while other cores enter the
+    /// wait-for-SIPI state.
+    fn init(&mut self, guest_cpu: &mut vmexit::GuestCpuState) -> Result<()> {
+        debug!("INIT of core ID '{}'", percore::read_core_id());
+
+        guest_cpu.cr2 = 0;
+        guest_cpu.r15 = 0;
+        guest_cpu.r14 = 0;
+        guest_cpu.r13 = 0;
+        guest_cpu.r12 = 0;
+        guest_cpu.r11 = 0;
+        guest_cpu.r10 = 0;
+        guest_cpu.r9 = 0;
+        guest_cpu.r8 = 0;
+        guest_cpu.rbp = 0;
+        guest_cpu.rdi = 0;
+        guest_cpu.rsi = 0;
+        guest_cpu.rcx = 0;
+        guest_cpu.rbx = 0;
+        guest_cpu.rax = 0;
+
+        // EDX holds the processor signature after INIT
+        guest_cpu.rdx = raw_cpuid::native_cpuid::cpuid_count(1, 0).eax as u64;
+
+        Self::initialize_guest_vmcs(self)?;
+
+        // The guest is no longer in IA-32e mode
+        let entry_ctrls =
+            self.vmcs.read_field(vmcs::VmcsField::VmEntryControls)?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryControls,
+            entry_ctrls & !vmcs::VmEntryCtrlFlags::IA32E_MODE.bits(),
+        )?;
+
+        // Drop any interrupts that have not been delivered yet
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryIntrInfoField, 0)?;
+        self.pending_interrupts.clear();
+        self.local_apic.init_reset(self.vm)?;
+
+        if self.local_apic.apic_id() != 0 {
+            self.vmcs.write_field(
+                vmcs::VmcsField::GuestActivityState,
+                vmcs::ActivityState::WaitForSipi as u64,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Handle a guest initiated change to the power state of the VM
+    ///
+    /// The action configured for the event is performed by every core of
+    /// the VM.
+    fn handle_power_event(
+        &mut self,
+        event: vm::PowerEvent,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        if !self.vm.begin_power_transition() {
+            debug!("Ignoring {:?} during a power state change", event);
+            return Ok(());
+        }
+
+        let action = self.vm.power_policy.action(event);
+        info!(
+            "VM id '{}' received {:?} (action: {:?})",
+            self.vm.id, event, action
+        );
+
+        let core_id = percore::read_core_id();
+        for core in self.vm.cpus.iter().filter(|core| **core != core_id) {
+            vm::virtual_machines().send_msg_core(
+                vm::VirtualMachineMsg::PowerAction(action),
+                *core,
+                true,
+            )?;
+        }
+
+        self.perform_power_action(action, guest_cpu)
+    }
+
+    fn perform_power_action(
+        &mut self,
+        action: config::PowerAction,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        match action {
+            config::PowerAction::Destroy => self.stop(),
+            config::PowerAction::Restart => {
+                self.init(guest_cpu)?;
+                self.local_apic.reset(self.vm)?;
+
+                // The BSP restores the VM state once the other cores are
+                // reset, then starts again at the reset vector
+                if percore::read_core_id() == self.vm.bsp_id() {
+                    self.vm.restart()
+                } else {
+                    self.vm.notify_reset();
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // Permanently stop this core (as part of destroying its VM)
+    //
+    // Interrupts are disabled during VMEXIT handling, so the core will not
+    // leave the halted state. The memory of the VM is not reclaimed.
+    fn stop(&mut self) -> ! {
+        info!(
+            "Stopping core ID '{}' of vm id '{}'",
+            percore::read_core_id(),
+            self.vm.id
+        );
+        loop {
+            unsafe {
+                asm!("hlt", options(nostack, nomem));
+            }
+        }
+    }
+
     fn initialize_host_vmcs(
         vmcs: &mut vmcs::ActiveVmcs,
         stack: u64,
@@ -356,7 +531,10 @@ impl VCpu {
         Ok(())
     }
 
-    fn initialize_ctrl_vmcs(vmcs: &mut vmcs::ActiveVmcs) -> Result<()> {
+    fn initialize_ctrl_vmcs(
+        vmcs: &mut vmcs::ActiveVmcs,
+        random_policy: config::RandomInstructionPolicy,
+    ) -> Result<()> {
         vmcs.write_with_fixed(
             vmcs::VmcsField::CpuBasedVmExecControl,
             (vmcs::CpuBasedCtrlFlags::UNCOND_IO_EXITING
@@ -367,15 +545,28 @@ impl VCpu {
             msr::IA32_VMX_PROCBASED_CTLS,
         )?;
 
+        let mut secondary = vmcs::SecondaryExecFlags::VIRTUALIZE_APIC_ACCESSES
+            | vmcs::SecondaryExecFlags::ENABLE_EPT
+            | vmcs::SecondaryExecFlags::ENABLE_RDTSCP
+            | vmcs::SecondaryExecFlags::ENABLE_VPID
+            | vmcs::SecondaryExecFlags::ENABLE_INVPCID
+            | vmcs::SecondaryExecFlags::UNRESTRICTED_GUEST;
+
+        // Exit on RDRAND and RDSEED unless the guest may execute them. A
+        // processor that can not exit on one of these instructions does
+        // not support it, so the guest gets a #UD anyway.
+        if random_policy != config::RandomInstructionPolicy::Passthrough {
+            let allowed = vmcs::SecondaryExecFlags::from_bits_truncate(
+                unsafe { msr::rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) } >> 32,
+            );
+            secondary |= allowed
+                & (vmcs::SecondaryExecFlags::RDRAND_EXITING
+                    | vmcs::SecondaryExecFlags::RDSEED_EXITING);
+        }
+
         vmcs.write_with_fixed(
             vmcs::VmcsField::SecondaryVmExecControl,
-            (vmcs::SecondaryExecFlags::VIRTUALIZE_APIC_ACCESSES
-                | vmcs::SecondaryExecFlags::ENABLE_EPT
-                | vmcs::SecondaryExecFlags::ENABLE_RDTSCP
-                | vmcs::SecondaryExecFlags::ENABLE_VPID
-                | vmcs::SecondaryExecFlags::ENABLE_INVPCID
-                | vmcs::SecondaryExecFlags::UNRESTRICTED_GUEST)
-                .bits(),
+            secondary.bits(),
             msr::IA32_VMX_PROCBASED_CTLS2,
         )?;
 
@@ -413,12 +604,25 @@ impl VCpu {
 
         let mut msr_page = Raw4kPage::default();
 
-        // For now, we need to exit on MSR_IA32_APICBASE (msr=0x1b)
-        // so we can tell the kernel the platform it's running on
-        // doesn't support x2apic
-        // TODO(alschwalm): remove this once we support x2apic in
-        // the guest
+        // Exit on reads and writes of MSR_IA32_APICBASE (msr=0x1b), as the
+        // guest sees the state of its virtual local apic (including whether
+        // it is in x2apic mode).
         msr_page.0[3] |= 1 << 3;
+        msr_page.0[2048 + 3] |= 1 << 3;
+
+        // Exit on all accesses to the x2apic MSRs (msr=0x800-0x8ff)
+        for byte in &mut msr_page.0[0x100..0x120] {
+            *byte = 0xff;
+        }
+        for byte in &mut msr_page.0[2048 + 0x100..2048 + 0x120] {
+            *byte = 0xff;
+        }
+
+        // Exit on reads and writes of IA32_TSC_DEADLINE (msr=0x6e0), as it
+        // is backed by the virtual local apic timer. The write bitmap for the
+        // low MSRs starts at offset 2048.
+        msr_page.0[0xdc] |= 1 << 0;
+        msr_page.0[2048 + 0xdc] |= 1 << 0;
 
         let msr_bitmap = Box::into_raw(Box::new(msr_page));
 
@@ -443,6 +647,48 @@ impl VCpu {
         Ok(())
     }
 
+    /// Inject a hardware exception (without an error code) in to the guest
+    /// on the next VM entry
+    ///
+    /// The instruction that caused the VMEXIT is not skipped, as it is the
+    /// faulting instruction.
+    pub fn inject_exception(&mut self, vector: u8) -> Result<()> {
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryIntrInfoField,
+            0x80000000
+                | vector as u64
+                | ((InjectedInterruptType::HardwareException as u64) << 8),
+        )
+    }
+
+    /// Inject a hardware exception with an error code (e.g., a #GP) in to
+    /// the guest on the next VM entry
+    ///
+    /// Like `inject_exception`, the faulting instruction is not skipped.
+    pub fn inject_exception_with_error_code(
+        &mut self,
+        vector: u8,
+        error_code: u32,
+    ) -> Result<()> {
+        // Exceptions in real mode never push an error code
+        let cr0 = self.vmcs.read_field(vmcs::VmcsField::GuestCr0)?;
+        if cr0 & CR0_PROTECTION_ENABLE == 0 {
+            return self.inject_exception(vector);
+        }
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryExceptionErrorCode,
+            error_code as u64,
+        )?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryIntrInfoField,
+            0x80000000
+                | DELIVER_ERROR_CODE
+                | vector as u64
+                | ((InjectedInterruptType::HardwareException as u64) << 8),
+        )
+    }
+
     fn skip_emulated_instruction(&mut self) -> Result<()> {
         let mut rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
         rip += self
@@ -453,8 +699,14 @@ impl VCpu {
         Ok(())
     }
 
-    pub fn route_interrupt(&mut self, gsi: u32) -> Result<()> {
-        let (destination, vector, kind) = self.vm.gsi_destination(gsi)?;
+    /// Inject an interrupt on the given core, which may belong to this
+    /// VCpu or another VCpu in the same VM.
+    pub fn inject_interrupt_on(
+        &mut self,
+        destination: percore::CoreId,
+        vector: u8,
+        kind: InjectedInterruptType,
+    ) -> Result<()> {
         if destination == percore::read_core_id() {
             self.inject_interrupt(vector, kind);
             Ok(())
@@ -467,6 +719,100 @@ impl VCpu {
         }
     }
 
+    pub fn route_interrupt(&mut self, gsi: u32) -> Result<()> {
+        // Legacy IRQs are delivered through the PIC (which is connected to
+        // the BSP) unless the guest has routed them through the IOAPIC.
+        let io_apic = &self.vm.static_virtual_devices.io_apic;
+        if gsi < 16 && io_apic.read().routes_to_pic(gsi) {
+            let vector = {
+                let mut pic = self.vm.static_virtual_devices.pic.write();
+                pic.raise_irq(gsi as u8)?;
+                pic.acknowledge()
+            };
+            if let Some(vector) = vector {
+                self.inject_interrupt_on(
+                    self.vm.bsp_id(),
+                    vector,
+                    InjectedInterruptType::ExternalInterrupt,
+                )?;
+            }
+            return Ok(());
+        }
+
+        if !self
+            .vm
+            .static_virtual_devices
+            .io_apic
+            .write()
+            .raise_irq(gsi)?
+        {
+            return Ok(());
+        }
+
+        self.deliver_gsi(gsi)
+    }
+
+    /// Set the level of the line connected to the given guest GSI
+    ///
+    /// This is used for level triggered interrupt sources, which remain
+    /// asserted until the guest services the device.
+    pub fn set_interrupt_level(&mut self, gsi: u32, level: bool) -> Result<()> {
+        let io_apic = &self.vm.static_virtual_devices.io_apic;
+        if gsi < 16 && io_apic.read().routes_to_pic(gsi) {
+            let vector = {
+                let mut pic = self.vm.static_virtual_devices.pic.write();
+                pic.set_irq(gsi as u8, level)?;
+                pic.acknowledge()
+            };
+            if let Some(vector) = vector {
+                self.inject_interrupt_on(
+                    self.vm.bsp_id(),
+                    vector,
+                    InjectedInterruptType::ExternalInterrupt,
+                )?;
+            }
+            return Ok(());
+        }
+
+        if !self
+            .vm
+            .static_virtual_devices
+            .io_apic
+            .write()
+            .set_irq(gsi, level)?
+        {
+            return Ok(());
+        }
+
+        self.deliver_gsi(gsi)
+    }
+
+    // Deliver the interrupt described by the guest I/O APIC redirection
+    // table entry for the given GSI
+    fn deliver_gsi(&mut self, gsi: u32) -> Result<()> {
+        let destination = self.vm.gsi_destination(gsi)?;
+        self.deliver_interrupt(destination)
+    }
+
+    /// Deliver a message signaled interrupt with the given address and
+    /// data (as programmed by the guest)
+    pub fn deliver_msi(&mut self, address: u64, data: u32) -> Result<()> {
+        let destination = self.vm.msi_destination(address, data)?;
+        self.deliver_interrupt(destination)
+    }
+
+    fn deliver_interrupt(
+        &mut self,
+        destination: Option<vm::InterruptDestination>,
+    ) -> Result<()> {
+        if let Some((destinations, vector, kind)) = destination {
+            for destination in destinations {
+                self.inject_interrupt_on(destination, vector, kind)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Handle an arbitrary guest VMEXIT.
     ///
     /// This is the rust 'entry' point when a guest exists.
@@ -506,6 +852,25 @@ impl VCpu {
             return Ok(());
         }
 
+        // An exception is already being injected, so wait for the next
+        // interrupt window
+        if self
+            .vmcs
+            .read_field(vmcs::VmcsField::VmEntryIntrInfoField)?
+            & 0x80000000
+            != 0
+        {
+            let field = self
+                .vmcs
+                .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+            self.vmcs.write_field(
+                vmcs::VmcsField::CpuBasedVmExecControl,
+                field
+                    | vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
+            )?;
+            return Ok(());
+        }
+
         let interruptibility = vmcs::InterruptibilityState::from_bits(
             self.vmcs
                 .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?,
@@ -539,6 +904,16 @@ impl VCpu {
         // At this point, we must have at least one pending interrupt, and the guest
         // can accept interrupts, so do the injection.
         if let Some(pending) = self.pending_interrupts.pop_first() {
+            // Interrupts supplied by the PIC (ExtINT) are acknowledged
+            // through the PIC, so they are not in service in the local apic
+            let devices = &self.vm.static_virtual_devices;
+            if pending.1 == InjectedInterruptType::ExternalInterrupt
+                && !devices.pic.read().supplied_vector(pending.0)
+            {
+                let level_triggered =
+                    devices.io_apic.read().awaiting_eoi(pending.0);
+                self.local_apic.accept_interrupt(pending.0, level_triggered);
+            }
             self.vmcs.write_field(
                 vmcs::VmcsField::VmEntryIntrInfoField,
                 0x80000000 | pending.0 as u64 | ((pending.1 as u64) << 8),
@@ -565,20 +940,67 @@ impl VCpu {
         Ok(())
     }
 
-    fn handle_ipc(&mut self) -> Result<()> {
+    fn handle_ipc(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        responses: &mut virtdev::ResponseEventArray,
+    ) -> Result<()> {
         for msg in vm::virtual_machines().recv_all_msgs() {
             match msg {
-                vm::VirtualMachineMsg::GrantConsole(serial) => {
-                    *self.vm.host_devices.serial.write() = Some(serial);
-                }
                 vm::VirtualMachineMsg::CancelTimer(timer_id) => {
                     time::cancel_timer(&timer_id)?;
                 }
                 vm::VirtualMachineMsg::GuestInterrupt { kind, vector } => {
+                    if self.is_waiting_for_sipi()? {
+                        continue;
+                    }
                     self.inject_interrupt(vector, kind);
                 }
-                vm::VirtualMachineMsg::StartVcpu(_) => {
-                    warn!("Received StartVcpu signal on running VCPU");
+                vm::VirtualMachineMsg::StartVcpu(addr) => {
+                    // Only the first SIPI after an INIT has any effect
+                    if self.is_waiting_for_sipi()? {
+                        self.start_at(addr)?;
+                    } else {
+                        debug!("Ignoring SIPI on running VCPU");
+                    }
+                }
+                vm::VirtualMachineMsg::InitVcpu => {
+                    if !self.is_waiting_for_sipi()? {
+                        self.init(guest_cpu)?;
+                    }
+                }
+                vm::VirtualMachineMsg::PowerAction(action) => {
+                    self.perform_power_action(action, guest_cpu)?;
+                }
+                vm::VirtualMachineMsg::PowerButton => {
+                    self.vm.dispatch_event(
+                        virtdev::acpi::PM_BASE,
+                        virtdev::DeviceEvent::PowerButtonPressed,
+                        self,
+                        responses,
+                    )?;
+                }
+                vm::VirtualMachineMsg::NetworkFrame { port, frame } => {
+                    switch::frame_received(port);
+                    self.vm.dispatch_pci_host_event(
+                        virtdev::DeviceEvent::NetworkFrameReceived(
+                            port, &frame,
+                        ),
+                        self,
+                        responses,
+                    )?;
+                }
+                vm::VirtualMachineMsg::SharedMemoryDoorbell {
+                    region,
+                    vector,
+                } => {
+                    self.vm.dispatch_pci_host_event(
+                        virtdev::DeviceEvent::SharedMemoryDoorbell(
+                            region, vector,
+                        ),
+                        self,
+                        responses,
+                    )?;
                 }
             }
         }
@@ -589,23 +1011,53 @@ impl VCpu {
         &mut self,
         responses: &mut virtdev::ResponseEventArray,
     ) -> Result<()> {
-        let serial_info = self
-            .vm
-            .host_devices
-            .serial
-            .read()
-            .as_ref()
-            .map(|serial| (serial.read(), serial.base_port()));
-
-        if let Some((key, port)) = serial_info {
-            self.vm.dispatch_event(
-                port,
-                virtdev::DeviceEvent::HostUartReceived(key),
-                self,
-                responses,
-            )
-        } else {
-            Ok(())
+        match console::handle_host_input()? {
+            console::ConsoleAction::Forward { vm_id, key }
+                if vm_id == self.vm.id =>
+            {
+                // The input is delivered to both the UART and any virtio
+                // consoles (which are on the PCI bus)
+                self.vm.dispatch_event(
+                    console::GUEST_CONSOLE_PORT,
+                    virtdev::DeviceEvent::HostUartReceived(key),
+                    self,
+                    responses,
+                )?;
+                self.vm.dispatch_pci_host_event(
+                    virtdev::DeviceEvent::HostUartReceived(key),
+                    self,
+                    responses,
+                )
+            }
+            console::ConsoleAction::Forward { vm_id, .. } => {
+                // The console interrupt is still being routed to this VM,
+                // but it is no longer attached.
+                debug!("Dropping console input for VM id '{}'", vm_id);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_keyboard_input(
+        &mut self,
+        responses: &mut virtdev::ResponseEventArray,
+    ) -> Result<()> {
+        loop {
+            let scancode =
+                match self.vm.host_devices.ps2_keyboard.write().as_mut() {
+                    Some(keyboard) => keyboard.try_read_keyboard(),
+                    None => None,
+                };
+            match scancode {
+                Some(scancode) => self.vm.dispatch_event(
+                    virtdev::keyboard::Keyboard8042::PS2_DATA,
+                    virtdev::DeviceEvent::HostKeyboardReceived(scancode),
+                    self,
+                    responses,
+                )?,
+                None => return Ok(()),
+            }
         }
     }
 
@@ -617,19 +1069,74 @@ impl VCpu {
         let mut responses = virtdev::ResponseEventArray::default();
 
         match exit.info {
-            //TODO(alschwalm): Once we have guest x2apic support, remove this
             vmexit::ExitInformation::RdMsr => {
-                match guest_cpu.rcx as u32 {
+                let valid = match guest_cpu.rcx as u32 {
                     msr::IA32_APIC_BASE => {
-                        let mut real_apic_base =
-                            unsafe { msr::rdmsr(msr::IA32_APIC_BASE) };
-                        real_apic_base &= !(1 << 10); // mask X2APIC_ENABLE
-                        guest_cpu.rdx = real_apic_base >> 32;
-                        guest_cpu.rax = real_apic_base & 0xffffffff;
+                        let apic_base = self.local_apic.apic_base();
+                        guest_cpu.rdx = apic_base >> 32;
+                        guest_cpu.rax = apic_base & 0xffffffff;
+                        true
+                    }
+                    msr if virtdev::lapic::is_x2apic_msr(msr) => {
+                        match self.local_apic.x2apic_msr_read(msr)? {
+                            Some(value) => {
+                                guest_cpu.rdx = value >> 32;
+                                guest_cpu.rax = value & 0xffffffff;
+                                true
+                            }
+                            None => false,
+                        }
+                    }
+                    msr::IA32_TSC_DEADLINE => {
+                        let deadline = self.local_apic.tsc_deadline();
+                        guest_cpu.rdx = deadline >> 32;
+                        guest_cpu.rax = deadline & 0xffffffff;
+                        true
                     }
-                    msr => warn!("Attempt to read unsupported MSR 0x{:x}", msr),
+                    msr => {
+                        warn!("Attempt to read unsupported MSR 0x{:x}", msr);
+                        true
+                    }
+                };
+                if valid {
+                    self.skip_emulated_instruction()?;
+                } else {
+                    self.inject_exception_with_error_code(
+                        GENERAL_PROTECTION_VECTOR,
+                        0,
+                    )?;
+                }
+            }
+            vmexit::ExitInformation::WrMsr => {
+                let value =
+                    (guest_cpu.rdx << 32) | (guest_cpu.rax & 0xffffffff);
+                let valid = match guest_cpu.rcx as u32 {
+                    msr::IA32_APIC_BASE => {
+                        self.local_apic.set_apic_base(self.vm, value)?
+                    }
+                    msr::IA32_TSC_DEADLINE => {
+                        self.local_apic.set_tsc_deadline(value)?;
+                        true
+                    }
+                    msr if virtdev::lapic::is_x2apic_msr(msr) => {
+                        self.local_apic.x2apic_msr_write(self.vm, msr, value)?
+                    }
+                    msr => {
+                        warn!(
+                            "Attempt to write unsupported MSR 0x{:x} (value=0x{:x})",
+                            msr, value
+                        );
+                        true
+                    }
+                };
+                if valid {
+                    self.skip_emulated_instruction()?;
+                } else {
+                    self.inject_exception_with_error_code(
+                        GENERAL_PROTECTION_VECTOR,
+                        0,
+                    )?;
                 }
-                self.skip_emulated_instruction()?;
             }
             vmexit::ExitInformation::ApicAccess(info) => {
                 emulate::memio::handle_apic_access(
@@ -649,6 +1156,21 @@ impl VCpu {
                 emulate::cpuid::emulate_cpuid(self, guest_cpu)?;
                 self.skip_emulated_instruction()?;
             }
+            vmexit::ExitInformation::RdRand(_)
+            | vmexit::ExitInformation::RdSeed(_)
+                if self.vm.random_policy
+                    != config::RandomInstructionPolicy::Emulate =>
+            {
+                self.inject_exception(UNDEFINED_OPCODE_VECTOR)?;
+            }
+            vmexit::ExitInformation::RdRand(info) => {
+                emulate::random::emulate_rdrand(self, guest_cpu, info)?;
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::RdSeed(info) => {
+                emulate::random::emulate_rdseed(self, guest_cpu, info)?;
+                self.skip_emulated_instruction()?;
+            }
             vmexit::ExitInformation::IoInstruction(info) => {
                 emulate::portio::emulate_portio(
                     self,
@@ -673,8 +1195,11 @@ impl VCpu {
                     interrupt::vector::UART => {
                         self.handle_uart_keypress(&mut responses)?
                     }
+                    interrupt::vector::KEYBOARD => {
+                        self.handle_keyboard_input(&mut responses)?
+                    }
                     interrupt::vector::IPC => {
-                        self.handle_ipc()?;
+                        self.handle_ipc(guest_cpu, &mut responses)?;
                     }
                     _ => (),
                 }
@@ -683,64 +1208,52 @@ impl VCpu {
                 // the local apic
                 apic::get_local_apic_mut().eoi();
             },
+            vmexit::ExitInformation::TripleFault => {
+                self.handle_power_event(vm::PowerEvent::Crash, guest_cpu)?;
+            }
             _ => {
                 info!("{}", self.vmcs);
                 panic!("No handler for exit reason: {:?}", exit);
             }
         }
 
+        // Deliver any interrupts the guest sent to its own local apic
+        for (vector, kind) in self.local_apic.take_self_interrupts() {
+            self.inject_interrupt(vector, kind);
+        }
+
         for response in responses {
             match response {
                 virtdev::DeviceEventResponse::GSI(gsi) => {
                     self.route_interrupt(gsi)?;
                 }
-                virtdev::DeviceEventResponse::NextConsole => {
-                    info!("Received Ctrl-a three times. Switching console to next VM");
-
-                    let serial = self
-                        .vm
-                        .host_devices
-                        .serial
-                        .write()
-                        .take()
-                        .ok_or_else(|| Error::NotFound)?;
-                    let vmid = self.vm.id;
-
-                    let next_vmid = (vmid + 1) % vm::virtual_machines().count();
-
-                    vm::virtual_machines().send_msg(
-                        vm::VirtualMachineMsg::GrantConsole(serial),
-                        next_vmid,
-                        true,
+                virtdev::DeviceEventResponse::GSILevel(gsi, level) => {
+                    self.set_interrupt_level(gsi, level)?;
+                }
+                virtdev::DeviceEventResponse::MSI(address, data) => {
+                    self.deliver_msi(address, data)?;
+                }
+                virtdev::DeviceEventResponse::ExtInt(vector) => {
+                    self.inject_interrupt_on(
+                        self.vm.bsp_id(),
+                        vector,
+                        InjectedInterruptType::ExternalInterrupt,
                     )?;
-
-                    let next_bsp = vm::virtual_machines()
-                        .bsp_core_id(next_vmid)
-                        .ok_or_else(|| Error::NotFound)?;
-
-                    //FIXME(alschwalm): this should be the APIC id of the bsp, not the core id
-                    ioapic::map_gsi_vector(
-                        interrupt::gsi::UART,
-                        interrupt::vector::UART,
-                        next_bsp.raw as u8,
-                    )
-                    .map_err(|_| {
-                        Error::DeviceError(
-                            "Failed to update console GSI mapping".into(),
-                        )
-                    })?;
                 }
                 virtdev::DeviceEventResponse::GuestUartTransmitted(val) => {
-                    if self.vm.host_devices.serial.read().is_some() {
-                        //TODO: This should be a write to the physical serial device
-                        let buff = &[val];
-                        let s = alloc::string::String::from_utf8_lossy(buff);
-                        crate::logger::write_console(&s);
-                    }
+                    console::write_guest_output(self.vm.id, val);
+                }
+                virtdev::DeviceEventResponse::PowerEvent(event) => {
+                    self.handle_power_event(event, guest_cpu)?;
                 }
             }
         }
 
+        // The guest can't run until it receives a SIPI
+        if self.is_waiting_for_sipi()? {
+            self.wait_for_init()?;
+        }
+
         Ok(())
     }
 }
@@ -1,12 +1,65 @@
 use crate::error::Result;
+use crate::interrupt;
 use crate::time;
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
+use crate::vm;
 use alloc::vec::Vec;
 
 const PMTIMER_HZ: u64 = 3579545;
 
+/// The base of the power management register blocks (as used by QEMU for
+/// the ICH9)
+pub const PM_BASE: Port = 0x600;
+
+/// The value written to the SMI command port to transfer ownership of the
+/// ACPI hardware to the OS
+pub const ACPI_ENABLE: u8 = 0x02;
+
+/// The value written to the SMI command port to return ownership of the
+/// ACPI hardware to the firmware
+pub const ACPI_DISABLE: u8 = 0x03;
+
+// PM1 status and enable register fields (the registers share a layout)
+const PM1_TMR: u16 = 1 << 0;
+const PM1_GBL: u16 = 1 << 5;
+const PM1_PWRBTN: u16 = 1 << 8;
+const PM1_SLPBTN: u16 = 1 << 9;
+const PM1_RTC: u16 = 1 << 10;
+const PM1_EN_MASK: u16 = PM1_TMR | PM1_GBL | PM1_PWRBTN | PM1_SLPBTN | PM1_RTC;
+
+// PM1 control register fields
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_GBL_RLS: u16 = 1 << 2;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+// The sleeping type for S5 (soft off). This is the value used by the QEMU
+// DSDT (and must match the \_S5 object given to the guest).
+const SLP_TYP_S5: u16 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AcpiRegister {
+    Pm1Status,
+    Pm1Enable,
+    Pm1Control,
+    Gpe0Status,
+    Gpe0Enable,
+}
+
 pub struct AcpiRuntime {
     pm_base: Port,
+    pm1_status: u16,
+    pm1_enable: u16,
+    pm1_control: u16,
+    gpe0_status: u16,
+    gpe0_enable: u16,
+
+    // The current level of the SCI line
+    sci_level: bool,
 }
 
 impl AcpiRuntime {
@@ -27,7 +80,19 @@ impl AcpiRuntime {
     const PCI_REMOVABILITY_STATUS_END: Port = 0xae0f;
 
     pub fn new(pm_base: Port) -> Result<Self> {
-        Ok(AcpiRuntime { pm_base })
+        Ok(AcpiRuntime {
+            pm_base,
+            pm1_status: 0,
+            pm1_enable: 0,
+            pm1_control: 0,
+            gpe0_status: 0,
+            gpe0_enable: 0,
+            sci_level: false,
+        })
+    }
+
+    fn pm1a_evt(&self) -> Port {
+        self.pm_base
     }
 
     fn pm1a_cnt(&self) -> Port {
@@ -37,6 +102,122 @@ impl AcpiRuntime {
     fn pmtimer(&self) -> Port {
         self.pm_base + 0x08
     }
+
+    // The register (and the shift of the byte within it) at 'port'
+    fn register(&self, port: Port) -> Option<(AcpiRegister, u16)> {
+        let (register, offset) = match port {
+            port if port >= self.pm1a_evt() && port < self.pm1a_evt() + 2 => {
+                (AcpiRegister::Pm1Status, port - self.pm1a_evt())
+            }
+            port if port >= self.pm1a_evt() + 2 && port < self.pm1a_cnt() => {
+                (AcpiRegister::Pm1Enable, port - self.pm1a_evt() - 2)
+            }
+            port if port >= self.pm1a_cnt() && port < self.pm1a_cnt() + 2 => {
+                (AcpiRegister::Pm1Control, port - self.pm1a_cnt())
+            }
+            Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END => {
+                let offset = port - Self::GPE_BLOCK_START;
+                if offset < 2 {
+                    (AcpiRegister::Gpe0Status, offset)
+                } else {
+                    (AcpiRegister::Gpe0Enable, offset - 2)
+                }
+            }
+            _ => return None,
+        };
+        Some((register, offset * 8))
+    }
+
+    fn read_byte(&self, port: Port) -> u8 {
+        let (register, shift) = match self.register(port) {
+            Some(register) => register,
+            None => return 0,
+        };
+        let value = match register {
+            AcpiRegister::Pm1Status => self.pm1_status,
+            AcpiRegister::Pm1Enable => self.pm1_enable,
+            AcpiRegister::Pm1Control => self.pm1_control,
+            AcpiRegister::Gpe0Status => self.gpe0_status,
+            AcpiRegister::Gpe0Enable => self.gpe0_enable,
+        };
+        (value >> shift) as u8
+    }
+
+    fn write_byte(
+        &mut self,
+        port: Port,
+        val: u8,
+        responses: &mut ResponseEventArray,
+    ) {
+        let (register, shift) = match self.register(port) {
+            Some(register) => register,
+            None => return,
+        };
+        let mask = 0xff << shift;
+        let val = (val as u16) << shift;
+
+        // The status registers are 'write one to clear'
+        match register {
+            AcpiRegister::Pm1Status => self.pm1_status &= !val,
+            AcpiRegister::Pm1Enable => {
+                self.pm1_enable =
+                    (self.pm1_enable & !mask) | (val & PM1_EN_MASK)
+            }
+            AcpiRegister::Pm1Control => self
+                .write_pm1_control((self.pm1_control & !mask) | val, responses),
+            AcpiRegister::Gpe0Status => self.gpe0_status &= !val,
+            AcpiRegister::Gpe0Enable => {
+                self.gpe0_enable = (self.gpe0_enable & !mask) | val
+            }
+        }
+    }
+
+    fn write_pm1_control(
+        &mut self,
+        val: u16,
+        responses: &mut ResponseEventArray,
+    ) {
+        // SCI_EN is owned by the hardware (it is only changed through the
+        // SMI command port), and SLP_EN and GBL_RLS are write-only
+        self.pm1_control = (val
+            & !(PM1_CNT_SCI_EN | PM1_CNT_GBL_RLS | PM1_CNT_SLP_EN))
+            | (self.pm1_control & PM1_CNT_SCI_EN);
+        if val & PM1_CNT_SLP_EN == 0 {
+            return;
+        }
+
+        let sleep_type = (val & PM1_CNT_SLP_TYP_MASK) >> PM1_CNT_SLP_TYP_SHIFT;
+        if sleep_type == SLP_TYP_S5 {
+            responses.push(DeviceEventResponse::PowerEvent(
+                vm::PowerEvent::PowerOff,
+            ));
+        } else {
+            warn!("Unsupported ACPI sleeping type {}", sleep_type);
+        }
+    }
+
+    fn on_smi_command(&mut self, val: u8) {
+        match val {
+            ACPI_ENABLE => self.pm1_control |= PM1_CNT_SCI_EN,
+            ACPI_DISABLE => self.pm1_control &= !PM1_CNT_SCI_EN,
+            val => info!("Ignoring unsupported SMI command 0x{:x}", val),
+        }
+    }
+
+    // The SCI is a level triggered interrupt that is asserted while any
+    // enabled event is pending (and ACPI mode is enabled)
+    fn update_sci(&mut self, responses: &mut ResponseEventArray) {
+        let pending = self.pm1_status & self.pm1_enable != 0
+            || self.gpe0_status & self.gpe0_enable != 0;
+        let level = pending && self.pm1_control & PM1_CNT_SCI_EN != 0;
+        if level != self.sci_level {
+            self.sci_level = level;
+            responses.push(DeviceEventResponse::GSILevel(
+                interrupt::gsi::SCI,
+                level,
+            ));
+        }
+    }
 }
 
 impl EmulatedDevice for AcpiRuntime {
@@ -45,8 +226,8 @@ impl EmulatedDevice for AcpiRuntime {
             DeviceRegion::PortIo(
                 Self::FADT_SMI_COMMAND..=Self::FADT_SMI_COMMAND,
             ),
-            DeviceRegion::PortIo(self.pm1a_cnt()..=self.pm1a_cnt()),
-            DeviceRegion::PortIo(self.pmtimer()..=self.pmtimer()),
+            DeviceRegion::PortIo(self.pm1a_evt()..=self.pm1a_cnt() + 1),
+            DeviceRegion::PortIo(self.pmtimer()..=self.pmtimer() + 3),
             DeviceRegion::PortIo(Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END),
             DeviceRegion::PortIo(
                 Self::PCI_SLOT_INJECTION_START..=Self::PCI_SLOT_INJECTION_END,
@@ -73,6 +254,27 @@ impl EmulatedDevice for AcpiRuntime {
                     let pm_time = (on_duration.as_nanos() * PMTIMER_HZ as u128)
                         / 1_000_000_000;
                     val.copy_from_u32(pm_time as u32);
+                } else {
+                    // The byte at the lowest port is the least significant
+                    let len = val.as_slice().len();
+                    let value = (0..len).fold(0, |value, i| {
+                        value
+                            | (self.read_byte(port + i as Port) as u32)
+                                << (i * 8)
+                    });
+                    val.copy_from_u32(value);
+                }
+            }
+            DeviceEvent::PortWrite(Self::FADT_SMI_COMMAND, val) => {
+                self.on_smi_command(val.as_u32() as u8);
+            }
+            DeviceEvent::PortWrite(port, val)
+                if self.register(port).is_some() =>
+            {
+                let value = val.as_u32();
+                for i in 0..val.as_slice().len() {
+                    let byte = (value >> (i * 8)) as u8;
+                    self.write_byte(port + i as Port, byte, event.responses);
                 }
             }
             DeviceEvent::PortWrite(port, val) => {
@@ -81,9 +283,147 @@ impl EmulatedDevice for AcpiRuntime {
                     port, val
                 );
             }
+            DeviceEvent::PowerButtonPressed => {
+                self.pm1_status |= PM1_PWRBTN;
+            }
             _ => (),
         }
 
+        self.update_sci(event.responses);
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new(self.pm_base)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{port_read, port_write, send_event};
+
+    fn write_word(
+        acpi: &mut AcpiRuntime,
+        port: Port,
+        value: u16,
+    ) -> ResponseEventArray {
+        port_write(acpi, port, value as u32, 2)
+    }
+
+    fn smi_command(acpi: &mut AcpiRuntime, command: u8) {
+        port_write(acpi, AcpiRuntime::FADT_SMI_COMMAND, command as u32, 1);
+    }
+
+    fn read_word(acpi: &mut AcpiRuntime, port: Port) -> u16 {
+        port_read(acpi, port, 2) as u16
+    }
+
+    fn is_poweroff(responses: &ResponseEventArray) -> bool {
+        responses.iter().any(|resp| match resp {
+            DeviceEventResponse::PowerEvent(vm::PowerEvent::PowerOff) => true,
+            _ => false,
+        })
+    }
+
+    fn sci_level(responses: &ResponseEventArray) -> Option<bool> {
+        responses.iter().find_map(|resp| match resp {
+            DeviceEventResponse::GSILevel(gsi, level)
+                if *gsi == interrupt::gsi::SCI =>
+            {
+                Some(*level)
+            }
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_s5_poweroff() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        let pm1a_cnt = acpi.pm1a_cnt();
+
+        // Setting the sleeping type alone has no effect
+        let s5 = SLP_TYP_S5 << PM1_CNT_SLP_TYP_SHIFT;
+        let responses = write_word(&mut acpi, pm1a_cnt, s5);
+        assert!(!is_poweroff(&responses));
+
+        let responses = write_word(&mut acpi, pm1a_cnt, s5 | PM1_CNT_SLP_EN);
+        assert!(is_poweroff(&responses));
+
+        // Other sleeping states are not supported
+        let s3 = 1 << PM1_CNT_SLP_TYP_SHIFT;
+        let responses = write_word(&mut acpi, pm1a_cnt, s3 | PM1_CNT_SLP_EN);
+        assert!(!is_poweroff(&responses));
+
+        // SLP_EN always reads as zero
+        assert_eq!(read_word(&mut acpi, pm1a_cnt), s3);
+    }
+
+    #[test]
+    fn test_acpi_enable() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        let pm1a_cnt = acpi.pm1a_cnt();
+        assert_eq!(read_word(&mut acpi, pm1a_cnt) & PM1_CNT_SCI_EN, 0);
+
+        // SCI_EN can't be changed by the OS directly
+        write_word(&mut acpi, pm1a_cnt, PM1_CNT_SCI_EN);
+        assert_eq!(read_word(&mut acpi, pm1a_cnt) & PM1_CNT_SCI_EN, 0);
+
+        smi_command(&mut acpi, ACPI_ENABLE);
+        assert_eq!(read_word(&mut acpi, pm1a_cnt), PM1_CNT_SCI_EN);
+
+        write_word(&mut acpi, pm1a_cnt, 0);
+        assert_eq!(read_word(&mut acpi, pm1a_cnt), PM1_CNT_SCI_EN);
+
+        smi_command(&mut acpi, ACPI_DISABLE);
+        assert_eq!(read_word(&mut acpi, pm1a_cnt), 0);
+    }
+
+    #[test]
+    fn test_power_button_sci() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        let pm1_status = acpi.pm1a_evt();
+        let pm1_enable = acpi.pm1a_evt() + 2;
+
+        smi_command(&mut acpi, ACPI_ENABLE);
+
+        // A disabled event is reported in the status register only
+        let responses = send_event(&mut acpi, DeviceEvent::PowerButtonPressed);
+        assert_eq!(sci_level(&responses), None);
+        assert_eq!(read_word(&mut acpi, pm1_status), PM1_PWRBTN);
+
+        // Byte accesses use the byte of the register at that port
+        let high = port_read(&mut acpi, pm1_status + 1, 1);
+        assert_eq!(high, (PM1_PWRBTN >> 8) as u32);
+
+        let responses = write_word(&mut acpi, pm1_enable, PM1_PWRBTN);
+        assert_eq!(sci_level(&responses), Some(true));
+        assert_eq!(read_word(&mut acpi, pm1_enable), PM1_PWRBTN);
+
+        // Writing zero does not clear the status
+        let responses = write_word(&mut acpi, pm1_status, 0);
+        assert_eq!(sci_level(&responses), None);
+
+        // Writing one does (which deasserts the SCI)
+        let responses = write_word(&mut acpi, pm1_status, PM1_PWRBTN);
+        assert_eq!(sci_level(&responses), Some(false));
+        assert_eq!(read_word(&mut acpi, pm1_status), 0);
+    }
+
+    #[test]
+    fn test_gpe0_sci() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        smi_command(&mut acpi, ACPI_ENABLE);
+
+        acpi.gpe0_status = 1 << 1;
+        let responses =
+            port_write(&mut acpi, AcpiRuntime::GPE_BLOCK_START + 2, 0x02, 1);
+        assert_eq!(sci_level(&responses), Some(true));
+
+        let responses =
+            port_write(&mut acpi, AcpiRuntime::GPE_BLOCK_START, 0x02, 1);
+        assert_eq!(sci_level(&responses), Some(false));
+        assert_eq!(read_word(&mut acpi, AcpiRuntime::GPE_BLOCK_START), 0);
+    }
 }
@@ -2,153 +2,752 @@ use crate::error::Result;
 use crate::interrupt;
 use crate::physdev::com::*;
 use crate::virtdev::{
-    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, PortReadRequest, PortWriteRequest, ResponseEventArray,
 };
 use alloc::vec::Vec;
+use arraydeque::ArrayDeque;
 use core::convert::TryInto;
 
+const FIFO_SIZE: usize = 16;
+const LCR_DIVISOR_LATCH_ACCESS: u8 = 1 << 7;
+
+/// An emulated 16550A UART
+///
+/// The transmit side of the line is always ready, so bytes written by the
+/// guest leave the transmit FIFO as soon as the write completes. There is
+/// also no notion of 'character time', so data sitting in the receive FIFO
+/// below the trigger level is immediately reported as a character timeout.
 pub struct Uart8250 {
     base_port: Port,
     divisor: u16,
-    receive_buffer: Option<u8>,
+    receive_fifo: ArrayDeque<[u8; FIFO_SIZE]>,
+    transmit_fifo: ArrayDeque<[u8; FIFO_SIZE]>,
     interrupt_enable_register: IerFlags,
-    interrupt_identification_register: u8,
-    _line_control_register: u8,
-    _modem_control_register: u8,
-    _line_status_register: LsrFlags,
-    _modem_status_register: u8,
-    _scratch_register: u8,
-
-    ctrl_a_count: u8,
+    fifo_control_register: FcrFlags,
+    line_control_register: u8,
+    modem_control_register: McrFlags,
+
+    // Only the 'sticky' error bits are stored here, the remaining bits are
+    // derived from the state of the FIFOs.
+    line_status_register: LsrFlags,
+    modem_status_register: MsrFlags,
+    scratch_register: u8,
+
+    thr_empty_pending: bool,
+    interrupt_line: bool,
 }
 
 impl Uart8250 {
     pub fn new(base_port: Port) -> Result<Self> {
-        Ok(Self {
+        let mut uart = Self {
             base_port: base_port,
             divisor: 0,
-            receive_buffer: None,
-            interrupt_identification_register: 0x01,
+            receive_fifo: ArrayDeque::new(),
+            transmit_fifo: ArrayDeque::new(),
             interrupt_enable_register: IerFlags::empty(),
-            _line_control_register: 0,
-            _modem_control_register: 0,
-            _line_status_register: LsrFlags::empty(),
-            _modem_status_register: 0,
-            _scratch_register: 0,
-            ctrl_a_count: 0,
-        })
+            fifo_control_register: FcrFlags::empty(),
+            line_control_register: 0,
+            modem_control_register: McrFlags::empty(),
+            line_status_register: LsrFlags::empty(),
+            modem_status_register: MsrFlags::empty(),
+            scratch_register: 0,
+            thr_empty_pending: false,
+            interrupt_line: false,
+        };
+
+        // The initial line state should not be reported as a change
+        uart.update_modem_status();
+        uart.modem_status_register &= !Self::msr_delta_flags();
+        Ok(uart)
+    }
+
+    fn msr_delta_flags() -> MsrFlags {
+        MsrFlags::DELTA_CLEAR_TO_SEND
+            | MsrFlags::DELTA_DATA_SET_READY
+            | MsrFlags::TRAILING_EDGE_RING_INDICATOR
+            | MsrFlags::DELTA_DATA_CARRIER_DETECT
+    }
+
+    fn lsr_error_flags() -> LsrFlags {
+        LsrFlags::OVERRUN_ERROR
+            | LsrFlags::PARITY_ERROR
+            | LsrFlags::FRAMING_ERROR
+            | LsrFlags::BREAK_INTERRUPT
     }
 
     fn divisor_latch_bit_set(&self) -> bool {
-        self._line_control_register & (1 << 7) != 0
+        self.line_control_register & LCR_DIVISOR_LATCH_ACCESS != 0
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fifo_control_register.contains(FcrFlags::ENABLE_FIFO)
+    }
+
+    fn loopback_enabled(&self) -> bool {
+        self.modem_control_register
+            .contains(McrFlags::LOOPBACK_MODE)
+    }
+
+    fn receive_trigger_level(&self) -> usize {
+        match self.fifo_control_register.bits() >> 6 {
+            0b00 => 1,
+            0b01 => 4,
+            0b10 => 8,
+            _ => 14,
+        }
     }
 
     /// Insert a byte to the receive buffer of the UART. This will be
     /// _read_ by the VM.
     pub fn write(&mut self, data: u8) {
-        self.receive_buffer = Some(data);
-        self.interrupt_identification_register = 0b100;
+        // Without the FIFOs, the UART behaves like a 16450 with a single
+        // byte receive holding register.
+        let capacity = if self.fifo_enabled() { FIFO_SIZE } else { 1 };
+
+        if self.receive_fifo.len() >= capacity {
+            self.line_status_register.insert(LsrFlags::OVERRUN_ERROR);
+
+            // With the FIFOs enabled, the character in the shift register
+            // is lost. Otherwise the holding register is overwritten.
+            if self.fifo_enabled() {
+                return;
+            }
+            self.receive_fifo.pop_back();
+        }
+
+        // This cannot fail, as we just ensured there is space
+        let _ = self.receive_fifo.push_back(data);
     }
-}
 
-impl EmulatedDevice for Uart8250 {
-    fn services(&self) -> Vec<DeviceRegion> {
-        vec![DeviceRegion::PortIo(self.base_port..=self.base_port + 7)]
+    fn line_status(&self) -> LsrFlags {
+        let mut flags = self.line_status_register;
+
+        if !self.receive_fifo.is_empty() {
+            flags.insert(LsrFlags::DATA_READY);
+        }
+
+        if self.transmit_fifo.is_empty() {
+            flags.insert(
+                LsrFlags::EMPTY_TRANSMIT_HOLDING_REGISTER
+                    | LsrFlags::EMPTY_DATA_HOLDING_REGISTER,
+            );
+        }
+
+        if self.fifo_enabled()
+            && self
+                .line_status_register
+                .intersects(Self::lsr_error_flags())
+        {
+            flags.insert(LsrFlags::RECV_FIFO_ERROR);
+        }
+
+        flags
     }
 
-    fn on_event(&mut self, event: Event) -> Result<()> {
-        match event.kind {
-            DeviceEvent::HostUartReceived(key) => {
-                event
-                    .responses
-                    .push(DeviceEventResponse::GSI(interrupt::gsi::UART));
-                if key == 0x01 {
-                    // ctrl+a
-                    self.ctrl_a_count += 1;
-                }
-                if self.ctrl_a_count == 3 {
-                    event.responses.push(DeviceEventResponse::NextConsole);
-                    self.ctrl_a_count = 0;
-                }
-                self.write(key)
+    /// Returns the highest priority pending interrupt identifier
+    fn pending_interrupt(&self) -> u8 {
+        let ier = self.interrupt_enable_register;
+        if ier.contains(IerFlags::RECEIVER_LINE_STATUS_INTERRUPT)
+            && self
+                .line_status_register
+                .intersects(Self::lsr_error_flags())
+        {
+            InterruptId::RECV_LINE_STATUS
+        } else if ier.contains(IerFlags::RECV_DATA_AVAIL_INTERRUPT)
+            && !self.receive_fifo.is_empty()
+        {
+            if !self.fifo_enabled()
+                || self.receive_fifo.len() >= self.receive_trigger_level()
+            {
+                InterruptId::RECV_DATA_AVAIL
+            } else {
+                InterruptId::CHAR_TIMEOUT
             }
-            DeviceEvent::PortRead(port, mut val) => {
-                if port - self.base_port == SerialOffset::DATA
-                    && !self.divisor_latch_bit_set()
-                {
-                    if let Some(receive_buffer) = self.receive_buffer {
-                        val.copy_from_u32(receive_buffer.into());
-                        self.receive_buffer = None;
-                        self.interrupt_identification_register = 1;
-                    }
-                } else if port - self.base_port == SerialOffset::DATA
-                    && self.divisor_latch_bit_set()
-                {
-                    val.copy_from_u32((self.divisor & 0xff).into());
-                } else if port - self.base_port == SerialOffset::DLL
-                    && self.divisor_latch_bit_set()
-                {
-                    val.copy_from_u32((self.divisor >> 8).into());
-                }
+        } else if ier.contains(IerFlags::THR_EMPTY_INTERRUPT)
+            && self.thr_empty_pending
+        {
+            InterruptId::THR_EMPTY
+        } else if ier.contains(IerFlags::MODEM_STATUS_INTERRUPT)
+            && self
+                .modem_status_register
+                .intersects(Self::msr_delta_flags())
+        {
+            InterruptId::MODEM_STATUS
+        } else {
+            InterruptId::NONE
+        }
+    }
 
-                if port - self.base_port == SerialOffset::IIR {
-                    val.copy_from_u32(
-                        self.interrupt_identification_register as u32,
-                    );
-
-                    // Reading the IIR clears it (the LSB = 1 indicates there is _not_ a
-                    // pending interrupt)
-                    self.interrupt_identification_register = 1;
-                } else if port - self.base_port == SerialOffset::IER {
-                    val.copy_from_u32(
-                        self.interrupt_enable_register.bits() as u32
-                    );
-                }
+    fn interrupt_identification(&self) -> u8 {
+        let mut iir = self.pending_interrupt();
+        if self.fifo_enabled() {
+            iir |= InterruptId::FIFOS_ENABLED;
+        }
+        iir
+    }
+
+    /// Raise the UART GSI if the interrupt output has just become active.
+    ///
+    /// On the PC, the interrupt output is gated by OUT2, which is never
+    /// driven externally in loopback mode.
+    fn update_interrupt(&mut self, responses: &mut ResponseEventArray) {
+        let active = self.pending_interrupt() != InterruptId::NONE
+            && self.modem_control_register.contains(McrFlags::AUX_OUTPUT_2)
+            && !self.loopback_enabled();
 
-                if port - self.base_port == SerialOffset::LSR {
-                    let mut flags = LsrFlags::EMPTY_TRANSMIT_HOLDING_REGISTER
-                        | LsrFlags::EMPTY_DATA_HOLDING_REGISTER;
+        if active && !self.interrupt_line {
+            responses.push(DeviceEventResponse::GSI(interrupt::gsi::UART));
+        }
+        self.interrupt_line = active;
+    }
+
+    /// Recalculate the modem input lines and record any changes in the
+    /// delta bits of the MSR.
+    fn update_modem_status(&mut self) {
+        let lines = if self.loopback_enabled() {
+            // In loopback mode the modem control outputs are internally
+            // connected to the modem status inputs.
+            let mcr = self.modem_control_register;
+            let mut lines = MsrFlags::empty();
+            lines.set(
+                MsrFlags::CLEAR_TO_SEND,
+                mcr.contains(McrFlags::REQUEST_TO_SEND),
+            );
+            lines.set(
+                MsrFlags::DATA_SET_READY,
+                mcr.contains(McrFlags::DATA_TERMINAL_READY),
+            );
+            lines.set(
+                MsrFlags::RING_INDICATOR,
+                mcr.contains(McrFlags::AUX_OUTPUT_1),
+            );
+            lines.set(
+                MsrFlags::DATA_CARRIER_DETECT,
+                mcr.contains(McrFlags::AUX_OUTPUT_2),
+            );
+            lines
+        } else {
+            // The host console is always connected and ready
+            MsrFlags::CLEAR_TO_SEND
+                | MsrFlags::DATA_SET_READY
+                | MsrFlags::DATA_CARRIER_DETECT
+        };
+
+        let old = self.modem_status_register;
+        let mut msr = (old & Self::msr_delta_flags()) | lines;
+
+        if old.contains(MsrFlags::CLEAR_TO_SEND)
+            != lines.contains(MsrFlags::CLEAR_TO_SEND)
+        {
+            msr.insert(MsrFlags::DELTA_CLEAR_TO_SEND);
+        }
+        if old.contains(MsrFlags::DATA_SET_READY)
+            != lines.contains(MsrFlags::DATA_SET_READY)
+        {
+            msr.insert(MsrFlags::DELTA_DATA_SET_READY);
+        }
+        if old.contains(MsrFlags::RING_INDICATOR)
+            && !lines.contains(MsrFlags::RING_INDICATOR)
+        {
+            msr.insert(MsrFlags::TRAILING_EDGE_RING_INDICATOR);
+        }
+        if old.contains(MsrFlags::DATA_CARRIER_DETECT)
+            != lines.contains(MsrFlags::DATA_CARRIER_DETECT)
+        {
+            msr.insert(MsrFlags::DELTA_DATA_CARRIER_DETECT);
+        }
+
+        self.modem_status_register = msr;
+    }
+
+    fn write_fifo_control(&mut self, fcr: FcrFlags) {
+        // Toggling the FIFO enable bit resets both FIFOs
+        if fcr.contains(FcrFlags::ENABLE_FIFO) != self.fifo_enabled() {
+            self.receive_fifo.clear();
+            self.transmit_fifo.clear();
+        }
+
+        // The remaining bits are only written when the FIFO enable bit is set
+        if !fcr.contains(FcrFlags::ENABLE_FIFO) {
+            self.fifo_control_register = FcrFlags::empty();
+            return;
+        }
+
+        if fcr.contains(FcrFlags::CLEAR_RECEIVE_FIFO) {
+            self.receive_fifo.clear();
+        }
+        if fcr.contains(FcrFlags::CLEAR_TRANSMIT_FIFO) {
+            self.transmit_fifo.clear();
+        }
 
-                    if self.receive_buffer.is_some() {
-                        flags.insert(LsrFlags::DATA_READY);
-                    }
+        // The FIFO reset bits are self-clearing
+        self.fifo_control_register = fcr
+            & !(FcrFlags::CLEAR_RECEIVE_FIFO | FcrFlags::CLEAR_TRANSMIT_FIFO);
+    }
+
+    fn transmit(&mut self, data: u8, responses: &mut ResponseEventArray) {
+        if self.transmit_fifo.push_back(data).is_err() {
+            // The transmit FIFO is drained on every write, so this should
+            // not be possible.
+            warn!("Virtual UART transmit FIFO overflow");
+            return;
+        }
 
-                    val.copy_from_u32(flags.bits() as u32);
+        self.thr_empty_pending = false;
+
+        while let Some(byte) = self.transmit_fifo.pop_front() {
+            if self.loopback_enabled() {
+                self.write(byte);
+            } else {
+                responses.push(DeviceEventResponse::GuestUartTransmitted(byte));
+            }
+        }
+
+        self.thr_empty_pending = true;
+    }
+
+    fn on_port_read(&mut self, offset: Port, mut val: PortReadRequest) {
+        match offset {
+            SerialOffset::DLL if self.divisor_latch_bit_set() => {
+                val.copy_from_u32((self.divisor & 0xff).into());
+            }
+            SerialOffset::DLH if self.divisor_latch_bit_set() => {
+                val.copy_from_u32((self.divisor >> 8).into());
+            }
+            SerialOffset::DATA => {
+                let data = self.receive_fifo.pop_front().unwrap_or(0);
+                val.copy_from_u32(data.into());
+            }
+            SerialOffset::IER => {
+                val.copy_from_u32(self.interrupt_enable_register.bits() as u32);
+            }
+            SerialOffset::IIR => {
+                let iir = self.interrupt_identification();
+                val.copy_from_u32(iir as u32);
+
+                // Reading the IIR clears a THR empty interrupt if that
+                // is the interrupt being reported.
+                if iir & 0b1111 == InterruptId::THR_EMPTY {
+                    self.thr_empty_pending = false;
                 }
             }
-            DeviceEvent::PortWrite(port, val) => {
-                let val: u8 = val.try_into()?;
-                if port - self.base_port == SerialOffset::DATA {
-                    if self.divisor_latch_bit_set() {
-                        self.divisor &= 0xff00 | val as u16;
-                    } else {
-                        event.responses.push(
-                            DeviceEventResponse::GuestUartTransmitted(val),
-                        );
-
-                        if self
-                            .interrupt_enable_register
-                            .contains(IerFlags::THR_EMPTY_INTERRUPT)
-                        {
-                            event.responses.push(DeviceEventResponse::GSI(
-                                interrupt::gsi::UART,
-                            ));
-                        }
-                        self.interrupt_identification_register = 0b10;
-                    }
-                } else if port - self.base_port == SerialOffset::DLL
-                    && self.divisor_latch_bit_set()
+            SerialOffset::LCR => {
+                val.copy_from_u32(self.line_control_register as u32);
+            }
+            SerialOffset::MCR => {
+                val.copy_from_u32(self.modem_control_register.bits() as u32);
+            }
+            SerialOffset::LSR => {
+                val.copy_from_u32(self.line_status().bits() as u32);
+
+                // The error bits are cleared by reading the LSR
+                self.line_status_register.remove(Self::lsr_error_flags());
+            }
+            SerialOffset::MSR => {
+                val.copy_from_u32(self.modem_status_register.bits() as u32);
+
+                // The delta bits are cleared by reading the MSR
+                self.modem_status_register.remove(Self::msr_delta_flags());
+            }
+            SerialOffset::SCR => {
+                val.copy_from_u32(self.scratch_register as u32);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn on_port_write(
+        &mut self,
+        offset: Port,
+        val: PortWriteRequest,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        match offset {
+            SerialOffset::DLL if self.divisor_latch_bit_set() => {
+                self.divisor = (self.divisor & 0xff00) | val as u16;
+            }
+            SerialOffset::DLH if self.divisor_latch_bit_set() => {
+                self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+            }
+            SerialOffset::DATA => self.transmit(val, responses),
+            SerialOffset::IER => {
+                let ier = IerFlags::from_bits_truncate(val & 0b1111);
+
+                // Enabling the THR empty interrupt while the transmitter is
+                // empty immediately raises the interrupt.
+                if ier.contains(IerFlags::THR_EMPTY_INTERRUPT)
+                    && !self
+                        .interrupt_enable_register
+                        .contains(IerFlags::THR_EMPTY_INTERRUPT)
+                    && self.transmit_fifo.is_empty()
                 {
-                    self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+                    self.thr_empty_pending = true;
                 }
+                self.interrupt_enable_register = ier;
+            }
+            SerialOffset::FCR => {
+                self.write_fifo_control(FcrFlags::from_bits_truncate(val))
+            }
+            SerialOffset::LCR => self.line_control_register = val,
+            SerialOffset::MCR => {
+                self.modem_control_register = McrFlags::from_bits_truncate(val);
+                self.update_modem_status();
+            }
+            SerialOffset::LSR | SerialOffset::MSR => {
+                // These registers are only writable in factory test modes
+                debug!(
+                    "Ignoring write to read-only UART register at offset {}",
+                    offset
+                );
+            }
+            SerialOffset::SCR => self.scratch_register = val,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for Uart8250 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.base_port..=self.base_port + 7)]
+    }
 
-                if port - self.base_port == SerialOffset::IER {
-                    self.interrupt_enable_register =
-                        IerFlags::from_bits_truncate(val);
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::HostUartReceived(key) => {
+                // In loopback mode the serial input is disconnected
+                if !self.loopback_enabled() {
+                    self.write(key)
                 }
             }
+            DeviceEvent::PortRead(port, val) => {
+                self.on_port_read(port - self.base_port, val)
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                self.on_port_write(port - self.base_port, val, event.responses)?
+            }
             _ => (),
         }
+        self.update_interrupt(event.responses);
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new(self.base_port)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{port_read, port_write, send_event};
+
+    const TEST_BASE_PORT: Port = 0x3f8;
+
+    fn write_reg(
+        uart: &mut Uart8250,
+        offset: Port,
+        value: u8,
+    ) -> ResponseEventArray {
+        port_write(uart, TEST_BASE_PORT + offset, value as u32, 1)
+    }
+
+    fn read_reg(uart: &mut Uart8250, offset: Port) -> u8 {
+        port_read(uart, TEST_BASE_PORT + offset, 1) as u8
+    }
+
+    fn receive(uart: &mut Uart8250, data: u8) -> ResponseEventArray {
+        send_event(uart, DeviceEvent::HostUartReceived(data))
+    }
+
+    fn has_gsi(responses: &ResponseEventArray) -> bool {
+        responses.iter().any(|resp| match resp {
+            DeviceEventResponse::GSI(gsi) => *gsi == interrupt::gsi::UART,
+            _ => false,
+        })
+    }
+
+    fn transmitted(responses: &ResponseEventArray) -> Vec<u8> {
+        responses
+            .iter()
+            .filter_map(|resp| match resp {
+                DeviceEventResponse::GuestUartTransmitted(val) => Some(*val),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scratch_register() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::SCR, 0xa5);
+        assert_eq!(read_reg(&mut uart, SerialOffset::SCR), 0xa5);
+    }
+
+    #[test]
+    fn test_divisor_latch() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::LCR, 0x83);
+        write_reg(&mut uart, SerialOffset::DLL, 0x0c);
+        write_reg(&mut uart, SerialOffset::DLH, 0x01);
+        assert_eq!(read_reg(&mut uart, SerialOffset::DLL), 0x0c);
+        assert_eq!(read_reg(&mut uart, SerialOffset::DLH), 0x01);
+        assert_eq!(uart.divisor, 0x010c);
+
+        // With DLAB clear, offset 1 is the IER again
+        write_reg(&mut uart, SerialOffset::LCR, 0x03);
+        assert_eq!(read_reg(&mut uart, SerialOffset::IER), 0x00);
+        assert_eq!(read_reg(&mut uart, SerialOffset::LCR), 0x03);
+    }
+
+    #[test]
+    fn test_fifo_detection() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        assert_eq!(read_reg(&mut uart, SerialOffset::IIR), 0x01);
+
+        write_reg(&mut uart, SerialOffset::FCR, 0x01);
+        assert_eq!(read_reg(&mut uart, SerialOffset::IIR) >> 6, 0b11);
+
+        write_reg(&mut uart, SerialOffset::FCR, 0x00);
+        assert_eq!(read_reg(&mut uart, SerialOffset::IIR) >> 6, 0b00);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        let responses = write_reg(&mut uart, SerialOffset::DATA, b'x');
+        assert_eq!(transmitted(&responses), vec![b'x']);
+
+        let lsr = LsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::LSR,
+        ));
+        assert!(lsr.contains(
+            LsrFlags::EMPTY_TRANSMIT_HOLDING_REGISTER
+                | LsrFlags::EMPTY_DATA_HOLDING_REGISTER
+        ));
+    }
+
+    #[test]
+    fn test_receive_fifo_order() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::FCR, 0x07);
+
+        for i in 0..FIFO_SIZE as u8 {
+            receive(&mut uart, i);
+        }
+
+        for i in 0..FIFO_SIZE as u8 {
+            let lsr = read_reg(&mut uart, SerialOffset::LSR);
+            assert!(LsrFlags::from_bits_truncate(lsr)
+                .contains(LsrFlags::DATA_READY));
+            assert_eq!(read_reg(&mut uart, SerialOffset::DATA), i);
+        }
+
+        let lsr = read_reg(&mut uart, SerialOffset::LSR);
+        assert!(
+            !LsrFlags::from_bits_truncate(lsr).contains(LsrFlags::DATA_READY)
+        );
+    }
+
+    #[test]
+    fn test_receive_overrun() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::FCR, 0x01);
+
+        for i in 0..(FIFO_SIZE + 1) as u8 {
+            receive(&mut uart, i);
+        }
+
+        let lsr = LsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::LSR,
+        ));
+        assert!(lsr.contains(LsrFlags::OVERRUN_ERROR));
+        assert!(lsr.contains(LsrFlags::RECV_FIFO_ERROR));
+
+        // Reading the LSR clears the error
+        let lsr = LsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::LSR,
+        ));
+        assert!(!lsr.contains(LsrFlags::OVERRUN_ERROR));
+
+        // The last byte was lost
+        for i in 0..FIFO_SIZE as u8 {
+            assert_eq!(read_reg(&mut uart, SerialOffset::DATA), i);
+        }
+    }
+
+    #[test]
+    fn test_non_fifo_overrun() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        receive(&mut uart, 1);
+        receive(&mut uart, 2);
+
+        let lsr = LsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::LSR,
+        ));
+        assert!(lsr.contains(LsrFlags::OVERRUN_ERROR));
+        assert!(!lsr.contains(LsrFlags::RECV_FIFO_ERROR));
+        assert_eq!(read_reg(&mut uart, SerialOffset::DATA), 2);
+    }
+
+    #[test]
+    fn test_trigger_level() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(
+            &mut uart,
+            SerialOffset::IER,
+            IerFlags::RECV_DATA_AVAIL_INTERRUPT.bits(),
+        );
+
+        // Trigger level of 4 bytes
+        write_reg(&mut uart, SerialOffset::FCR, 0x41);
+
+        for i in 0..3 {
+            receive(&mut uart, i);
+        }
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::FIFOS_ENABLED | InterruptId::CHAR_TIMEOUT
+        );
+
+        receive(&mut uart, 3);
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::FIFOS_ENABLED | InterruptId::RECV_DATA_AVAIL
+        );
+
+        // Clearing the receive FIFO removes the interrupt
+        write_reg(&mut uart, SerialOffset::FCR, 0x43);
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::FIFOS_ENABLED | InterruptId::NONE
+        );
+    }
+
+    #[test]
+    fn test_iir_priority() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::IER, 0x0f);
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::THR_EMPTY
+        );
+
+        // Cause an overrun in non-FIFO mode
+        receive(&mut uart, 1);
+        receive(&mut uart, 2);
+        write_reg(&mut uart, SerialOffset::DATA, b'a');
+
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::RECV_LINE_STATUS
+        );
+        read_reg(&mut uart, SerialOffset::LSR);
+
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::RECV_DATA_AVAIL
+        );
+        read_reg(&mut uart, SerialOffset::DATA);
+
+        assert_eq!(
+            read_reg(&mut uart, SerialOffset::IIR),
+            InterruptId::THR_EMPTY
+        );
+
+        // Reading the IIR cleared the THR empty interrupt
+        assert_eq!(read_reg(&mut uart, SerialOffset::IIR), InterruptId::NONE);
+    }
+
+    #[test]
+    fn test_interrupt_gated_by_out2() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(
+            &mut uart,
+            SerialOffset::IER,
+            IerFlags::RECV_DATA_AVAIL_INTERRUPT.bits(),
+        );
+        assert!(!has_gsi(&receive(&mut uart, b'a')));
+        read_reg(&mut uart, SerialOffset::DATA);
+
+        write_reg(&mut uart, SerialOffset::MCR, McrFlags::AUX_OUTPUT_2.bits());
+        assert!(has_gsi(&receive(&mut uart, b'b')));
+
+        // The line is already active, so no additional edge is generated
+        assert!(!has_gsi(&receive(&mut uart, b'c')));
+
+        read_reg(&mut uart, SerialOffset::DATA);
+        assert!(has_gsi(&receive(&mut uart, b'd')));
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        write_reg(&mut uart, SerialOffset::FCR, 0x01);
+        write_reg(&mut uart, SerialOffset::MCR, McrFlags::LOOPBACK_MODE.bits());
+
+        // The initial line changes from the host side to loopback
+        read_reg(&mut uart, SerialOffset::MSR);
+
+        let responses = write_reg(&mut uart, SerialOffset::DATA, 0x55);
+        assert!(transmitted(&responses).is_empty());
+        assert_eq!(read_reg(&mut uart, SerialOffset::DATA), 0x55);
+
+        // Host input is disconnected in loopback mode
+        receive(&mut uart, 0xaa);
+        let lsr = LsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::LSR,
+        ));
+        assert!(!lsr.contains(LsrFlags::DATA_READY));
+
+        // Modem control outputs are reflected in the modem status
+        write_reg(&mut uart, SerialOffset::MCR, 0x1f);
+        let msr = MsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::MSR,
+        ));
+        assert_eq!(
+            msr,
+            MsrFlags::all() & !MsrFlags::TRAILING_EDGE_RING_INDICATOR
+        );
+
+        write_reg(&mut uart, SerialOffset::MCR, 0x10);
+        let msr = MsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::MSR,
+        ));
+        assert_eq!(
+            msr,
+            MsrFlags::DELTA_CLEAR_TO_SEND
+                | MsrFlags::DELTA_DATA_SET_READY
+                | MsrFlags::TRAILING_EDGE_RING_INDICATOR
+                | MsrFlags::DELTA_DATA_CARRIER_DETECT
+        );
+
+        // Reading the MSR clears the deltas
+        assert_eq!(read_reg(&mut uart, SerialOffset::MSR), 0);
+    }
+
+    #[test]
+    fn test_modem_status_not_loopback() {
+        let mut uart = Uart8250::new(TEST_BASE_PORT).unwrap();
+        let msr = MsrFlags::from_bits_truncate(read_reg(
+            &mut uart,
+            SerialOffset::MSR,
+        ));
+        assert_eq!(
+            msr,
+            MsrFlags::CLEAR_TO_SEND
+                | MsrFlags::DATA_SET_READY
+                | MsrFlags::DATA_CARRIER_DETECT
+        );
+    }
 }
@@ -0,0 +1,636 @@
+use crate::error::{Error, Result};
+use crate::memory::GuestPhysAddr;
+use crate::time;
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::time::Duration;
+
+/// The guest physical address of the HPET registers
+pub const HPET_BASE: u64 = 0xfed00000;
+
+/// The number of comparators supported by the HPET
+pub const HPET_COMPARATOR_COUNT: usize = 3;
+
+/// The minimum number of ticks that should be used in periodic mode
+pub const HPET_MIN_TICK: u16 = 0x80;
+
+// The main counter runs at 10MHz
+const HPET_TICK_NS: u64 = 100;
+const HPET_CLK_PERIOD_FS: u64 = HPET_TICK_NS * 1_000_000;
+
+const HPET_REV_ID: u64 = 0x01;
+const HPET_VENDOR_ID: u64 = 0x8086;
+
+// Comparators may be routed to I/O APIC inputs 20-23 (or use the
+// legacy replacement routes)
+const HPET_INT_ROUTE_CAP: u64 = 0x00f00000;
+const HPET_LEGACY_GSIS: [u32; 2] = [0, 8];
+
+mod reg {
+    pub const CAPABILITIES: u64 = 0x000;
+    pub const CONFIG: u64 = 0x010;
+    pub const INTERRUPT_STATUS: u64 = 0x020;
+    pub const MAIN_COUNTER: u64 = 0x0f0;
+
+    pub const TIMER_BASE: u64 = 0x100;
+    pub const TIMER_SIZE: u64 = 0x20;
+    pub const TIMER_CONFIG: u64 = 0x00;
+    pub const TIMER_COMPARATOR: u64 = 0x08;
+    pub const TIMER_FSB_ROUTE: u64 = 0x10;
+}
+
+bitflags! {
+    struct HpetConfig: u64 {
+        const ENABLE = 1 << 0;
+        const LEGACY_ROUTE = 1 << 1;
+    }
+}
+
+bitflags! {
+    struct TimerConfig: u64 {
+        const LEVEL_TRIGGERED = 1 << 1;
+        const INTERRUPT_ENABLE = 1 << 2;
+        const PERIODIC = 1 << 3;
+        const PERIODIC_CAPABLE = 1 << 4;
+        const SIZE_64BIT = 1 << 5;
+        const VALUE_SET = 1 << 6;
+        const MODE_32BIT = 1 << 8;
+        const INTERRUPT_ROUTE = 0x1f << 9;
+        const FSB_ENABLE = 1 << 14;
+        const FSB_CAPABLE = 1 << 15;
+    }
+}
+
+impl TimerConfig {
+    const WRITABLE: TimerConfig = TimerConfig::from_bits_truncate(
+        TimerConfig::LEVEL_TRIGGERED.bits
+            | TimerConfig::INTERRUPT_ENABLE.bits
+            | TimerConfig::PERIODIC.bits
+            | TimerConfig::VALUE_SET.bits
+            | TimerConfig::MODE_32BIT.bits
+            | TimerConfig::INTERRUPT_ROUTE.bits,
+    );
+
+    fn route(&self) -> u32 {
+        ((self.bits & TimerConfig::INTERRUPT_ROUTE.bits) >> 9) as u32
+    }
+}
+
+struct Comparator {
+    config: TimerConfig,
+    comparator: u64,
+    period: u64,
+
+    // The main counter value at which the armed timer will fire
+    deadline: Option<u64>,
+    timer: Option<time::TimerId>,
+}
+
+impl Default for Comparator {
+    fn default() -> Self {
+        Self {
+            config: TimerConfig::PERIODIC_CAPABLE | TimerConfig::SIZE_64BIT,
+            comparator: u64::MAX,
+            period: 0,
+            deadline: None,
+            timer: None,
+        }
+    }
+}
+
+impl Comparator {
+    fn is_32bit(&self) -> bool {
+        self.config.contains(TimerConfig::MODE_32BIT)
+    }
+
+    fn is_periodic(&self) -> bool {
+        self.config.contains(TimerConfig::PERIODIC)
+    }
+
+    // The number of ticks from the given counter value until this
+    // comparator matches
+    fn ticks_until_match(&self, counter: u64) -> u64 {
+        if self.is_32bit() {
+            (self.comparator as u32).wrapping_sub(counter as u32) as u64
+        } else {
+            self.comparator.wrapping_sub(counter)
+        }
+    }
+
+    // In periodic mode, the comparator advances by the period each time
+    // it matches the main counter
+    fn current_comparator(&self, counter: u64) -> u64 {
+        match self.deadline {
+            Some(deadline)
+                if self.is_periodic()
+                    && self.period != 0
+                    && counter >= deadline =>
+            {
+                let periods = (counter - deadline) / self.period + 1;
+                self.comparator
+                    .wrapping_add(periods.wrapping_mul(self.period))
+            }
+            _ => self.comparator,
+        }
+    }
+
+    // The time until the comparator next matches (given the number of
+    // ticks until the match), and the time between matches after that
+    // for periodic comparators
+    fn timer_schedule(&self, ticks: u64) -> (Duration, Option<Duration>) {
+        let delay = Duration::from_nanos(ticks.saturating_mul(HPET_TICK_NS));
+        if self.is_periodic() && self.period != 0 {
+            let period = self.period.saturating_mul(HPET_TICK_NS);
+            (delay, Some(Duration::from_nanos(period)))
+        } else {
+            (delay, None)
+        }
+    }
+
+    fn register_value(&self, counter: u64) -> u64 {
+        let comparator = self.current_comparator(counter);
+        if self.is_32bit() {
+            comparator & 0xffffffff
+        } else {
+            comparator
+        }
+    }
+}
+
+pub struct Hpet {
+    config: HpetConfig,
+    interrupt_status: u64,
+
+    // The value of the main counter when it was last written or halted
+    counter_offset: u64,
+
+    // When the main counter was last started (if it is running)
+    started: Option<time::Instant>,
+
+    comparators: [Comparator; HPET_COMPARATOR_COUNT],
+}
+
+impl Hpet {
+    pub fn new() -> Result<Self> {
+        Ok(Hpet {
+            config: HpetConfig::empty(),
+            interrupt_status: 0,
+            counter_offset: 0,
+            started: None,
+            comparators: Default::default(),
+        })
+    }
+
+    fn capabilities(&self) -> u64 {
+        HPET_REV_ID
+            | (((HPET_COMPARATOR_COUNT - 1) as u64) << 8)
+            // 64 bit counter and legacy replacement route capable
+            | (1 << 13)
+            | (1 << 15)
+            | (HPET_VENDOR_ID << 16)
+            | (HPET_CLK_PERIOD_FS << 32)
+    }
+
+    fn main_counter(&self) -> u64 {
+        match self.started {
+            Some(started) => {
+                let elapsed = (time::now() - started).as_nanos();
+                self.counter_offset
+                    .wrapping_add((elapsed / HPET_TICK_NS as u128) as u64)
+            }
+            None => self.counter_offset,
+        }
+    }
+
+    fn comparator_gsi(&self, index: usize) -> u32 {
+        if self.config.contains(HpetConfig::LEGACY_ROUTE)
+            && index < HPET_LEGACY_GSIS.len()
+        {
+            HPET_LEGACY_GSIS[index]
+        } else {
+            self.comparators[index].config.route()
+        }
+    }
+
+    fn disarm(&mut self, index: usize) -> Result<()> {
+        let comparator = &mut self.comparators[index];
+        comparator.deadline = None;
+        if let Some(id) = comparator.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+        Ok(())
+    }
+
+    // Schedule the interrupt for a comparator based on the current state
+    // of the main counter
+    fn arm(&mut self, index: usize) -> Result<()> {
+        self.disarm(index)?;
+
+        if !self.config.contains(HpetConfig::ENABLE) {
+            return Ok(());
+        }
+
+        let counter = self.main_counter();
+        let gsi = self.comparator_gsi(index);
+        let comparator = &mut self.comparators[index];
+
+        let ticks = comparator.ticks_until_match(counter);
+        comparator.deadline = Some(counter.wrapping_add(ticks));
+
+        if !comparator.config.contains(TimerConfig::INTERRUPT_ENABLE) {
+            return Ok(());
+        }
+
+        let kind = time::TimerInterruptType::GSI(gsi);
+        comparator.timer = Some(match comparator.timer_schedule(ticks) {
+            (delay, Some(period)) => {
+                time::set_periodic_timer_after(delay, period, kind)
+            }
+            (delay, None) => time::set_oneshot_timer(delay, kind),
+        });
+        Ok(())
+    }
+
+    fn rearm_all(&mut self) -> Result<()> {
+        for index in 0..HPET_COMPARATOR_COUNT {
+            self.arm(index)?;
+        }
+        Ok(())
+    }
+
+    // Level triggered comparators report a pending interrupt in the
+    // status register until it is cleared by the guest
+    fn update_interrupt_status(&mut self) {
+        if self.started.is_none() {
+            return;
+        }
+
+        let counter = self.main_counter();
+        for (index, comparator) in self.comparators.iter().enumerate() {
+            if !comparator.config.contains(
+                TimerConfig::LEVEL_TRIGGERED | TimerConfig::INTERRUPT_ENABLE,
+            ) {
+                continue;
+            }
+            if let Some(deadline) = comparator.deadline {
+                if counter >= deadline {
+                    self.interrupt_status |= 1 << index;
+                }
+            }
+        }
+    }
+
+    fn read_register(&mut self, offset: u64) -> u64 {
+        match offset {
+            reg::CAPABILITIES => self.capabilities(),
+            reg::CONFIG => self.config.bits(),
+            reg::INTERRUPT_STATUS => {
+                self.update_interrupt_status();
+                self.interrupt_status
+            }
+            reg::MAIN_COUNTER => self.main_counter(),
+            offset if offset >= reg::TIMER_BASE => {
+                let index =
+                    ((offset - reg::TIMER_BASE) / reg::TIMER_SIZE) as usize;
+                let counter = self.main_counter();
+                let comparator = match self.comparators.get(index) {
+                    Some(comparator) => comparator,
+                    None => return 0,
+                };
+                match (offset - reg::TIMER_BASE) % reg::TIMER_SIZE {
+                    reg::TIMER_CONFIG => {
+                        comparator.config.bits() | (HPET_INT_ROUTE_CAP << 32)
+                    }
+                    reg::TIMER_COMPARATOR => comparator.register_value(counter),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u64) -> Result<()> {
+        match offset {
+            reg::CAPABILITIES => (),
+            reg::CONFIG => {
+                let old = self.config;
+                self.config = HpetConfig::from_bits_truncate(value);
+
+                let was_enabled = old.contains(HpetConfig::ENABLE);
+                let enabled = self.config.contains(HpetConfig::ENABLE);
+                if !was_enabled && enabled {
+                    self.started = Some(time::now());
+                } else if was_enabled && !enabled {
+                    self.counter_offset = self.main_counter();
+                    self.started = None;
+                }
+
+                if old != self.config {
+                    self.rearm_all()?;
+                }
+            }
+            reg::INTERRUPT_STATUS => {
+                // Write 1 to clear
+                self.interrupt_status &= !value;
+            }
+            reg::MAIN_COUNTER => {
+                if self.started.is_some() {
+                    warn!("Write to HPET main counter while it is running");
+                    self.started = Some(time::now());
+                }
+                self.counter_offset = value;
+                self.rearm_all()?;
+            }
+            offset if offset >= reg::TIMER_BASE => {
+                let index =
+                    ((offset - reg::TIMER_BASE) / reg::TIMER_SIZE) as usize;
+                let comparator = match self.comparators.get_mut(index) {
+                    Some(comparator) => comparator,
+                    None => return Ok(()),
+                };
+                match (offset - reg::TIMER_BASE) % reg::TIMER_SIZE {
+                    reg::TIMER_CONFIG => {
+                        let value = TimerConfig::from_bits_truncate(value);
+                        comparator.config = (comparator.config
+                            & !TimerConfig::WRITABLE)
+                            | (value & TimerConfig::WRITABLE);
+
+                        let route = comparator.config.route();
+                        if HPET_INT_ROUTE_CAP & (1 << route) == 0 {
+                            debug!(
+                                "HPET comparator {} routed to invalid GSI {}",
+                                index, route
+                            );
+                        }
+                    }
+                    reg::TIMER_COMPARATOR => {
+                        let value = if comparator.is_32bit() {
+                            value & 0xffffffff
+                        } else {
+                            value
+                        };
+
+                        // In periodic mode, writes set the period unless
+                        // the guest has explicitly requested to set the
+                        // comparator value.
+                        if !comparator.is_periodic()
+                            || comparator
+                                .config
+                                .contains(TimerConfig::VALUE_SET)
+                        {
+                            comparator.comparator = value;
+                        }
+                        comparator.period = value;
+                        comparator.config.remove(TimerConfig::VALUE_SET);
+                    }
+                    reg::TIMER_FSB_ROUTE => {
+                        warn!("HPET FSB interrupt delivery is not supported");
+                        return Ok(());
+                    }
+                    _ => return Ok(()),
+                }
+                self.arm(index)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // The value that a partial write to the given register is merged
+    // with. Writes to a periodic comparator set the period (unless the
+    // guest has set VALUE_SET), so they must not be merged with the
+    // current comparator value.
+    fn partial_write_base(&mut self, offset: u64) -> u64 {
+        if offset >= reg::TIMER_BASE
+            && (offset - reg::TIMER_BASE) % reg::TIMER_SIZE
+                == reg::TIMER_COMPARATOR
+        {
+            let index = ((offset - reg::TIMER_BASE) / reg::TIMER_SIZE) as usize;
+            if let Some(comparator) = self.comparators.get(index) {
+                if comparator.is_periodic()
+                    && !comparator.config.contains(TimerConfig::VALUE_SET)
+                {
+                    return comparator.period;
+                }
+            }
+        }
+        self.read_register(offset)
+    }
+}
+
+impl EmulatedDevice for Hpet {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(HPET_BASE)..=GuestPhysAddr::new(0xfed003ff),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = addr.as_u64() - HPET_BASE;
+                // Registers are 64 bits wide, but may be accessed as two
+                // 32 bit halves
+                let value = self.read_register(offset & !0b111)
+                    >> ((offset & 0b111) * 8);
+                let bytes = value.to_le_bytes();
+                for (i, byte) in req.as_mut_slice().iter_mut().enumerate() {
+                    *byte = bytes.get(i).copied().unwrap_or(0);
+                }
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = addr.as_u64() - HPET_BASE;
+                let shift = (offset & 0b111) * 8;
+                let mask = match req.as_slice().len() {
+                    8 => u64::MAX,
+                    len => ((1u64 << (len * 8)) - 1) << shift,
+                };
+
+                let register = offset & !0b111;
+                let old = self.partial_write_base(register);
+                let value = (old & !mask) | ((req.value() << shift) & mask);
+
+                // Avoid clearing interrupt status bits that were not
+                // written by the guest
+                let value = if register == reg::INTERRUPT_STATUS {
+                    value & mask
+                } else {
+                    value
+                };
+                self.write_register(register, value)?;
+            }
+            _ => return Err(Error::NotSupported),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        for index in 0..HPET_COMPARATOR_COUNT {
+            self.disarm(index)?;
+        }
+        *self = Self::new()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{define_test_view, mem_read, mem_write};
+
+    fn write_u32(hpet: &mut Hpet, offset: u64, value: u32) {
+        let addr = HPET_BASE + offset;
+        mem_write(define_test_view(), addr, value as u64, 4, |event| {
+            hpet.on_event(event)
+        });
+    }
+
+    fn write_u64(hpet: &mut Hpet, offset: u64, value: u64) {
+        let addr = HPET_BASE + offset;
+        mem_write(define_test_view(), addr, value, 8, |event| {
+            hpet.on_event(event)
+        });
+    }
+
+    fn read_mem(hpet: &mut Hpet, offset: u64, len: usize) -> u64 {
+        let addr = HPET_BASE + offset;
+        mem_read(define_test_view(), addr, len, |event| hpet.on_event(event)).0
+    }
+
+    fn read_u32(hpet: &mut Hpet, offset: u64) -> u32 {
+        read_mem(hpet, offset, 4) as u32
+    }
+
+    fn read_u64(hpet: &mut Hpet, offset: u64) -> u64 {
+        read_mem(hpet, offset, 8)
+    }
+
+    fn timer_offset(index: u64, register: u64) -> u64 {
+        reg::TIMER_BASE + index * reg::TIMER_SIZE + register
+    }
+
+    #[test]
+    fn test_hpet_capabilities() {
+        let mut hpet = Hpet::new().unwrap();
+        assert_eq!(read_u32(&mut hpet, reg::CAPABILITIES), 0x8086a201);
+        assert_eq!(
+            read_u32(&mut hpet, reg::CAPABILITIES + 4),
+            HPET_CLK_PERIOD_FS as u32
+        );
+
+        // The capabilities are read only
+        write_u64(&mut hpet, reg::CAPABILITIES, 0);
+        assert_eq!(read_u64(&mut hpet, reg::CAPABILITIES), hpet.capabilities());
+    }
+
+    #[test]
+    fn test_hpet_halted_counter() {
+        let mut hpet = Hpet::new().unwrap();
+        write_u64(&mut hpet, reg::MAIN_COUNTER, 0x1_0000_1234);
+        assert_eq!(read_u64(&mut hpet, reg::MAIN_COUNTER), 0x1_0000_1234);
+
+        // Each half can be written independently
+        write_u32(&mut hpet, reg::MAIN_COUNTER + 4, 0x5);
+        assert_eq!(read_u32(&mut hpet, reg::MAIN_COUNTER), 0x1234);
+        assert_eq!(read_u32(&mut hpet, reg::MAIN_COUNTER + 4), 0x5);
+    }
+
+    #[test]
+    fn test_hpet_timer_config() {
+        let mut hpet = Hpet::new().unwrap();
+        let offset = timer_offset(2, reg::TIMER_CONFIG);
+
+        let config = read_u64(&mut hpet, offset);
+        assert_eq!(config >> 32, HPET_INT_ROUTE_CAP);
+        assert_eq!(config & 0xffff, 0x30);
+
+        // Route to GSI 20, level triggered, 32 bit mode. The capability
+        // bits can't be cleared.
+        write_u32(&mut hpet, offset, (20 << 9) | (1 << 8) | (1 << 1));
+        assert_eq!(read_u32(&mut hpet, offset), 0x2932);
+        assert_eq!(hpet.comparator_gsi(2), 20);
+
+        write_u32(&mut hpet, reg::CONFIG, 0b10);
+        assert_eq!(hpet.comparator_gsi(0), 0);
+        assert_eq!(hpet.comparator_gsi(1), 8);
+        assert_eq!(hpet.comparator_gsi(2), 20);
+    }
+
+    #[test]
+    fn test_hpet_comparator() {
+        let mut hpet = Hpet::new().unwrap();
+        let config = timer_offset(0, reg::TIMER_CONFIG);
+        let comparator = timer_offset(0, reg::TIMER_COMPARATOR);
+
+        write_u64(&mut hpet, comparator, 0x1_0000_1000);
+        assert_eq!(read_u64(&mut hpet, comparator), 0x1_0000_1000);
+
+        // 32 bit mode truncates the comparator
+        write_u32(&mut hpet, config, 1 << 8);
+        write_u64(&mut hpet, comparator, 0x1_0000_1000);
+        assert_eq!(read_u64(&mut hpet, comparator), 0x1000);
+
+        // In periodic mode, the comparator is only set with VALUE_SET
+        write_u32(&mut hpet, config, (1 << 3) | (1 << 8));
+        write_u32(&mut hpet, comparator, 0x2000);
+        assert_eq!(read_u32(&mut hpet, comparator), 0x1000);
+
+        write_u32(&mut hpet, config, (1 << 3) | (1 << 6) | (1 << 8));
+        write_u32(&mut hpet, comparator, 0x3000);
+        assert_eq!(read_u32(&mut hpet, comparator), 0x3000);
+        assert_eq!(read_u32(&mut hpet, config) & (1 << 6), 0);
+    }
+
+    #[test]
+    fn test_hpet_split_period_write() {
+        let mut hpet = Hpet::new().unwrap();
+        let config = timer_offset(0, reg::TIMER_CONFIG);
+        let comparator = timer_offset(0, reg::TIMER_COMPARATOR);
+
+        write_u32(&mut hpet, config, 1 << 3);
+        write_u32(&mut hpet, comparator, 0x2000);
+        write_u32(&mut hpet, comparator + 4, 0x1);
+
+        // Each half is merged with the period, not the comparator value
+        assert_eq!(hpet.comparators[0].period, 0x1_0000_2000);
+        assert_eq!(read_u64(&mut hpet, comparator), u64::MAX);
+    }
+
+    #[test]
+    fn test_hpet_timer_schedule() {
+        let mut comparator = Comparator::default();
+        comparator.period = 0x100;
+        assert_eq!(
+            comparator.timer_schedule(0x10),
+            (Duration::from_nanos(0x10 * HPET_TICK_NS), None)
+        );
+
+        // Periodic comparators first fire at the comparator value, then
+        // every period after that
+        comparator.config |= TimerConfig::PERIODIC;
+        assert_eq!(
+            comparator.timer_schedule(0x10),
+            (
+                Duration::from_nanos(0x10 * HPET_TICK_NS),
+                Some(Duration::from_nanos(0x100 * HPET_TICK_NS))
+            )
+        );
+
+        // A periodic comparator without a period fires once
+        comparator.period = 0;
+        assert_eq!(
+            comparator.timer_schedule(0x10),
+            (Duration::from_nanos(0x10 * HPET_TICK_NS), None)
+        );
+    }
+
+    #[test]
+    fn test_hpet_interrupt_status() {
+        let mut hpet = Hpet::new().unwrap();
+        hpet.interrupt_status = 0b111;
+
+        write_u32(&mut hpet, reg::INTERRUPT_STATUS, 0b010);
+        assert_eq!(read_u32(&mut hpet, reg::INTERRUPT_STATUS), 0b101);
+    }
+}
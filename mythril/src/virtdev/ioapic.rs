@@ -1,31 +1,386 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::ioapic::{IoRedTblEntry, TriggerMode, IOREDTBL_RW_MASK};
 use crate::memory::GuestPhysAddr;
-use crate::virtdev::{DeviceRegion, EmulatedDevice, Event};
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 
-#[derive(Default)]
-pub struct IoApic;
+/// The number of pins (and redirection table entries) of the I/O APIC
+pub const IOAPIC_PIN_COUNT: usize = 24;
+
+const IOAPIC_BASE: u64 = 0xfec00000;
+const IOAPIC_VERSION: u32 = 0x11;
+
+const IOREGSEL_OFFSET: u64 = 0x00;
+const IOWIN_OFFSET: u64 = 0x10;
+
+const IOREDTBL_DELIVERY_MODE: u64 = 0b111 << 8;
+const IOREDTBL_EXTINT: u64 = 0b111 << 8;
+const IOREDTBL_REMOTE_IRR: u64 = 1 << 14;
+const IOREDTBL_TRIGGER_MODE: u64 = 1 << 15;
+const IOREDTBL_MASK: u64 = 1 << 16;
+
+/// I/O APIC registers accessed through IOWIN
+mod reg {
+    pub const IOAPICID: u8 = 0x00;
+    pub const IOAPICVER: u8 = 0x01;
+    pub const IOAPICARB: u8 = 0x02;
+    pub const IOREDTBL_OFFSET: u8 = 0x10;
+}
+
+pub struct IoApic {
+    id: u8,
+    ioregsel: u8,
+    redirection_table: [u64; IOAPIC_PIN_COUNT],
+    // A bitmap of the pins currently being asserted by their source
+    pin_level: u32,
+}
 
 impl IoApic {
     pub fn new() -> Result<Self> {
-        Ok(IoApic {})
+        Ok(IoApic {
+            id: 0,
+            ioregsel: 0,
+            // All entries start masked
+            redirection_table: [IOREDTBL_MASK; IOAPIC_PIN_COUNT],
+            pin_level: 0,
+        })
+    }
+
+    /// Returns true if interrupts on the given legacy GSI should be
+    /// delivered through the PIC rather than this I/O APIC.
+    ///
+    /// This is the case while the entry for the pin is masked, or when it
+    /// is in ExtINT mode (e.g., pin 0 in virtual wire mode), as the PIC
+    /// then provides the vector.
+    pub fn routes_to_pic(&self, gsi: u32) -> bool {
+        match self.redirection_table.get(gsi as usize) {
+            Some(entry) => {
+                entry & IOREDTBL_MASK != 0
+                    || entry & IOREDTBL_DELIVERY_MODE == IOREDTBL_EXTINT
+            }
+            None => false,
+        }
+    }
+
+    fn pin(gsi: u32) -> Result<usize> {
+        if (gsi as usize) < IOAPIC_PIN_COUNT {
+            Ok(gsi as usize)
+        } else {
+            Err(Error::InvalidValue(format!(
+                "GSI {} is not handled by the I/O APIC",
+                gsi
+            )))
+        }
+    }
+
+    /// Decode the redirection table entry associated with the given GSI
+    pub fn redirection_entry(&self, gsi: u32) -> Result<IoRedTblEntry> {
+        IoRedTblEntry::try_from(self.redirection_table[Self::pin(gsi)?])
+    }
+
+    /// Set the level of the line connected to the given GSI.
+    ///
+    /// Levels are logical (i.e., `true` means the source is requesting an
+    /// interrupt) so the pin polarity has no effect. Returns true if an
+    /// interrupt should be delivered to the destination of the entry.
+    pub fn set_irq(&mut self, gsi: u32, level: bool) -> Result<bool> {
+        let pin = Self::pin(gsi)?;
+        let was_asserted = self.pin_level & (1 << pin) != 0;
+        if level {
+            self.pin_level |= 1 << pin;
+        } else {
+            self.pin_level &= !(1 << pin);
+            return Ok(false);
+        }
+
+        let entry = &mut self.redirection_table[pin];
+        if *entry & IOREDTBL_MASK != 0 {
+            return Ok(false);
+        }
+
+        if *entry & IOREDTBL_TRIGGER_MODE == 0 {
+            // Edge triggered entries fire on the rising edge only
+            Ok(!was_asserted)
+        } else if *entry & IOREDTBL_REMOTE_IRR == 0 {
+            // Level triggered entries fire until the guest sends an EOI
+            *entry |= IOREDTBL_REMOTE_IRR;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Pulse the line connected to the given GSI. Returns true if an
+    /// interrupt should be delivered to the destination of the entry.
+    pub fn raise_irq(&mut self, gsi: u32) -> Result<bool> {
+        let deliver = self.set_irq(gsi, true)?;
+        self.set_irq(gsi, false)?;
+        Ok(deliver)
+    }
+
+    /// Returns true if a level triggered interrupt with the given vector
+    /// has been delivered and is waiting for an EOI.
+    pub fn awaiting_eoi(&self, vector: u8) -> bool {
+        self.redirection_table.iter().any(|entry| {
+            entry & IOREDTBL_REMOTE_IRR != 0 && *entry as u8 == vector
+        })
+    }
+
+    /// Process an EOI broadcast from a local APIC for the given vector.
+    ///
+    /// Clears the Remote IRR of any matching level triggered entries and
+    /// returns the GSIs of those that are still asserted, and so should be
+    /// delivered again.
+    pub fn end_of_interrupt(&mut self, vector: u8) -> Vec<u32> {
+        let mut redeliver = vec![];
+        for (pin, entry) in self.redirection_table.iter_mut().enumerate() {
+            if *entry & IOREDTBL_REMOTE_IRR == 0 || *entry as u8 != vector {
+                continue;
+            }
+            *entry &= !IOREDTBL_REMOTE_IRR;
+
+            if self.pin_level & (1 << pin) != 0 && *entry & IOREDTBL_MASK == 0 {
+                *entry |= IOREDTBL_REMOTE_IRR;
+                redeliver.push(pin as u32);
+            }
+        }
+        redeliver
+    }
+
+    fn read_register(&self, register: u8) -> u32 {
+        match register {
+            reg::IOAPICID => (self.id as u32) << 24,
+            reg::IOAPICVER => {
+                IOAPIC_VERSION | ((IOAPIC_PIN_COUNT as u32 - 1) << 16)
+            }
+            reg::IOAPICARB => (self.id as u32) << 24,
+            _ => {
+                let index = register.wrapping_sub(reg::IOREDTBL_OFFSET);
+                match self.redirection_table.get(index as usize / 2) {
+                    Some(entry) if index % 2 == 0 => *entry as u32,
+                    Some(entry) => (*entry >> 32) as u32,
+                    None => 0,
+                }
+            }
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u32) {
+        match register {
+            reg::IOAPICID => self.id = ((value >> 24) & 0x0f) as u8,
+            reg::IOAPICVER | reg::IOAPICARB => (),
+            _ => {
+                let index = register.wrapping_sub(reg::IOREDTBL_OFFSET);
+                let entry =
+                    match self.redirection_table.get_mut(index as usize / 2) {
+                        Some(entry) => entry,
+                        None => {
+                            info!(
+                                "Write to unknown I/O APIC register 0x{:x}",
+                                register
+                            );
+                            return;
+                        }
+                    };
+
+                let shift = if index % 2 == 0 { 0 } else { 32 };
+                let mask = IOREDTBL_RW_MASK & (0xffffffff << shift);
+                *entry = (*entry & !mask) | (((value as u64) << shift) & mask);
+
+                if *entry & IOREDTBL_TRIGGER_MODE == 0 {
+                    *entry &= !IOREDTBL_REMOTE_IRR;
+                }
+            }
+        }
     }
 }
 
 impl EmulatedDevice for IoApic {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfec00000)..=GuestPhysAddr::new(0xfec010f0),
-            ),
-            //FIXME: this is actually the 1st HPET
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfed00000)..=GuestPhysAddr::new(0xfed010f0),
-            ),
-        ]
-    }
-
-    fn on_event(&mut self, _event: Event) -> Result<()> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(IOAPIC_BASE)..=GuestPhysAddr::new(0xfec010f0),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = addr.as_u64().wrapping_sub(IOAPIC_BASE);
+                let value = match offset & !0b11 {
+                    IOREGSEL_OFFSET => self.ioregsel as u32,
+                    IOWIN_OFFSET => self.read_register(self.ioregsel),
+                    _ => 0,
+                };
+                let value = value >> ((offset & 0b11) * 8);
+                let bytes = value.to_le_bytes();
+                for (i, byte) in req.as_mut_slice().iter_mut().enumerate() {
+                    *byte = bytes.get(i).copied().unwrap_or(0);
+                }
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let value = req.value() as u32;
+                match addr.as_u64().wrapping_sub(IOAPIC_BASE) {
+                    IOREGSEL_OFFSET => self.ioregsel = value as u8,
+                    IOWIN_OFFSET => self.write_register(self.ioregsel, value),
+                    offset => info!(
+                        "Ignoring write to I/O APIC offset 0x{:x}",
+                        offset
+                    ),
+                }
+            }
+            _ => return Err(Error::NotSupported),
+        }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ioapic::{DeliveryMode, DestinationMode};
+    use crate::virtdev::testing::{define_test_view, mem_read, mem_write};
+
+    fn write_mem(ioapic: &mut IoApic, offset: u64, value: u32) {
+        let addr = IOAPIC_BASE + offset;
+        mem_write(define_test_view(), addr, value as u64, 4, |event| {
+            ioapic.on_event(event)
+        });
+    }
+
+    fn read_mem(ioapic: &mut IoApic, offset: u64) -> u32 {
+        let addr = IOAPIC_BASE + offset;
+        let (value, _) = mem_read(define_test_view(), addr, 4, |event| {
+            ioapic.on_event(event)
+        });
+        value as u32
+    }
+
+    fn write_reg(ioapic: &mut IoApic, register: u8, value: u32) {
+        write_mem(ioapic, IOREGSEL_OFFSET, register as u32);
+        write_mem(ioapic, IOWIN_OFFSET, value);
+    }
+
+    fn read_reg(ioapic: &mut IoApic, register: u8) -> u32 {
+        write_mem(ioapic, IOREGSEL_OFFSET, register as u32);
+        read_mem(ioapic, IOWIN_OFFSET)
+    }
+
+    fn write_entry(ioapic: &mut IoApic, pin: u8, value: u64) {
+        write_reg(ioapic, 0x10 + pin * 2, value as u32);
+        write_reg(ioapic, 0x11 + pin * 2, (value >> 32) as u32);
+    }
+
+    #[test]
+    fn test_ioapic_id_version() {
+        let mut ioapic = IoApic::new().unwrap();
+        assert_eq!(read_reg(&mut ioapic, reg::IOAPICVER), 0x00170011);
+
+        write_reg(&mut ioapic, reg::IOAPICID, 0xff000000);
+        assert_eq!(read_reg(&mut ioapic, reg::IOAPICID), 0x0f000000);
+        assert_eq!(read_reg(&mut ioapic, reg::IOAPICARB), 0x0f000000);
+
+        // The version register is read only
+        write_reg(&mut ioapic, reg::IOAPICVER, 0);
+        assert_eq!(read_reg(&mut ioapic, reg::IOAPICVER), 0x00170011);
+    }
+
+    #[test]
+    fn test_ioapic_redirection_entry() {
+        let mut ioapic = IoApic::new().unwrap();
+        assert!(ioapic.routes_to_pic(1));
+        assert_eq!(read_reg(&mut ioapic, 0x10), 0x00010000);
+
+        // Vector 0x31, logical destination 0x3, active low, level triggered
+        write_entry(&mut ioapic, 1, 0x03000000_0000a931);
+        assert!(!ioapic.routes_to_pic(1));
+        assert!(ioapic.routes_to_pic(2));
+        assert_eq!(read_reg(&mut ioapic, 0x12), 0x0000a931);
+        assert_eq!(read_reg(&mut ioapic, 0x13), 0x03000000);
+
+        let entry = ioapic.redirection_entry(1).unwrap();
+        assert_eq!(entry.vector(), 0x31);
+        assert_eq!(entry.delivery_mode(), DeliveryMode::LowestPriority);
+        assert_eq!(entry.destination_mode(), DestinationMode::Logical);
+        assert_eq!(entry.trigger_mode(), TriggerMode::Level);
+        assert_eq!(entry.destination(), 0x03);
+        assert!(!entry.is_masked());
+    }
+
+    #[test]
+    fn test_ioapic_read_only_bits() {
+        let mut ioapic = IoApic::new().unwrap();
+
+        // Delivery status and remote IRR can't be set by the guest
+        write_entry(&mut ioapic, 2, 0x00000000_00005030);
+        assert_eq!(read_reg(&mut ioapic, 0x14), 0x00000030);
+    }
+
+    #[test]
+    fn test_ioapic_masked_entry() {
+        let mut ioapic = IoApic::new().unwrap();
+        assert!(!ioapic.raise_irq(4).unwrap());
+
+        write_entry(&mut ioapic, 4, 0x34);
+        assert!(ioapic.raise_irq(4).unwrap());
+        assert!(ioapic.raise_irq(4).unwrap());
+
+        assert!(ioapic.raise_irq(IOAPIC_PIN_COUNT as u32).is_err());
+    }
+
+    #[test]
+    fn test_ioapic_edge_triggered() {
+        let mut ioapic = IoApic::new().unwrap();
+        write_entry(&mut ioapic, 3, 0x33);
+
+        assert!(ioapic.set_irq(3, true).unwrap());
+        // No new edge while the line is held
+        assert!(!ioapic.set_irq(3, true).unwrap());
+        assert!(!ioapic.set_irq(3, false).unwrap());
+        assert!(ioapic.set_irq(3, true).unwrap());
+    }
+
+    #[test]
+    fn test_ioapic_level_triggered_eoi() {
+        let mut ioapic = IoApic::new().unwrap();
+        write_entry(&mut ioapic, 9, 0x00000000_00008039);
+
+        assert!(ioapic.set_irq(9, true).unwrap());
+        assert!(ioapic.awaiting_eoi(0x39));
+        assert_eq!(read_reg(&mut ioapic, 0x22) & (1 << 14), 1 << 14);
+
+        // Remote IRR blocks further delivery until the EOI
+        assert!(!ioapic.set_irq(9, true).unwrap());
+        assert!(ioapic.end_of_interrupt(0x40).is_empty());
+
+        // The line is still asserted, so the EOI causes a redelivery
+        assert_eq!(ioapic.end_of_interrupt(0x39), vec![9]);
+        assert!(ioapic.awaiting_eoi(0x39));
+
+        ioapic.set_irq(9, false).unwrap();
+        assert!(ioapic.end_of_interrupt(0x39).is_empty());
+        assert!(!ioapic.awaiting_eoi(0x39));
+        assert_eq!(read_reg(&mut ioapic, 0x22) & (1 << 14), 0);
+    }
+
+    #[test]
+    fn test_ioapic_virtual_wire_mode() {
+        let mut ioapic = IoApic::new().unwrap();
+
+        // Pin 0 unmasked in ExtINT mode passes the PIC output through
+        write_entry(&mut ioapic, 0, 0x00000000_00000700);
+        assert!(ioapic.routes_to_pic(0));
+        assert!(ioapic.routes_to_pic(1));
+
+        // Unmasking another pin only moves that pin to the I/O APIC
+        write_entry(&mut ioapic, 4, 0x34);
+        assert!(ioapic.routes_to_pic(0));
+        assert!(ioapic.routes_to_pic(1));
+        assert!(!ioapic.routes_to_pic(4));
+    }
 }
@@ -0,0 +1,447 @@
+//! An Inter-VM shared memory device, compatible with the ivshmem-doorbell
+//! device of QEMU (see docs/specs/ivshmem-spec.txt in the QEMU sources)
+//!
+//! BAR0 holds the registers, BAR1 the MSI-X table and BAR2 the shared
+//! memory itself. The shared memory is mapped directly in to the guest
+//! address space by the `VirtualMachine` (see the `shmem` module), so its
+//! address is fixed: the guest may size BAR2, but attempts to move it are
+//! ignored.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceView;
+use crate::shmem::{self, PeerId, RegionId, SharedMemoryMapping};
+use crate::virtdev::msi::MsixCapability;
+use crate::virtdev::pci::{PciBar, PciConfigSpace, PciDevice};
+use crate::virtdev::{DeviceEvent, Event, ResponseEventArray};
+
+const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+const IVSHMEM_REVISION_ID: u8 = 1;
+const PCI_REVISION_ID_OFFSET: u16 = 0x08;
+
+// PCI class and subclass of a 'RAM memory controller'
+const CLASS_MEMORY: u8 = 0x05;
+const SUBCLASS_RAM: u8 = 0x00;
+
+// The registers in BAR0
+const REGISTER_BAR: usize = 0;
+const REGISTER_BAR_SIZE: u32 = 0x100;
+const INTR_MASK: u64 = 0x00;
+const INTR_STATUS: u64 = 0x04;
+const IV_POSITION: u64 = 0x08;
+const DOORBELL: u64 = 0x0c;
+
+// The layout of the BAR holding the MSI-X table and PBA
+const MSIX_BAR: usize = 1;
+const MSIX_BAR_SIZE: u32 = 0x1000;
+const MSIX_TABLE_OFFSET: u32 = 0x000;
+const MSIX_PBA_OFFSET: u32 = 0x800;
+
+/// The number of interrupt vectors of each ivshmem device
+pub const IVSHMEM_VECTORS: u16 = 4;
+
+// The BAR that decodes the shared memory
+const SHARED_MEMORY_BAR: usize = 2;
+const SHARED_MEMORY_BAR_OFFSET: u16 = 0x18;
+const BAR_FLAGS_MASK: u32 = 0b1111;
+
+/// An ivshmem device for one peer of a shared memory region
+pub struct IvshmemDevice {
+    config: PciConfigSpace,
+    msix: MsixCapability,
+    region: RegionId,
+    peer: PeerId,
+    address: u64,
+    size: u64,
+
+    // The interrupt registers (only used when MSI-X is disabled)
+    intr_mask: u32,
+    intr_status: u32,
+}
+
+impl IvshmemDevice {
+    /// Create a new device for the given mapping of a shared memory region
+    pub fn new(mapping: &SharedMemoryMapping) -> Result<Self> {
+        let mut config = PciConfigSpace::new(
+            IVSHMEM_VENDOR_ID,
+            IVSHMEM_DEVICE_ID,
+            CLASS_MEMORY,
+            SUBCLASS_RAM,
+        );
+        config.set_u8(PCI_REVISION_ID_OFFSET, IVSHMEM_REVISION_ID);
+        config.set_interrupt_pin(1);
+        config.set_bar(
+            REGISTER_BAR,
+            PciBar::Memory32 {
+                size: REGISTER_BAR_SIZE,
+                prefetchable: false,
+            },
+        )?;
+        config.set_bar(
+            MSIX_BAR,
+            PciBar::Memory32 {
+                size: MSIX_BAR_SIZE,
+                prefetchable: false,
+            },
+        )?;
+        config.set_bar(
+            SHARED_MEMORY_BAR,
+            PciBar::Memory64 {
+                size: mapping.size,
+                prefetchable: true,
+            },
+        )?;
+
+        let msix = MsixCapability::new(
+            &mut config,
+            IVSHMEM_VECTORS,
+            MSIX_BAR,
+            MSIX_TABLE_OFFSET,
+            MSIX_PBA_OFFSET,
+        )?;
+
+        let mut device = Self {
+            config,
+            msix,
+            region: mapping.region,
+            peer: mapping.peer,
+            address: mapping.address.as_u64(),
+            size: mapping.size,
+            intr_mask: 0,
+            intr_status: 0,
+        };
+        device.restore_shared_memory_bar(0);
+        device.restore_shared_memory_bar(1);
+        Ok(device)
+    }
+
+    // Return the given dword of the shared memory BAR to the configured
+    // address, unless the guest is determining the size of the BAR
+    fn restore_shared_memory_bar(&mut self, dword: u16) {
+        let offset = SHARED_MEMORY_BAR_OFFSET + dword * 4;
+        let current = self.config.read(offset, 4);
+        let shift = dword * 32;
+        let size_mask = (!(self.size - 1) >> shift) as u32 & !BAR_FLAGS_MASK;
+        let address = (self.address >> shift) as u32;
+        let flags = if dword == 0 {
+            current & BAR_FLAGS_MASK
+        } else {
+            0
+        };
+
+        if current & !flags != size_mask {
+            self.config.set_u32(offset, address | flags);
+        }
+    }
+
+    // Assert INTx if an unmasked interrupt is pending
+    fn update_intx(&mut self, responses: &mut ResponseEventArray) {
+        let pending = self.intr_status & self.intr_mask != 0;
+        self.config.set_interrupt_status(pending, responses);
+    }
+
+    fn read_register(
+        &mut self,
+        offset: u64,
+        responses: &mut ResponseEventArray,
+    ) -> u32 {
+        match offset {
+            INTR_MASK => self.intr_mask,
+            INTR_STATUS => {
+                // Reading the status clears it
+                let status = self.intr_status;
+                self.intr_status = 0;
+                self.update_intx(responses);
+                status
+            }
+            IV_POSITION => self.peer as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_register(
+        &mut self,
+        offset: u64,
+        value: u32,
+        responses: &mut ResponseEventArray,
+    ) {
+        match offset {
+            INTR_MASK => {
+                self.intr_mask = value;
+                self.update_intx(responses);
+            }
+            INTR_STATUS => {
+                self.intr_status = value;
+                self.update_intx(responses);
+            }
+            // The high word selects the peer, and the low word the vector
+            DOORBELL => shmem::ring_doorbell(
+                self.region,
+                (value >> 16) as PeerId,
+                value as u16,
+            ),
+            _ => (),
+        }
+    }
+
+    // Interrupt the guest in response to a doorbell rung by another peer
+    fn interrupt(&mut self, vector: u16, responses: &mut ResponseEventArray) {
+        if self.msix.is_enabled(&self.config) {
+            self.msix.signal(&self.config, vector, responses);
+        } else {
+            self.intr_status |= 1;
+            self.update_intx(responses);
+        }
+    }
+}
+
+impl PciDevice for IvshmemDevice {
+    fn config_space(&self) -> &PciConfigSpace {
+        &self.config
+    }
+
+    fn config_space_mut(&mut self) -> &mut PciConfigSpace {
+        &mut self.config
+    }
+
+    fn on_bar_event(
+        &mut self,
+        bar: usize,
+        offset: u64,
+        event: Event,
+    ) -> Result<()> {
+        match bar {
+            MSIX_BAR => {
+                if self.msix.contains(bar, offset) {
+                    self.msix.on_bar_event(&self.config, offset, event)?;
+                }
+                Ok(())
+            }
+            REGISTER_BAR => match event.kind {
+                // The registers are 32 bits, so smaller accesses use the
+                // containing register
+                DeviceEvent::MemRead(_, mut req) => {
+                    let data = req.as_mut_slice();
+                    let value =
+                        self.read_register(offset & !0b11, event.responses);
+                    let bytes = value.to_le_bytes();
+                    let start = (offset & 0b11) as usize;
+                    for (i, byte) in data.iter_mut().enumerate() {
+                        *byte = bytes.get(start + i).copied().unwrap_or(0);
+                    }
+                    Ok(())
+                }
+                DeviceEvent::MemWrite(_, req) => {
+                    if offset & 0b11 == 0 && req.as_slice().len() == 4 {
+                        let value = req.value() as u32;
+                        self.write_register(offset, value, event.responses);
+                    }
+                    Ok(())
+                }
+                _ => Err(Error::InvalidValue(format!(
+                    "Invalid ivshmem event: {:?}",
+                    event.kind
+                ))),
+            },
+            // The shared memory only traps if the guest writes to a
+            // read-only mapping, in which case the write is discarded
+            _ => {
+                if let DeviceEvent::MemRead(_, mut req) = event.kind {
+                    for byte in req.as_mut_slice().iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn on_config_write(
+        &mut self,
+        offset: u16,
+        len: usize,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let bar_end = SHARED_MEMORY_BAR_OFFSET + 8;
+        if offset < bar_end && offset + len as u16 > SHARED_MEMORY_BAR_OFFSET {
+            self.restore_shared_memory_bar(0);
+            self.restore_shared_memory_bar(1);
+        }
+        self.msix.on_config_write(&self.config, responses);
+        Ok(())
+    }
+
+    fn on_host_event(
+        &mut self,
+        kind: &DeviceEvent,
+        _space: &GuestAddressSpaceView,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        match kind {
+            DeviceEvent::SharedMemoryDoorbell(region, vector)
+                if *region == self.region =>
+            {
+                self.interrupt(*vector, responses);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.config.reset();
+        self.msix.reset();
+        self.intr_mask = 0;
+        self.intr_status = 0;
+        self.restore_shared_memory_bar(0);
+        self.restore_shared_memory_bar(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::GuestPhysAddr;
+    use crate::virtdev::testing::{
+        define_test_view, handle_event, mem_read, mem_write,
+    };
+    use crate::virtdev::{DeviceEventResponse, MemWriteRequest};
+
+    const SHARED_MEMORY_ADDRESS: u64 = 0xd0000000;
+    const SHARED_MEMORY_SIZE: u64 = 0x100000;
+
+    fn test_device() -> IvshmemDevice {
+        let mut device = IvshmemDevice::new(&SharedMemoryMapping {
+            region: 1,
+            peer: 2,
+            address: GuestPhysAddr::new(SHARED_MEMORY_ADDRESS),
+            size: SHARED_MEMORY_SIZE,
+            readonly: false,
+            frames: &[],
+        })
+        .unwrap();
+
+        // Enable memory decoding and route INTx to line 11
+        device.config_space_mut().write(0x04, 0b110, 2);
+        device.config_space_mut().write(0x3c, 11, 1);
+        device
+    }
+
+    fn register_write(
+        device: &mut IvshmemDevice,
+        offset: u64,
+        value: u32,
+    ) -> ResponseEventArray {
+        let addr = 0xc0000000 + offset;
+        mem_write(define_test_view(), addr, value as u64, 4, |event| {
+            device.on_bar_event(REGISTER_BAR, offset, event)
+        })
+    }
+
+    fn register_read(
+        device: &mut IvshmemDevice,
+        offset: u64,
+    ) -> (u32, ResponseEventArray) {
+        let addr = 0xc0000000 + offset;
+        let (value, responses) =
+            mem_read(define_test_view(), addr, 4, |event| {
+                device.on_bar_event(REGISTER_BAR, offset, event)
+            });
+        (value as u32, responses)
+    }
+
+    fn config_write(device: &mut IvshmemDevice, offset: u16, value: u32) {
+        let mut responses = ResponseEventArray::default();
+        device.config_space_mut().write(offset, value, 4);
+        device.on_config_write(offset, 4, &mut responses).unwrap();
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut device = test_device();
+        let config = device.config_space();
+        assert_eq!(config.read(0x00, 2), IVSHMEM_VENDOR_ID as u32);
+        assert_eq!(config.read(0x02, 2), IVSHMEM_DEVICE_ID as u32);
+        assert_eq!(
+            config.bar_address(SHARED_MEMORY_BAR),
+            Some(SHARED_MEMORY_ADDRESS)
+        );
+        assert_eq!(register_read(&mut device, IV_POSITION).0, 2);
+    }
+
+    #[test]
+    fn test_fixed_shared_memory_bar() {
+        let mut device = test_device();
+
+        // The size of the BAR can be determined
+        config_write(&mut device, SHARED_MEMORY_BAR_OFFSET, 0xffffffff);
+        let low = device.config_space().read(SHARED_MEMORY_BAR_OFFSET, 4);
+        assert_eq!(low & !BAR_FLAGS_MASK, !(SHARED_MEMORY_SIZE as u32 - 1));
+
+        // But it can not be moved
+        config_write(&mut device, SHARED_MEMORY_BAR_OFFSET, 0xe0000000);
+        assert_eq!(
+            device.config_space().bar_address(SHARED_MEMORY_BAR),
+            Some(SHARED_MEMORY_ADDRESS)
+        );
+
+        device.reset().unwrap();
+        assert_eq!(
+            device.config_space().bar_address(SHARED_MEMORY_BAR),
+            Some(SHARED_MEMORY_ADDRESS)
+        );
+    }
+
+    #[test]
+    fn test_register_write_order() {
+        let mut device = test_device();
+
+        // Writes are reported most significant byte first
+        let data = [0x00, 0x00, 0x00, 0x01];
+        let kind = DeviceEvent::MemWrite(
+            GuestPhysAddr::new(0xc0000000 + INTR_MASK),
+            MemWriteRequest::new(&data),
+        );
+        handle_event(kind, define_test_view(), |event| {
+            device.on_bar_event(REGISTER_BAR, INTR_MASK, event)
+        });
+        assert_eq!(device.intr_mask, 1);
+        assert_eq!(register_read(&mut device, INTR_MASK).0, 1);
+    }
+
+    #[test]
+    fn test_doorbell_intx() {
+        let mut device = test_device();
+        let doorbell = DeviceEvent::SharedMemoryDoorbell(1, 0);
+        let mut responses = ResponseEventArray::default();
+
+        // Doorbells for other regions are ignored
+        let other = DeviceEvent::SharedMemoryDoorbell(0, 0);
+        device
+            .on_host_event(&other, &define_test_view(), &mut responses)
+            .unwrap();
+        assert_eq!(device.intr_status, 0);
+
+        // The interrupt is masked
+        device
+            .on_host_event(&doorbell, &define_test_view(), &mut responses)
+            .unwrap();
+        assert!(responses.is_empty());
+
+        let responses = register_write(&mut device, INTR_MASK, 1);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(11, true)]
+        ));
+
+        // Reading the status clears it
+        let (status, responses) = register_read(&mut device, INTR_STATUS);
+        assert_eq!(status, 1);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(11, false)]
+        ));
+        assert_eq!(register_read(&mut device, INTR_STATUS).0, 0);
+    }
+}
@@ -1,16 +1,490 @@
 use crate::error::Result;
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::interrupt;
+use crate::physdev::keyboard::{Ps2ConfigurationFlags, Ps2StatusFlags};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, PortReadRequest, PortWriteRequest, ResponseEventArray,
+};
+use crate::vm;
 use alloc::vec::Vec;
+use arraydeque::ArrayDeque;
+use core::convert::TryInto;
 
-#[derive(Default, Debug)]
-pub struct Keyboard8042;
+const FIFO_SIZE: usize = 16;
+
+// Controller commands (written to the command port)
+const CMD_READ_RAM_FIRST: u8 = 0x20;
+const CMD_READ_RAM_LAST: u8 = 0x3f;
+const CMD_WRITE_RAM_FIRST: u8 = 0x60;
+const CMD_WRITE_RAM_LAST: u8 = 0x7f;
+const CMD_DISABLE_SECOND: u8 = 0xa7;
+const CMD_ENABLE_SECOND: u8 = 0xa8;
+const CMD_TEST_SECOND: u8 = 0xa9;
+const CMD_TEST_CONTROLLER: u8 = 0xaa;
+const CMD_TEST_FIRST: u8 = 0xab;
+const CMD_DISABLE_FIRST: u8 = 0xad;
+const CMD_ENABLE_FIRST: u8 = 0xae;
+const CMD_READ_OUTPUT_PORT: u8 = 0xd0;
+const CMD_WRITE_OUTPUT_PORT: u8 = 0xd1;
+const CMD_WRITE_FIRST_OUTPUT: u8 = 0xd2;
+const CMD_WRITE_SECOND_OUTPUT: u8 = 0xd3;
+const CMD_WRITE_SECOND: u8 = 0xd4;
+const CMD_DISABLE_A20: u8 = 0xdd;
+const CMD_ENABLE_A20: u8 = 0xdf;
+const CMD_PULSE_OUTPUT_FIRST: u8 = 0xf0;
+
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Output port bits (the reset line is active low)
+const OUTPUT_PORT_RESET: u8 = 1 << 0;
+const OUTPUT_PORT_A20: u8 = 1 << 1;
+
+// Commands common to the keyboard and the aux device
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_ENABLE: u8 = 0xf4;
+const DEV_DISABLE: u8 = 0xf5;
+const DEV_SET_DEFAULTS: u8 = 0xf6;
+const DEV_RESEND: u8 = 0xfe;
+const DEV_RESET: u8 = 0xff;
+
+// Keyboard commands
+const KBD_SET_LEDS: u8 = 0xed;
+const KBD_ECHO: u8 = 0xee;
+const KBD_SCANCODE_SET: u8 = 0xf0;
+const KBD_SET_TYPEMATIC: u8 = 0xf3;
+
+// Aux (mouse) commands
+const AUX_SET_SCALING_1_1: u8 = 0xe6;
+const AUX_SET_SCALING_2_1: u8 = 0xe7;
+const AUX_SET_RESOLUTION: u8 = 0xe8;
+const AUX_STATUS_REQUEST: u8 = 0xe9;
+const AUX_SET_STREAM_MODE: u8 = 0xea;
+const AUX_READ_DATA: u8 = 0xeb;
+const AUX_SET_SAMPLE_RATE: u8 = 0xf3;
+
+// Device responses
+const RESP_ACK: u8 = 0xfa;
+const RESP_RESEND: u8 = 0xfe;
+const RESP_SELF_TEST_PASSED: u8 = 0xaa;
+
+// The aux status byte bits
+const AUX_STATUS_SCALING_2_1: u8 = 1 << 4;
+const AUX_STATUS_ENABLED: u8 = 1 << 5;
+
+// A device attached to one of the controller ports
+struct Ps2Device {
+    output: ArrayDeque<[u8; FIFO_SIZE]>,
+
+    // A command that is waiting for its parameter byte
+    pending: Option<u8>,
+
+    // Whether the device sends input (scancodes or movement) to the host
+    enabled: bool,
+}
+
+impl Ps2Device {
+    fn new() -> Self {
+        Self {
+            output: ArrayDeque::new(),
+            pending: None,
+            enabled: true,
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        for &byte in data {
+            // Like a real device, data is dropped when the buffer is full
+            let _ = self.output.push_back(byte);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.output.clear();
+        self.pending = None;
+        self.enabled = true;
+    }
+}
+
+/// An emulated 8042 PS2 controller with a keyboard and an aux device
+///
+/// Keyboard input is provided by the physical PS2 controller (which
+/// always translates to scancode set 1), so scancodes are passed to the
+/// guest unchanged. The aux device is a PS2 mouse that never moves.
+pub struct Keyboard8042 {
+    // The controller RAM (the first byte is the configuration byte)
+    ram: [u8; 32],
+    output_port: u8,
+
+    // The contents of the output buffer and whether it is from the aux
+    // device. The last byte remains readable after the buffer is emptied.
+    output: u8,
+    output_full: bool,
+    output_aux: bool,
+
+    // Data produced by the controller itself (and whether it should
+    // appear to be from the aux device)
+    controller_output: ArrayDeque<[(u8, bool); FIFO_SIZE]>,
+
+    // A controller command that is waiting for a data byte
+    pending_command: Option<u8>,
+
+    // Whether the last write was to the command port
+    last_write_command: bool,
+
+    // Set when the guest has pulsed the system reset line
+    reset_pending: bool,
+
+    keyboard: Ps2Device,
+    scancode_set: u8,
+
+    aux: Ps2Device,
+    aux_scaling_2_1: bool,
+    aux_resolution: u8,
+    aux_sample_rate: u8,
+}
 
 impl Keyboard8042 {
-    const PS2_DATA: Port = 0x0060;
+    /// The data port of the controller
+    pub const PS2_DATA: Port = 0x0060;
     const PS2_STATUS: Port = 0x0064;
+    const PS2_COMMAND: Port = 0x0064;
 
     pub fn new() -> Result<Self> {
-        Ok(Self::default())
+        let mut ram = [0u8; 32];
+        ram[0] = (Ps2ConfigurationFlags::FIRST_PORT_INTERRUPT
+            | Ps2ConfigurationFlags::SECOND_PORT_INTERRUPT)
+            .bits();
+
+        Ok(Self {
+            ram,
+            output_port: OUTPUT_PORT_RESET | OUTPUT_PORT_A20,
+            output: 0,
+            output_full: false,
+            output_aux: false,
+            controller_output: ArrayDeque::new(),
+            pending_command: None,
+            last_write_command: false,
+            reset_pending: false,
+            keyboard: Ps2Device::new(),
+            scancode_set: 2,
+            aux: Ps2Device::new(),
+            aux_scaling_2_1: false,
+            aux_resolution: 2,
+            aux_sample_rate: 100,
+        })
+    }
+
+    fn config(&self) -> Ps2ConfigurationFlags {
+        Ps2ConfigurationFlags::from_bits_truncate(self.ram[0])
+    }
+
+    fn set_config(&mut self, config: Ps2ConfigurationFlags) {
+        self.ram[0] = config.bits();
+    }
+
+    fn status(&self) -> Ps2StatusFlags {
+        let mut status = Ps2StatusFlags::KEYBOARD_UNLOCKED;
+        if self.output_full {
+            status.insert(Ps2StatusFlags::OUTPUT_BUFFER_FULL);
+            if self.output_aux {
+                status.insert(Ps2StatusFlags::SECOND_PORT_OUTPUT_FULL);
+            }
+        }
+        if self.config().contains(Ps2ConfigurationFlags::SYSTEM_POST) {
+            status.insert(Ps2StatusFlags::SELF_TEST_PASS);
+        }
+        if self.last_write_command {
+            status.insert(Ps2StatusFlags::INPUT_FOR_CONTROLLER);
+        }
+        status
+    }
+
+    fn respond(&mut self, data: u8) {
+        let _ = self.controller_output.push_back((data, false));
+    }
+
+    // Move the next pending byte (if any) to the output buffer, raising
+    // the interrupt for the port it came from
+    fn fill_output_buffer(&mut self, responses: &mut ResponseEventArray) {
+        if self.output_full {
+            return;
+        }
+
+        // Controller output takes priority over the (enabled) devices
+        let config = self.config();
+        let mut next = self.controller_output.pop_front();
+        if next.is_none()
+            && !config
+                .contains(Ps2ConfigurationFlags::FIRST_PORT_CLOCK_DISABLED)
+        {
+            next = self.keyboard.output.pop_front().map(|byte| (byte, false));
+        }
+        if next.is_none()
+            && !config
+                .contains(Ps2ConfigurationFlags::SECOND_PORT_CLOCK_DISABLED)
+        {
+            next = self.aux.output.pop_front().map(|byte| (byte, true));
+        }
+
+        if let Some((byte, aux)) = next {
+            self.output = byte;
+            self.output_full = true;
+            self.output_aux = aux;
+
+            let (flag, gsi) = if aux {
+                (
+                    Ps2ConfigurationFlags::SECOND_PORT_INTERRUPT,
+                    interrupt::gsi::MOUSE,
+                )
+            } else {
+                (
+                    Ps2ConfigurationFlags::FIRST_PORT_INTERRUPT,
+                    interrupt::gsi::KEYBOARD,
+                )
+            };
+            if config.contains(flag) {
+                responses.push(DeviceEventResponse::GSI(gsi));
+            }
+        }
+    }
+
+    fn write_output_port(&mut self, val: u8) {
+        if val & OUTPUT_PORT_A20 == 0 {
+            // The guest address space does not wrap at 1MB
+            warn!("Guest disabled A20 through the 8042 (not supported)");
+        }
+        if val & OUTPUT_PORT_RESET == 0 {
+            self.pulse_reset();
+        }
+
+        // The reset line only stays low while the system is reset
+        self.output_port = val | OUTPUT_PORT_RESET;
+    }
+
+    fn pulse_reset(&mut self) {
+        self.reset_pending = true;
+    }
+
+    fn on_command(&mut self, cmd: u8) {
+        self.last_write_command = true;
+
+        match cmd {
+            CMD_READ_RAM_FIRST..=CMD_READ_RAM_LAST => {
+                self.respond(self.ram[(cmd & 0x1f) as usize])
+            }
+            CMD_WRITE_RAM_FIRST..=CMD_WRITE_RAM_LAST
+            | CMD_WRITE_OUTPUT_PORT
+            | CMD_WRITE_FIRST_OUTPUT
+            | CMD_WRITE_SECOND_OUTPUT
+            | CMD_WRITE_SECOND => self.pending_command = Some(cmd),
+            CMD_DISABLE_SECOND => self.set_config(
+                self.config()
+                    | Ps2ConfigurationFlags::SECOND_PORT_CLOCK_DISABLED,
+            ),
+            CMD_ENABLE_SECOND => self.set_config(
+                self.config()
+                    - Ps2ConfigurationFlags::SECOND_PORT_CLOCK_DISABLED,
+            ),
+            CMD_TEST_SECOND | CMD_TEST_FIRST => self.respond(PORT_TEST_PASSED),
+            CMD_TEST_CONTROLLER => {
+                self.set_config(
+                    self.config() | Ps2ConfigurationFlags::SYSTEM_POST,
+                );
+                self.respond(CONTROLLER_TEST_PASSED);
+            }
+            CMD_DISABLE_FIRST => self.set_config(
+                self.config()
+                    | Ps2ConfigurationFlags::FIRST_PORT_CLOCK_DISABLED,
+            ),
+            CMD_ENABLE_FIRST => self.set_config(
+                self.config()
+                    - Ps2ConfigurationFlags::FIRST_PORT_CLOCK_DISABLED,
+            ),
+            CMD_READ_OUTPUT_PORT => self.respond(self.output_port),
+            CMD_DISABLE_A20 => {
+                self.write_output_port(self.output_port & !OUTPUT_PORT_A20)
+            }
+            CMD_ENABLE_A20 => {
+                self.write_output_port(self.output_port | OUTPUT_PORT_A20)
+            }
+            CMD_PULSE_OUTPUT_FIRST..=0xff => {
+                // The low bits select the output lines to pulse low, and
+                // bit 0 is the reset line
+                if cmd & OUTPUT_PORT_RESET == 0 {
+                    self.pulse_reset();
+                }
+            }
+            _ => warn!("Unsupported 8042 command 0x{:x}", cmd),
+        }
+    }
+
+    fn on_data(&mut self, val: u8) {
+        self.last_write_command = false;
+
+        match self.pending_command.take() {
+            Some(cmd @ CMD_WRITE_RAM_FIRST..=CMD_WRITE_RAM_LAST) => {
+                self.ram[(cmd & 0x1f) as usize] = val
+            }
+            Some(CMD_WRITE_OUTPUT_PORT) => self.write_output_port(val),
+            Some(CMD_WRITE_FIRST_OUTPUT) => {
+                let _ = self.controller_output.push_back((val, false));
+            }
+            Some(CMD_WRITE_SECOND_OUTPUT) => {
+                let _ = self.controller_output.push_back((val, true));
+            }
+            Some(CMD_WRITE_SECOND) => self.on_aux_data(val),
+            _ => self.on_keyboard_data(val),
+        }
+    }
+
+    fn on_keyboard_data(&mut self, val: u8) {
+        let translated = self
+            .config()
+            .contains(Ps2ConfigurationFlags::FIRST_PORT_TRANSLATION);
+        let keyboard = &mut self.keyboard;
+
+        if let Some(cmd) = keyboard.pending.take() {
+            match cmd {
+                KBD_SCANCODE_SET if val == 0 => {
+                    keyboard.send(&[RESP_ACK, self.scancode_set])
+                }
+                KBD_SCANCODE_SET => {
+                    // The selected set is only reported back to the guest
+                    if (1..=3).contains(&val) {
+                        self.scancode_set = val;
+                    }
+                    keyboard.send(&[RESP_ACK]);
+                }
+                // The LEDs and typematic rate are not emulated
+                _ => keyboard.send(&[RESP_ACK]),
+            }
+            return;
+        }
+
+        match val {
+            KBD_SET_LEDS | KBD_SCANCODE_SET | KBD_SET_TYPEMATIC => {
+                keyboard.pending = Some(val);
+                keyboard.send(&[RESP_ACK]);
+            }
+            KBD_ECHO => keyboard.send(&[KBD_ECHO]),
+            DEV_IDENTIFY => {
+                let id = if translated { 0x41 } else { 0x83 };
+                keyboard.send(&[RESP_ACK, 0xab, id]);
+            }
+            DEV_ENABLE => {
+                keyboard.enabled = true;
+                keyboard.send(&[RESP_ACK]);
+            }
+            DEV_DISABLE => {
+                keyboard.output.clear();
+                keyboard.enabled = false;
+                self.scancode_set = 2;
+                keyboard.send(&[RESP_ACK]);
+            }
+            DEV_SET_DEFAULTS => {
+                self.scancode_set = 2;
+                keyboard.send(&[RESP_ACK]);
+            }
+            DEV_RESEND => {
+                // There is no record of the last byte sent, so resend is
+                // not supported
+                keyboard.send(&[RESP_RESEND]);
+            }
+            DEV_RESET => {
+                keyboard.reset();
+                self.scancode_set = 2;
+                keyboard.send(&[RESP_ACK, RESP_SELF_TEST_PASSED]);
+            }
+            // The per-key typematic/make/break settings are ignored
+            0xf7..=0xfd => keyboard.send(&[RESP_ACK]),
+            _ => keyboard.send(&[RESP_RESEND]),
+        }
+    }
+
+    fn on_aux_data(&mut self, val: u8) {
+        if let Some(cmd) = self.aux.pending.take() {
+            match cmd {
+                AUX_SET_RESOLUTION => self.aux_resolution = val,
+                AUX_SET_SAMPLE_RATE => self.aux_sample_rate = val,
+                _ => (),
+            }
+            self.aux.send(&[RESP_ACK]);
+            return;
+        }
+
+        match val {
+            AUX_SET_RESOLUTION | AUX_SET_SAMPLE_RATE => {
+                self.aux.pending = Some(val);
+                self.aux.send(&[RESP_ACK]);
+            }
+            AUX_SET_SCALING_1_1 | AUX_SET_SCALING_2_1 => {
+                self.aux_scaling_2_1 = val == AUX_SET_SCALING_2_1;
+                self.aux.send(&[RESP_ACK]);
+            }
+            AUX_STATUS_REQUEST => {
+                let mut status = 0;
+                if self.aux.enabled {
+                    status |= AUX_STATUS_ENABLED;
+                }
+                if self.aux_scaling_2_1 {
+                    status |= AUX_STATUS_SCALING_2_1;
+                }
+                let (resolution, rate) =
+                    (self.aux_resolution, self.aux_sample_rate);
+                self.aux.send(&[RESP_ACK, status, resolution, rate]);
+            }
+            AUX_SET_STREAM_MODE => self.aux.send(&[RESP_ACK]),
+            AUX_READ_DATA => {
+                // The mouse never moves, so always report an empty packet
+                self.aux.send(&[RESP_ACK, 0x08, 0x00, 0x00]);
+            }
+            DEV_IDENTIFY => self.aux.send(&[RESP_ACK, 0x00]),
+            DEV_ENABLE | DEV_DISABLE => {
+                self.aux.enabled = val == DEV_ENABLE;
+                self.aux.send(&[RESP_ACK]);
+            }
+            DEV_SET_DEFAULTS => {
+                self.aux_scaling_2_1 = false;
+                self.aux_resolution = 2;
+                self.aux_sample_rate = 100;
+                self.aux.send(&[RESP_ACK]);
+            }
+            DEV_RESET => {
+                self.aux.reset();
+                self.aux.enabled = false;
+                self.aux_scaling_2_1 = false;
+                self.aux_resolution = 2;
+                self.aux_sample_rate = 100;
+                self.aux.send(&[RESP_ACK, RESP_SELF_TEST_PASSED, 0x00]);
+            }
+            _ => self.aux.send(&[RESP_RESEND]),
+        }
+    }
+
+    fn on_port_read(&mut self, port: Port, mut val: PortReadRequest) {
+        match port {
+            Self::PS2_DATA => {
+                self.output_full = false;
+                val.copy_from_u32(self.output as u32);
+            }
+            Self::PS2_STATUS => val.copy_from_u32(self.status().bits() as u32),
+            _ => unreachable!(),
+        }
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+    ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        match port {
+            Self::PS2_DATA => self.on_data(val),
+            Self::PS2_COMMAND => self.on_command(val),
+            _ => unreachable!(),
+        }
+        Ok(())
     }
 }
 
@@ -24,12 +498,236 @@ impl EmulatedDevice for Keyboard8042 {
 
     fn on_event(&mut self, event: Event) -> Result<()> {
         match event.kind {
-            DeviceEvent::PortRead(_port, mut val) => {
-                //FIXME: For now just return 0xff for everything
-                val.copy_from_u32(0xff);
+            DeviceEvent::HostKeyboardReceived(scancode) => {
+                if self.keyboard.enabled {
+                    self.keyboard.send(&[scancode]);
+                }
+            }
+            DeviceEvent::PortRead(port, val) => self.on_port_read(port, val),
+            DeviceEvent::PortWrite(port, val) => {
+                self.on_port_write(port, val)?
             }
             _ => (),
         }
+
+        if self.reset_pending {
+            self.reset_pending = false;
+            event
+                .responses
+                .push(DeviceEventResponse::PowerEvent(vm::PowerEvent::Reboot));
+        }
+        self.fill_output_buffer(event.responses);
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{port_read, port_write, send_event};
+
+    fn write_port(
+        ps2: &mut Keyboard8042,
+        port: Port,
+        value: u8,
+    ) -> ResponseEventArray {
+        port_write(ps2, port, value as u32, 1)
+    }
+
+    fn read_port(ps2: &mut Keyboard8042, port: Port) -> u8 {
+        port_read(ps2, port, 1) as u8
+    }
+
+    fn status(ps2: &mut Keyboard8042) -> Ps2StatusFlags {
+        Ps2StatusFlags::from_bits_truncate(read_port(
+            ps2,
+            Keyboard8042::PS2_STATUS,
+        ))
+    }
+
+    // Read all of the pending output
+    fn read_output(ps2: &mut Keyboard8042) -> Vec<u8> {
+        let mut output = vec![];
+        while status(ps2).contains(Ps2StatusFlags::OUTPUT_BUFFER_FULL) {
+            output.push(read_port(ps2, Keyboard8042::PS2_DATA));
+        }
+        output
+    }
+
+    fn has_gsi(responses: &ResponseEventArray, expected: u32) -> bool {
+        responses.iter().any(|resp| match resp {
+            DeviceEventResponse::GSI(gsi) => *gsi == expected,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn test_controller_self_test() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+        assert!(!status(&mut ps2).contains(Ps2StatusFlags::SELF_TEST_PASS));
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_TEST_CONTROLLER);
+        let status_flags = status(&mut ps2);
+        assert!(status_flags.contains(Ps2StatusFlags::INPUT_FOR_CONTROLLER));
+        assert_eq!(read_output(&mut ps2), vec![CONTROLLER_TEST_PASSED]);
+        assert!(status(&mut ps2).contains(Ps2StatusFlags::SELF_TEST_PASS));
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_TEST_FIRST);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_TEST_SECOND);
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![PORT_TEST_PASSED, PORT_TEST_PASSED]
+        );
+    }
+
+    #[test]
+    fn test_configuration_byte() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_RAM_FIRST);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, 0x61);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_READ_RAM_FIRST);
+        assert_eq!(read_output(&mut ps2), vec![0x61]);
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_ENABLE_FIRST);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_DISABLE_SECOND);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_READ_RAM_FIRST);
+        assert_eq!(read_output(&mut ps2), vec![0x61]);
+    }
+
+    #[test]
+    fn test_keyboard_reset() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        let responses = write_port(&mut ps2, Keyboard8042::PS2_DATA, DEV_RESET);
+        assert!(has_gsi(&responses, interrupt::gsi::KEYBOARD));
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![RESP_ACK, RESP_SELF_TEST_PASSED]
+        );
+
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, KBD_SCANCODE_SET);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, 0);
+        assert_eq!(read_output(&mut ps2), vec![RESP_ACK, RESP_ACK, 2]);
+    }
+
+    #[test]
+    fn test_host_scancodes() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        let responses =
+            send_event(&mut ps2, DeviceEvent::HostKeyboardReceived(0x1e));
+        assert!(has_gsi(&responses, interrupt::gsi::KEYBOARD));
+
+        // Further scancodes wait for the output buffer to be read
+        let responses =
+            send_event(&mut ps2, DeviceEvent::HostKeyboardReceived(0x9e));
+        assert!(!has_gsi(&responses, interrupt::gsi::KEYBOARD));
+        assert_eq!(read_output(&mut ps2), vec![0x1e, 0x9e]);
+
+        // Scancodes are held while the port is disabled
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_DISABLE_FIRST);
+        send_event(&mut ps2, DeviceEvent::HostKeyboardReceived(0x1e));
+        assert_eq!(read_output(&mut ps2), vec![]);
+        let responses =
+            write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_ENABLE_FIRST);
+        assert!(has_gsi(&responses, interrupt::gsi::KEYBOARD));
+        assert_eq!(read_output(&mut ps2), vec![0x1e]);
+
+        // And dropped while scanning is disabled
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, DEV_DISABLE);
+        send_event(&mut ps2, DeviceEvent::HostKeyboardReceived(0x1e));
+        assert_eq!(read_output(&mut ps2), vec![RESP_ACK]);
+    }
+
+    #[test]
+    fn test_aux_device() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_SECOND);
+        let responses = write_port(&mut ps2, Keyboard8042::PS2_DATA, DEV_RESET);
+        assert!(has_gsi(&responses, interrupt::gsi::MOUSE));
+        assert!(
+            status(&mut ps2).contains(Ps2StatusFlags::SECOND_PORT_OUTPUT_FULL)
+        );
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![RESP_ACK, RESP_SELF_TEST_PASSED, 0x00]
+        );
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_SECOND);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, AUX_SET_SAMPLE_RATE);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_SECOND);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, 40);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_SECOND);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, AUX_STATUS_REQUEST);
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![RESP_ACK, RESP_ACK, RESP_ACK, 0x00, 2, 40]
+        );
+    }
+
+    #[test]
+    fn test_output_port() {
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_READ_OUTPUT_PORT);
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![OUTPUT_PORT_RESET | OUTPUT_PORT_A20]
+        );
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_OUTPUT_PORT);
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, OUTPUT_PORT_RESET);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_READ_OUTPUT_PORT);
+        assert_eq!(read_output(&mut ps2), vec![OUTPUT_PORT_RESET]);
+
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_ENABLE_A20);
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_READ_OUTPUT_PORT);
+        assert_eq!(
+            read_output(&mut ps2),
+            vec![OUTPUT_PORT_RESET | OUTPUT_PORT_A20]
+        );
+
+        // Data written through the controller appears as device output
+        write_port(
+            &mut ps2,
+            Keyboard8042::PS2_COMMAND,
+            CMD_WRITE_SECOND_OUTPUT,
+        );
+        write_port(&mut ps2, Keyboard8042::PS2_DATA, 0x42);
+        assert!(
+            status(&mut ps2).contains(Ps2StatusFlags::SECOND_PORT_OUTPUT_FULL)
+        );
+        assert_eq!(read_output(&mut ps2), vec![0x42]);
+    }
+
+    #[test]
+    fn test_pulse_reset() {
+        let is_reboot = |responses: &ResponseEventArray| {
+            responses.iter().any(|resp| match resp {
+                DeviceEventResponse::PowerEvent(vm::PowerEvent::Reboot) => true,
+                _ => false,
+            })
+        };
+        let mut ps2 = Keyboard8042::new().unwrap();
+
+        // Pulsing lines other than reset has no effect
+        let responses = write_port(&mut ps2, Keyboard8042::PS2_COMMAND, 0xfd);
+        assert!(!is_reboot(&responses));
+
+        let responses = write_port(&mut ps2, Keyboard8042::PS2_COMMAND, 0xfe);
+        assert!(is_reboot(&responses));
+
+        // Writing the output port with the reset line low also resets
+        write_port(&mut ps2, Keyboard8042::PS2_COMMAND, CMD_WRITE_OUTPUT_PORT);
+        let responses =
+            write_port(&mut ps2, Keyboard8042::PS2_DATA, OUTPUT_PORT_A20);
+        assert!(is_reboot(&responses));
+    }
 }
@@ -1,13 +1,43 @@
 use crate::apic::*;
 use crate::error::{Error, Result};
+use crate::ioapic;
 use crate::memory;
 use crate::percore;
+use crate::time;
+use crate::vcpu;
 use crate::vm;
+use arrayvec::ArrayVec;
 use core::convert::TryFrom;
 use core::pin::Pin;
-use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, AtomicU32};
+use core::time::Duration;
 use num_enum::TryFromPrimitive;
 
+// Version 0x14 with 6 LVT entries
+const APIC_VERSION: u32 = 0x00050014;
+
+// The virtual APIC timer counts at 100MHz (before division)
+const APIC_TIMER_TICK_NS: u64 = 10;
+
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+const X2APIC_MSR_END: u32 = 0x8ff;
+
+/// Returns true if the given MSR is one of the x2APIC registers
+pub fn is_x2apic_msr(msr: u32) -> bool {
+    (X2APIC_MSR_BASE..=X2APIC_MSR_END).contains(&msr)
+}
+
+const X2APIC_SELF_IPI: u32 = 0x83f;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_WRITABLE: u32 = 0x000700ff;
+const LVT_WRITABLE: u32 = 0x0001a7ff;
+
 #[derive(Debug)]
 enum ApicRegisterOffset {
     Simple(ApicRegisterSimpleOffset),
@@ -52,13 +82,18 @@ enum ApicRegisterSimpleOffset {
 pub struct LogicalApicState {
     pub logical_destination: AtomicU32,
     pub destination_format: AtomicU32,
+
+    /// Whether the local APIC is in x2APIC mode (in which case the logical
+    /// destination is always interpreted in cluster mode)
+    pub x2apic: AtomicBool,
 }
 
 impl core::default::Default for LogicalApicState {
     fn default() -> Self {
         Self {
             logical_destination: AtomicU32::new(0),
-            destination_format: AtomicU32::new(0),
+            destination_format: AtomicU32::new(0xffffffff),
+            x2apic: AtomicBool::new(false),
         }
     }
 }
@@ -103,121 +138,712 @@ impl TryFrom<u16> for ApicRegisterOffset {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimerMode {
+    OneShot,
+    Periodic,
+    TscDeadline,
+}
+
+/// The guest local APIC timer
+///
+/// The timer is backed by the TimerWheel of the core running the VCpu, so
+/// expirations are delivered directly to this VCpu.
+struct ApicTimer {
+    lvt: u32,
+    divide_config: u32,
+    initial_count: u32,
+    tsc_deadline: u64,
+
+    // When the current count was last loaded from the initial count
+    started: Option<time::Instant>,
+    timer: Option<time::TimerId>,
+}
+
+impl Default for ApicTimer {
+    fn default() -> Self {
+        Self {
+            lvt: LVT_MASKED,
+            divide_config: 0,
+            initial_count: 0,
+            tsc_deadline: 0,
+            started: None,
+            timer: None,
+        }
+    }
+}
+
+impl ApicTimer {
+    fn mode(&self) -> TimerMode {
+        match (self.lvt >> LVT_TIMER_MODE_SHIFT) & 0b11 {
+            0b00 => TimerMode::OneShot,
+            0b01 => TimerMode::Periodic,
+            // 0b11 is reserved, so treat it as TSC-deadline as well
+            _ => TimerMode::TscDeadline,
+        }
+    }
+
+    fn vector(&self) -> u8 {
+        self.lvt as u8
+    }
+
+    fn is_masked(&self) -> bool {
+        self.lvt & LVT_MASKED != 0
+    }
+
+    fn divisor(&self) -> u64 {
+        // Bits 0, 1 and 3 encode the divisor
+        let value =
+            ((self.divide_config & 0b1000) >> 1) | (self.divide_config & 0b11);
+        if value == 0b111 {
+            1
+        } else {
+            2 << value
+        }
+    }
+
+    fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos(
+            ticks
+                .saturating_mul(self.divisor())
+                .saturating_mul(APIC_TIMER_TICK_NS),
+        )
+    }
+
+    fn elapsed_ticks(&self, started: time::Instant) -> u64 {
+        let elapsed = (time::now() - started).as_nanos();
+        (elapsed / (self.divisor() * APIC_TIMER_TICK_NS) as u128) as u64
+    }
+
+    fn current_count(&self) -> u32 {
+        let started = match self.started {
+            Some(started) if self.initial_count != 0 => started,
+            _ => return 0,
+        };
+
+        let elapsed = self.elapsed_ticks(started);
+        let initial = self.initial_count as u64;
+        match self.mode() {
+            TimerMode::OneShot => initial.saturating_sub(elapsed) as u32,
+            TimerMode::Periodic => (initial - elapsed % initial) as u32,
+            TimerMode::TscDeadline => 0,
+        }
+    }
+
+    fn tsc_deadline(&self) -> u64 {
+        // The deadline is cleared once the timer has fired
+        if self.mode() != TimerMode::TscDeadline
+            || time::now() >= time::Instant(self.tsc_deadline)
+        {
+            0
+        } else {
+            self.tsc_deadline
+        }
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(id) = self.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+        Ok(())
+    }
+
+    // Schedule the next expiration of the timer (if any) on this core
+    fn rearm(&mut self) -> Result<()> {
+        self.stop()?;
+
+        if self.is_masked() {
+            return Ok(());
+        }
+
+        let kind = time::TimerInterruptType::Direct {
+            vector: self.vector(),
+            kind: crate::vcpu::InjectedInterruptType::ExternalInterrupt,
+        };
+
+        self.timer = match self.mode() {
+            TimerMode::OneShot => match self.current_count() {
+                0 => None,
+                remaining => Some(time::set_oneshot_timer(
+                    self.ticks_to_duration(remaining as u64),
+                    kind,
+                )),
+            },
+            TimerMode::Periodic if self.initial_count != 0 => {
+                // Keep the phase of a running timer by first firing when
+                // the current count reaches zero
+                Some(time::set_periodic_timer_after(
+                    self.ticks_to_duration(self.current_count() as u64),
+                    self.ticks_to_duration(self.initial_count as u64),
+                    kind,
+                ))
+            }
+            TimerMode::Periodic => None,
+            TimerMode::TscDeadline if self.tsc_deadline != 0 => {
+                // The global time source is the TSC, so the deadline is
+                // already in terms of an Instant.
+                let deadline = time::Instant(self.tsc_deadline);
+                let now = time::now();
+                let duration = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                Some(time::set_oneshot_timer(duration, kind))
+            }
+            TimerMode::TscDeadline => None,
+        };
+        Ok(())
+    }
+
+    fn write_lvt(&mut self, value: u32) -> Result<()> {
+        let old_mode = self.mode();
+        self.lvt = value & LVT_TIMER_WRITABLE;
+
+        // Switching to or from TSC-deadline mode disarms the timer
+        let mode = self.mode();
+        if mode != old_mode
+            && (mode == TimerMode::TscDeadline
+                || old_mode == TimerMode::TscDeadline)
+        {
+            self.initial_count = 0;
+            self.started = None;
+            self.tsc_deadline = 0;
+        }
+        self.rearm()
+    }
+
+    fn write_divide_config(&mut self, value: u32) -> Result<()> {
+        // Keep the current count consistent across the change of rate
+        let current = self.current_count();
+        self.divide_config = value & 0b1011;
+        if self.started.is_some() && self.mode() == TimerMode::OneShot {
+            self.initial_count = current;
+            self.started = Some(time::now());
+        }
+        self.rearm()
+    }
+
+    fn write_initial_count(&mut self, value: u32) -> Result<()> {
+        if self.mode() == TimerMode::TscDeadline {
+            return Ok(());
+        }
+
+        self.initial_count = value;
+        self.started = if value == 0 { None } else { Some(time::now()) };
+        self.rearm()
+    }
+
+    fn write_tsc_deadline(&mut self, value: u64) -> Result<()> {
+        if self.mode() != TimerMode::TscDeadline {
+            return Ok(());
+        }
+        self.tsc_deadline = value;
+        self.rearm()
+    }
+}
+
 #[derive(Default)]
 pub struct LocalApic {
+    apic_id: u32,
+    apic_base: u64,
+    icr_low: u32,
     icr_destination: Option<u32>,
+
+    // Interrupts sent to this local apic by itself
+    self_interrupts: Vec<(u8, vcpu::InjectedInterruptType)>,
+
+    // The vectors that have been delivered to the guest and not yet
+    // acknowledged with an EOI (ISR), and which of them are level
+    // triggered (TMR)
+    in_service: [u32; 8],
+    trigger_mode: [u32; 8],
+
+    task_priority: u32,
+    logical_destination: u32,
+    destination_format: u32,
+    spurious_vector: u32,
+    error_status: u32,
+
+    // CMCI, thermal, performance counter, LINT0, LINT1 and error LVTs
+    lvt: [u32; 6],
+    timer: ApicTimer,
 }
 
 impl LocalApic {
-    pub fn new() -> Self {
+    /// Create a new local apic with the given virtual APIC id (the BSP
+    /// always has APIC id 0)
+    pub fn new(apic_id: u32) -> Self {
+        let mut apic_base =
+            vm::GUEST_LOCAL_APIC_ADDR.as_u64() | APIC_BASE_ENABLE;
+        if apic_id == 0 {
+            apic_base |= APIC_BASE_BSP;
+        }
+
         LocalApic {
+            apic_id,
+            apic_base,
+            icr_low: 0,
             icr_destination: None,
+            self_interrupts: vec![],
+            in_service: [0; 8],
+            trigger_mode: [0; 8],
+            task_priority: 0,
+            logical_destination: 0,
+            destination_format: 0xffffffff,
+            spurious_vector: 0xff,
+            error_status: 0,
+            lvt: [LVT_MASKED; 6],
+            timer: ApicTimer::default(),
         }
     }
 
-    fn process_sipi_request(&self, value: u32) -> Result<()> {
-        // TODO(alschwalm): check the destination and delivery modes to
-        // be sure this is actually what we should be doing.
-        if let Some(dest) = self.icr_destination {
-            let vector = value as u64 & 0xff;
-            let addr = memory::GuestPhysAddr::new(vector << 12);
+    /// Reset the local apic in response to an INIT signal
+    ///
+    /// The APIC id and IA32_APIC_BASE (including x2APIC mode) are preserved,
+    /// everything else returns to its power-on value.
+    pub fn init_reset(&mut self, vm: Pin<&vm::VirtualMachine>) -> Result<()> {
+        self.timer.stop()?;
 
-            // FIXME(alschwalm): The destination is actually a virtual local
-            // apic id. We should convert that to a global core id for this.
-            let core_id = percore::CoreId::from(dest >> 24);
+        let mut apic = Self::new(self.apic_id);
+        apic.apic_base = self.apic_base;
+        if apic.is_x2apic() {
+            apic.logical_destination = apic.x2apic_logical_destination();
+        }
+        *self = apic;
+
+        vm.update_core_logical_destination(self.logical_destination);
+        vm.update_core_destination_format(self.destination_format);
+        Ok(())
+    }
 
-            debug!(
-                "Sending startup message for address = {:?} to core {}",
-                addr, core_id
+    /// Reset the local apic in response to a system reset
+    ///
+    /// Unlike an INIT, this also returns IA32_APIC_BASE to its power-on
+    /// value (so the local apic leaves x2APIC mode).
+    pub fn reset(&mut self, vm: Pin<&vm::VirtualMachine>) -> Result<()> {
+        self.timer.stop()?;
+        *self = Self::new(self.apic_id);
+
+        vm.update_core_x2apic_mode(false, self.logical_destination);
+        vm.update_core_destination_format(self.destination_format);
+        Ok(())
+    }
+
+    fn lvt_index(register: &ApicRegisterSimpleOffset) -> Option<usize> {
+        match register {
+            ApicRegisterSimpleOffset::LvtCorrectMachineCheckInterrupt => {
+                Some(0)
+            }
+            ApicRegisterSimpleOffset::LvtThermalSensor => Some(1),
+            ApicRegisterSimpleOffset::LvtPerformanceMonitoringCounter => {
+                Some(2)
+            }
+            ApicRegisterSimpleOffset::LvtLINT0 => Some(3),
+            ApicRegisterSimpleOffset::LvtLINT1 => Some(4),
+            ApicRegisterSimpleOffset::LvtError => Some(5),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the guest has put this local APIC in x2APIC mode
+    pub fn is_x2apic(&self) -> bool {
+        self.apic_base & APIC_BASE_X2APIC_ENABLE != 0
+    }
+
+    /// The virtual APIC id of this local apic
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    // In x2APIC mode, the logical destination is derived from the APIC id
+    // (the upper bits are the cluster and the lower 16 bits are a bitmask)
+    fn x2apic_logical_destination(&self) -> u32 {
+        let id = self.apic_id();
+        ((id >> 4) << 16) | (1 << (id & 0xf))
+    }
+
+    /// The destination APIC id (or logical destination) of the ICR
+    fn icr_destination_id(&self) -> Option<u32> {
+        self.icr_destination.map(|dest| {
+            if self.is_x2apic() {
+                dest
+            } else {
+                dest >> 24
+            }
+        })
+    }
+
+    /// Read the guest IA32_APIC_BASE MSR
+    pub fn apic_base(&self) -> u64 {
+        self.apic_base
+    }
+
+    /// Write the guest IA32_APIC_BASE MSR
+    ///
+    /// Returns false if the value is invalid, in which case the write
+    /// should raise a #GP in the guest.
+    pub fn set_apic_base(
+        &mut self,
+        vm: Pin<&vm::VirtualMachine>,
+        value: u64,
+    ) -> Result<bool> {
+        if value & !0xfff != vm::GUEST_LOCAL_APIC_ADDR.as_u64() {
+            warn!("Attempt to relocate the local apic to 0x{:x}", value);
+        }
+
+        if value & APIC_BASE_X2APIC_ENABLE != 0 && value & APIC_BASE_ENABLE == 0
+        {
+            warn!("Invalid IA32_APIC_BASE value: 0x{:x}", value);
+            return Ok(false);
+        }
+
+        let was_x2apic = self.is_x2apic();
+        self.apic_base = (self.apic_base & APIC_BASE_BSP)
+            | vm::GUEST_LOCAL_APIC_ADDR.as_u64()
+            | (value & (APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE));
+
+        if self.is_x2apic() != was_x2apic {
+            if self.is_x2apic() {
+                self.logical_destination = self.x2apic_logical_destination();
+            } else {
+                self.logical_destination = 0;
+            }
+            vm.update_core_x2apic_mode(
+                self.is_x2apic(),
+                self.logical_destination,
             );
+        }
+        Ok(true)
+    }
 
-            vm::virtual_machines().send_msg_core(
-                vm::VirtualMachineMsg::StartVcpu(addr),
-                core_id,
-                false,
-            )?;
+    // The register accessed through an x2APIC MSR, or None if the MSR
+    // can't be accessed (i.e., the access should raise a #GP)
+    fn x2apic_msr_register(&self, msr: u32) -> Option<ApicRegisterOffset> {
+        if !self.is_x2apic() {
+            warn!("Access to x2APIC MSR 0x{:x} while not in x2APIC mode", msr);
+            return None;
+        }
+
+        let offset = ((msr - X2APIC_MSR_BASE) << 4) as u16;
+        match ApicRegisterOffset::try_from(offset) {
+            Ok(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::ArbitrationPriority,
+            ))
+            | Ok(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::RemoteRead,
+            ))
+            | Ok(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::DestinationFormat,
+            ))
+            | Ok(ApicRegisterOffset::InterruptCommand(1))
+            | Err(_) => {
+                warn!("Access to invalid x2APIC MSR 0x{:x}", msr);
+                None
+            }
+            Ok(register) => Some(register),
         }
-        Ok(())
     }
 
-    fn process_interrupt_command(
+    /// Read an x2APIC register through its MSR
+    ///
+    /// Returns None if the MSR can't be read, in which case the read
+    /// should raise a #GP in the guest.
+    pub fn x2apic_msr_read(&mut self, msr: u32) -> Result<Option<u64>> {
+        let value = match self.x2apic_msr_register(msr) {
+            Some(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::ApicId,
+            )) => self.apic_id() as u64,
+            Some(ApicRegisterOffset::InterruptCommand(_)) => {
+                ((self.icr_destination.unwrap_or(0) as u64) << 32)
+                    | self.icr_low as u64
+            }
+            Some(_) => {
+                let offset = ((msr - X2APIC_MSR_BASE) << 4) as u16;
+                self.register_read(offset)? as u64
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// Write an x2APIC register through its MSR
+    ///
+    /// Returns false if the MSR can't be written, in which case the write
+    /// should raise a #GP in the guest.
+    pub fn x2apic_msr_write(
         &mut self,
         vm: Pin<&vm::VirtualMachine>,
-        value: u32,
-    ) -> Result<()> {
-        let mode = DeliveryMode::try_from((value >> 8) as u8 & 0b111)?;
-        match mode {
-            // TODO: for now, just ignore the INIT signal
-            DeliveryMode::Init => return Ok(()),
-            DeliveryMode::StartUp => return self.process_sipi_request(value),
-            _ => (),
+        msr: u32,
+        value: u64,
+    ) -> Result<bool> {
+        // The self IPI register only exists in x2APIC mode
+        if msr == X2APIC_SELF_IPI {
+            if !self.is_x2apic() {
+                warn!("Write to x2APIC self IPI while not in x2APIC mode");
+                return Ok(false);
+            }
+            self.process_interrupt_command(
+                vm,
+                (value as u32 & 0xff) | ((DstShorthand::MySelf as u32) << 18),
+            )?;
+            return Ok(true);
         }
 
-        let vector = value as u64 & 0xff;
-        let dst_mode = DstMode::try_from((value >> 11 & 0b1) as u8)?;
-        let shorthand = DstShorthand::try_from((value >> 18 & 0b11) as u8)?;
+        match self.x2apic_msr_register(msr) {
+            // In x2APIC mode, the ICR is written with a single MSR write
+            Some(ApicRegisterOffset::InterruptCommand(_)) => {
+                self.icr_destination = Some((value >> 32) as u32);
+                self.icr_low = value as u32;
+                self.process_interrupt_command(vm, value as u32)?;
+            }
+            Some(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::ApicId,
+            ))
+            | Some(ApicRegisterOffset::Simple(
+                ApicRegisterSimpleOffset::LogicalDestination,
+            )) => {
+                warn!("Write to read-only x2APIC MSR 0x{:x}", msr);
+            }
+            Some(_) => {
+                let offset = ((msr - X2APIC_MSR_BASE) << 4) as u16;
+                self.register_write(vm, offset, value as u32)?;
+            }
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Read the guest IA32_TSC_DEADLINE MSR
+    pub fn tsc_deadline(&self) -> u64 {
+        self.timer.tsc_deadline()
+    }
+
+    /// Write the guest IA32_TSC_DEADLINE MSR
+    pub fn set_tsc_deadline(&mut self, value: u64) -> Result<()> {
+        self.timer.write_tsc_deadline(value)
+    }
+
+    /// Record that an interrupt has been delivered to the guest, so it is
+    /// in service until the guest writes the EOI register. The EOI for a
+    /// level triggered interrupt is broadcast to the I/O APIC.
+    pub fn accept_interrupt(&mut self, vector: u8, level_triggered: bool) {
+        let (index, bit) = (vector as usize / 32, 1 << (vector % 32));
+        self.in_service[index] |= bit;
+        if level_triggered {
+            self.trigger_mode[index] |= bit;
+        } else {
+            self.trigger_mode[index] &= !bit;
+        }
+    }
+
+    // Clear the highest priority in-service vector, returning it and
+    // whether it was level triggered
+    fn end_of_interrupt(&mut self) -> Option<(u8, bool)> {
+        let index = self.in_service.iter().rposition(|bits| *bits != 0)?;
+        let bit = 31 - self.in_service[index].leading_zeros();
+        self.in_service[index] &= !(1 << bit);
+        let level_triggered = self.trigger_mode[index] & (1 << bit) != 0;
+        Some(((index as u32 * 32 + bit) as u8, level_triggered))
+    }
 
+    /// Take the interrupts the guest has sent to this local apic (i.e.,
+    /// through a self IPI) that have not yet been injected
+    pub fn take_self_interrupts(
+        &mut self,
+    ) -> Vec<(u8, vcpu::InjectedInterruptType)> {
+        core::mem::take(&mut self.self_interrupts)
+    }
+
+    fn send_interrupt(
+        &mut self,
+        core: percore::CoreId,
+        vector: u8,
+        kind: vcpu::InjectedInterruptType,
+    ) -> Result<()> {
+        if core == percore::read_core_id() {
+            self.self_interrupts.push((vector, kind));
+            Ok(())
+        } else {
+            vm::virtual_machines().send_msg_core(
+                vm::VirtualMachineMsg::GuestInterrupt { kind, vector },
+                core,
+                true,
+            )
+        }
+    }
+
+    // Resolve the cores addressed by an interrupt command. See 10.6.2 of
+    // Volume 3A of the Intel software developer's manual
+    fn icr_destinations(
+        &self,
+        vm: Pin<&vm::VirtualMachine>,
+        dst_mode: DstMode,
+        shorthand: DstShorthand,
+    ) -> Result<ArrayVec<[percore::CoreId; vm::MAX_PER_VM_CORE_COUNT]>> {
+        let current = percore::read_core_id();
+        let mut destinations = ArrayVec::new();
         match shorthand {
+            DstShorthand::MySelf => destinations.push(current),
             DstShorthand::AllIncludingSelf => {
-                warn!("Unsupported local apic command register shorthand AllIncludingSelf");
+                destinations.extend(vm.cpus.iter().copied())
+            }
+            DstShorthand::AllExcludingSelf => destinations.extend(
+                vm.cpus.iter().copied().filter(|core| *core != current),
+            ),
+            DstShorthand::NoShorthand => {
+                let (destination, mode) = match dst_mode {
+                    DstMode::Logical => (
+                        self.icr_destination.unwrap_or(0),
+                        ioapic::DestinationMode::Logical,
+                    ),
+                    DstMode::Physical => (
+                        self.icr_destination_id().unwrap_or(0),
+                        ioapic::DestinationMode::Physical,
+                    ),
+                };
+                let broadcast =
+                    if self.is_x2apic() { 0xffffffff } else { 0xff };
+                destinations.extend(vm.apic_destination(
+                    destination,
+                    mode,
+                    broadcast,
+                )?);
+            }
+        }
+        Ok(destinations)
+    }
+
+    fn process_interrupt_command(
+        &mut self,
+        vm: Pin<&vm::VirtualMachine>,
+        value: u32,
+    ) -> Result<()> {
+        let vector = value as u8;
+        let (mode, dst_mode, shorthand, level) = match (
+            DeliveryMode::try_from((value >> 8) as u8 & 0b111),
+            DstMode::try_from((value >> 11 & 0b1) as u8),
+            DstShorthand::try_from((value >> 18 & 0b11) as u8),
+            Level::try_from((value >> 14 & 0b1) as u8),
+        ) {
+            (Ok(mode), Ok(dst_mode), Ok(shorthand), Ok(level)) => {
+                (mode, dst_mode, shorthand, level)
+            }
+            _ => {
+                warn!("Ignoring invalid interrupt command: 0x{:x}", value);
                 return Ok(());
             }
-            DstShorthand::AllExcludingSelf => {
-                for core in vm.cpus.iter() {
-                    if *core == percore::read_core_id() {
-                        continue;
+        };
+
+        let destinations = self.icr_destinations(vm, dst_mode, shorthand)?;
+
+        match mode {
+            DeliveryMode::Fixed
+            | DeliveryMode::LowestPriority
+            | DeliveryMode::NMI => {
+                let delivery =
+                    ioapic::DeliveryMode::try_from((value >> 8) as u8 & 0b111)?;
+                if let Some((cores, vector, kind)) =
+                    vm::interrupt_delivery(destinations, delivery, vector)
+                {
+                    for core in cores {
+                        self.send_interrupt(core, vector, kind)?;
                     }
-                    vm::virtual_machines().send_msg_core(vm::VirtualMachineMsg::GuestInterrupt{
-                        kind: crate::vcpu::InjectedInterruptType::ExternalInterrupt,
-                        vector: vector as u8
-                    }, *core, true)?
                 }
-                return Ok(());
             }
-            DstShorthand::MySelf => {
-                warn!("Unsupported local apic command register shorthand Self");
-                return Ok(());
+            DeliveryMode::SMI => {
+                warn!("Ignoring SMI IPI (SMM is not supported)");
             }
-            DstShorthand::NoShorthand => (),
-        }
-
-        // No shorthand was used, so we should have an ICR destination of some sort
-        if let Some(dest) = self.icr_destination {
-            match dst_mode {
-                DstMode::Logical => {
-                    for core in vm.logical_apic_destination(dest)? {
-                        // FIXME(alschwalm): we need to support sending to ourselves (I think)
-                        if *core == percore::read_core_id() {
-                            continue;
-                        }
-                        vm::virtual_machines().send_msg_core(vm::VirtualMachineMsg::GuestInterrupt{
-                            kind: crate::vcpu::InjectedInterruptType::ExternalInterrupt,
-                            vector: vector as u8
-                        }, *core, true)?
+            DeliveryMode::Init => {
+                // The INIT level de-assert is only used to synchronize
+                // arbitration IDs, so there is nothing to do for it
+                if let Level::DeAssert = level {
+                    return Ok(());
+                }
+                for core in destinations {
+                    if core == percore::read_core_id() {
+                        warn!("Ignoring INIT IPI sent to self");
+                        continue;
                     }
+                    // The destination may be running, so it must be
+                    // notified of the INIT
+                    vm::virtual_machines().send_msg_core(
+                        vm::VirtualMachineMsg::InitVcpu,
+                        core,
+                        true,
+                    )?;
                 }
-                DstMode::Physical => {
-                    warn!("Unsupported Physical address for IPI vector=0x{:x} (dest=0x{:x}/short={:?}/mode={:?})",
-                          vector, dest, shorthand, mode);
+            }
+            DeliveryMode::StartUp => {
+                let addr = memory::GuestPhysAddr::new((vector as u64) << 12);
+                for core in destinations {
+                    if core == percore::read_core_id() {
+                        warn!("Ignoring SIPI sent to self");
+                        continue;
+                    }
+                    debug!(
+                        "Sending startup message for address = {:?} to core {}",
+                        addr, core
+                    );
+                    vm::virtual_machines().send_msg_core(
+                        vm::VirtualMachineMsg::StartVcpu(addr),
+                        core,
+                        false,
+                    )?;
                 }
             }
-        } else {
-            warn!("IPI with no icr_destination vector=0x{:x} (dest={:?}/short={:?})",
-                  vector, dst_mode, shorthand);
+            mode => {
+                warn!("Ignoring IPI with reserved delivery mode: {:?}", mode);
+            }
         }
-
         Ok(())
     }
 
     pub fn register_read(&mut self, offset: u16) -> Result<u32> {
         let offset = ApicRegisterOffset::try_from(offset)?;
-        match offset {
-            ApicRegisterOffset::Simple(ApicRegisterSimpleOffset::ApicId) => {
-                // FIXME(alschwalm): we shouldn't really use the core id for this
-                Ok(percore::read_core_id().raw)
+        let value = match offset {
+            ApicRegisterOffset::Simple(ref simple) => match simple {
+                ApicRegisterSimpleOffset::ApicId => self.apic_id() << 24,
+                ApicRegisterSimpleOffset::ApicVersion => APIC_VERSION,
+                ApicRegisterSimpleOffset::TaskPriority => self.task_priority,
+                ApicRegisterSimpleOffset::LogicalDestination => {
+                    self.logical_destination
+                }
+                ApicRegisterSimpleOffset::DestinationFormat => {
+                    self.destination_format
+                }
+                ApicRegisterSimpleOffset::SpuriousInterruptVector => {
+                    self.spurious_vector
+                }
+                ApicRegisterSimpleOffset::ErrorStatus => self.error_status,
+                ApicRegisterSimpleOffset::LvtTimer => self.timer.lvt,
+                ApicRegisterSimpleOffset::TimerInitialCount => {
+                    self.timer.initial_count
+                }
+                ApicRegisterSimpleOffset::TimerCurrentCount => {
+                    self.timer.current_count()
+                }
+                ApicRegisterSimpleOffset::TimerDivideConfig => {
+                    self.timer.divide_config
+                }
+                register => match Self::lvt_index(register) {
+                    Some(index) => self.lvt[index],
+                    None => 0,
+                },
+            },
+            ApicRegisterOffset::InterruptCommand(0) => self.icr_low,
+            ApicRegisterOffset::InterruptCommand(1) => {
+                self.icr_destination.unwrap_or(0)
             }
-            _ => Ok(0),
-        }
+            ApicRegisterOffset::InService(index) => {
+                self.in_service[index as usize]
+            }
+            ApicRegisterOffset::TriggerMode(index) => {
+                self.trigger_mode[index as usize]
+            }
+            _ => 0,
+        };
+        Ok(value)
     }
 
     pub fn register_write(
@@ -229,22 +855,52 @@ impl LocalApic {
         let offset = ApicRegisterOffset::try_from(offset)?;
         match offset {
             ApicRegisterOffset::Simple(ref simple) => match simple {
-                ApicRegisterSimpleOffset::EndOfInterrupt => (),
+                ApicRegisterSimpleOffset::EndOfInterrupt => {
+                    if let Some((vector, true)) = self.end_of_interrupt() {
+                        vm.broadcast_eoi(vector)?;
+                    }
+                }
                 ApicRegisterSimpleOffset::LogicalDestination => {
+                    self.logical_destination = value & 0xff000000;
                     vm.update_core_logical_destination(value);
                 }
-                _ => info!(
-                    "Write to virtual local apic: {:?}, value=0x{:x}",
-                    offset, value
-                ),
+                ApicRegisterSimpleOffset::TaskPriority => {
+                    self.task_priority = value & 0xff;
+                }
+                ApicRegisterSimpleOffset::DestinationFormat => {
+                    self.destination_format = value | 0x0fffffff;
+                    vm.update_core_destination_format(self.destination_format);
+                }
+                ApicRegisterSimpleOffset::SpuriousInterruptVector => {
+                    self.spurious_vector = value & 0x13ff;
+                }
+                ApicRegisterSimpleOffset::ErrorStatus => {
+                    self.error_status = 0;
+                }
+                ApicRegisterSimpleOffset::LvtTimer => {
+                    self.timer.write_lvt(value)?;
+                }
+                ApicRegisterSimpleOffset::TimerInitialCount => {
+                    self.timer.write_initial_count(value)?;
+                }
+                ApicRegisterSimpleOffset::TimerDivideConfig => {
+                    self.timer.write_divide_config(value)?;
+                }
+                register => match Self::lvt_index(register) {
+                    Some(index) => self.lvt[index] = value & LVT_WRITABLE,
+                    None => info!(
+                        "Write to virtual local apic: {:?}, value=0x{:x}",
+                        offset, value
+                    ),
+                },
             },
             ApicRegisterOffset::InterruptCommand(offset) => {
                 match offset {
                     0 => {
+                        self.icr_low = value;
+                        // The ICR high half keeps its value, so it can be
+                        // reused by the next command
                         self.process_interrupt_command(vm, value)?;
-
-                        // TODO(alschwalm): What is the expected behavior here?
-                        self.icr_destination = None;
                     }
                     1 => {
                         self.icr_destination = Some(value);
@@ -260,3 +916,107 @@ impl LocalApic {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timer_divisor() {
+        let mut timer = ApicTimer::default();
+        let expected = [
+            (0b0000, 2),
+            (0b0001, 4),
+            (0b0010, 8),
+            (0b0011, 16),
+            (0b1000, 32),
+            (0b1001, 64),
+            (0b1010, 128),
+            (0b1011, 1),
+        ];
+        for (config, divisor) in expected.iter() {
+            timer.divide_config = *config;
+            assert_eq!(timer.divisor(), *divisor);
+        }
+    }
+
+    #[test]
+    fn test_timer_mode() {
+        let mut timer = ApicTimer::default();
+        assert!(timer.is_masked());
+        assert_eq!(timer.mode(), TimerMode::OneShot);
+
+        timer.lvt = 0x20030;
+        assert!(!timer.is_masked());
+        assert_eq!(timer.mode(), TimerMode::Periodic);
+        assert_eq!(timer.vector(), 0x30);
+
+        timer.lvt = 0x40030;
+        assert_eq!(timer.mode(), TimerMode::TscDeadline);
+        assert_eq!(timer.tsc_deadline, 0);
+    }
+
+    #[test]
+    fn test_register_defaults() {
+        let mut lapic = LocalApic::new(0);
+        assert_eq!(lapic.register_read(0x30).unwrap(), APIC_VERSION);
+        assert_eq!(lapic.register_read(0x320).unwrap(), LVT_MASKED);
+        assert_eq!(lapic.register_read(0x350).unwrap(), LVT_MASKED);
+        assert_eq!(lapic.register_read(0x380).unwrap(), 0);
+        assert_eq!(lapic.register_read(0x390).unwrap(), 0);
+        assert_eq!(lapic.register_read(0xe0).unwrap(), 0xffffffff);
+        assert!(lapic.register_read(0x321).is_err());
+    }
+
+    #[test]
+    fn test_apic_base_defaults() {
+        let lapic = LocalApic::new(0);
+        assert_eq!(lapic.apic_base(), 0xfee00900);
+        assert!(!lapic.is_x2apic());
+
+        let lapic = LocalApic::new(1);
+        assert_eq!(lapic.apic_base(), 0xfee00800);
+    }
+
+    #[test]
+    fn test_x2apic_msr_requires_x2apic_mode() {
+        let mut lapic = LocalApic::new(0);
+        assert_eq!(lapic.x2apic_msr_read(0x803), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_x2apic_msr_read() {
+        let mut lapic = LocalApic::new(0);
+        lapic.apic_base |= APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE;
+        assert_eq!(lapic.x2apic_msr_read(0x802), Ok(Some(0)));
+
+        // Registers that don't exist in x2APIC mode, the ICR high half
+        // and unused MSRs can't be read
+        assert_eq!(lapic.x2apic_msr_read(0x809), Ok(None));
+        assert_eq!(lapic.x2apic_msr_read(0x831), Ok(None));
+        assert_eq!(lapic.x2apic_msr_read(0x804), Ok(None));
+    }
+
+    #[test]
+    fn test_end_of_interrupt() {
+        let mut lapic = LocalApic::new(0);
+        lapic.accept_interrupt(0x40, true);
+        lapic.accept_interrupt(0x50, false);
+        assert_eq!(lapic.register_read(0x120).unwrap(), 0x00010001);
+        assert_eq!(lapic.register_read(0x1a0).unwrap(), 0x00000001);
+
+        // The EOI for the edge triggered interrupt must not end the level
+        // triggered one
+        assert_eq!(lapic.end_of_interrupt(), Some((0x50, false)));
+        assert_eq!(lapic.end_of_interrupt(), Some((0x40, true)));
+        assert_eq!(lapic.end_of_interrupt(), None);
+        assert_eq!(lapic.register_read(0x120).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_virtual_apic_id() {
+        let mut lapic = LocalApic::new(0x23);
+        assert_eq!(lapic.register_read(0x20).unwrap(), 0x23000000);
+        assert_eq!(lapic.x2apic_logical_destination(), 0x00020008);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::error::{Error, Result};
 use crate::memory::{GuestAddressSpaceView, GuestPhysAddr};
+use crate::shmem;
+use crate::switch;
+use crate::vm;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::vec::Vec;
 use arrayvec::ArrayVec;
@@ -12,15 +15,22 @@ use spin::RwLock;
 pub mod acpi;
 pub mod com;
 pub mod debug;
+pub mod hpet;
 pub mod ioapic;
+pub mod ivshmem;
 pub mod keyboard;
 pub mod lapic;
+pub mod msi;
 pub mod pci;
 pub mod pic;
 pub mod pit;
 pub mod qemu_fw_cfg;
+pub mod reset;
 pub mod rtc;
+#[cfg(test)]
+pub mod testing;
 pub mod vga;
+pub mod virtio;
 
 const MAX_EVENT_RESPONSES: usize = 8;
 pub type ResponseEventArray =
@@ -51,11 +61,25 @@ impl EmulatedDevice for DynamicVirtualDevice {
             DynamicVirtualDevice::Qemu(qemu) => qemu.on_event(event),
         }
     }
+
+    fn reset(&mut self) -> Result<()> {
+        match self {
+            DynamicVirtualDevice::DebugPort(port) => port.reset(),
+            DynamicVirtualDevice::Uart(uart) => uart.reset(),
+            DynamicVirtualDevice::Qemu(qemu) => qemu.reset(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum DeviceEvent<'a> {
+    HostKeyboardReceived(u8),
     HostUartReceived(u8),
+    // A frame received by the given switch port
+    NetworkFrameReceived(switch::PortId, &'a [u8]),
+    // A peer rang the doorbell of the given shared memory region
+    SharedMemoryDoorbell(shmem::RegionId, u16),
+    PowerButtonPressed,
     MemRead(GuestPhysAddr, MemReadRequest<'a>),
     MemWrite(GuestPhysAddr, MemWriteRequest<'a>),
     PortRead(Port, PortReadRequest<'a>),
@@ -65,8 +89,15 @@ pub enum DeviceEvent<'a> {
 #[derive(Debug)]
 pub enum DeviceEventResponse {
     GuestUartTransmitted(u8),
-    NextConsole,
     GSI(u32),
+    // Set the level of a level triggered GSI
+    GSILevel(u32, bool),
+    // A message signaled interrupt with the given address and data
+    MSI(u64, u32),
+    // A vector supplied by the legacy PIC that should be delivered to the BSP
+    ExtInt(u8),
+    // The guest changed the power state of the whole machine
+    PowerEvent(vm::PowerEvent),
 }
 
 pub struct Event<'a> {
@@ -229,6 +260,11 @@ pub trait EmulatedDevice: Send + Sync {
     fn on_event(&mut self, _event: Event) -> Result<()> {
         Ok(())
     }
+
+    /// Return the device to its power-on state (as part of a guest restart)
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -436,6 +472,16 @@ impl<'a> MemWriteRequest<'a> {
     pub fn as_slice(&self) -> &'a [u8] {
         self.data
     }
+
+    /// The value written by the guest
+    ///
+    /// Memory writes are reported with the most significant byte first
+    /// (see `emulate::memio`), so the bytes are decoded as big endian.
+    pub fn value(&self) -> u64 {
+        self.data
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64)
+    }
 }
 
 impl<'a> fmt::Display for MemWriteRequest<'a> {
@@ -538,6 +584,16 @@ mod test {
         assert_eq!(val.as_u32(), 0x1234);
     }
 
+    #[test]
+    fn test_memio_write_value() {
+        let data = 0x12345678u32.to_be_bytes();
+        assert_eq!(MemWriteRequest::new(&data).value(), 0x12345678);
+
+        let data = 0x1122334455667788u64.to_be_bytes();
+        assert_eq!(MemWriteRequest::new(&data).value(), 0x1122334455667788);
+        assert_eq!(MemWriteRequest::new(&[0xab]).value(), 0xab);
+    }
+
     #[test]
     fn test_portio_value_read() {
         let mut arr = [0x00, 0x00];
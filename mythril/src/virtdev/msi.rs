@@ -0,0 +1,594 @@
+//! Message signaled interrupts (MSI and MSI-X) for emulated PCI devices
+//!
+//! Both capabilities produce a `DeviceEventResponse::MSI` with the address
+//! and data programmed by the guest, which the vcpu decodes into an
+//! interrupt on the right core.
+
+use crate::error::{Error, Result};
+use crate::virtdev::pci::{PciBar, PciConfigSpace};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, Event, ResponseEventArray,
+};
+use alloc::vec::Vec;
+
+pub const MSI_CAPABILITY_ID: u8 = 0x05;
+pub const MSIX_CAPABILITY_ID: u8 = 0x11;
+
+/// The maximum number of MSI-X vectors of a single function
+pub const MSIX_MAX_VECTORS: u16 = 2048;
+
+// Offsets of the MSI registers from the start of the capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_DATA: u16 = 0x0c;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+
+// The writable bits of the body of the MSI capability. Only a single
+// message is supported, so the multiple message enable field is read-only.
+const MSI_WRITE_MASK: [u8; 12] = [
+    0x01, 0x00, // control
+    0xfc, 0xff, 0xff, 0xff, // address
+    0xff, 0xff, 0xff, 0xff, // upper address
+    0xff, 0xff, // data
+];
+
+// Offsets of the MSI-X registers from the start of the capability
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_PBA: u16 = 0x08;
+
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+// The writable bits of the body of the MSI-X capability
+const MSIX_WRITE_MASK: [u8; 2] = [0x00, 0xc0];
+
+// The layout of each entry of the MSI-X table
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0;
+const MSIX_ENTRY_DATA: usize = 8;
+const MSIX_ENTRY_CONTROL: usize = 12;
+const MSIX_ENTRY_MASKED: u8 = 1 << 0;
+
+/// The MSI capability of a PCI function
+///
+/// Only a single message with a 64 bit address is supported.
+pub struct MsiCapability {
+    offset: u16,
+}
+
+impl MsiCapability {
+    /// Add an MSI capability to the given configuration space
+    pub fn new(config: &mut PciConfigSpace) -> Result<Self> {
+        let mut data = [0u8; MSI_WRITE_MASK.len()];
+        data[..2].copy_from_slice(&MSI_64BIT.to_le_bytes());
+        let offset =
+            config.add_capability(MSI_CAPABILITY_ID, &data, &MSI_WRITE_MASK)?;
+        Ok(Self { offset })
+    }
+
+    /// Whether the guest has enabled MSI for the function
+    pub fn is_enabled(&self, config: &PciConfigSpace) -> bool {
+        config.read(self.offset + MSI_CONTROL, 2) as u16 & MSI_ENABLE != 0
+    }
+
+    /// Send the message programmed by the guest
+    ///
+    /// Returns false if the message could not be sent (e.g., because MSI
+    /// is not enabled), in which case the device may fall back to INTx.
+    pub fn signal(
+        &self,
+        config: &PciConfigSpace,
+        responses: &mut ResponseEventArray,
+    ) -> bool {
+        if !self.is_enabled(config) || !config.bus_master_enabled() {
+            return false;
+        }
+
+        let address = (config.read(self.offset + MSI_ADDRESS_HIGH, 4) as u64)
+            << 32
+            | config.read(self.offset + MSI_ADDRESS_LOW, 4) as u64;
+        let data = config.read(self.offset + MSI_DATA, 2);
+        responses.push(DeviceEventResponse::MSI(address, data));
+        true
+    }
+}
+
+/// The MSI-X capability of a PCI function, along with the vector table and
+/// pending bit array (PBA) that it describes
+///
+/// The table and PBA live in one of the memory BARs of the function, so
+/// the device must forward accesses to that BAR for which `contains`
+/// returns true to `on_bar_event`.
+pub struct MsixCapability {
+    offset: u16,
+    bar: usize,
+    table_offset: u64,
+    pba_offset: u64,
+    entries: Vec<[u8; MSIX_ENTRY_SIZE]>,
+    pending: Vec<u8>,
+}
+
+impl MsixCapability {
+    /// Add an MSI-X capability with the given number of vectors to the
+    /// configuration space
+    ///
+    /// The table and PBA are placed at the given (8 byte aligned) offsets
+    /// in `bar`, which must already be defined as a memory BAR.
+    pub fn new(
+        config: &mut PciConfigSpace,
+        vectors: u16,
+        bar: usize,
+        table_offset: u32,
+        pba_offset: u32,
+    ) -> Result<Self> {
+        if vectors == 0 || vectors > MSIX_MAX_VECTORS {
+            return Err(Error::InvalidValue(format!(
+                "Invalid MSI-X vector count: {}",
+                vectors
+            )));
+        }
+        if table_offset % 8 != 0 || pba_offset % 8 != 0 {
+            return Err(Error::InvalidValue(
+                "MSI-X table and PBA must be 8 byte aligned".into(),
+            ));
+        }
+
+        let bar_size = match config.bar(bar) {
+            Some(PciBar::Memory32 { size, .. }) => size as u64,
+            Some(PciBar::Memory64 { size, .. }) => size,
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "BAR {} is not a memory BAR",
+                    bar
+                )))
+            }
+        };
+        if table_offset as u64 + Self::table_size(vectors) > bar_size
+            || pba_offset as u64 + Self::pba_size(vectors) > bar_size
+        {
+            return Err(Error::InvalidValue(format!(
+                "MSI-X structures do not fit in BAR {}",
+                bar
+            )));
+        }
+
+        // The BAR index is stored in the low bits of the offsets
+        let mut data = [0u8; 10];
+        data[..2].copy_from_slice(&(vectors - 1).to_le_bytes());
+        data[2..6].copy_from_slice(&(table_offset | bar as u32).to_le_bytes());
+        data[6..].copy_from_slice(&(pba_offset | bar as u32).to_le_bytes());
+        let offset = config.add_capability(
+            MSIX_CAPABILITY_ID,
+            &data,
+            &MSIX_WRITE_MASK,
+        )?;
+
+        let mut capability = Self {
+            offset,
+            bar,
+            table_offset: table_offset as u64,
+            pba_offset: pba_offset as u64,
+            entries: vec![[0u8; MSIX_ENTRY_SIZE]; vectors as usize],
+            pending: vec![0u8; Self::pba_size(vectors) as usize],
+        };
+        capability.reset();
+        Ok(capability)
+    }
+
+    /// The size of a table with the given number of vectors
+    pub fn table_size(vectors: u16) -> u64 {
+        vectors as u64 * MSIX_ENTRY_SIZE as u64
+    }
+
+    /// The size of a PBA for the given number of vectors (the PBA is
+    /// accessed as an array of qwords)
+    pub fn pba_size(vectors: u16) -> u64 {
+        (vectors as u64 + 63) / 64 * 8
+    }
+
+    fn control(&self, config: &PciConfigSpace) -> u16 {
+        config.read(self.offset + MSIX_CONTROL, 2) as u16
+    }
+
+    /// Whether the guest has enabled MSI-X for the function
+    pub fn is_enabled(&self, config: &PciConfigSpace) -> bool {
+        self.control(config) & MSIX_ENABLE != 0
+    }
+
+    /// Whether the offset in the given BAR is part of the table or PBA
+    pub fn contains(&self, bar: usize, offset: u64) -> bool {
+        let vectors = self.entries.len() as u16;
+        bar == self.bar
+            && ((offset >= self.table_offset
+                && offset < self.table_offset + Self::table_size(vectors))
+                || (offset >= self.pba_offset
+                    && offset < self.pba_offset + Self::pba_size(vectors)))
+    }
+
+    fn read_byte(&self, offset: u64) -> u8 {
+        if offset >= self.table_offset {
+            let index =
+                ((offset - self.table_offset) as usize) / MSIX_ENTRY_SIZE;
+            if let Some(entry) = self.entries.get(index) {
+                return entry
+                    [(offset - self.table_offset) as usize % MSIX_ENTRY_SIZE];
+            }
+        }
+        if offset >= self.pba_offset {
+            let index = (offset - self.pba_offset) as usize;
+            if let Some(byte) = self.pending.get(index) {
+                return *byte;
+            }
+        }
+        0
+    }
+
+    fn write_byte(&mut self, offset: u64, value: u8) {
+        // The PBA is read-only
+        if offset < self.table_offset {
+            return;
+        }
+        let index = ((offset - self.table_offset) as usize) / MSIX_ENTRY_SIZE;
+        let field = (offset - self.table_offset) as usize % MSIX_ENTRY_SIZE;
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry[field] = match field {
+                // Only the mask bit of the vector control is writable
+                MSIX_ENTRY_CONTROL => value & MSIX_ENTRY_MASKED,
+                field if field > MSIX_ENTRY_CONTROL => 0,
+                _ => value,
+            };
+        }
+    }
+
+    /// Handle an access to the table or PBA
+    ///
+    /// `offset` is the offset of the access from the start of the BAR.
+    pub fn on_bar_event(
+        &mut self,
+        config: &PciConfigSpace,
+        offset: u64,
+        event: Event,
+    ) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(_, mut req) => {
+                for (i, byte) in req.as_mut_slice().iter_mut().enumerate() {
+                    *byte = self.read_byte(offset + i as u64);
+                }
+            }
+            DeviceEvent::MemWrite(_, req) => {
+                // The table is little endian, but the value is reported
+                // with the most significant byte first
+                let len = req.as_slice().len();
+                let bytes = req.value().to_le_bytes();
+                for (i, byte) in bytes.iter().take(len).enumerate() {
+                    self.write_byte(offset + i as u64, *byte);
+                }
+
+                // The guest may have unmasked a pending vector
+                self.deliver_pending(config, event.responses);
+            }
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid MSI-X event: {:?}",
+                    event.kind
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a write to the configuration space of the function
+    ///
+    /// This delivers any pending vectors if the guest cleared the function
+    /// mask (or enabled MSI-X).
+    pub fn on_config_write(
+        &mut self,
+        config: &PciConfigSpace,
+        responses: &mut ResponseEventArray,
+    ) {
+        self.deliver_pending(config, responses);
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        self.pending[vector / 8] & (1 << (vector % 8)) != 0
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        if pending {
+            self.pending[vector / 8] |= 1 << (vector % 8);
+        } else {
+            self.pending[vector / 8] &= !(1 << (vector % 8));
+        }
+    }
+
+    fn is_masked(&self, config: &PciConfigSpace, vector: usize) -> bool {
+        self.control(config) & MSIX_FUNCTION_MASK != 0
+            || self.entries[vector][MSIX_ENTRY_CONTROL] & MSIX_ENTRY_MASKED != 0
+    }
+
+    fn send(&self, vector: usize, responses: &mut ResponseEventArray) {
+        let entry = &self.entries[vector];
+        let mut address = [0u8; 8];
+        address.copy_from_slice(&entry[MSIX_ENTRY_ADDRESS..][..8]);
+        let mut data = [0u8; 4];
+        data.copy_from_slice(&entry[MSIX_ENTRY_DATA..][..4]);
+        responses.push(DeviceEventResponse::MSI(
+            u64::from_le_bytes(address),
+            u32::from_le_bytes(data),
+        ));
+    }
+
+    // Send the pending vectors that are no longer masked. Vectors that
+    // don't fit in `responses` are left pending until the next delivery.
+    fn deliver_pending(
+        &mut self,
+        config: &PciConfigSpace,
+        responses: &mut ResponseEventArray,
+    ) {
+        if !self.is_enabled(config) || !config.bus_master_enabled() {
+            return;
+        }
+        for vector in 0..self.entries.len() {
+            if responses.is_full() {
+                break;
+            }
+            if self.is_pending(vector) && !self.is_masked(config, vector) {
+                self.set_pending(vector, false);
+                self.send(vector, responses);
+            }
+        }
+    }
+
+    /// Signal the given vector
+    ///
+    /// If the vector is masked, it is marked as pending and sent once the
+    /// guest unmasks it. Returns false if the vector could not be signaled
+    /// (e.g., because MSI-X is not enabled).
+    pub fn signal(
+        &mut self,
+        config: &PciConfigSpace,
+        vector: u16,
+        responses: &mut ResponseEventArray,
+    ) -> bool {
+        let vector = vector as usize;
+        if vector >= self.entries.len()
+            || !self.is_enabled(config)
+            || !config.bus_master_enabled()
+        {
+            return false;
+        }
+
+        if self.is_masked(config, vector) {
+            self.set_pending(vector, true);
+        } else {
+            self.send(vector, responses);
+        }
+        true
+    }
+
+    /// Return the table and PBA to their power-on state (all vectors are
+    /// masked and none are pending)
+    pub fn reset(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = [0u8; MSIX_ENTRY_SIZE];
+            entry[MSIX_ENTRY_CONTROL] = MSIX_ENTRY_MASKED;
+        }
+        for byte in self.pending.iter_mut() {
+            *byte = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::GuestPhysAddr;
+    use crate::virtdev::testing::{define_test_view, handle_event, mem_read};
+    use crate::virtdev::MemWriteRequest;
+
+    // The offsets of the command register and the first BAR
+    const COMMAND: u16 = 0x04;
+    const BAR_0: u16 = 0x10;
+
+    fn test_config() -> PciConfigSpace {
+        let mut config = PciConfigSpace::new(0x1234, 0x5678, 0xff, 0);
+        config
+            .set_bar(
+                0,
+                PciBar::Memory32 {
+                    size: 0x1000,
+                    prefetchable: false,
+                },
+            )
+            .unwrap();
+        config.write(BAR_0, 0xc0000000, 4);
+
+        // Enable memory decoding and bus mastering
+        config.write(COMMAND, 0b110, 2);
+        config
+    }
+
+    fn table_write(
+        msix: &mut MsixCapability,
+        config: &PciConfigSpace,
+        offset: u64,
+        data: &[u8],
+    ) -> ResponseEventArray {
+        let request = MemWriteRequest::new(data);
+        let kind =
+            DeviceEvent::MemWrite(GuestPhysAddr::new(0xc0000000), request);
+        handle_event(kind, define_test_view(), |event| {
+            msix.on_bar_event(config, offset, event)
+        })
+    }
+
+    fn table_read(
+        msix: &mut MsixCapability,
+        config: &PciConfigSpace,
+        offset: u64,
+    ) -> u32 {
+        let (value, _) = mem_read(define_test_view(), 0xc0000000, 4, |event| {
+            msix.on_bar_event(config, offset, event)
+        });
+        value as u32
+    }
+
+    #[test]
+    fn test_msi() {
+        let mut config = test_config();
+        let msi = MsiCapability::new(&mut config).unwrap();
+        let offset = config.find_capability(MSI_CAPABILITY_ID).unwrap();
+
+        let mut responses = ResponseEventArray::default();
+        assert!(!msi.signal(&config, &mut responses));
+
+        config.write(offset + MSI_ADDRESS_LOW, 0xfee01000, 4);
+        config.write(offset + MSI_DATA, 0x4041, 2);
+        config.write(offset + MSI_CONTROL, 0xffff, 2);
+        assert_eq!(config.read(offset + MSI_CONTROL, 2), 0x81);
+
+        assert!(msi.signal(&config, &mut responses));
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::MSI(0xfee01000, 0x4041)]
+        ));
+    }
+
+    #[test]
+    fn test_msix_capability() {
+        let mut config = test_config();
+        let msix = MsixCapability::new(&mut config, 4, 0, 0x800, 0x900);
+        assert!(msix.is_ok());
+        let offset = config.find_capability(MSIX_CAPABILITY_ID).unwrap();
+
+        // The table size is encoded as N - 1
+        assert_eq!(config.read(offset + MSIX_CONTROL, 2), 3);
+        assert_eq!(config.read(offset + MSIX_TABLE, 4), 0x800);
+        assert_eq!(config.read(offset + MSIX_PBA, 4), 0x900);
+
+        let msix = msix.unwrap();
+        assert!(msix.contains(0, 0x83c));
+        assert!(msix.contains(0, 0x900));
+        assert!(!msix.contains(0, 0x840));
+        assert!(!msix.contains(1, 0x800));
+
+        // The structures must fit in the BAR
+        assert!(MsixCapability::new(&mut config, 4, 0, 0xff8, 0x900).is_err());
+    }
+
+    #[test]
+    fn test_msix_masking() {
+        let mut config = test_config();
+        let mut msix =
+            MsixCapability::new(&mut config, 4, 0, 0x800, 0x900).unwrap();
+        let offset = config.find_capability(MSIX_CAPABILITY_ID).unwrap();
+
+        let mut responses = ResponseEventArray::default();
+        assert!(!msix.signal(&config, 1, &mut responses));
+        config.write(offset + MSIX_CONTROL, MSIX_ENABLE as u32, 2);
+
+        // Vectors are masked after reset, so the signal is only pending
+        assert_eq!(table_read(&mut msix, &config, 0x81c), 1);
+        table_write(&mut msix, &config, 0x810, &0xfee00000u64.to_be_bytes());
+        table_write(&mut msix, &config, 0x818, &0x30u32.to_be_bytes());
+        assert!(msix.signal(&config, 1, &mut responses));
+        assert!(responses.is_empty());
+        assert_eq!(table_read(&mut msix, &config, 0x900), 0b10);
+
+        // Unmasking the vector sends the pending message
+        let responses = table_write(&mut msix, &config, 0x81c, &[0, 0, 0, 0]);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::MSI(0xfee00000, 0x30)]
+        ));
+        assert_eq!(table_read(&mut msix, &config, 0x900), 0);
+        assert_eq!(table_read(&mut msix, &config, 0x818), 0x30);
+
+        // The function mask applies to all vectors
+        config.write(
+            offset + MSIX_CONTROL,
+            (MSIX_ENABLE | MSIX_FUNCTION_MASK) as u32,
+            2,
+        );
+        let mut responses = ResponseEventArray::default();
+        assert!(msix.signal(&config, 1, &mut responses));
+        assert!(responses.is_empty());
+
+        config.write(offset + MSIX_CONTROL, MSIX_ENABLE as u32, 2);
+        msix.on_config_write(&config, &mut responses);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::MSI(0xfee00000, 0x30)]
+        ));
+    }
+    #[test]
+    fn test_msix_table_write_order() {
+        let mut config = test_config();
+        let mut msix =
+            MsixCapability::new(&mut config, 4, 0, 0x800, 0x900).unwrap();
+
+        // Writes are reported in the byte order used by emulate::memio
+        table_write(&mut msix, &config, 0x800, &0xfee01000u32.to_be_bytes());
+        table_write(&mut msix, &config, 0x808, &[0x41]);
+        table_write(&mut msix, &config, 0x809, &[0x40]);
+        assert_eq!(table_read(&mut msix, &config, 0x800), 0xfee01000);
+        assert_eq!(table_read(&mut msix, &config, 0x808), 0x4041);
+
+        table_write(
+            &mut msix,
+            &config,
+            0x810,
+            &0x00000001_fee02000u64.to_be_bytes(),
+        );
+        assert_eq!(table_read(&mut msix, &config, 0x810), 0xfee02000);
+        assert_eq!(table_read(&mut msix, &config, 0x814), 1);
+    }
+
+    #[test]
+    fn test_msix_deliver_many_pending() {
+        const VECTORS: u16 = 12;
+        let mut config = test_config();
+        let mut msix =
+            MsixCapability::new(&mut config, VECTORS, 0, 0x800, 0x900).unwrap();
+        let offset = config.find_capability(MSIX_CAPABILITY_ID).unwrap();
+        config.write(
+            offset + MSIX_CONTROL,
+            (MSIX_ENABLE | MSIX_FUNCTION_MASK) as u32,
+            2,
+        );
+
+        let mut responses = ResponseEventArray::default();
+        for vector in 0..VECTORS {
+            let entry = 0x800 + vector as u64 * MSIX_ENTRY_SIZE as u64;
+            table_write(
+                &mut msix,
+                &config,
+                entry,
+                &0xfee00000u32.to_be_bytes(),
+            );
+            table_write(&mut msix, &config, entry + 12, &[0, 0, 0, 0]);
+            assert!(msix.signal(&config, vector, &mut responses));
+        }
+        assert!(responses.is_empty());
+        assert_eq!(table_read(&mut msix, &config, 0x900), 0xfff);
+
+        // Unmasking the function sends as many vectors as fit in the
+        // responses, and the rest are sent on the next delivery
+        config.write(offset + MSIX_CONTROL, MSIX_ENABLE as u32, 2);
+        msix.on_config_write(&config, &mut responses);
+        assert!(responses.is_full());
+        assert_eq!(
+            table_read(&mut msix, &config, 0x900),
+            0xfff & !((1 << responses.len()) - 1)
+        );
+
+        let mut responses = ResponseEventArray::default();
+        msix.on_config_write(&config, &mut responses);
+        assert_eq!(responses.len(), VECTORS as usize - responses.capacity());
+        assert_eq!(table_read(&mut msix, &config, 0x900), 0);
+    }
+}
@@ -1,11 +1,96 @@
 use crate::error::{Error, Result};
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::memory::{GuestAddressSpaceView, GuestPhysAddr};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
 use alloc::vec::Vec;
-use core::convert::TryInto;
 use num_enum::TryFromPrimitive;
 use ux;
 
+/// The size of the (extended) configuration space of each PCI function
+pub const PCI_CONFIG_SPACE_SIZE: usize = 4096;
+
+// The size of the configuration space that is accessible through the
+// legacy port interface (which is also where the capability list lives)
+const CONVENTIONAL_CONFIG_SPACE_SIZE: usize = 256;
+
+/// The number of Base Address Registers in a type 0 header
+pub const PCI_BAR_COUNT: usize = 6;
+
+/// The start of the guest physical memory window for PCI memory BARs
+pub const PCI_MMIO_START: u64 = 0xc0000000;
+
+/// The end of the guest physical memory window for PCI memory BARs
+pub const PCI_MMIO_END: u64 = 0xfebfffff;
+
+/// The start of the I/O port window for PCI I/O BARs
+pub const PCI_IO_START: Port = 0xc000;
+
+/// The end of the I/O port window for PCI I/O BARs
+pub const PCI_IO_END: Port = 0xffff;
+
+/// The guest physical address of the memory mapped (ECAM) configuration
+/// space. This is the same address used by QEMU for the Q35 machine.
+pub const PCI_ECAM_BASE: u64 = 0xb0000000;
+
+/// The number of buses decoded by the ECAM region (only bus 0 is
+/// populated)
+pub const PCI_ECAM_BUS_COUNT: u64 = 1;
+
+// Each bus has 32 devices with 8 functions, each with a 4KB config space
+const PCI_ECAM_SIZE: u64 = PCI_ECAM_BUS_COUNT << 20;
+
+/// The offsets of the registers in a type 0 configuration space header
+mod offsets {
+    pub const VENDOR_ID: u16 = 0x00;
+    pub const DEVICE_ID: u16 = 0x02;
+    pub const COMMAND: u16 = 0x04;
+    pub const STATUS: u16 = 0x06;
+    pub const SUBCLASS: u16 = 0x0a;
+    pub const CLASS: u16 = 0x0b;
+    pub const CACHE_LINE_SIZE: u16 = 0x0c;
+    pub const LATENCY_TIMER: u16 = 0x0d;
+    pub const BAR_0: u16 = 0x10;
+    pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+    pub const SUBSYSTEM_ID: u16 = 0x2e;
+    pub const CAPABILITIES_POINTER: u16 = 0x34;
+    pub const INTERRUPT_LINE: u16 = 0x3c;
+    pub const INTERRUPT_PIN: u16 = 0x3d;
+}
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_PARITY_ERROR: u16 = 1 << 6;
+const COMMAND_SERR: u16 = 1 << 8;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const COMMAND_WRITE_MASK: u16 = COMMAND_IO
+    | COMMAND_MEMORY
+    | COMMAND_BUS_MASTER
+    | COMMAND_PARITY_ERROR
+    | COMMAND_SERR
+    | COMMAND_INTX_DISABLE;
+
+const STATUS_INTERRUPT: u16 = 1 << 3;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+// The error bits of the status register are cleared by writing a 1
+const STATUS_CLEAR_MASK: u16 = 0b1111_1001 << 8;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+// The first offset available for capabilities (after the type 0 header)
+const CAPABILITIES_START: u16 = 0x40;
+
+// The INTx line has not been assigned an interrupt by the guest
+const INTERRUPT_LINE_UNKNOWN: u8 = 0xff;
+
 #[derive(Clone, Copy, Debug, TryFromPrimitive)]
 #[repr(u16)]
 enum VendorId {
@@ -22,92 +107,444 @@ enum DeviceId {
     Ich9 = 0x2918,
 }
 
-#[repr(C)]
-#[repr(packed)]
-#[derive(Default)]
-struct PciNonBridgeHeader {
-    vendor_id: u16,
-    device_id: u16,
-    command: u16,
-    status: u16,
-    revision_id: u8,
-    prog_if: u8,
-    subclass: u8,
-    class: u8,
-    cache_line_size: u8,
-    latency_timer: u8,
-    header_type: u8,
-    bist: u8,
-    bar_0: u32,
-    bar_1: u32,
-    bar_2: u32,
-    bar_3: u32,
-    bar_4: u32,
-    bar_5: u32,
-    cardbus_cis: u32,
-    subsystem_vendor_id: u16,
-    subsystem_id: u16,
-    expansion_rom_addr: u32,
-    capabilities: u8,
-    _reserved: [u8; 7],
-    interrupt_line: u8,
-    interrupt_pin: u8,
-    min_grant: u8,
-    max_latency: u8,
-}
+/// A Base Address Register of a PCI function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciBar {
+    /// A region of I/O ports
+    Io { size: u32 },
+
+    /// A region of memory below 4GB
+    Memory32 { size: u32, prefetchable: bool },
 
-#[repr(C)]
-#[repr(packed)]
-struct PciNonBridgeSpace {
-    header: PciNonBridgeHeader,
-    _data: [u32; 48],
+    /// A region of memory anywhere in the physical address space. These
+    /// BARs also occupy the following BAR index.
+    Memory64 { size: u64, prefetchable: bool },
 }
 
-impl PciNonBridgeSpace {
-    fn new(header: PciNonBridgeHeader) -> Self {
-        Self {
-            header,
-            _data: [0u32; 48],
+impl PciBar {
+    /// The size of the region decoded by the BAR
+    pub fn size(&self) -> u64 {
+        match *self {
+            PciBar::Io { size } => size as u64,
+            PciBar::Memory32 { size, .. } => size as u64,
+            PciBar::Memory64 { size, .. } => size,
         }
     }
-}
 
-#[repr(C)]
-#[repr(packed)]
-struct PciToPciBridgeSpace {
-    _data: [u32; 64],
+    /// Whether the BAR decodes I/O ports (as opposed to memory)
+    pub fn is_io(&self) -> bool {
+        matches!(self, PciBar::Io { .. })
+    }
 }
 
-#[repr(C)]
-#[repr(packed)]
-struct PciToCardbusBridgeSpace {
-    _data: [u32; 64],
-}
+/// The configuration space of a PCI function with a type 0 header
+///
+/// Each byte of the space has a write mask, so guest writes only modify
+/// the bits that are writable on real hardware. This is also what allows
+/// the guest to determine the size of a BAR: writing all ones to it and
+/// reading the value back returns the inverse of the size (plus the
+/// read-only type bits).
+pub struct PciConfigSpace {
+    data: Box<[u8]>,
+    write_mask: Box<[u8]>,
+
+    // Bits that are cleared by writing a one (RW1C)
+    clear_mask: Box<[u8]>,
 
-#[allow(dead_code)]
-enum PciConfigSpace {
-    Type0(PciNonBridgeSpace),
-    Type1(PciToPciBridgeSpace),
-    Type2(PciToCardbusBridgeSpace),
+    bars: [Option<PciBar>; PCI_BAR_COUNT],
+    capabilities_end: u16,
+    last_capability: Option<u16>,
 }
 
 impl PciConfigSpace {
-    fn as_registers(&self) -> &[u32; 64] {
-        match self {
-            PciConfigSpace::Type0(space) => unsafe {
-                core::mem::transmute(space)
-            },
-            PciConfigSpace::Type1(space) => unsafe {
-                core::mem::transmute(space)
-            },
-            PciConfigSpace::Type2(space) => unsafe {
-                core::mem::transmute(space)
-            },
+    /// Create a new configuration space for a function with the given ids
+    /// and class code
+    pub fn new(
+        vendor_id: u16,
+        device_id: u16,
+        class: u8,
+        subclass: u8,
+    ) -> Self {
+        let mut space = Self {
+            data: vec![0; PCI_CONFIG_SPACE_SIZE].into_boxed_slice(),
+            write_mask: vec![0; PCI_CONFIG_SPACE_SIZE].into_boxed_slice(),
+            clear_mask: vec![0; PCI_CONFIG_SPACE_SIZE].into_boxed_slice(),
+            bars: [None; PCI_BAR_COUNT],
+            capabilities_end: CAPABILITIES_START,
+            last_capability: None,
+        };
+
+        space.set_u16(offsets::VENDOR_ID, vendor_id);
+        space.set_u16(offsets::DEVICE_ID, device_id);
+        space.set_u8(offsets::CLASS, class);
+        space.set_u8(offsets::SUBCLASS, subclass);
+        space.set_u8(offsets::INTERRUPT_LINE, INTERRUPT_LINE_UNKNOWN);
+
+        space.set_write_mask(
+            offsets::COMMAND,
+            &COMMAND_WRITE_MASK.to_le_bytes(),
+        );
+        space.clear_mask[offsets::STATUS as usize..][..2]
+            .copy_from_slice(&STATUS_CLEAR_MASK.to_le_bytes());
+        space.set_write_mask(offsets::CACHE_LINE_SIZE, &[0xff]);
+        space.set_write_mask(offsets::LATENCY_TIMER, &[0xff]);
+        space.set_write_mask(offsets::INTERRUPT_LINE, &[0xff]);
+        space
+    }
+
+    /// Read `len` bytes at `offset`, as the guest would
+    pub fn read(&self, offset: u16, len: usize) -> u32 {
+        (0..len).rev().fold(0, |value, i| {
+            let byte = self.data.get(offset as usize + i).copied().unwrap_or(0);
+            (value << 8) | byte as u32
+        })
+    }
+
+    /// Write the low `len` bytes of `value` to `offset`, as the guest
+    /// would (so only the writable bits are modified)
+    pub fn write(&mut self, offset: u16, value: u32, len: usize) {
+        for i in 0..len {
+            let offset = offset as usize + i;
+            if offset >= PCI_CONFIG_SPACE_SIZE {
+                break;
+            }
+            let byte = (value >> (i * 8)) as u8;
+            let mask = self.write_mask[offset];
+            let updated = (self.data[offset] & !mask) | (byte & mask);
+            self.data[offset] = updated & !(byte & self.clear_mask[offset]);
+        }
+    }
+
+    /// Set a byte of the configuration space (ignoring the write mask)
+    pub fn set_u8(&mut self, offset: u16, value: u8) {
+        self.data[offset as usize] = value;
+    }
+
+    /// Set a word of the configuration space (ignoring the write mask)
+    pub fn set_u16(&mut self, offset: u16, value: u16) {
+        self.data[offset as usize..][..2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Set a dword of the configuration space (ignoring the write mask)
+    pub fn set_u32(&mut self, offset: u16, value: u32) {
+        self.data[offset as usize..][..4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_write_mask(&mut self, offset: u16, mask: &[u8]) {
+        self.write_mask[offset as usize..][..mask.len()].copy_from_slice(mask);
+    }
+
+    /// Set the subsystem vendor and subsystem ids
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set_u16(offsets::SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(offsets::SUBSYSTEM_ID, id);
+    }
+
+    /// Set the INTx pin used by the function (1 for INTA# through 4 for
+    /// INTD#, or 0 if the function does not use INTx)
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.set_u8(offsets::INTERRUPT_PIN, pin);
+    }
+
+    /// The interrupt line assigned to the function by the guest
+    pub fn interrupt_line(&self) -> u8 {
+        self.data[offsets::INTERRUPT_LINE as usize]
+    }
+
+    fn command(&self) -> u16 {
+        self.read(offsets::COMMAND, 2) as u16
+    }
+
+    fn status(&self) -> u16 {
+        self.read(offsets::STATUS, 2) as u16
+    }
+
+    /// Whether the guest has enabled decoding of the I/O BARs
+    pub fn io_enabled(&self) -> bool {
+        self.command() & COMMAND_IO != 0
+    }
+
+    /// Whether the guest has enabled decoding of the memory BARs
+    pub fn memory_enabled(&self) -> bool {
+        self.command() & COMMAND_MEMORY != 0
+    }
+
+    /// Whether the guest allows the function to access memory (e.g., DMA)
+    pub fn bus_master_enabled(&self) -> bool {
+        self.command() & COMMAND_BUS_MASTER != 0
+    }
+
+    /// Define the BAR with the given index
+    ///
+    /// The size of the BAR must be a power of two. 64 bit memory BARs also
+    /// use the BAR at `index + 1`.
+    pub fn set_bar(&mut self, index: usize, bar: PciBar) -> Result<()> {
+        let slots = if let PciBar::Memory64 { .. } = bar {
+            2
+        } else {
+            1
+        };
+        if index + slots > PCI_BAR_COUNT {
+            return Err(Error::InvalidValue(format!(
+                "Invalid BAR index: {}",
+                index
+            )));
+        }
+
+        let size = bar.size();
+        let min_size = if bar.is_io() { 4 } else { 16 };
+        if !size.is_power_of_two() || size < min_size {
+            return Err(Error::InvalidValue(format!(
+                "Invalid BAR size: 0x{:x}",
+                size
+            )));
+        }
+
+        let (flags, mask) = match bar {
+            PciBar::Io { .. } => (BAR_IO, !(size - 1) & !0b11),
+            PciBar::Memory32 { prefetchable, .. }
+            | PciBar::Memory64 { prefetchable, .. } => {
+                let mut flags = if prefetchable { BAR_PREFETCHABLE } else { 0 };
+                if slots == 2 {
+                    flags |= BAR_MEMORY_64;
+                }
+                (flags, !(size - 1) & !0b1111)
+            }
+        };
+
+        let offset = offsets::BAR_0 + (index * 4) as u16;
+        self.set_u32(offset, flags);
+        self.set_write_mask(offset, &(mask as u32).to_le_bytes());
+        if slots == 2 {
+            self.set_u32(offset + 4, 0);
+            self.set_write_mask(
+                offset + 4,
+                &((mask >> 32) as u32).to_le_bytes(),
+            );
+        }
+        self.bars[index] = Some(bar);
+        Ok(())
+    }
+
+    /// The BAR with the given index (if it has been defined)
+    pub fn bar(&self, index: usize) -> Option<PciBar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// The address the guest has assigned to the BAR with the given index
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = self.bar(index)?;
+        let offset = offsets::BAR_0 + (index * 4) as u16;
+        let low = self.read(offset, 4) as u64;
+        Some(match bar {
+            PciBar::Io { .. } => low & !0b11,
+            PciBar::Memory32 { .. } => low & !0b1111,
+            PciBar::Memory64 { .. } => {
+                (self.read(offset + 4, 4) as u64) << 32 | (low & !0b1111)
+            }
+        })
+    }
+
+    // The BAR (and the offset within it) that decodes the given address
+    fn decode_bar(&self, io: bool, addr: u64) -> Option<(usize, u64)> {
+        let enabled = if io {
+            self.io_enabled()
+        } else {
+            self.memory_enabled()
+        };
+        if !enabled {
+            return None;
+        }
+
+        (0..PCI_BAR_COUNT).find_map(|index| {
+            let bar = self.bar(index)?;
+            let base = self.bar_address(index)?;
+            // Unassigned BARs never decode anything
+            if bar.is_io() != io || base == 0 {
+                return None;
+            }
+            let offset = addr.checked_sub(base)?;
+            if offset < bar.size() {
+                Some((index, offset))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Add a capability to the capability list of the function
+    ///
+    /// `data` is the body of the capability (after the id and next
+    /// pointer), and `write_mask` gives the guest writable bits of a
+    /// prefix of the body. Returns the offset of the new capability.
+    pub fn add_capability(
+        &mut self,
+        id: u8,
+        data: &[u8],
+        write_mask: &[u8],
+    ) -> Result<u16> {
+        if write_mask.len() > data.len() {
+            return Err(Error::InvalidValue(
+                "Capability write mask is longer than the capability".into(),
+            ));
+        }
+
+        let offset = self.capabilities_end;
+        let len = data.len() + 2;
+        if offset as usize + len > CONVENTIONAL_CONFIG_SPACE_SIZE {
+            return Err(Error::Exhausted);
+        }
+
+        self.set_u8(offset, id);
+        self.set_u8(offset + 1, 0);
+        self.data[offset as usize + 2..][..data.len()].copy_from_slice(data);
+        self.set_write_mask(offset + 2, write_mask);
+
+        match self.last_capability {
+            Some(last) => self.set_u8(last + 1, offset as u8),
+            None => {
+                self.set_u8(offsets::CAPABILITIES_POINTER, offset as u8);
+                let status = self.status() | STATUS_CAPABILITIES_LIST;
+                self.set_u16(offsets::STATUS, status);
+            }
+        }
+        self.last_capability = Some(offset);
+
+        // Capabilities are dword aligned
+        self.capabilities_end = (offset + len as u16 + 3) & !0b11;
+        Ok(offset)
+    }
+
+    /// The ids and offsets of the capabilities of the function
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut next = if self.status() & STATUS_CAPABILITIES_LIST != 0 {
+            self.data[offsets::CAPABILITIES_POINTER as usize] & !0b11
+        } else {
+            0
+        };
+
+        // Avoid looping forever if the list is malformed
+        let mut remaining =
+            (CONVENTIONAL_CONFIG_SPACE_SIZE - CAPABILITIES_START as usize) / 4;
+
+        core::iter::from_fn(move || {
+            if next == 0 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+
+            let offset = next as u16;
+            next = self.data[offset as usize + 1] & !0b11;
+            Some((self.data[offset as usize], offset))
+        })
+    }
+
+    /// The offset of the first capability with the given id
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|(cap_id, _)| *cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Whether the INTx line of the function is currently asserted
+    pub fn intx_asserted(&self) -> bool {
+        self.data[offsets::INTERRUPT_PIN as usize] != 0
+            && self.status() & STATUS_INTERRUPT != 0
+            && self.command() & COMMAND_INTX_DISABLE == 0
+    }
+
+    /// Set the interrupt status of the function, which asserts or
+    /// deasserts its INTx line (unless the guest has disabled INTx)
+    pub fn set_interrupt_status(
+        &mut self,
+        pending: bool,
+        responses: &mut ResponseEventArray,
+    ) {
+        let was_asserted = self.intx_asserted();
+        let status = if pending {
+            self.status() | STATUS_INTERRUPT
+        } else {
+            self.status() & !STATUS_INTERRUPT
+        };
+        self.set_u16(offsets::STATUS, status);
+        self.update_intx(was_asserted, responses);
+    }
+
+    // Change the level of the INTx line if its state has changed. The line
+    // may be shared with other functions, so the root complex combines
+    // these changes before they reach the guest.
+    fn update_intx(
+        &self,
+        was_asserted: bool,
+        responses: &mut ResponseEventArray,
+    ) {
+        let asserted = self.intx_asserted();
+        let line = self.interrupt_line();
+        if asserted != was_asserted && line != INTERRUPT_LINE_UNKNOWN {
+            responses
+                .push(DeviceEventResponse::GSILevel(line as u32, asserted));
         }
     }
 
-    fn read_register(&self, register: u8) -> u32 {
-        self.as_registers()[register as usize]
+    /// Return the configuration space to its power-on state
+    ///
+    /// All guest writable bits are cleared.
+    pub fn reset(&mut self) {
+        for i in 0..PCI_CONFIG_SPACE_SIZE {
+            self.data[i] &= !(self.write_mask[i] | self.clear_mask[i]);
+        }
+        self.set_u8(offsets::INTERRUPT_LINE, INTERRUPT_LINE_UNKNOWN);
+    }
+}
+
+/// An emulated device that can be attached to the `PciRootComplex`
+pub trait PciDevice: Send + Sync {
+    /// The configuration space of the device, which the root complex uses
+    /// to serve guest configuration accesses and to decode the BARs
+    fn config_space(&self) -> &PciConfigSpace;
+
+    /// Mutable access to the configuration space of the device (e.g., for
+    /// guest configuration writes)
+    fn config_space_mut(&mut self) -> &mut PciConfigSpace;
+
+    /// Handle an access to one of the BARs of the device
+    ///
+    /// `offset` is the offset of the access from the start of the BAR.
+    fn on_bar_event(
+        &mut self,
+        _bar: usize,
+        _offset: u64,
+        _event: Event,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the guest has written to the configuration space of
+    /// the device (e.g., to react to MSI-X being unmasked)
+    fn on_config_write(
+        &mut self,
+        _offset: u16,
+        _len: usize,
+        _responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle an event from the host that is not an access by the guest
+    /// (e.g., input from the physical console)
+    fn on_host_event(
+        &mut self,
+        _kind: &DeviceEvent,
+        _space: &GuestAddressSpaceView,
+        _responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Return the device to its power-on state (as part of a guest restart)
+    fn reset(&mut self) -> Result<()> {
+        self.config_space_mut().reset();
+        Ok(())
     }
 }
 
@@ -118,6 +555,20 @@ pub struct PciBdf {
     function: ux::u3,
 }
 
+impl PciBdf {
+    /// Create a new bus/device/function address
+    ///
+    /// Panics if the device is greater than 31 or the function is greater
+    /// than 7.
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device: ux::u5::new(device),
+            function: ux::u3::new(function),
+        }
+    }
+}
+
 impl From<u16> for PciBdf {
     fn from(bytes: u16) -> Self {
         Self {
@@ -136,14 +587,42 @@ impl Into<u16> for PciBdf {
     }
 }
 
-pub struct PciDevice {
+// A PCI function that only has a configuration space (e.g., the chipset
+// bridges)
+struct ChipsetFunction {
     config_space: PciConfigSpace,
-    bdf: PciBdf,
+}
+
+impl ChipsetFunction {
+    fn new(device_id: DeviceId, class: u8, subclass: u8) -> Self {
+        Self {
+            config_space: PciConfigSpace::new(
+                VendorId::Intel as u16,
+                device_id as u16,
+                class,
+                subclass,
+            ),
+        }
+    }
+}
+
+impl PciDevice for ChipsetFunction {
+    fn config_space(&self) -> &PciConfigSpace {
+        &self.config_space
+    }
+
+    fn config_space_mut(&mut self) -> &mut PciConfigSpace {
+        &mut self.config_space
+    }
 }
 
 pub struct PciRootComplex {
     current_address: u32,
-    devices: BTreeMap<u16, PciDevice>,
+    config_type: u8,
+    devices: BTreeMap<u16, Box<dyn PciDevice>>,
+
+    // The INTx lines currently asserted by at least one function
+    asserted_lines: BTreeSet<u8>,
 }
 
 impl PciRootComplex {
@@ -152,38 +631,320 @@ impl PciRootComplex {
     const PCI_CONFIG_DATA: Port = 0xcfc;
     const PCI_CONFIG_DATA_MAX: Port = Self::PCI_CONFIG_DATA + 3;
 
+    // Configuration data accesses are only forwarded to the devices while
+    // this bit of the config address is set
+    const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+    const CLASS_BRIDGE: u8 = 0x06;
+    const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+    const SUBCLASS_ISA_BRIDGE: u8 = 0x01;
+
     pub fn new() -> Result<Self> {
-        let mut devices = BTreeMap::new();
-
-        let host_bridge = PciDevice {
-            bdf: PciBdf::from(0x0000),
-            config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
-                PciNonBridgeHeader {
-                    vendor_id: VendorId::Intel as u16,
-                    device_id: DeviceId::P35Mch as u16,
-                    ..PciNonBridgeHeader::default()
-                },
-            )),
+        let mut complex = Self {
+            current_address: 0,
+            config_type: 0,
+            devices: BTreeMap::new(),
+            asserted_lines: BTreeSet::new(),
         };
-        devices.insert(host_bridge.bdf.into(), host_bridge);
-
-        let ich9 = PciDevice {
-            bdf: PciBdf::from(0b1000),
-            config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
-                PciNonBridgeHeader {
-                    vendor_id: VendorId::Intel as u16,
-                    device_id: DeviceId::Ich9 as u16,
-                    ..PciNonBridgeHeader::default()
-                },
+
+        complex.add_device(
+            PciBdf::from(0x0000),
+            Box::new(ChipsetFunction::new(
+                DeviceId::P35Mch,
+                Self::CLASS_BRIDGE,
+                Self::SUBCLASS_HOST_BRIDGE,
             )),
-        };
-        devices.insert(ich9.bdf.into(), ich9);
+        )?;
+        complex.add_device(
+            PciBdf::from(0b1000),
+            Box::new(ChipsetFunction::new(
+                DeviceId::Ich9,
+                Self::CLASS_BRIDGE,
+                Self::SUBCLASS_ISA_BRIDGE,
+            )),
+        )?;
 
-        Ok(Self {
-            current_address: 0,
-            devices: devices,
+        Ok(complex)
+    }
+
+    /// Offer an event from the host (e.g., input from the physical console)
+    /// to every attached device
+    pub fn on_host_event(
+        &mut self,
+        kind: &DeviceEvent,
+        space: &GuestAddressSpaceView,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let start = responses.len();
+        for device in self.devices.values_mut() {
+            device.on_host_event(kind, space, responses)?;
+        }
+        self.update_intx_lines(start, responses);
+        Ok(())
+    }
+
+    // INTx lines are shared (wire-OR) by the functions connected to them,
+    // so replace the level changes reported by the individual functions
+    // (starting at the given response) with the changes of the lines.
+    fn update_intx_lines(
+        &mut self,
+        start: usize,
+        responses: &mut ResponseEventArray,
+    ) {
+        let mut index = start;
+        while index < responses.len() {
+            match responses[index] {
+                DeviceEventResponse::GSILevel(..) => {
+                    responses.remove(index);
+                }
+                _ => index += 1,
+            }
+        }
+
+        let asserted: BTreeSet<u8> = self
+            .devices
+            .values()
+            .map(|device| device.config_space())
+            .filter(|config| config.intx_asserted())
+            .map(|config| config.interrupt_line())
+            .filter(|line| *line != INTERRUPT_LINE_UNKNOWN)
+            .collect();
+        for line in self.asserted_lines.symmetric_difference(&asserted) {
+            responses.push(DeviceEventResponse::GSILevel(
+                *line as u32,
+                asserted.contains(line),
+            ));
+        }
+        self.asserted_lines = asserted;
+    }
+
+    /// Attach a device to the root complex at the given address
+    pub fn add_device(
+        &mut self,
+        bdf: PciBdf,
+        device: Box<dyn PciDevice>,
+    ) -> Result<()> {
+        let bdf: u16 = bdf.into();
+        if self.devices.contains_key(&bdf) {
+            return Err(Error::InvalidDevice(format!(
+                "PCI device already attached at 0x{:x}",
+                bdf
+            )));
+        }
+        self.devices.insert(bdf, device);
+        Ok(())
+    }
+
+    // The bdf and register offset selected by the config address and
+    // the given data port, or None if the config address is not enabled
+    fn config_target(&self, port: Port) -> Option<(u16, u16)> {
+        if self.current_address & Self::CONFIG_ADDRESS_ENABLE == 0 {
+            return None;
+        }
+        let bdf = ((self.current_address & 0xffff00) >> 8) as u16;
+        let offset = (self.current_address & 0xfc) as u16
+            + (port - Self::PCI_CONFIG_DATA);
+        Some((bdf, offset))
+    }
+
+    fn config_read(&self, bdf: u16, offset: u16, len: usize) -> u32 {
+        match self.devices.get(&bdf) {
+            Some(device) => device.config_space().read(offset, len),
+            // If no device is present, just return all 0xFFs
+            None => 0xffffffff,
+        }
+    }
+
+    fn config_write(
+        &mut self,
+        bdf: u16,
+        offset: u16,
+        value: u32,
+        len: usize,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if let Some(device) = self.devices.get_mut(&bdf) {
+            let config = device.config_space_mut();
+            let was_asserted = config.intx_asserted();
+            config.write(offset, value, len);
+
+            // The guest may have disabled (or enabled) INTx
+            config.update_intx(was_asserted, responses);
+            device.on_config_write(offset, len, responses)?;
+        }
+        Ok(())
+    }
+
+    // Handle an access to the memory mapped configuration space
+    fn on_ecam_event(&mut self, offset: u64, event: Event) -> Result<()> {
+        // The address selects the bus/device/function and register
+        let bdf = (offset >> 12) as u16;
+        let register = (offset & 0xfff) as u16;
+
+        // Accesses wider than a dword are split into dword accesses
+        match event.kind {
+            DeviceEvent::MemRead(_, mut req) => {
+                for (i, chunk) in req.as_mut_slice().chunks_mut(4).enumerate() {
+                    let len = chunk.len();
+                    let value =
+                        self.config_read(bdf, register + (i * 4) as u16, len);
+                    chunk.copy_from_slice(&value.to_le_bytes()[..len]);
+                }
+            }
+            DeviceEvent::MemWrite(_, req) => {
+                // The low dword of the value is written to the register
+                let len = req.as_slice().len();
+                let value = req.value();
+                for offset in (0..len).step_by(4) {
+                    self.config_write(
+                        bdf,
+                        register + offset as u16,
+                        (value >> (offset * 8)) as u32,
+                        (len - offset).min(4),
+                        event.responses,
+                    )?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // The device, BAR and offset that decode the given address
+    fn decode_bar(&self, io: bool, addr: u64) -> Option<(u16, usize, u64)> {
+        self.devices.iter().find_map(|(bdf, device)| {
+            device
+                .config_space()
+                .decode_bar(io, addr)
+                .map(|(bar, offset)| (*bdf, bar, offset))
         })
     }
+
+    fn on_bar_event(
+        &mut self,
+        target: Option<(u16, usize, u64)>,
+        event: Event,
+    ) -> Result<()> {
+        match target {
+            Some((bdf, bar, offset)) => {
+                let device = self
+                    .devices
+                    .get_mut(&bdf)
+                    .expect("Missing decoding PCI device");
+                device.on_bar_event(bar, offset, event)
+            }
+            None => {
+                // Accesses that no device decodes are master aborts, which
+                // read as all 0xFFs
+                match event.kind {
+                    DeviceEvent::MemRead(_, mut req) => {
+                        for byte in req.as_mut_slice().iter_mut() {
+                            *byte = 0xff;
+                        }
+                    }
+                    DeviceEvent::PortRead(_, mut req) => {
+                        req.copy_from_u32(0xffffffff);
+                    }
+                    _ => (),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn dispatch_event(&mut self, event: Event) -> Result<()> {
+        let ecam_offset = match &event.kind {
+            DeviceEvent::MemRead(addr, _) | DeviceEvent::MemWrite(addr, _) => {
+                addr.as_u64()
+                    .checked_sub(PCI_ECAM_BASE)
+                    .filter(|offset| *offset < PCI_ECAM_SIZE)
+            }
+            _ => None,
+        };
+        if let Some(offset) = ecam_offset {
+            return self.on_ecam_event(offset, event);
+        }
+
+        let bar_target = match &event.kind {
+            DeviceEvent::MemRead(addr, _) | DeviceEvent::MemWrite(addr, _) => {
+                Some(self.decode_bar(false, addr.as_u64()))
+            }
+            DeviceEvent::PortRead(port, _)
+            | DeviceEvent::PortWrite(port, _)
+                if *port >= PCI_IO_START =>
+            {
+                Some(self.decode_bar(true, *port as u64))
+            }
+            _ => None,
+        };
+        if let Some(target) = bar_target {
+            return self.on_bar_event(target, event);
+        }
+
+        match event.kind {
+            DeviceEvent::PortRead(port, mut val) => match port {
+                Self::PCI_CONFIG_ADDRESS => {
+                    val.copy_from_u32(self.current_address);
+                }
+                Self::PCI_CONFIG_TYPE => {
+                    val.copy_from_u32(self.config_type as u32);
+                }
+                Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
+                    let (bdf, offset) = match self.config_target(port) {
+                        Some(target) => target,
+                        None => {
+                            val.copy_from_u32(0xffffffff);
+                            return Ok(());
+                        }
+                    };
+                    let len = val.as_slice().len();
+                    let res = self.config_read(bdf, offset, len);
+                    val.copy_from_u32(res);
+                    debug!(
+                        "pci: port=0x{:x}, bdf=0x{:x}, offset=0x{:x}, val={}",
+                        port, bdf, offset, val
+                    );
+                }
+                _ => {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid PCI port read 0x{:x}",
+                        port
+                    )))
+                }
+            },
+            DeviceEvent::PortWrite(port, val) => match port {
+                // Only dword accesses select the config address
+                Self::PCI_CONFIG_ADDRESS if val.as_slice().len() == 4 => {
+                    self.current_address = val.as_u32();
+                }
+                // Software writes to this register when probing for the
+                // configuration mechanism. The value is simply retained.
+                Self::PCI_CONFIG_TYPE => {
+                    self.config_type = val.as_u32() as u8;
+                }
+                Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
+                    if let Some((bdf, offset)) = self.config_target(port) {
+                        let len = val.as_slice().len();
+                        self.config_write(
+                            bdf,
+                            offset,
+                            val.as_u32(),
+                            len,
+                            event.responses,
+                        )?;
+                    }
+                }
+                _ => {
+                    debug!(
+                        "pci: Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
+                        port, self.current_address
+                    );
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
 }
 
 impl EmulatedDevice for PciRootComplex {
@@ -196,63 +957,36 @@ impl EmulatedDevice for PciRootComplex {
                 Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX,
             ),
             DeviceRegion::PortIo(Self::PCI_CONFIG_TYPE..=Self::PCI_CONFIG_TYPE),
+            DeviceRegion::PortIo(PCI_IO_START..=PCI_IO_END),
+            DeviceRegion::MemIo(
+                GuestPhysAddr::new(PCI_MMIO_START)
+                    ..=GuestPhysAddr::new(PCI_MMIO_END),
+            ),
+            DeviceRegion::MemIo(
+                GuestPhysAddr::new(PCI_ECAM_BASE)
+                    ..=GuestPhysAddr::new(PCI_ECAM_BASE + PCI_ECAM_SIZE - 1),
+            ),
         ]
     }
 
     fn on_event(&mut self, event: Event) -> Result<()> {
-        match event.kind {
-            DeviceEvent::PortRead(port, mut val) => {
-                match port {
-                    Self::PCI_CONFIG_ADDRESS => {
-                        // For now, always set the enable bit
-                        let addr = 0x80000000 | self.current_address;
-                        val.copy_from_u32(addr);
-                    }
-                    Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
-                        let bdf =
-                            ((self.current_address & 0xffff00) >> 8) as u16;
-                        let register = (self.current_address & 0xff >> 2) as u8;
-                        let offset = (port - Self::PCI_CONFIG_DATA) as u8;
-
-                        match self.devices.get(&bdf) {
-                            Some(device) => {
-                                let res =
-                                    device.config_space.read_register(register)
-                                        >> (offset * 8);
-                                val.copy_from_u32(res);
-                                debug!(
-                                    "pci: port=0x{:x}, register=0x{:x}, offset=0x{:x}, val={}",
-                                    port, register, offset, val
-                                );
-                            }
-                            None => {
-                                // If no device is present, just return all 0xFFs
-                                let res = 0xffffffffu32;
-                                val.copy_from_u32(res);
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PCI port read 0x{:x}",
-                            port
-                        )))
-                    }
-                }
-            }
-            DeviceEvent::PortWrite(port, val) => match port {
-                Self::PCI_CONFIG_ADDRESS => {
-                    let addr: u32 = val.try_into()?;
-                    self.current_address = addr & 0x7fffffffu32;
-                }
-                _ => {
-                    debug!(
-                            "pci: Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
-                            port, self.current_address
-                        );
-                }
-            },
-            _ => (),
+        let Event {
+            kind,
+            space,
+            responses,
+        } = event;
+        let start = responses.len();
+        self.dispatch_event(Event::new(kind, space, &mut *responses)?)?;
+        self.update_intx_lines(start, responses);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.current_address = 0;
+        self.config_type = 0;
+        self.asserted_lines.clear();
+        for device in self.devices.values_mut() {
+            device.reset()?;
         }
         Ok(())
     }
@@ -261,22 +995,15 @@ impl EmulatedDevice for PciRootComplex {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::memory::{
-        GuestAddressSpace, GuestAddressSpaceView, GuestPhysAddr,
+    use crate::virtdev::testing::{
+        define_test_view, mem_read, mem_write, port_read, port_write,
     };
     use crate::virtdev::*;
-    use alloc::boxed::Box;
-
-    fn define_test_view() -> GuestAddressSpaceView<'static> {
-        let space: &'static mut GuestAddressSpace =
-            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
-        GuestAddressSpaceView::new(GuestPhysAddr::new(0), space)
-    }
 
     fn complex_ready_for_reg_read(reg: u8) -> PciRootComplex {
         let view = define_test_view();
         let mut complex = PciRootComplex::new().unwrap();
-        let addr = ((reg << 2) as u32).to_be_bytes();
+        let addr = (0x80000000 | (reg << 2) as u32).to_be_bytes();
         let request = PortWriteRequest::try_from(&addr[..]).unwrap();
         let mut responses = ResponseEventArray::default();
         let event = Event::new(
@@ -289,6 +1016,118 @@ mod test {
         complex
     }
 
+    // A device with one of each kind of BAR and some capabilities
+    struct TestDevice {
+        config_space: PciConfigSpace,
+    }
+
+    const TEST_BDF: u16 = 0b10000;
+
+    fn test_complex() -> PciRootComplex {
+        let mut config_space = PciConfigSpace::new(0x1234, 0x5678, 0xff, 0);
+        config_space
+            .set_bar(
+                0,
+                PciBar::Memory32 {
+                    size: 0x1000,
+                    prefetchable: false,
+                },
+            )
+            .unwrap();
+        config_space.set_bar(1, PciBar::Io { size: 0x20 }).unwrap();
+        config_space
+            .set_bar(
+                2,
+                PciBar::Memory64 {
+                    size: 0x100000,
+                    prefetchable: true,
+                },
+            )
+            .unwrap();
+        config_space.set_interrupt_pin(1);
+        config_space
+            .add_capability(0x09, &[0x04, 0xaa], &[0x00, 0xff])
+            .unwrap();
+        config_space.add_capability(0x05, &[0x00; 12], &[]).unwrap();
+
+        let mut complex = PciRootComplex::new().unwrap();
+        complex
+            .add_device(
+                PciBdf::from(TEST_BDF),
+                Box::new(TestDevice { config_space }),
+            )
+            .unwrap();
+        complex
+    }
+
+    impl PciDevice for TestDevice {
+        fn config_space(&self) -> &PciConfigSpace {
+            &self.config_space
+        }
+
+        fn config_space_mut(&mut self) -> &mut PciConfigSpace {
+            &mut self.config_space
+        }
+
+        fn on_bar_event(
+            &mut self,
+            bar: usize,
+            offset: u64,
+            event: Event,
+        ) -> Result<()> {
+            // Reads return the BAR index and offset that was accessed
+            if let DeviceEvent::PortRead(_, mut req) = event.kind {
+                req.copy_from_u32((bar as u32) << 4 | offset as u32);
+            }
+            Ok(())
+        }
+
+        fn on_host_event(
+            &mut self,
+            kind: &DeviceEvent,
+            _space: &GuestAddressSpaceView,
+            responses: &mut ResponseEventArray,
+        ) -> Result<()> {
+            // Console input is reflected as a GSI
+            if let DeviceEvent::HostUartReceived(key) = kind {
+                responses.push(DeviceEventResponse::GSI(*key as u32));
+            }
+            Ok(())
+        }
+    }
+
+    fn config_write(
+        complex: &mut PciRootComplex,
+        bdf: u16,
+        offset: u16,
+        value: u32,
+        len: usize,
+    ) -> ResponseEventArray {
+        let addr = 0x80000000 | (bdf as u32) << 8 | (offset & 0xfc) as u32;
+        port_write(complex, PciRootComplex::PCI_CONFIG_ADDRESS, addr, 4);
+        port_write(
+            complex,
+            PciRootComplex::PCI_CONFIG_DATA + (offset & 0b11),
+            value,
+            len,
+        )
+    }
+
+    fn config_read(
+        complex: &mut PciRootComplex,
+        bdf: u16,
+        offset: u16,
+        len: usize,
+    ) -> u32 {
+        let addr = 0x80000000 | (bdf as u32) << 8 | (offset & 0xfc) as u32;
+        port_write(complex, PciRootComplex::PCI_CONFIG_ADDRESS, addr, 4);
+        port_read(
+            complex,
+            PciRootComplex::PCI_CONFIG_DATA + (offset & 0b11),
+            len,
+        )
+    }
+
     #[test]
     fn test_full_register_read() {
         let view = define_test_view();
@@ -386,4 +1225,244 @@ mod test {
         complex.on_event(event).unwrap();
         assert_eq!(u8::from_be_bytes(buff), 0x29);
     }
+
+    #[test]
+    fn test_register_index() {
+        let mut complex = test_complex();
+
+        // The class code is not in the first dword of the header
+        assert_eq!(config_read(&mut complex, 0x0000, 0x0a, 2), 0x0600);
+        assert_eq!(config_read(&mut complex, 0b1000, 0x0a, 2), 0x0601);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x3d, 1), 1);
+
+        // Missing devices read as all ones
+        assert_eq!(config_read(&mut complex, 0xff00, 0x00, 4), 0xffffffff);
+    }
+
+    #[test]
+    fn test_config_address_enable() {
+        let mut complex = test_complex();
+        let line = config_read(&mut complex, TEST_BDF, 0x3c, 1);
+
+        // The address is stored as written, including the enable bit
+        let addr = (TEST_BDF as u32) << 8 | 0x3c;
+        port_write(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS, addr, 4);
+        assert_eq!(
+            port_read(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS, 4),
+            addr
+        );
+
+        // Data accesses are ignored while the enable bit is clear
+        assert_eq!(
+            port_read(&mut complex, PciRootComplex::PCI_CONFIG_DATA, 4),
+            0xffffffff
+        );
+        port_write(&mut complex, PciRootComplex::PCI_CONFIG_DATA, 0x0a, 1);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x3c, 1), line);
+        assert_eq!(
+            port_read(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS, 4),
+            0x80000000 | addr
+        );
+    }
+
+    #[test]
+    fn test_bar_sizing() {
+        let mut complex = test_complex();
+
+        for bar in 0..PCI_BAR_COUNT as u16 {
+            config_write(&mut complex, TEST_BDF, 0x10 + bar * 4, !0, 4);
+        }
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x10, 4), 0xfffff000);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x14, 4), 0xffffffe1);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x18, 4), 0xfff0000c);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x1c, 4), 0xffffffff);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x20, 4), 0);
+
+        config_write(&mut complex, TEST_BDF, 0x18, 0xe0000000, 4);
+        config_write(&mut complex, TEST_BDF, 0x1c, 0x1, 4);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x18, 4), 0xe000000c);
+        let device = complex.devices.get(&TEST_BDF).unwrap();
+        assert_eq!(device.config_space().bar_address(2), Some(0x1e0000000));
+    }
+
+    #[test]
+    fn test_command_and_status() {
+        let mut complex = test_complex();
+
+        // Only the defined command bits are writable
+        config_write(&mut complex, TEST_BDF, 0x04, 0xffff, 2);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x04, 2), 0x0547);
+
+        // The capabilities bit can't be cleared, but the error bits can
+        let device = complex.devices.get_mut(&TEST_BDF).unwrap();
+        device.config_space_mut().set_u16(offsets::STATUS, 0x8010);
+        config_write(&mut complex, TEST_BDF, 0x06, 0xffff, 2);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x06, 2), 0x0010);
+
+        // The interrupt line is writable, but the pin is not
+        config_write(&mut complex, TEST_BDF, 0x3c, 0x0b0b, 2);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x3c, 2), 0x010b);
+    }
+
+    #[test]
+    fn test_capability_list() {
+        let mut complex = test_complex();
+        let device = complex.devices.get(&TEST_BDF).unwrap();
+        let capabilities: Vec<_> =
+            device.config_space().capabilities().collect();
+        assert_eq!(capabilities, vec![(0x09, 0x40), (0x05, 0x44)]);
+        assert_eq!(device.config_space().find_capability(0x05), Some(0x44));
+        assert_eq!(device.config_space().find_capability(0x11), None);
+
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x34, 1), 0x40);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x40, 4), 0xaa044409);
+
+        // Only the masked bytes of the capability are writable
+        config_write(&mut complex, TEST_BDF, 0x40, 0x55555555, 4);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x40, 4), 0x55044409);
+    }
+
+    // Set the interrupt status of a function, returning the resulting
+    // changes of the INTx lines
+    fn set_intx(
+        complex: &mut PciRootComplex,
+        bdf: u16,
+        pending: bool,
+    ) -> ResponseEventArray {
+        let mut responses = ResponseEventArray::default();
+        let device = complex.devices.get_mut(&bdf).unwrap();
+        device
+            .config_space_mut()
+            .set_interrupt_status(pending, &mut responses);
+        complex.update_intx_lines(0, &mut responses);
+        responses
+    }
+
+    #[test]
+    fn test_intx() {
+        let mut complex = test_complex();
+        config_write(&mut complex, TEST_BDF, 0x3c, 10, 1);
+
+        let responses = set_intx(&mut complex, TEST_BDF, true);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(10, true)]
+        ));
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x06, 1), 0x18);
+
+        // Disabling INTx deasserts the line
+        let responses = config_write(&mut complex, TEST_BDF, 0x04, 0x400, 2);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(10, false)]
+        ));
+    }
+
+    #[test]
+    fn test_shared_intx_line() {
+        let mut complex = test_complex();
+        let mut config_space = PciConfigSpace::new(0x1234, 0x5678, 0xff, 0);
+        config_space.set_interrupt_pin(1);
+        let other = TEST_BDF + 0b1000;
+        complex
+            .add_device(
+                PciBdf::from(other),
+                Box::new(TestDevice { config_space }),
+            )
+            .unwrap();
+        config_write(&mut complex, TEST_BDF, 0x3c, 10, 1);
+        config_write(&mut complex, other, 0x3c, 10, 1);
+
+        assert!(matches!(
+            set_intx(&mut complex, TEST_BDF, true).as_slice(),
+            [DeviceEventResponse::GSILevel(10, true)]
+        ));
+        assert!(set_intx(&mut complex, other, true).is_empty());
+
+        // The line stays asserted until every function deasserts it
+        assert!(set_intx(&mut complex, TEST_BDF, false).is_empty());
+        assert!(matches!(
+            set_intx(&mut complex, other, false).as_slice(),
+            [DeviceEventResponse::GSILevel(10, false)]
+        ));
+    }
+
+    #[test]
+    fn test_bar_access() {
+        let mut complex = test_complex();
+        config_write(&mut complex, TEST_BDF, 0x14, 0xc020, 4);
+
+        // The BAR is not decoded until I/O is enabled
+        assert_eq!(port_read(&mut complex, 0xc024, 1), 0xff);
+
+        config_write(&mut complex, TEST_BDF, 0x04, 0x1, 2);
+        assert_eq!(port_read(&mut complex, 0xc024, 1), 0x14);
+        let device = complex.devices.get(&TEST_BDF).unwrap();
+        let device = device.config_space();
+        assert!(device.io_enabled() && !device.memory_enabled());
+
+        // Reset disables decoding again
+        complex.reset().unwrap();
+        assert_eq!(port_read(&mut complex, 0xc024, 1), 0xff);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x14, 4), 0x1);
+    }
+
+    fn ecam_read(complex: &mut PciRootComplex, bdf: u16, offset: u16) -> u32 {
+        let addr = PCI_ECAM_BASE + ((bdf as u64) << 12) + offset as u64;
+        let (value, _) = mem_read(define_test_view(), addr, 4, |event| {
+            complex.on_event(event)
+        });
+        value as u32
+    }
+
+    fn ecam_write(
+        complex: &mut PciRootComplex,
+        bdf: u16,
+        offset: u16,
+        value: u64,
+        len: usize,
+    ) {
+        let addr = PCI_ECAM_BASE + ((bdf as u64) << 12) + offset as u64;
+        mem_write(define_test_view(), addr, value, len, |event| {
+            complex.on_event(event)
+        });
+    }
+
+    #[test]
+    fn test_host_event() {
+        let mut complex = test_complex();
+        let mut responses = ResponseEventArray::default();
+        complex
+            .on_host_event(
+                &DeviceEvent::HostUartReceived(7),
+                &define_test_view(),
+                &mut responses,
+            )
+            .unwrap();
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSI(7)]
+        ));
+    }
+
+    #[test]
+    fn test_ecam_access() {
+        let mut complex = test_complex();
+        assert_eq!(ecam_read(&mut complex, 0x0000, 0x00), 0x29c08086);
+        assert_eq!(ecam_read(&mut complex, TEST_BDF, 0x100), 0);
+        assert_eq!(ecam_read(&mut complex, 0x00ff, 0x00), 0xffffffff);
+
+        // The ECAM region and the legacy ports share the config space
+        ecam_write(&mut complex, TEST_BDF, 0x10, 0xc0001000, 4);
+        assert_eq!(config_read(&mut complex, TEST_BDF, 0x10, 4), 0xc0001000);
+        config_write(&mut complex, TEST_BDF, 0x3c, 0x05, 1);
+        assert_eq!(ecam_read(&mut complex, TEST_BDF, 0x3c) & 0xff, 0x05);
+        ecam_write(&mut complex, TEST_BDF, 0x3c, 0x0a, 1);
+        assert_eq!(ecam_read(&mut complex, TEST_BDF, 0x3c) & 0xff, 0x0a);
+
+        // Wide accesses are split into dwords, starting with the low dword
+        ecam_write(&mut complex, TEST_BDF, 0x18, 0x12345678_fff00000, 8);
+        assert_eq!(ecam_read(&mut complex, TEST_BDF, 0x18), 0xfff0000c);
+        assert_eq!(ecam_read(&mut complex, TEST_BDF, 0x1c), 0x12345678);
+    }
 }
@@ -1,14 +1,292 @@
-use crate::error::Result;
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::error::{Error, Result};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, PortReadRequest, PortWriteRequest,
+};
 use alloc::vec::Vec;
 use core::convert::TryInto;
 
+const ICW1_ICW4_NEEDED: u8 = 1 << 0;
+const ICW1_SINGLE_MODE: u8 = 1 << 1;
+const ICW1_LEVEL_TRIGGERED: u8 = 1 << 3;
+const ICW1_INIT: u8 = 1 << 4;
+
+const ICW4_8086_MODE: u8 = 1 << 0;
+const ICW4_AUTO_EOI: u8 = 1 << 1;
+const ICW4_SPECIAL_FULLY_NESTED: u8 = 1 << 4;
+
+const OCW3_SELECT: u8 = 1 << 3;
+const OCW3_READ_ISR: u8 = 1 << 0;
+const OCW3_READ_REGISTER: u8 = 1 << 1;
+const OCW3_POLL: u8 = 1 << 2;
+const OCW3_SPECIAL_MASK: u8 = 1 << 5;
+const OCW3_SET_SPECIAL_MASK: u8 = 1 << 6;
+
+const CASCADE_IRQ: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+impl Default for InitState {
+    fn default() -> Self {
+        InitState::Ready
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct PicState {
+    irr: u8,
+    isr: u8,
     imr: u8,
+
+    // The current level of each input line (used for edge detection)
+    line_level: u8,
+    elcr: u8,
+    elcr_mask: u8,
+
+    vector_base: u8,
+    init_state: InitState,
+    initialized: bool,
+    icw4_needed: bool,
+    single_mode: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_fully_nested: bool,
+    special_mask: bool,
+    poll: bool,
+    read_isr: bool,
+
+    // The IRQ with the lowest priority is 'priority_add - 1'
+    priority_add: u8,
+
+    is_master: bool,
 }
 
-#[derive(Default, Debug)]
+impl PicState {
+    fn new(is_master: bool, elcr_mask: u8) -> Self {
+        Self {
+            is_master,
+            elcr_mask,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the priority (0 is the highest) of the highest priority
+    /// IRQ in the given mask
+    fn priority(&self, mask: u8) -> Option<u8> {
+        (0..8).find(|p| mask & (1 << ((p + self.priority_add) & 7)) != 0)
+    }
+
+    /// Returns the IRQ that should be raised to the CPU (if any)
+    fn pending_irq(&self) -> Option<u8> {
+        // The output of the PIC is never raised before it is initialized,
+        // as there is no meaningful vector to deliver.
+        if !self.initialized {
+            return None;
+        }
+
+        let priority = self.priority(self.irr & !self.imr)?;
+
+        // In special mask mode, masked interrupts do not block lower
+        // priority interrupts. In special fully nested mode, the slave
+        // may interrupt while another of its IRQs is in service.
+        let mut in_service = self.isr;
+        if self.special_mask {
+            in_service &= !self.imr;
+        }
+        if self.special_fully_nested && self.is_master {
+            in_service &= !(1 << CASCADE_IRQ);
+        }
+
+        match self.priority(in_service) {
+            Some(current) if current <= priority => None,
+            _ => Some((priority + self.priority_add) & 7),
+        }
+    }
+
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let mask = 1 << irq;
+        if self.elcr & mask != 0 {
+            if level {
+                self.irr |= mask;
+            } else {
+                self.irr &= !mask;
+            }
+        } else if level && self.line_level & mask == 0 {
+            self.irr |= mask;
+        }
+
+        if level {
+            self.line_level |= mask;
+        } else {
+            self.line_level &= !mask;
+        }
+    }
+
+    fn is_level_triggered(&self, irq: u8) -> bool {
+        self.elcr & (1 << irq) != 0
+    }
+
+    fn acknowledge(&mut self, irq: u8) {
+        let mask = 1 << irq;
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.priority_add = (irq + 1) & 7;
+            }
+        } else {
+            self.isr |= mask;
+        }
+
+        // Interrupt sources only signal events (they never explicitly
+        // deassert a line), so a level triggered input is considered
+        // deasserted once it has been acknowledged.
+        if self.is_level_triggered(irq) {
+            self.line_level &= !mask;
+        }
+        self.irr &= !mask;
+    }
+
+    fn poll_read(&mut self) -> u8 {
+        self.poll = false;
+        match self.pending_irq() {
+            Some(irq) => {
+                self.acknowledge(irq);
+                0x80 | irq
+            }
+            None => 0,
+        }
+    }
+
+    fn write_icw1(&mut self, val: u8) {
+        if val & ICW1_LEVEL_TRIGGERED != 0 {
+            warn!("Level triggered PIC mode is not supported (use the ELCR)");
+        }
+
+        self.irr = 0;
+        self.isr = 0;
+        self.imr = 0;
+        self.line_level = 0;
+        self.priority_add = 0;
+        self.special_mask = false;
+        self.special_fully_nested = false;
+        self.auto_eoi = false;
+        self.rotate_on_auto_eoi = false;
+        self.poll = false;
+        self.read_isr = false;
+        self.icw4_needed = val & ICW1_ICW4_NEEDED != 0;
+        self.single_mode = val & ICW1_SINGLE_MODE != 0;
+        self.initialized = false;
+        self.init_state = InitState::Icw2;
+    }
+
+    fn write_ocw2(&mut self, val: u8) {
+        let level = val & 0b111;
+        match val >> 5 {
+            0b000 => self.rotate_on_auto_eoi = false,
+            0b100 => self.rotate_on_auto_eoi = true,
+            // Non-specific EOI (with or without rotation)
+            cmd @ 0b001 | cmd @ 0b101 => {
+                if let Some(priority) = self.priority(self.isr) {
+                    let irq = (priority + self.priority_add) & 7;
+                    self.isr &= !(1 << irq);
+                    if cmd == 0b101 {
+                        self.priority_add = (irq + 1) & 7;
+                    }
+                }
+            }
+            // Specific EOI (with or without rotation)
+            cmd @ 0b011 | cmd @ 0b111 => {
+                self.isr &= !(1 << level);
+                if cmd == 0b111 {
+                    self.priority_add = (level + 1) & 7;
+                }
+            }
+            // Set priority
+            0b110 => self.priority_add = (level + 1) & 7,
+            _ => (),
+        }
+    }
+
+    fn write_ocw3(&mut self, val: u8) {
+        if val & OCW3_POLL != 0 {
+            self.poll = true;
+        }
+        if val & OCW3_READ_REGISTER != 0 {
+            self.read_isr = val & OCW3_READ_ISR != 0;
+        }
+        if val & OCW3_SET_SPECIAL_MASK != 0 {
+            self.special_mask = val & OCW3_SPECIAL_MASK != 0;
+        }
+    }
+
+    fn write_command(&mut self, val: u8) {
+        if val & ICW1_INIT != 0 {
+            self.write_icw1(val);
+        } else if val & OCW3_SELECT != 0 {
+            self.write_ocw3(val);
+        } else {
+            self.write_ocw2(val);
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        match self.init_state {
+            InitState::Ready => self.imr = val,
+            InitState::Icw2 => {
+                self.vector_base = val & 0xf8;
+                self.init_state = if !self.single_mode {
+                    InitState::Icw3
+                } else if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw3 => {
+                // The cascade wiring is fixed on the PC, so ICW3 is ignored
+                self.init_state = if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw4 => {
+                if val & ICW4_8086_MODE == 0 {
+                    warn!("MCS-80/85 PIC mode is not supported");
+                }
+                self.auto_eoi = val & ICW4_AUTO_EOI != 0;
+                self.special_fully_nested =
+                    val & ICW4_SPECIAL_FULLY_NESTED != 0;
+                self.init_state = InitState::Ready;
+            }
+        }
+
+        if self.init_state == InitState::Ready {
+            self.initialized = true;
+        }
+    }
+
+    fn read_command(&mut self) -> u8 {
+        if self.poll {
+            self.poll_read()
+        } else if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+}
+
+/// An emulated pair of cascaded 8259A programmable interrupt controllers
+///
+/// The output of the master PIC is connected to the BSP of the VM. The
+/// output of the slave PIC is connected to IRQ2 of the master.
+#[derive(Debug)]
 pub struct Pic8259 {
     master_state: PicState,
     slave_state: PicState,
@@ -22,8 +300,131 @@ impl Pic8259 {
     const PIC_ECLR_COMMAND: Port = 0x4d0;
     const PIC_ECLR_DATA: Port = Self::PIC_ECLR_COMMAND + 1;
 
+    // IRQ0, 1, 2, 8 and 13 are always edge triggered
+    const MASTER_ELCR_MASK: u8 = 0xf8;
+    const SLAVE_ELCR_MASK: u8 = 0xde;
+
     pub fn new() -> Result<Self> {
-        Ok(Pic8259::default())
+        Ok(Pic8259 {
+            master_state: PicState::new(true, Self::MASTER_ELCR_MASK),
+            slave_state: PicState::new(false, Self::SLAVE_ELCR_MASK),
+        })
+    }
+
+    /// Propagate the output of the slave PIC to the master
+    fn update_cascade(&mut self) {
+        let level = self.slave_state.pending_irq().is_some();
+        self.master_state.set_irq(CASCADE_IRQ, level);
+    }
+
+    /// Set the level of one of the 16 legacy IRQ lines
+    pub fn set_irq(&mut self, irq: u8, level: bool) -> Result<()> {
+        match irq {
+            0..=7 => self.master_state.set_irq(irq, level),
+            8..=15 => self.slave_state.set_irq(irq - 8, level),
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid PIC irq: {}",
+                    irq
+                )))
+            }
+        }
+        self.update_cascade();
+        Ok(())
+    }
+
+    /// Signal an interrupt on the given legacy IRQ line
+    ///
+    /// Edge triggered lines are asserted and immediately deasserted, while
+    /// level triggered lines remain asserted until the interrupt is
+    /// acknowledged.
+    pub fn raise_irq(&mut self, irq: u8) -> Result<()> {
+        self.set_irq(irq, true)?;
+
+        let level_triggered = match irq {
+            0..=7 => self.master_state.is_level_triggered(irq),
+            _ => self.slave_state.is_level_triggered(irq - 8),
+        };
+        if !level_triggered {
+            self.set_irq(irq, false)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the given vector was supplied by the PIC (and so
+    /// is acknowledged with an EOI to the PIC, not the local apic)
+    ///
+    /// This is the case if the corresponding IRQ is in service, or the
+    /// PIC is in automatic EOI mode (where the IRQ is never in service).
+    pub fn supplied_vector(&self, vector: u8) -> bool {
+        [&self.master_state, &self.slave_state].iter().any(|pic| {
+            pic.initialized
+                && vector & !0b111 == pic.vector_base
+                && (pic.auto_eoi || pic.isr & (1 << (vector & 0b111)) != 0)
+        })
+    }
+
+    /// Perform an interrupt acknowledge cycle, returning the vector to be
+    /// delivered to the CPU (if the PIC output is asserted).
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master_state.pending_irq()?;
+
+        let vector = if irq == CASCADE_IRQ && !self.master_state.single_mode {
+            self.master_state.acknowledge(irq);
+
+            // If the slave request disappeared, the slave supplies its
+            // spurious vector (IRQ7).
+            match self.slave_state.pending_irq() {
+                Some(slave_irq) => {
+                    self.slave_state.acknowledge(slave_irq);
+                    self.slave_state.vector_base + slave_irq
+                }
+                None => self.slave_state.vector_base + 7,
+            }
+        } else {
+            self.master_state.acknowledge(irq);
+            self.master_state.vector_base + irq
+        };
+
+        self.update_cascade();
+        Some(vector)
+    }
+
+    fn on_port_read(&mut self, port: Port, mut val: PortReadRequest) {
+        let data = match port {
+            Self::PIC_MASTER_COMMAND => self.master_state.read_command(),
+            Self::PIC_MASTER_DATA => self.master_state.imr,
+            Self::PIC_SLAVE_COMMAND => self.slave_state.read_command(),
+            Self::PIC_SLAVE_DATA => self.slave_state.imr,
+            Self::PIC_ECLR_COMMAND => self.master_state.elcr,
+            Self::PIC_ECLR_DATA => self.slave_state.elcr,
+            _ => unreachable!(),
+        };
+        val.copy_from_u32(data as u32);
+        self.update_cascade();
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+    ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        match port {
+            Self::PIC_MASTER_COMMAND => self.master_state.write_command(val),
+            Self::PIC_MASTER_DATA => self.master_state.write_data(val),
+            Self::PIC_SLAVE_COMMAND => self.slave_state.write_command(val),
+            Self::PIC_SLAVE_DATA => self.slave_state.write_data(val),
+            Self::PIC_ECLR_COMMAND => {
+                self.master_state.elcr = val & self.master_state.elcr_mask;
+            }
+            Self::PIC_ECLR_DATA => {
+                self.slave_state.elcr = val & self.slave_state.elcr_mask;
+            }
+            _ => unreachable!(),
+        }
+        self.update_cascade();
+        Ok(())
     }
 }
 
@@ -42,27 +443,236 @@ impl EmulatedDevice for Pic8259 {
 
     fn on_event(&mut self, event: Event) -> Result<()> {
         match event.kind {
-            DeviceEvent::PortRead(port, mut val) => {
-                let data = match port {
-                    Self::PIC_MASTER_DATA => self.master_state.imr,
-                    Self::PIC_SLAVE_DATA => self.master_state.imr,
-                    _ => {
-                        return Ok(());
-                    }
-                };
-                val.copy_from_u32(data as u32);
-            }
-            DeviceEvent::PortWrite(port, val) => match port {
-                Self::PIC_MASTER_DATA => {
-                    self.master_state.imr = val.try_into()?;
-                }
-                Self::PIC_SLAVE_DATA => {
-                    self.master_state.imr = val.try_into()?;
+            DeviceEvent::PortRead(port, val) => self.on_port_read(port, val),
+            DeviceEvent::PortWrite(port, val) => {
+                self.on_port_write(port, val)?;
+
+                // An EOI or change to the IMR may allow a pending interrupt
+                // to be delivered.
+                if let Some(vector) = self.acknowledge() {
+                    event.responses.push(DeviceEventResponse::ExtInt(vector));
                 }
-                _ => (),
-            },
+            }
             _ => (),
         }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{port_read, port_write};
+
+    fn write_port(pic: &mut Pic8259, port: Port, value: u8) -> Option<u8> {
+        let responses = port_write(pic, port, value as u32, 1);
+        responses.iter().find_map(|resp| match resp {
+            DeviceEventResponse::ExtInt(vector) => Some(*vector),
+            _ => None,
+        })
+    }
+
+    fn read_port(pic: &mut Pic8259, port: Port) -> u8 {
+        port_read(pic, port, 1) as u8
+    }
+
+    fn initialized_pic() -> Pic8259 {
+        let mut pic = Pic8259::new().unwrap();
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x11);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x20);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x04);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x01);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_COMMAND, 0x11);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_DATA, 0x28);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_DATA, 0x02);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_DATA, 0x01);
+        pic
+    }
+
+    fn non_specific_eoi(pic: &mut Pic8259, port: Port) -> Option<u8> {
+        write_port(pic, port, 0x20)
+    }
+
+    #[test]
+    fn test_uninitialized_pic_is_silent() {
+        let mut pic = Pic8259::new().unwrap();
+        pic.raise_irq(0).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn test_init_sequence_vectors() {
+        let mut pic = initialized_pic();
+        pic.raise_irq(1).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x21));
+
+        pic.raise_irq(12).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+        assert_eq!(
+            non_specific_eoi(&mut pic, Pic8259::PIC_MASTER_COMMAND),
+            Some(0x2c)
+        );
+    }
+
+    #[test]
+    fn test_imr() {
+        let mut pic = initialized_pic();
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0xfe);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_DATA, 0xef);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_DATA), 0xfe);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_SLAVE_DATA), 0xef);
+
+        pic.raise_irq(1).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+
+        // Unmasking the IRQ delivers the pending interrupt
+        assert_eq!(
+            write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0xfc),
+            Some(0x21)
+        );
+    }
+
+    #[test]
+    fn test_priority_and_isr() {
+        let mut pic = initialized_pic();
+        pic.raise_irq(4).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x24));
+
+        // A lower priority interrupt is blocked by the one in service
+        pic.raise_irq(6).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+
+        // But a higher priority interrupt is not
+        pic.raise_irq(3).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x23));
+
+        // Read the ISR with OCW3
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0b);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x18);
+
+        // Read the IRR with OCW3
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0a);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x40);
+
+        // Specific EOI of IRQ4 leaves IRQ3 in service
+        assert_eq!(
+            write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x64),
+            None
+        );
+
+        // Non-specific EOI clears IRQ3 and allows IRQ6
+        assert_eq!(
+            non_specific_eoi(&mut pic, Pic8259::PIC_MASTER_COMMAND),
+            Some(0x26)
+        );
+    }
+
+    #[test]
+    fn test_rotating_eoi() {
+        let mut pic = initialized_pic();
+        pic.raise_irq(1).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x21));
+
+        // Rotate on non-specific EOI makes IRQ1 the lowest priority
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0xa0);
+
+        pic.raise_irq(1).unwrap();
+        pic.raise_irq(5).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x25));
+
+        // Rotate on specific EOI of IRQ5 allows IRQ1 to be delivered
+        assert_eq!(
+            write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0xe5),
+            Some(0x21)
+        );
+    }
+
+    #[test]
+    fn test_cascade() {
+        let mut pic = initialized_pic();
+        pic.raise_irq(8).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x28));
+
+        // Both PICs have the interrupt in service
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0b);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_COMMAND, 0x0b);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x04);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_SLAVE_COMMAND), 0x01);
+
+        // Masking the cascade line blocks all slave interrupts
+        non_specific_eoi(&mut pic, Pic8259::PIC_SLAVE_COMMAND);
+        non_specific_eoi(&mut pic, Pic8259::PIC_MASTER_COMMAND);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x04);
+        pic.raise_irq(9).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+        assert_eq!(
+            write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x00),
+            Some(0x29)
+        );
+    }
+
+    #[test]
+    fn test_auto_eoi() {
+        let mut pic = Pic8259::new().unwrap();
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x13);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x08);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0x03);
+
+        pic.raise_irq(0).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x08));
+        pic.raise_irq(0).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x08));
+    }
+
+    #[test]
+    fn test_supplied_vector() {
+        let mut pic = initialized_pic();
+        pic.raise_irq(4).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x24));
+        assert!(pic.supplied_vector(0x24));
+        assert!(!pic.supplied_vector(0x25));
+        assert!(!pic.supplied_vector(0x44));
+
+        non_specific_eoi(&mut pic, Pic8259::PIC_MASTER_COMMAND);
+        assert!(!pic.supplied_vector(0x24));
+    }
+
+    #[test]
+    fn test_poll() {
+        let mut pic = initialized_pic();
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0c);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x00);
+
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0c);
+        pic.raise_irq(5).unwrap();
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x85);
+
+        // The poll acknowledged the interrupt
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, 0x0b);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_MASTER_COMMAND), 0x20);
+    }
+
+    #[test]
+    fn test_elcr() {
+        let mut pic = initialized_pic();
+        write_port(&mut pic, Pic8259::PIC_ECLR_COMMAND, 0xff);
+        write_port(&mut pic, Pic8259::PIC_ECLR_DATA, 0xff);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_ECLR_COMMAND), 0xf8);
+        assert_eq!(read_port(&mut pic, Pic8259::PIC_ECLR_DATA), 0xde);
+
+        // A level triggered line tracks the level of the input
+        pic.set_irq(5, true).unwrap();
+        pic.set_irq(5, false).unwrap();
+        assert_eq!(pic.acknowledge(), None);
+
+        // Edge triggered lines latch the request
+        pic.set_irq(0, true).unwrap();
+        pic.set_irq(0, false).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x20));
+    }
 }
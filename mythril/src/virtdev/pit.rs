@@ -1,4 +1,4 @@
-use crate::error::{Error, Result};
+use crate::error::Result;
 use crate::interrupt;
 use crate::physdev::pit::*;
 use crate::time;
@@ -9,58 +9,461 @@ use crate::virtdev::{
 
 use alloc::vec::Vec;
 use core::convert::TryFrom;
+use core::time::Duration;
 
-#[derive(Debug)]
-enum OperatingModeState {
-    Mode0 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
-    Mode2 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
+// System control port B (0x61) bits
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_WRITABLE: u8 = 0x0f;
+const PORT_B_REFRESH: u8 = 1 << 4;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+// The DRAM refresh bit of port B toggles roughly every 15us
+const REFRESH_TOGGLE_TICKS: u64 = 18;
+
+// Read-back command bits (note that these are active low)
+const READBACK_NO_COUNT: u8 = 1 << 5;
+const READBACK_NO_STATUS: u8 = 1 << 4;
+
+fn to_bcd(value: u64) -> u16 {
+    let mut bcd = 0;
+    for digit in 0..4 {
+        bcd |= (((value / 10u64.pow(digit)) % 10) as u16) << (digit * 4);
+    }
+    bcd
 }
 
-#[derive(Debug)]
-enum AccessModeState {
-    LatchCount,
-    LoByte,
-    HiByte,
-    Word { lo_byte: Option<u8> },
+fn from_bcd(value: u16) -> u64 {
+    let mut binary = 0;
+    for digit in (0..4).rev() {
+        binary = binary * 10 + ((value >> (digit * 4)) & 0xf) as u64;
+    }
+    binary
 }
 
 #[derive(Debug)]
 struct ChannelState {
-    mode: OperatingModeState,
-    access: AccessModeState,
+    mode: OperatingMode,
+    access: AccessMode,
+    bcd: bool,
+
+    // The count register (a written count of 0 is the maximum count)
+    reload: Option<u64>,
+
+    // The low byte of a count being written in word access mode
+    write_lo: Option<u8>,
+
+    // Whether the next read in word access mode returns the high byte
+    read_hi: bool,
+
+    latched_count: Option<u16>,
+    latched_status: Option<u8>,
+
+    // Set when a count has been written but not loaded into the counter
+    null_count: bool,
+
+    gate: bool,
+
+    // The PIT tick when the counter started counting (None if stopped)
+    started: Option<u64>,
+
+    // The ticks counted before 'started' (modes 0 and 4 may be paused
+    // by the gate)
+    counted: u64,
+
+    // A count written in modes 2 or 3 while counting, which is loaded at
+    // the end of the current period (the count and the PIT tick when the
+    // period ends)
+    pending_reload: Option<(u64, u64)>,
+
+    timer: Option<time::TimerId>,
 }
 
-impl Default for ChannelState {
-    fn default() -> Self {
+impl ChannelState {
+    fn new(gate: bool) -> Self {
         Self {
-            mode: OperatingModeState::Mode0 {
-                start_counter: None,
-                timer: None,
-                start_time: None,
-            },
-            access: AccessModeState::LoByte,
+            mode: OperatingMode::Mode0,
+            access: AccessMode::Word,
+            bcd: false,
+            reload: None,
+            write_lo: None,
+            read_hi: false,
+            latched_count: None,
+            latched_status: None,
+            null_count: true,
+            gate,
+            started: None,
+            counted: 0,
+            pending_reload: None,
+            timer: None,
+        }
+    }
+
+    fn modulus(&self) -> u64 {
+        if self.bcd {
+            10000
+        } else {
+            0x10000
+        }
+    }
+
+    fn ticks_counted(&self, now: u64) -> u64 {
+        self.counted + self.started.map_or(0, |start| now.saturating_sub(start))
+    }
+
+    // Load a count written in modes 2 or 3 if the period it was written
+    // in has ended
+    fn update(&mut self, now: u64) {
+        if let Some((reload, end)) = self.pending_reload {
+            if now >= end {
+                self.reload = Some(reload);
+                self.null_count = false;
+                self.counted = 0;
+                self.started = Some(end);
+                self.pending_reload = None;
+            }
+        }
+    }
+
+    // Whether the counter has been loaded (and possibly paused since)
+    fn is_loaded(&self) -> bool {
+        self.started.is_some() || self.counted > 0
+    }
+
+    fn out(&self, now: u64) -> bool {
+        let reload = match self.reload {
+            Some(reload) => reload,
+            None => {
+                // Writing the control word sets OUT low only in mode 0
+                return !matches!(self.mode, OperatingMode::Mode0);
+            }
+        };
+        let counted = self.ticks_counted(now);
+
+        match self.mode {
+            OperatingMode::Mode0 => self.is_loaded() && counted >= reload,
+            OperatingMode::Mode1 => !self.is_loaded() || counted >= reload,
+            OperatingMode::Mode2 => {
+                // OUT goes low for the last tick of each period
+                !self.gate
+                    || !self.is_loaded()
+                    || counted % reload != reload - 1
+            }
+            OperatingMode::Mode3 => {
+                // OUT is high for the first half of each period (rounded up)
+                !self.gate
+                    || !self.is_loaded()
+                    || counted % reload < (reload + 1) / 2
+            }
+            OperatingMode::Mode4 | OperatingMode::Mode5 => {
+                // OUT goes low for one tick when the count expires
+                !self.is_loaded() || counted != reload
+            }
+        }
+    }
+
+    fn count(&self, now: u64) -> u16 {
+        let reload = match self.reload {
+            Some(reload) => reload,
+            None => return 0,
+        };
+        let modulus = self.modulus();
+
+        let count = if !self.is_loaded() {
+            reload
+        } else {
+            let counted = self.ticks_counted(now);
+            match self.mode {
+                OperatingMode::Mode2 => reload - counted % reload,
+                OperatingMode::Mode3 => {
+                    // The counter decrements by two, reloading at the
+                    // start of each half period
+                    let phase = counted % reload;
+                    let high = (reload + 1) / 2;
+                    let remaining = if phase < high {
+                        high - phase
+                    } else {
+                        reload - phase
+                    };
+                    (remaining * 2).min(reload)
+                }
+                // The other modes count down and wrap around
+                _ => reload + modulus - counted % modulus,
+            }
+        };
+
+        if self.bcd {
+            to_bcd(count % modulus)
+        } else {
+            (count % modulus) as u16
+        }
+    }
+
+    fn status(&self, now: u64) -> u8 {
+        ((self.out(now) as u8) << 7)
+            | ((self.null_count as u8) << 6)
+            | ((self.access as u8) << 4)
+            | ((self.mode as u8) << 1)
+            | self.bcd as u8
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        // Subsequent latch commands are ignored until the count is read
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.count(now));
+            self.read_hi = false;
+        }
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn write_control(
+        &mut self,
+        access: AccessMode,
+        mode: OperatingMode,
+        bcd: bool,
+    ) {
+        self.mode = mode;
+        self.access = access;
+        self.bcd = bcd;
+        self.reload = None;
+        self.write_lo = None;
+        self.read_hi = false;
+        self.latched_count = None;
+        self.latched_status = None;
+        self.null_count = true;
+        self.started = None;
+        self.counted = 0;
+        self.pending_reload = None;
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+
+        let count = match self.latched_count {
+            Some(count) => count,
+            None => self.count(now),
+        };
+        let (value, done) = match self.access {
+            AccessMode::LoByte => (count as u8, true),
+            AccessMode::HiByte => ((count >> 8) as u8, true),
+            AccessMode::Word | AccessMode::LatchCount => {
+                self.read_hi = !self.read_hi;
+                if self.read_hi {
+                    (count as u8, false)
+                } else {
+                    ((count >> 8) as u8, true)
+                }
+            }
+        };
+
+        if done {
+            self.latched_count = None;
+        }
+        value
+    }
+
+    // Returns whether the counter was (re)started by this write
+    fn write(&mut self, val: u8, now: u64) -> bool {
+        let value = match self.access {
+            AccessMode::LoByte => val as u16,
+            AccessMode::HiByte => (val as u16) << 8,
+            AccessMode::Word | AccessMode::LatchCount => {
+                match self.write_lo.take() {
+                    Some(lo) => ((val as u16) << 8) | lo as u16,
+                    None => {
+                        // Writing the first byte stops the count in mode 0
+                        if let OperatingMode::Mode0 = self.mode {
+                            self.started = None;
+                            self.counted = 0;
+                        }
+                        self.write_lo = Some(val);
+                        return false;
+                    }
+                }
+            }
+        };
+
+        let reload = if self.bcd {
+            from_bcd(value)
+        } else {
+            value as u64
+        };
+        let reload = if reload == 0 { self.modulus() } else { reload };
+
+        match (self.mode, self.reload) {
+            // The hardware triggered modes load the count on the next
+            // rising edge of the gate
+            (OperatingMode::Mode1, _) | (OperatingMode::Mode5, _) => {
+                self.reload = Some(reload);
+                self.null_count = true;
+                false
+            }
+            // In modes 2 and 3 a new count doesn't affect the current
+            // period, so it is loaded when the period ends
+            (OperatingMode::Mode2, Some(current))
+            | (OperatingMode::Mode3, Some(current))
+                if self.started.is_some() =>
+            {
+                let counted = self.ticks_counted(now);
+                let end = now + current - counted % current;
+                self.pending_reload = Some((reload, end));
+                self.null_count = true;
+                true
+            }
+            _ => {
+                self.reload = Some(reload);
+                self.null_count = false;
+                self.counted = 0;
+                self.started = if self.gate { Some(now) } else { None };
+                self.pending_reload = None;
+                true
+            }
+        }
+    }
+
+    // Returns whether the counter was (re)started by the gate change
+    fn set_gate(&mut self, gate: bool, now: u64) -> bool {
+        if gate == self.gate {
+            return false;
+        }
+
+        let counted = self.ticks_counted(now);
+        self.gate = gate;
+
+        if self.reload.is_none() {
+            return false;
+        }
+
+        match self.mode {
+            // The gate pauses the count in modes 0 and 4
+            OperatingMode::Mode0 | OperatingMode::Mode4 => {
+                if gate {
+                    self.started = Some(now);
+                } else {
+                    self.counted = counted;
+                    self.started = None;
+                }
+                gate
+            }
+            // The gate stops the count in modes 2 and 3, and the rising
+            // edge reloads it (including any count written since)
+            OperatingMode::Mode2 | OperatingMode::Mode3 => {
+                if let Some((reload, _)) = self.pending_reload.take() {
+                    self.reload = Some(reload);
+                    self.null_count = false;
+                }
+                self.counted = 0;
+                self.started = if gate { Some(now) } else { None };
+                gate
+            }
+            // The rising edge of the gate (re)triggers modes 1 and 5
+            OperatingMode::Mode1 | OperatingMode::Mode5 => {
+                if gate {
+                    self.null_count = false;
+                    self.counted = 0;
+                    self.started = Some(now);
+                }
+                gate
+            }
         }
     }
 }
 
-#[derive(Default, Debug)]
 pub struct Pit8254 {
-    channel0: ChannelState,
-    // channel1 is not supported
-    channel2: ChannelState,
+    channels: [ChannelState; 3],
+    port_b: u8,
+
+    // The time source used for counting (None for the global system
+    // time source)
+    clock: Option<&'static dyn time::TimeSource>,
 }
 
 impl Pit8254 {
     pub fn new() -> Result<Self> {
-        Ok(Pit8254::default())
+        Ok(Pit8254 {
+            // The gates of channels 0 and 1 are always high, the channel 2
+            // gate is controlled by port B
+            channels: [
+                ChannelState::new(true),
+                ChannelState::new(true),
+                ChannelState::new(false),
+            ],
+            port_b: 0,
+            clock: None,
+        })
+    }
+
+    /// Create a PIT that counts using the given time source instead of the
+    /// global system time source
+    pub fn with_time_source(
+        clock: &'static dyn time::TimeSource,
+    ) -> Result<Self> {
+        let mut pit = Self::new()?;
+        pit.clock = Some(clock);
+        Ok(pit)
+    }
+
+    // The current time in PIT ticks
+    fn now(&self) -> u64 {
+        let clock = self.clock.unwrap_or_else(time::global_time_source);
+        (clock.now().0 as u128 * PIT_HZ as u128 / clock.frequency() as u128)
+            as u64
+    }
+
+    // Load any counts written in modes 2 or 3 whose period has ended
+    fn update_channels(&mut self, now: u64) {
+        for channel in self.channels.iter_mut() {
+            channel.update(now);
+        }
+    }
+
+    // Channel 0 is connected to GSI 0, so arm a timer for the next rising
+    // edge of OUT
+    fn rearm_channel0(&mut self, now: u64) -> Result<()> {
+        // Host timers are driven by the global time source, so they can only
+        // be used when the PIT is also using it
+        let use_timers = self.clock.is_none();
+        let channel = &mut self.channels[0];
+
+        if let Some(id) = channel.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+
+        let reload = match channel.reload {
+            Some(reload) if use_timers && channel.started.is_some() => reload,
+            _ => return Ok(()),
+        };
+
+        let ticks = |count| Duration::from_nanos(PIT_NS_PER_TICK * count);
+        let kind = time::TimerInterruptType::GSI(interrupt::gsi::PIT);
+        channel.timer = match channel.mode {
+            OperatingMode::Mode0 => {
+                Some(time::set_oneshot_timer(ticks(reload), kind))
+            }
+            OperatingMode::Mode2 | OperatingMode::Mode3 => {
+                Some(match channel.pending_reload {
+                    // The current period ends before the new count is used
+                    Some((pending, end)) => time::set_periodic_timer_after(
+                        ticks(end - now),
+                        ticks(pending),
+                        kind,
+                    ),
+                    None => time::set_periodic_timer(ticks(reload), kind),
+                })
+            }
+            OperatingMode::Mode4 => {
+                Some(time::set_oneshot_timer(ticks(reload + 1), kind))
+            }
+            // The channel 0 gate is always high, so it is never triggered
+            OperatingMode::Mode1 | OperatingMode::Mode5 => None,
+        };
+        Ok(())
     }
 
     fn on_port_read(
@@ -68,31 +471,26 @@ impl Pit8254 {
         port: Port,
         mut val: PortReadRequest,
     ) -> Result<()> {
+        let now = self.now();
+        self.update_channels(now);
         match port {
-            //FIXME: much of this 'PS2' handling is a hack. I'm not aware of
-            // a good source for exactly what's supposed to happen here.
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
+                let channel =
+                    &mut self.channels[(port - PIT_COUNTER_0) as usize];
+                val.copy_from_u32(channel.read(now) as u32);
+            }
             PIT_PS2_CTRL_B => {
-                if let OperatingModeState::Mode0 {
-                    start_time,
-                    start_counter,
-                    ..
-                } = self.channel2.mode
-                {
-                    match (start_time, start_counter) {
-                        (Some(start_time), Some(start_counter)) => {
-                            let duration = time::now() - start_time;
-                            let ticks =
-                                duration.as_nanos() / PIT_NS_PER_TICK as u128;
-                            if ticks as u16 > start_counter {
-                                val.copy_from_u32(1 << 5);
-                            }
-                        }
-                        _ => (),
-                    }
+                let mut value = self.port_b & PORT_B_WRITABLE;
+                if (now / REFRESH_TOGGLE_TICKS) & 1 != 0 {
+                    value |= PORT_B_REFRESH;
                 }
+                if self.channels[2].out(now) {
+                    value |= PORT_B_OUT2;
+                }
+                val.copy_from_u32(value as u32);
             }
             _ => {
-                info!("PIT read from unsupported port");
+                info!("PIT read from unsupported port: 0x{:x}", port);
             }
         }
         Ok(())
@@ -103,154 +501,56 @@ impl Pit8254 {
         port: Port,
         val: PortWriteRequest,
     ) -> Result<()> {
+        let now = self.now();
+        self.update_channels(now);
         match port {
             PIT_MODE_CONTROL => {
                 let val = u8::try_from(val)?;
-                let channel = (0b11000000 & val) >> 6;
-                let access = (0b00110000 & val) >> 4;
-                let operating = (0b00001110 & val) >> 1;
-
-                if val & 0b00000001 != 0 {
-                    return Err(Error::InvalidValue(
-                        "PIT BCD mode is not supported".into(),
-                    ));
-                }
-
-                let operating_state = match operating {
-                    0b000 => OperatingModeState::Mode0 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    0b010 => OperatingModeState::Mode2 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT operating state '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
+                let channel = Channel::try_from(val >> 6)?;
 
-                let access_state = match access {
-                    0b00 => AccessModeState::LatchCount,
-                    0b01 => AccessModeState::LoByte,
-                    0b10 => AccessModeState::HiByte,
-                    0b11 => AccessModeState::Word { lo_byte: None },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT access state '0x{:x}'",
-                            value
-                        )))
+                let index = match channel {
+                    Channel::ReadBack => {
+                        for (i, channel) in self.channels.iter_mut().enumerate()
+                        {
+                            if val & (1 << (i + 1)) == 0 {
+                                continue;
+                            }
+                            if val & READBACK_NO_COUNT == 0 {
+                                channel.latch_count(now);
+                            }
+                            if val & READBACK_NO_STATUS == 0 {
+                                channel.latch_status(now);
+                            }
+                        }
+                        return Ok(());
                     }
+                    channel => channel as usize,
                 };
 
-                let channel_state = ChannelState {
-                    mode: operating_state,
-                    access: access_state,
-                };
+                let access = AccessMode::try_from((val >> 4) & 0b11)?;
+                if let AccessMode::LatchCount = access {
+                    self.channels[index].latch_count(now);
+                    return Ok(());
+                }
 
-                let current_channel = match channel {
-                    0b00 => &mut self.channel0,
-                    0b10 => &mut self.channel2,
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT channel '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
+                let mode = OperatingMode::try_from((val >> 1) & 0b111)?;
+                self.channels[index].write_control(access, mode, val & 1 != 0);
 
-                // Stop any running timers
-                match &current_channel.mode {
-                    OperatingModeState::Mode0 { ref timer, .. } => timer,
-                    OperatingModeState::Mode2 { ref timer, .. } => timer,
+                if index == 0 {
+                    self.rearm_channel0(now)?;
                 }
-                .as_ref()
-                .map(|id| time::cancel_timer(id));
-
-                *current_channel = channel_state;
             }
-            port @ PIT_COUNTER_0..=PIT_COUNTER_2 => {
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
                 let val = u8::try_from(val)?;
-                let channel_state = match port {
-                    PIT_COUNTER_0 => &mut self.channel0,
-                    PIT_COUNTER_1 => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT port '0x{:x}'",
-                            port
-                        )))
-                    }
-                    PIT_COUNTER_2 => &mut self.channel2,
-                    _ => unreachable!(),
-                };
-
-                let counter = match channel_state.access {
-                    AccessModeState::LoByte => val as u16,
-                    AccessModeState::HiByte => (val as u16) << 8,
-                    AccessModeState::Word { ref mut lo_byte } => {
-                        if let Some(lo_byte) = lo_byte {
-                            ((val as u16) << 8) | (*lo_byte as u16)
-                        } else {
-                            // We are just setting the low byte in word access mode.
-                            // There is nothing else to do, so return.
-                            *lo_byte = Some(val);
-                            return Ok(());
-                        }
-                    }
-                    _ => unreachable!(),
-                };
-
-                if counter == 0 {
-                    warn!("PIT: ignoring counter set to 0");
-                    return Ok(());
+                let index = (port - PIT_COUNTER_0) as usize;
+                if self.channels[index].write(val, now) && index == 0 {
+                    self.rearm_channel0(now)?;
                 }
-
-                let duration = core::time::Duration::from_nanos(
-                    PIT_NS_PER_TICK * counter as u64,
-                );
-
-                match channel_state.mode {
-                    OperatingModeState::Mode0 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        // Only channel 0 produces timer interrupts
-                        if port == PIT_COUNTER_0 {
-                            *timer = Some(time::set_oneshot_timer(
-                                duration,
-                                time::TimerInterruptType::GSI(
-                                    interrupt::gsi::PIT,
-                                ),
-                            ));
-                        }
-                    }
-
-                    OperatingModeState::Mode2 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        if port == PIT_COUNTER_0 {
-                            *timer = Some(time::set_periodic_timer(
-                                duration,
-                                time::TimerInterruptType::GSI(
-                                    interrupt::gsi::PIT,
-                                ),
-                            ));
-                        }
-                    }
-                };
+            }
+            PIT_PS2_CTRL_B => {
+                let val = u8::try_from(val)?;
+                self.port_b = val & PORT_B_WRITABLE;
+                self.channels[2].set_gate(val & PORT_B_GATE2 != 0, now);
             }
             _ => {
                 info!("PIT: write to unsupported port: 0x{:x}", port);
@@ -279,4 +579,220 @@ impl EmulatedDevice for Pit8254 {
         }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        if let Some(id) = self.channels[0].timer.take() {
+            time::cancel_timer(&id)?;
+        }
+        let clock = self.clock;
+        *self = Self::new()?;
+        self.clock = clock;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time::mock::MockTimeSource;
+    use crate::virtdev::testing::{port_read, port_write};
+
+    // A PIT and a time source that ticks at the PIT frequency
+    fn define_test_pit() -> (Pit8254, &'static MockTimeSource) {
+        let clock = MockTimeSource::new(PIT_HZ);
+        (Pit8254::with_time_source(clock).unwrap(), clock)
+    }
+
+    fn write_port(pit: &mut Pit8254, port: Port, value: u8) {
+        port_write(pit, port, value as u32, 1);
+    }
+
+    fn read_port(pit: &mut Pit8254, port: Port) -> u8 {
+        port_read(pit, port, 1) as u8
+    }
+
+    fn write_count(pit: &mut Pit8254, port: Port, count: u16) {
+        write_port(pit, port, count as u8);
+        write_port(pit, port, (count >> 8) as u8);
+    }
+
+    fn read_count(pit: &mut Pit8254, port: Port) -> u16 {
+        let lo = read_port(pit, port) as u16;
+        let hi = read_port(pit, port) as u16;
+        (hi << 8) | lo
+    }
+
+    fn out2(pit: &mut Pit8254) -> bool {
+        read_port(pit, PIT_PS2_CTRL_B) & PORT_B_OUT2 != 0
+    }
+
+    #[test]
+    fn test_bcd_conversion() {
+        assert_eq!(to_bcd(1234), 0x1234);
+        assert_eq!(from_bcd(0x9999), 9999);
+        assert_eq!(from_bcd(to_bcd(10)), 10);
+    }
+
+    #[test]
+    fn test_mode0_terminal_count() {
+        let (mut pit, clock) = define_test_pit();
+
+        // Channel 2, word access, mode 0 with the gate enabled
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_11_000_0);
+        write_count(&mut pit, PIT_COUNTER_2, 0x1000);
+        assert!(!out2(&mut pit));
+
+        clock.advance(0x800);
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_00_000_0);
+        clock.advance(0x10);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2), 0x800);
+        assert!(!out2(&mut pit));
+
+        clock.advance(0x7f0);
+        assert!(out2(&mut pit));
+
+        // The counter wraps after the terminal count
+        clock.advance(1);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2), 0xffff);
+    }
+
+    #[test]
+    fn test_mode0_gate_pauses_count() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_11_000_0);
+        write_count(&mut pit, PIT_COUNTER_2, 100);
+
+        clock.advance(40);
+        write_port(&mut pit, PIT_PS2_CTRL_B, 0);
+        clock.advance(1000);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2), 60);
+
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        clock.advance(60);
+        assert!(out2(&mut pit));
+    }
+
+    #[test]
+    fn test_mode1_gate_trigger() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_11_001_0);
+        write_count(&mut pit, PIT_COUNTER_2, 5);
+        assert!(out2(&mut pit));
+
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        assert!(!out2(&mut pit));
+        clock.advance(4);
+        assert!(!out2(&mut pit));
+        clock.advance(1);
+        assert!(out2(&mut pit));
+    }
+
+    #[test]
+    fn test_mode2_rate_generator() {
+        let (mut pit, clock) = define_test_pit();
+
+        // Channel 1 always has its gate enabled
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b01_11_010_0);
+        write_count(&mut pit, PIT_COUNTER_1, 100);
+        clock.advance(250);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1), 50);
+    }
+
+    #[test]
+    fn test_mode2_count_loaded_at_period_end() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b01_11_010_0);
+        write_count(&mut pit, PIT_COUNTER_1, 100);
+        clock.advance(30);
+
+        // The new count is only used once the current period ends
+        write_count(&mut pit, PIT_COUNTER_1, 50);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1), 70);
+        clock.advance(69);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1), 1);
+        clock.advance(1);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1), 50);
+        clock.advance(60);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1), 40);
+    }
+
+    #[test]
+    fn test_mode3_square_wave() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_11_011_0);
+        write_count(&mut pit, PIT_COUNTER_2, 10);
+
+        // OUT is high while the gate is low
+        clock.advance(7);
+        assert!(out2(&mut pit));
+
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        for tick in 0..20 {
+            assert_eq!(out2(&mut pit), tick % 10 < 5);
+            clock.advance(1);
+        }
+    }
+
+    #[test]
+    fn test_mode4_strobe_status() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b01_11_100_0);
+        write_count(&mut pit, PIT_COUNTER_1, 3);
+
+        // Read back the status of channel 1
+        let read_status = |pit: &mut Pit8254| {
+            write_port(pit, PIT_MODE_CONTROL, 0b11_1_0_010_0);
+            read_port(pit, PIT_COUNTER_1)
+        };
+
+        assert_eq!(read_status(&mut pit), 0b1_0_11_100_0);
+        clock.advance(3);
+        assert_eq!(read_status(&mut pit), 0b0_0_11_100_0);
+        clock.advance(1);
+        assert_eq!(read_status(&mut pit), 0b1_0_11_100_0);
+    }
+
+    #[test]
+    fn test_readback_status_and_count() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_PS2_CTRL_B, PORT_B_GATE2);
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b10_11_000_0);
+
+        // No count has been written yet
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b11_1_0_100_0);
+        assert_eq!(read_port(&mut pit, PIT_COUNTER_2), 0b0_1_11_000_0);
+
+        write_count(&mut pit, PIT_COUNTER_2, 0x100);
+        clock.advance(0x10);
+
+        // Latch both the status and count, then let the counter continue
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b11_0_0_100_0);
+        clock.advance(0x10);
+        assert_eq!(read_port(&mut pit, PIT_COUNTER_2), 0b0_0_11_000_0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2), 0xf0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2), 0xe0);
+    }
+
+    #[test]
+    fn test_bcd_count() {
+        let (mut pit, clock) = define_test_pit();
+        write_port(&mut pit, PIT_MODE_CONTROL, 0b00_11_000_1);
+        write_count(&mut pit, PIT_COUNTER_0, 0x1000);
+        clock.advance(1);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0), 0x0999);
+
+        // A count of zero is the maximum count (10000 in BCD)
+        write_count(&mut pit, PIT_COUNTER_0, 0);
+        clock.advance(1);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0), 0x9999);
+    }
+
+    #[test]
+    fn test_port_b() {
+        let (mut pit, _) = define_test_pit();
+        write_port(&mut pit, PIT_PS2_CTRL_B, 0xff);
+        assert_eq!(read_port(&mut pit, PIT_PS2_CTRL_B) & 0x0f, 0x0f);
+    }
 }
@@ -413,6 +413,13 @@ impl EmulatedDevice for QemuFwCfg {
         }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        self.selector = FwCfgSelector::SIGNATURE;
+        self.data_idx = 0;
+        self.dma_addr = 0;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
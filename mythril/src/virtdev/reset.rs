@@ -0,0 +1,98 @@
+use crate::error::Result;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
+};
+use crate::vm;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+// A transition of this bit from 0 to 1 resets the system
+const RESET_CPU: u8 = 1 << 2;
+
+// The system reset (hard reset) and full reset bits only select the type
+// of reset, so they are the only bits that are retained.
+const RESET_TYPE_MASK: u8 = 0b1010;
+
+/// The chipset Reset Control Register (as found in the PIIX and ICH)
+pub struct ResetControl {
+    value: u8,
+}
+
+impl ResetControl {
+    pub const RESET_CONTROL: Port = 0xcf9;
+
+    pub fn new() -> Result<Self> {
+        Ok(Self { value: 0 })
+    }
+}
+
+impl EmulatedDevice for ResetControl {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            Self::RESET_CONTROL..=Self::RESET_CONTROL,
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(_port, mut val) => {
+                val.copy_from_u32(self.value as u32);
+            }
+            DeviceEvent::PortWrite(_port, val) => {
+                let val: u8 = val.try_into()?;
+
+                // All types of reset are treated as a full system reset
+                if val & RESET_CPU != 0 && self.value & RESET_CPU == 0 {
+                    event.responses.push(DeviceEventResponse::PowerEvent(
+                        vm::PowerEvent::Reboot,
+                    ));
+                }
+                self.value = val & (RESET_TYPE_MASK | RESET_CPU);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{port_read, port_write};
+    use crate::virtdev::ResponseEventArray;
+
+    fn write_port(rcr: &mut ResetControl, value: u8) -> ResponseEventArray {
+        port_write(rcr, ResetControl::RESET_CONTROL, value as u32, 1)
+    }
+
+    fn read_port(rcr: &mut ResetControl) -> u8 {
+        port_read(rcr, ResetControl::RESET_CONTROL, 1) as u8
+    }
+
+    fn is_reboot(responses: &ResponseEventArray) -> bool {
+        responses.iter().any(|resp| match resp {
+            DeviceEventResponse::PowerEvent(vm::PowerEvent::Reboot) => true,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn test_reset_control() {
+        let mut rcr = ResetControl::new().unwrap();
+
+        // Selecting the type of reset does not reset the system
+        assert!(!is_reboot(&write_port(&mut rcr, 0x02)));
+        assert!(is_reboot(&write_port(&mut rcr, 0x06)));
+        assert_eq!(read_port(&mut rcr), 0x06);
+
+        // Only a transition of the reset bit resets the system
+        assert!(!is_reboot(&write_port(&mut rcr, 0x06)));
+        assert!(!is_reboot(&write_port(&mut rcr, 0x02)));
+        assert!(is_reboot(&write_port(&mut rcr, 0x04)));
+    }
+}
@@ -1,12 +1,58 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::interrupt;
+use crate::physdev::rtc::{boot_time, DateTime};
+use crate::time;
 use crate::virtdev::{
     DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port, PortReadRequest,
     PortWriteRequest,
 };
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
+use core::time::Duration;
 use num_enum::TryFromPrimitive;
 
+// Register A bits
+const STATUS_A_UIP: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0f;
+
+// Register B bits
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_PIE: u8 = 1 << 6;
+const STATUS_B_AIE: u8 = 1 << 5;
+const STATUS_B_UIE: u8 = 1 << 4;
+const STATUS_B_SQWE: u8 = 1 << 3;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24H: u8 = 1 << 1;
+
+// Register C bits (each interrupt flag has the same bit position as its
+// enable bit in register B)
+const STATUS_C_IRQF: u8 = 1 << 7;
+const STATUS_C_PF: u8 = 1 << 6;
+const STATUS_C_AF: u8 = 1 << 5;
+const STATUS_C_UF: u8 = 1 << 4;
+const INTERRUPT_MASK: u8 = STATUS_C_PF | STATUS_C_AF | STATUS_C_UF;
+
+const HOUR_PM: u8 = 1 << 7;
+
+// Alarm register values with the two high bits set match any time
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 86400;
+
+// The update-in-progress bit is set this long before each update cycle
+const UIP_NS: u64 = 244_000;
+
+// The time used when the host time is unavailable (2000-01-01 00:00:00)
+const DEFAULT_DATE: DateTime = DateTime {
+    year: 2000,
+    month: 1,
+    day: 1,
+    hour: 0,
+    minute: 0,
+    second: 0,
+};
+
 #[derive(Copy, Clone, Debug, TryFromPrimitive)]
 #[repr(u8)]
 enum CmosRegister {
@@ -60,14 +106,71 @@ enum CmosRegister {
     QemuMemAbove4GbLsb = 0x5b,
     QemuMemAbove4GbMmsb = 0x5c,
     QemuMemAbove4GbMsb = 0x5d,
+}
+
+// The registers that describe the VM memory size, which always reflect the
+// VM configuration (rather than any initial NVRAM image)
+const MEMORY_SIZE_REGISTERS: [CmosRegister; 11] = [
+    CmosRegister::BaseSystemMemoryLsb,
+    CmosRegister::BaseSystemMemoryMsb,
+    CmosRegister::TotalExtendedMemoryLsb,
+    CmosRegister::TotalExtendedMemoryMsb,
+    CmosRegister::ExtendedPostMemLsb,
+    CmosRegister::ExtendedPostMemMsb,
+    CmosRegister::QemuMemAbove16MbLsb,
+    CmosRegister::QemuMemAbove16MbMsb,
+    CmosRegister::QemuMemAbove4GbLsb,
+    CmosRegister::QemuMemAbove4GbMmsb,
+    CmosRegister::QemuMemAbove4GbMsb,
+];
+
+// The first byte of NVRAM (the bytes before this are the RTC registers)
+const NVRAM_START: usize = CmosRegister::DiagnosticStatus as usize;
+
+// The range of bytes covered by the standard CMOS checksum
+const CHECKSUM_START: usize = CmosRegister::DisketteDriveType as usize;
+const CHECKSUM_END: usize = 0x2d;
+
+// Only the first 128 bytes are addressable through the RTC ports
+const CMOS_SIZE: usize = 128;
 
-    // Not in any spec, used to represent unknown values
-    Unknown = 0xff,
+fn clock_ns(clock: &dyn time::TimeSource, instant: time::Instant) -> u64 {
+    (instant.0 as u128 * NS_PER_SEC as u128 / clock.frequency() as u128) as u64
+}
+
+// The number of periodic interrupt intervals before the given time
+fn periodic_index(wall: u64, hz: u64) -> u64 {
+    (wall as u128 * hz as u128 / NS_PER_SEC as u128) as u64
 }
 
 pub struct CmosRtc {
-    addr: CmosRegister,
-    data: [u8; 256],
+    addr: u8,
+
+    // Set when the guest masks NMIs through the high bit of the address
+    // port. NMIs are never sourced from the RTC, so this is only stored.
+    nmi_disabled: bool,
+
+    data: [u8; CMOS_SIZE],
+
+    // The time source used for timekeeping (None for the global system
+    // time source)
+    clock: Option<&'static dyn time::TimeSource>,
+
+    // The guest wall-clock time (in nanoseconds since the unix epoch) minus
+    // the time source nanoseconds, or None if the RTC has not started yet.
+    // This uses wrapping arithmetic, as the guest may set any time.
+    offset: Option<u64>,
+
+    // The date while updates are inhibited by the SET bit of register B
+    frozen: Option<DateTime>,
+
+    // The interrupt flags of register C
+    flags: u8,
+
+    // The time source nanoseconds when the flags were last updated
+    checked: u64,
+
+    timer: Option<time::TimerId>,
 }
 
 impl CmosRtc {
@@ -75,16 +178,42 @@ impl CmosRtc {
     const RTC_DATA: Port = 0x0071;
 
     pub fn new(mem: u64) -> Result<Self> {
+        // The RTC is started on first access, as the global time source may
+        // not be available yet.
         Ok(Self {
-            addr: CmosRegister::Seconds, // For now, just set the default reg as seconds
+            // For now, just set the default reg as seconds
+            addr: CmosRegister::Seconds as u8,
+            nmi_disabled: false,
             data: Self::default_register_values(mem),
+            clock: None,
+            offset: None,
+            frozen: None,
+            flags: 0,
+            checked: 0,
+            timer: None,
         })
     }
 
-    fn default_register_values(mem: u64) -> [u8; 256] {
+    /// Create an RTC that keeps time using the given time source (starting
+    /// at the given date) instead of the global system time source
+    pub fn with_time_source(
+        mem: u64,
+        clock: &'static dyn time::TimeSource,
+        start: DateTime,
+    ) -> Result<Self> {
+        let mut rtc = Self::new(mem)?;
+        let now = clock_ns(clock, clock.now());
+        rtc.clock = Some(clock);
+        rtc.offset =
+            Some((start.unix_seconds() * NS_PER_SEC).wrapping_sub(now));
+        rtc.checked = now;
+        Ok(rtc)
+    }
+
+    fn default_register_values(mem: u64) -> [u8; CMOS_SIZE] {
         //TODO: support memory above 4GB
 
-        let mut data = [0u8; 256];
+        let mut data = [0u8; CMOS_SIZE];
 
         let megs_under_4gb = mem & 0xfff;
         // Subtrack 16 because it's really 'blocks_under_4gb_over_16mb'
@@ -92,6 +221,10 @@ impl CmosRtc {
         let blocks_under_4gb: u16 = ((megs_under_4gb - 16) << 4) as u16;
 
         let defaults = [
+            // A 32.768kHz time base with a 1024Hz periodic rate
+            (CmosRegister::StatusRegisterA, 0x26),
+            // 24 hour BCD mode
+            (CmosRegister::StatusRegisterB, STATUS_B_24H),
             // The MSB of register D indicates the CMOS battery is working
             (CmosRegister::StatusRegisterD, 0b10000000),
             (CmosRegister::QemuMemAbove16MbLsb, blocks_under_4gb as u8),
@@ -103,21 +236,388 @@ impl CmosRtc {
         for &(reg, val) in &defaults {
             data[reg as usize] = val
         }
+        Self::update_checksum(&mut data);
         data
     }
 
+    fn update_checksum(data: &mut [u8; CMOS_SIZE]) {
+        let checksum = data[CHECKSUM_START..=CHECKSUM_END]
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        data[CmosRegister::CmosChecksumMsb as usize] = (checksum >> 8) as u8;
+        data[CmosRegister::CmosChecksumLsb as usize] = checksum as u8;
+    }
+
+    /// Initialize the NVRAM from an image of the CMOS contents
+    ///
+    /// The image starts at CMOS address 0, but the RTC registers and the
+    /// memory size registers are not changed. The checksum is recomputed
+    /// after the image is loaded.
+    pub fn load_nvram(&mut self, image: &[u8]) -> Result<()> {
+        if image.len() > CMOS_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "NVRAM image is too large ({} bytes)",
+                image.len()
+            )));
+        }
+
+        let memory_size = MEMORY_SIZE_REGISTERS
+            .iter()
+            .map(|&reg| (reg, self.data[reg as usize]))
+            .collect::<Vec<_>>();
+
+        if image.len() > NVRAM_START {
+            self.data[NVRAM_START..image.len()]
+                .copy_from_slice(&image[NVRAM_START..]);
+        }
+
+        for (reg, val) in memory_size {
+            self.data[reg as usize] = val;
+        }
+        Self::update_checksum(&mut self.data);
+        Ok(())
+    }
+
+    // The register currently selected by the address port (if it is not
+    // just an NVRAM byte)
+    fn register(&self) -> Option<CmosRegister> {
+        CmosRegister::try_from(self.addr).ok()
+    }
+
+    fn status_a(&self) -> u8 {
+        self.data[CmosRegister::StatusRegisterA as usize]
+    }
+
+    fn status_b(&self) -> u8 {
+        self.data[CmosRegister::StatusRegisterB as usize]
+    }
+
+    // Start the RTC (if it has not already been started) and return the
+    // current time source nanoseconds
+    fn start(&mut self) -> u64 {
+        let clock = self.clock.unwrap_or_else(time::global_time_source);
+        let now = clock_ns(clock, clock.now());
+        if self.offset.is_none() {
+            let (date, at) = match boot_time() {
+                Some((date, instant)) => (date, clock_ns(clock, instant)),
+                None => {
+                    warn!("No host time, starting the RTC at 2000-01-01");
+                    (DEFAULT_DATE, now)
+                }
+            };
+            self.offset =
+                Some((date.unix_seconds() * NS_PER_SEC).wrapping_sub(at));
+            self.checked = now;
+        }
+        now
+    }
+
+    // The guest wall-clock time in nanoseconds
+    fn wall_ns(&self, now: u64) -> u64 {
+        now.wrapping_add(self.offset.unwrap_or(0))
+    }
+
+    fn current_date(&self, now: u64) -> DateTime {
+        self.frozen.unwrap_or_else(|| {
+            DateTime::from_unix_seconds(self.wall_ns(now) / NS_PER_SEC)
+        })
+    }
+
+    fn set_date(&mut self, date: DateTime, now: u64) {
+        if self.frozen.is_some() {
+            self.frozen = Some(date);
+        } else {
+            // Keep the phase of the divider chain
+            let subsec = self.wall_ns(now) % NS_PER_SEC;
+            let wall = date.unix_seconds() * NS_PER_SEC + subsec;
+            self.offset = Some(wall.wrapping_sub(now));
+        }
+    }
+
+    // The periodic interrupt rate (if enabled)
+    fn periodic_hz(&self) -> Option<u64> {
+        match self.status_a() & STATUS_A_RATE {
+            0 => None,
+            // Rates 1 and 2 are the same as 8 and 9 with a 32.768kHz
+            // time base
+            rate @ 1..=2 => Some(32768 >> (rate + 6)),
+            rate => Some(32768 >> (rate - 1)),
+        }
+    }
+
+    fn update_in_progress(&self, now: u64) -> bool {
+        self.frozen.is_none()
+            && self.wall_ns(now) % NS_PER_SEC >= NS_PER_SEC - UIP_NS
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        }
+    }
+
+    fn encode_hours(&self, hour: u8) -> u8 {
+        if self.status_b() & STATUS_B_24H != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOUR_PM } else { 0 };
+        match hour % 12 {
+            0 => self.encode(12) | pm,
+            hour => self.encode(hour) | pm,
+        }
+    }
+
+    fn decode_hours(&self, value: u8) -> u8 {
+        if self.status_b() & STATUS_B_24H != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !HOUR_PM) % 12;
+        if value & HOUR_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    fn alarm_matches(&self, seconds: u64) -> bool {
+        let time = seconds % SECONDS_PER_DAY;
+        let alarms = [
+            (CmosRegister::SecondsAlarm, self.encode((time % 60) as u8)),
+            (
+                CmosRegister::MinutesAlarm,
+                self.encode((time / 60 % 60) as u8),
+            ),
+            (
+                CmosRegister::HoursAlarm,
+                self.encode_hours((time / 3600) as u8),
+            ),
+        ];
+        alarms.iter().all(|&(reg, value)| {
+            let alarm = self.data[reg as usize];
+            alarm >= ALARM_DONT_CARE || alarm == value
+        })
+    }
+
+    // Set the interrupt flags for any events since the last update
+    fn update_flags(&mut self, now: u64) {
+        let prev = self.wall_ns(self.checked);
+        let next = self.wall_ns(now);
+        self.checked = now;
+        if next <= prev {
+            return;
+        }
+
+        if let Some(hz) = self.periodic_hz() {
+            if periodic_index(prev, hz) != periodic_index(next, hz) {
+                self.flags |= STATUS_C_PF;
+            }
+        }
+
+        // Update cycles (and therefore alarms) are inhibited by SET
+        if self.frozen.is_some() {
+            return;
+        }
+
+        let (prev_sec, next_sec) = (prev / NS_PER_SEC, next / NS_PER_SEC);
+        if next_sec > prev_sec {
+            self.flags |= STATUS_C_UF;
+
+            // Only the time of day is compared, so there is no need to
+            // check more than a day of updates
+            let first =
+                (prev_sec + 1).max(next_sec.saturating_sub(SECONDS_PER_DAY));
+            if (first..=next_sec).any(|sec| self.alarm_matches(sec)) {
+                self.flags |= STATUS_C_AF;
+            }
+        }
+    }
+
+    // The nanoseconds until the next of the given events
+    fn next_event(&self, now: u64, enabled: u8) -> Option<u64> {
+        let wall = self.wall_ns(now);
+
+        let periodic = match self.periodic_hz() {
+            Some(hz) if enabled & STATUS_B_PIE != 0 => {
+                let index = periodic_index(wall, hz) as u128 + 1;
+                let at =
+                    (index * NS_PER_SEC as u128 + hz as u128 - 1) / hz as u128;
+                Some(at as u64 - wall)
+            }
+            _ => None,
+        };
+
+        let (update, alarm) = if self.frozen.is_none() {
+            let until_update = NS_PER_SEC - wall % NS_PER_SEC;
+            let sec = wall / NS_PER_SEC;
+            let update = if enabled & STATUS_B_UIE != 0 {
+                Some(until_update)
+            } else {
+                None
+            };
+            let alarm = if enabled & STATUS_B_AIE != 0 {
+                (1..=SECONDS_PER_DAY)
+                    .find(|n| self.alarm_matches(sec + n))
+                    .map(|n| until_update + (n - 1) * NS_PER_SEC)
+            } else {
+                None
+            };
+            (update, alarm)
+        } else {
+            (None, None)
+        };
+
+        [periodic, update, alarm].iter().flatten().min().copied()
+    }
+
+    // Arm a timer to raise GSI 8 at the next enabled event
+    fn rearm(&mut self, now: u64) -> Result<()> {
+        if let Some(id) = self.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+
+        // Host timers are driven by the global time source, so they can only
+        // be used when the RTC is also using it
+        if self.clock.is_some() {
+            return Ok(());
+        }
+
+        // The interrupt line stays asserted until register C is read, so
+        // there is nothing to do while an enabled flag is pending
+        let enabled = self.status_b() & INTERRUPT_MASK;
+        if enabled == 0 || self.flags & enabled != 0 {
+            return Ok(());
+        }
+
+        if let Some(ns) = self.next_event(now, enabled) {
+            self.timer = Some(time::set_oneshot_timer(
+                Duration::from_nanos(ns),
+                time::TimerInterruptType::GSI(interrupt::gsi::RTC),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_register(&mut self, now: u64) -> Result<u8> {
+        let date = self.current_date(now);
+        let value = match self.register() {
+            Some(CmosRegister::Seconds) => self.encode(date.second),
+            Some(CmosRegister::Minutes) => self.encode(date.minute),
+            Some(CmosRegister::Hours) => self.encode_hours(date.hour),
+            Some(CmosRegister::DayOfWeek) => self.encode(date.day_of_week()),
+            Some(CmosRegister::DayOfMonth) => self.encode(date.day),
+            Some(CmosRegister::Month) => self.encode(date.month),
+            Some(CmosRegister::Year) => self.encode((date.year % 100) as u8),
+            Some(CmosRegister::BcdCenturyDate) => {
+                self.encode((date.year / 100) as u8)
+            }
+            Some(CmosRegister::StatusRegisterA) => {
+                if self.update_in_progress(now) {
+                    self.status_a() | STATUS_A_UIP
+                } else {
+                    self.status_a()
+                }
+            }
+            Some(CmosRegister::StatusRegisterC) => {
+                // Reading register C clears the flags (and releases IRQ8)
+                let mut value = self.flags;
+                if self.flags & self.status_b() & INTERRUPT_MASK != 0 {
+                    value |= STATUS_C_IRQF;
+                }
+                self.flags = 0;
+                self.rearm(now)?;
+                value
+            }
+            _ => self.data[self.addr as usize],
+        };
+        Ok(value)
+    }
+
+    fn write_register(&mut self, val: u8, now: u64) -> Result<()> {
+        let mut date = self.current_date(now);
+        match self.register() {
+            Some(CmosRegister::Seconds) => date.second = self.decode(val),
+            Some(CmosRegister::Minutes) => date.minute = self.decode(val),
+            Some(CmosRegister::Hours) => date.hour = self.decode_hours(val),
+            Some(CmosRegister::DayOfMonth) => date.day = self.decode(val),
+            Some(CmosRegister::Month) => date.month = self.decode(val),
+            Some(CmosRegister::Year) => {
+                date.year = date.year / 100 * 100 + self.decode(val) as u32
+            }
+            Some(CmosRegister::BcdCenturyDate) => {
+                date.year = self.decode(val) as u32 * 100 + date.year % 100
+            }
+            Some(CmosRegister::DayOfWeek) => {
+                // The day of the week is always derived from the date
+                return Ok(());
+            }
+            Some(CmosRegister::StatusRegisterA) => {
+                self.data[CmosRegister::StatusRegisterA as usize] =
+                    val & !STATUS_A_UIP;
+                return self.rearm(now);
+            }
+            Some(CmosRegister::StatusRegisterB) => {
+                let mut val = val;
+                if val & STATUS_B_SET != 0 {
+                    // Setting SET aborts any update cycle and clears UIE
+                    val &= !STATUS_B_UIE;
+                    if self.frozen.is_none() {
+                        self.frozen = Some(date);
+                    }
+                } else if let Some(date) = self.frozen.take() {
+                    // The first update cycle starts a second after SET is
+                    // cleared
+                    let wall = date.unix_seconds() * NS_PER_SEC;
+                    self.offset = Some(wall.wrapping_sub(now));
+                }
+                self.data[CmosRegister::StatusRegisterB as usize] = val;
+                return self.rearm(now);
+            }
+            Some(CmosRegister::ShutdownStatus) => {
+                // It's not clear what's supposed to happen here, just ignore
+                // it for now
+                return Ok(());
+            }
+            Some(CmosRegister::StatusRegisterD)
+            | Some(CmosRegister::StatusRegisterC) => {
+                // Status register C and D are read-only (but OVMF will attempt
+                // to write to them, so we must explicitly ignore the writes)
+                return Ok(());
+            }
+            _ => {
+                // For now, any other register write is just directly performed
+                self.data[self.addr as usize] = val;
+                return self.rearm(now);
+            }
+        }
+        self.set_date(date, now);
+        self.rearm(now)
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
         mut val: PortReadRequest,
     ) -> Result<()> {
         match port {
-            Self::RTC_ADDRESS => val.copy_from_u32(self.addr as u8 as u32),
-            Self::RTC_DATA => match self.addr {
-                addr => {
-                    val.copy_from_u32(self.data[addr as usize] as u32);
-                }
-            },
+            Self::RTC_ADDRESS => {
+                let nmi_disabled = if self.nmi_disabled { 0x80 } else { 0 };
+                val.copy_from_u32((self.addr | nmi_disabled) as u32);
+            }
+            Self::RTC_DATA => {
+                let now = self.start();
+                self.update_flags(now);
+                val.copy_from_u32(self.read_register(now)? as u32);
+            }
             _ => unreachable!(),
         }
 
@@ -129,33 +629,20 @@ impl CmosRtc {
         port: Port,
         val: PortWriteRequest,
     ) -> Result<()> {
-        // For now, just ignore the NMI masking
         let val: u8 = val.try_into()?;
-        let val = val & 0x7f;
 
         match port {
             Self::RTC_ADDRESS => {
-                // OVMF expects to be able to read pretty much any address
-                // (and just get zeros for meaningless ones)
-                self.addr = CmosRegister::try_from(val)
-                    .unwrap_or(CmosRegister::Unknown);
+                // The high bit masks NMIs. OVMF expects to be able to read
+                // pretty much any address (and just get zeros for
+                // meaningless ones)
+                self.nmi_disabled = val & 0x80 != 0;
+                self.addr = val & 0x7f;
             }
             Self::RTC_DATA => {
-                match self.addr {
-                    CmosRegister::ShutdownStatus => {
-                        // It's not clear what's supposed to happen here, just ignore
-                        // it for now
-                    }
-                    CmosRegister::StatusRegisterD
-                    | CmosRegister::StatusRegisterC => {
-                        // Status register C and D are read-only (but OVMF will attempt
-                        // to write to them, so we must explicitly ignore the writes)
-                    }
-                    addr => {
-                        // For now, any other register write is just directly performed
-                        self.data[addr as usize] = val;
-                    }
-                }
+                let now = self.start();
+                self.update_flags(now);
+                self.write_register(val, now)?;
             }
             _ => unreachable!(),
         }
@@ -163,7 +650,6 @@ impl CmosRtc {
     }
 }
 
-//TODO: support the NMI masking stuff
 impl EmulatedDevice for CmosRtc {
     fn services(&self) -> Vec<DeviceRegion> {
         vec![DeviceRegion::PortIo(Self::RTC_ADDRESS..=Self::RTC_DATA)]
@@ -179,4 +665,320 @@ impl EmulatedDevice for CmosRtc {
         }
         Ok(())
     }
+
+    // A system reset disables the RTC interrupts and clears any pending
+    // interrupt flags. The time and the contents of NVRAM are preserved.
+    fn reset(&mut self) -> Result<()> {
+        self.data[CmosRegister::StatusRegisterB as usize] &=
+            !(STATUS_B_PIE | STATUS_B_AIE | STATUS_B_UIE | STATUS_B_SQWE);
+
+        // Discard any events that occurred before the reset
+        let now = self.start();
+        self.update_flags(now);
+        self.flags = 0;
+
+        if let Some(id) = self.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time::mock::MockTimeSource;
+    use crate::virtdev::testing::{port_read, port_write};
+
+    const SECOND: u64 = NS_PER_SEC;
+
+    // An RTC started at 2021-03-14 15:09:26 (a Sunday) and a time source
+    // that ticks every nanosecond
+    fn define_test_rtc() -> (CmosRtc, &'static MockTimeSource) {
+        let clock = MockTimeSource::new(NS_PER_SEC);
+        let start = DateTime {
+            year: 2021,
+            month: 3,
+            day: 14,
+            hour: 15,
+            minute: 9,
+            second: 26,
+        };
+        let mut rtc = CmosRtc::with_time_source(256, clock, start).unwrap();
+
+        // Disable the periodic flag so it doesn't obscure the other flags
+        write_reg(&mut rtc, CmosRegister::StatusRegisterA, 0x20);
+        (rtc, clock)
+    }
+
+    fn write_port(rtc: &mut CmosRtc, port: Port, value: u8) {
+        port_write(rtc, port, value as u32, 1);
+    }
+
+    fn read_port(rtc: &mut CmosRtc, port: Port) -> u8 {
+        port_read(rtc, port, 1) as u8
+    }
+
+    fn read_reg(rtc: &mut CmosRtc, reg: CmosRegister) -> u8 {
+        write_port(rtc, CmosRtc::RTC_ADDRESS, reg as u8);
+        read_port(rtc, CmosRtc::RTC_DATA)
+    }
+
+    fn write_reg(rtc: &mut CmosRtc, reg: CmosRegister, value: u8) {
+        write_port(rtc, CmosRtc::RTC_ADDRESS, reg as u8);
+        write_port(rtc, CmosRtc::RTC_DATA, value);
+    }
+
+    #[test]
+    fn test_time_advances() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x26);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Minutes), 0x09);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Hours), 0x15);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::DayOfWeek), 0x01);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::DayOfMonth), 0x14);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Month), 0x03);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Year), 0x21);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::BcdCenturyDate), 0x20);
+
+        clock.advance(34 * SECOND);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x00);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Minutes), 0x10);
+    }
+
+    #[test]
+    fn test_binary_12_hour_mode() {
+        let (mut rtc, _) = define_test_rtc();
+
+        write_reg(&mut rtc, CmosRegister::StatusRegisterB, STATUS_B_BINARY);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 26);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Hours), 3 | HOUR_PM);
+
+        // 12AM is midnight
+        write_reg(&mut rtc, CmosRegister::Hours, 12);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Hours), 12);
+        write_reg(&mut rtc, CmosRegister::StatusRegisterB, STATUS_B_24H);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Hours), 0x00);
+    }
+
+    #[test]
+    fn test_update_in_progress() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        let status_a = read_reg(&mut rtc, CmosRegister::StatusRegisterA);
+        assert_eq!(status_a, 0x20);
+
+        clock.advance(SECOND - UIP_NS);
+        let status_a = read_reg(&mut rtc, CmosRegister::StatusRegisterA);
+        assert_eq!(status_a, 0x20 | STATUS_A_UIP);
+
+        clock.advance(UIP_NS);
+        let status_a = read_reg(&mut rtc, CmosRegister::StatusRegisterA);
+        assert_eq!(status_a, 0x20);
+    }
+
+    #[test]
+    fn test_update_ended_flag() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        // The flag is set even when the interrupt is disabled
+        clock.advance(SECOND);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_UF);
+
+        write_reg(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            STATUS_B_24H | STATUS_B_UIE,
+        );
+        clock.advance(SECOND);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_IRQF | STATUS_C_UF);
+
+        // Reading register C clears it
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, 0);
+    }
+
+    #[test]
+    fn test_alarm() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        write_reg(&mut rtc, CmosRegister::SecondsAlarm, 0x30);
+        write_reg(&mut rtc, CmosRegister::MinutesAlarm, ALARM_DONT_CARE);
+        write_reg(&mut rtc, CmosRegister::HoursAlarm, ALARM_DONT_CARE);
+        write_reg(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            STATUS_B_24H | STATUS_B_AIE,
+        );
+
+        clock.advance(3 * SECOND);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_UF);
+
+        clock.advance(SECOND);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_IRQF | STATUS_C_AF | STATUS_C_UF);
+
+        // An alarm that passed between reads is still flagged
+        clock.advance(60 * SECOND);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_IRQF | STATUS_C_AF | STATUS_C_UF);
+    }
+
+    #[test]
+    fn test_periodic_interrupt() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        // Rate 15 is 2Hz
+        write_reg(&mut rtc, CmosRegister::StatusRegisterA, 0x2f);
+        write_reg(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            STATUS_B_24H | STATUS_B_PIE,
+        );
+
+        clock.advance(SECOND * 4 / 10);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, 0);
+
+        clock.advance(SECOND * 2 / 10);
+        let status_c = read_reg(&mut rtc, CmosRegister::StatusRegisterC);
+        assert_eq!(status_c, STATUS_C_IRQF | STATUS_C_PF);
+
+        // The next interrupt is at the start of the next second
+        let now = rtc.start();
+        assert_eq!(rtc.next_event(now, STATUS_B_PIE), Some(SECOND * 4 / 10));
+    }
+
+    #[test]
+    fn test_set_inhibits_updates() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        write_reg(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            STATUS_B_24H | STATUS_B_SET | STATUS_B_UIE,
+        );
+        assert_eq!(
+            read_reg(&mut rtc, CmosRegister::StatusRegisterB),
+            STATUS_B_24H | STATUS_B_SET
+        );
+
+        clock.advance(5 * SECOND);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x26);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::StatusRegisterC), 0);
+
+        write_reg(&mut rtc, CmosRegister::Seconds, 0x00);
+        write_reg(&mut rtc, CmosRegister::Minutes, 0x30);
+        write_reg(&mut rtc, CmosRegister::Year, 0x22);
+        write_reg(&mut rtc, CmosRegister::StatusRegisterB, STATUS_B_24H);
+
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x00);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Minutes), 0x30);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Year), 0x22);
+
+        clock.advance(SECOND);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x01);
+    }
+
+    fn checksum(rtc: &mut CmosRtc) -> u16 {
+        let msb = read_reg(rtc, CmosRegister::CmosChecksumMsb) as u16;
+        let lsb = read_reg(rtc, CmosRegister::CmosChecksumLsb) as u16;
+        (msb << 8) | lsb
+    }
+
+    #[test]
+    fn test_default_checksum() {
+        let (mut rtc, _) = define_test_rtc();
+        let sum = (0x10..=0x2d)
+            .map(|addr| {
+                write_port(&mut rtc, CmosRtc::RTC_ADDRESS, addr);
+                read_port(&mut rtc, CmosRtc::RTC_DATA) as u16
+            })
+            .sum::<u16>();
+        assert_eq!(checksum(&mut rtc), sum);
+    }
+
+    #[test]
+    fn test_load_nvram() {
+        let (mut rtc, _) = define_test_rtc();
+        let memory = read_reg(&mut rtc, CmosRegister::QemuMemAbove16MbMsb);
+
+        let mut image = [0u8; 0x40];
+        image[CmosRegister::Seconds as usize] = 0x59;
+        image[CmosRegister::DisketteDriveType as usize] = 0x40;
+        image[CmosRegister::DriveCExtension as usize] = 0x2f;
+        image[CmosRegister::QemuMemAbove16MbMsb as usize] = 0xff;
+        image[0x3f] = 0xaa;
+        rtc.load_nvram(&image).unwrap();
+
+        // The RTC and memory size registers are unchanged
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x26);
+        assert_eq!(
+            read_reg(&mut rtc, CmosRegister::QemuMemAbove16MbMsb),
+            memory
+        );
+
+        assert_eq!(read_reg(&mut rtc, CmosRegister::DisketteDriveType), 0x40);
+        assert_eq!(checksum(&mut rtc), 0x40 + 0x2f);
+        write_port(&mut rtc, CmosRtc::RTC_ADDRESS, 0x3f);
+        assert_eq!(read_port(&mut rtc, CmosRtc::RTC_DATA), 0xaa);
+
+        assert!(rtc.load_nvram(&[0u8; 256]).is_err());
+    }
+
+    #[test]
+    fn test_nvram_addressing() {
+        let (mut rtc, _) = define_test_rtc();
+
+        // Bytes without a named register are still distinct
+        for &addr in &[0x40, 0x41, 0x7f] {
+            write_port(&mut rtc, CmosRtc::RTC_ADDRESS, addr);
+            write_port(&mut rtc, CmosRtc::RTC_DATA, addr);
+        }
+        for &addr in &[0x40, 0x41, 0x7f] {
+            write_port(&mut rtc, CmosRtc::RTC_ADDRESS, addr | 0x80);
+            assert_eq!(read_port(&mut rtc, CmosRtc::RTC_ADDRESS), addr | 0x80);
+            assert_eq!(read_port(&mut rtc, CmosRtc::RTC_DATA), addr);
+        }
+    }
+
+    #[test]
+    fn test_nmi_mask() {
+        let (mut rtc, _) = define_test_rtc();
+        assert_eq!(read_port(&mut rtc, CmosRtc::RTC_ADDRESS) & 0x80, 0);
+
+        write_port(&mut rtc, CmosRtc::RTC_ADDRESS, 0x80 | 0x40);
+        assert!(rtc.nmi_disabled);
+        assert_eq!(read_port(&mut rtc, CmosRtc::RTC_ADDRESS), 0x80 | 0x40);
+
+        write_port(&mut rtc, CmosRtc::RTC_ADDRESS, 0x40);
+        assert!(!rtc.nmi_disabled);
+        assert_eq!(read_port(&mut rtc, CmosRtc::RTC_ADDRESS), 0x40);
+    }
+
+    #[test]
+    fn test_reset_preserves_nvram() {
+        let (mut rtc, clock) = define_test_rtc();
+
+        write_reg(&mut rtc, CmosRegister::Equipment, 0x06);
+        write_reg(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            STATUS_B_24H | STATUS_B_UIE,
+        );
+        clock.advance(SECOND);
+        rtc.reset().unwrap();
+
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Equipment), 0x06);
+        assert_eq!(
+            read_reg(&mut rtc, CmosRegister::StatusRegisterB),
+            STATUS_B_24H
+        );
+        assert_eq!(read_reg(&mut rtc, CmosRegister::StatusRegisterC), 0);
+        assert_eq!(read_reg(&mut rtc, CmosRegister::Seconds), 0x27);
+    }
 }
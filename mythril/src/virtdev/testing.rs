@@ -0,0 +1,310 @@
+//! Helpers shared by the unit tests of the virtual devices
+
+use crate::error::Result;
+use crate::memory::{
+    GuestAccess, GuestAddressSpace, GuestAddressSpaceView, GuestPhysAddr,
+    GuestVirtAddr, PrivilegeLevel,
+};
+use crate::virtdev::virtio::queue::Virtqueue;
+use crate::virtdev::{
+    DeviceEvent, EmulatedDevice, Event, MemReadRequest, MemWriteRequest, Port,
+    PortReadRequest, PortWriteRequest, ResponseEventArray,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+/// Returns a guest address space with `pages` pages of memory mapped from
+/// address zero
+pub fn define_test_space(pages: u64) -> &'static GuestAddressSpace {
+    let space: &'static mut GuestAddressSpace =
+        Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+    for page in 0..pages {
+        space
+            .map_new_frame(GuestPhysAddr::new(page * 0x1000), false)
+            .unwrap();
+    }
+    space
+}
+
+/// Returns a view of the given address space, without paging
+pub fn view_of(space: &GuestAddressSpace) -> GuestAddressSpaceView {
+    GuestAddressSpaceView::new(GuestPhysAddr::new(0), space)
+}
+
+/// Returns a view of a guest address space with no memory
+pub fn define_test_view() -> GuestAddressSpaceView<'static> {
+    view_of(define_test_space(0))
+}
+
+/// Returns a view of a guest address space with `pages` pages of memory
+/// mapped from address zero
+pub fn define_mapped_test_view(pages: u64) -> GuestAddressSpaceView<'static> {
+    view_of(define_test_space(pages))
+}
+
+/// Write `data` to guest memory at the given physical address
+pub fn guest_write(space: &GuestAddressSpaceView, addr: u64, data: &[u8]) {
+    space
+        .write_bytes(
+            GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+            data,
+            GuestAccess::Write(PrivilegeLevel(0)),
+        )
+        .unwrap();
+}
+
+/// Read `len` bytes of guest memory at the given physical address
+pub fn guest_read(
+    space: &GuestAddressSpaceView,
+    addr: u64,
+    len: usize,
+) -> Vec<u8> {
+    space
+        .read_bytes(
+            GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+            len,
+            GuestAccess::Read(PrivilegeLevel(0)),
+        )
+        .unwrap()
+}
+
+/// Pass an event of the given kind to `handler`, returning the responses
+///
+/// This allows events to be sent to things other than an `EmulatedDevice`
+/// (e.g., the BAR of a PCI device).
+pub fn handle_event(
+    kind: DeviceEvent,
+    space: GuestAddressSpaceView,
+    handler: impl FnOnce(Event) -> Result<()>,
+) -> ResponseEventArray {
+    let mut responses = ResponseEventArray::default();
+    let event = Event::new(kind, space, &mut responses).unwrap();
+    handler(event).unwrap();
+    responses
+}
+
+/// Send an event of the given kind to a device, returning the responses
+pub fn send_event(
+    device: &mut impl EmulatedDevice,
+    kind: DeviceEvent,
+) -> ResponseEventArray {
+    handle_event(kind, define_test_view(), |event| device.on_event(event))
+}
+
+/// Write the low `len` bytes of `value` to a port
+///
+/// The bytes are sent in the order used by `emulate::portio` (i.e., the
+/// most significant byte first).
+pub fn port_write(
+    device: &mut impl EmulatedDevice,
+    port: Port,
+    value: u32,
+    len: usize,
+) -> ResponseEventArray {
+    let data = value.to_be_bytes();
+    let request = PortWriteRequest::try_from(&data[4 - len..]).unwrap();
+    send_event(device, DeviceEvent::PortWrite(port, request))
+}
+
+/// Read `len` bytes from a port (in the order used by `emulate::portio`)
+pub fn port_read(
+    device: &mut impl EmulatedDevice,
+    port: Port,
+    len: usize,
+) -> u32 {
+    let mut data = [0u8; 4];
+    let request = PortReadRequest::try_from(&mut data[4 - len..]).unwrap();
+    send_event(device, DeviceEvent::PortRead(port, request));
+    u32::from_be_bytes(data)
+}
+
+/// Write the low `len` bytes of `value` to memory at the given address,
+/// passing the event to `handler`
+///
+/// The bytes are sent in the order used by `emulate::memio` (i.e., the
+/// most significant byte first).
+pub fn mem_write(
+    space: GuestAddressSpaceView,
+    addr: u64,
+    value: u64,
+    len: usize,
+    handler: impl FnOnce(Event) -> Result<()>,
+) -> ResponseEventArray {
+    let data = value.to_be_bytes();
+    let request = MemWriteRequest::new(&data[8 - len..]);
+    let kind = DeviceEvent::MemWrite(GuestPhysAddr::new(addr), request);
+    handle_event(kind, space, handler)
+}
+
+/// Read `len` bytes of memory at the given address, passing the event to
+/// `handler`
+///
+/// The bytes are decoded as little endian, as they are filled in by the
+/// devices.
+pub fn mem_read(
+    space: GuestAddressSpaceView,
+    addr: u64,
+    len: usize,
+    handler: impl FnOnce(Event) -> Result<()>,
+) -> (u64, ResponseEventArray) {
+    let mut data = [0u8; 8];
+    let request = MemReadRequest::new(&mut data[..len]);
+    let kind = DeviceEvent::MemRead(GuestPhysAddr::new(addr), request);
+    let responses = handle_event(kind, space, handler);
+    (u64::from_le_bytes(data), responses)
+}
+
+/// Descriptor flag: the buffer continues in the `next` descriptor
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+
+/// Descriptor flag: the buffer is written by the device
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// The number of pages of guest memory used by the `TestVirtqueue`s of a
+/// device with up to eight queues
+pub const VIRTQUEUE_TEST_PAGES: u64 = 0x50;
+
+// The rings of queue `n` are in the page at `VIRTQUEUE_RINGS + n * 0x1000`,
+// and each slot of the queue has a page for its buffer after
+// `VIRTQUEUE_BUFFERS + n * 0x8000`
+const VIRTQUEUE_RINGS: u64 = 0x1000;
+const VIRTQUEUE_BUFFERS: u64 = 0x10000;
+const VIRTQUEUE_MAX_SLOTS: u16 = 8;
+
+/// A virtqueue in guest memory, used by tests acting as the driver of a
+/// virtio device
+pub struct TestVirtqueue {
+    /// The number of entries in the queue
+    pub size: u16,
+    /// The guest physical address of the descriptor table
+    pub desc_table: u64,
+    /// The guest physical address of the available (driver) ring
+    pub avail_ring: u64,
+    /// The guest physical address of the used (device) ring
+    pub used_ring: u64,
+    buffers: u64,
+}
+
+impl TestVirtqueue {
+    /// The layout of the queue with the given index, with `size` entries
+    pub fn new(index: u16, size: u16) -> Self {
+        assert!(size <= VIRTQUEUE_MAX_SLOTS);
+        let rings = VIRTQUEUE_RINGS + index as u64 * 0x1000;
+        Self {
+            size,
+            desc_table: rings,
+            avail_ring: rings + 0x800,
+            used_ring: rings + 0xc00,
+            buffers: VIRTQUEUE_BUFFERS + index as u64 * 0x8000,
+        }
+    }
+
+    /// The layouts of the first `count` queues of a device (each with
+    /// `size` entries), and the matching device queues
+    pub fn queues(count: u16, size: u16) -> (Vec<Self>, Vec<Virtqueue>) {
+        let layouts: Vec<_> =
+            (0..count).map(|index| Self::new(index, size)).collect();
+        let queues = layouts.iter().map(Self::virtqueue).collect();
+        (layouts, queues)
+    }
+
+    /// A device queue with this layout, as if the driver had configured
+    /// and enabled it
+    pub fn virtqueue(&self) -> Virtqueue {
+        Virtqueue::configured(
+            self.size,
+            self.desc_table,
+            self.avail_ring,
+            self.used_ring,
+        )
+    }
+
+    /// The guest physical address of the (page sized) buffer of a slot
+    pub fn buffer_addr(&self, slot: u16) -> u64 {
+        self.buffers + slot as u64 * 0x1000
+    }
+
+    /// Write the descriptor with the given index
+    pub fn write_desc(
+        &self,
+        space: &GuestAddressSpaceView,
+        index: u16,
+        addr: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let mut desc = [0u8; 16];
+        LittleEndian::write_u64(&mut desc[0..8], addr);
+        LittleEndian::write_u32(&mut desc[8..12], len);
+        LittleEndian::write_u16(&mut desc[12..14], flags);
+        LittleEndian::write_u16(&mut desc[14..16], next);
+        guest_write(space, self.desc_table + index as u64 * 16, &desc);
+    }
+
+    /// Make the chain starting at descriptor `head` available as the
+    /// `slot`th entry of the available ring
+    pub fn make_available(
+        &self,
+        space: &GuestAddressSpaceView,
+        slot: u16,
+        head: u16,
+    ) {
+        let entry = self.avail_ring + 4 + (slot % self.size) as u64 * 2;
+        guest_write(space, entry, &head.to_le_bytes());
+        guest_write(space, self.avail_ring + 2, &(slot + 1).to_le_bytes());
+    }
+
+    /// Set the flags of the available ring
+    pub fn set_avail_flags(&self, space: &GuestAddressSpaceView, flags: u16) {
+        guest_write(space, self.avail_ring, &flags.to_le_bytes());
+    }
+
+    /// Make a single buffer available as the `slot`th entry of the queue,
+    /// using the descriptor and buffer of the slot
+    ///
+    /// If `writable_len` is zero, the buffer contains `data` and is read by
+    /// the device. Otherwise it is a device writable buffer of that length.
+    /// Returns the guest physical address of the buffer.
+    pub fn add_buffer(
+        &self,
+        space: &GuestAddressSpaceView,
+        slot: u16,
+        data: &[u8],
+        writable_len: u32,
+    ) -> u64 {
+        let addr = self.buffer_addr(slot);
+        let index = slot % self.size;
+        if writable_len > 0 {
+            let flags = VIRTQ_DESC_F_WRITE;
+            self.write_desc(space, index, addr, writable_len, flags, 0);
+        } else {
+            guest_write(space, addr, data);
+            self.write_desc(space, index, addr, data.len() as u32, 0, 0);
+        }
+        self.make_available(space, slot, index);
+        addr
+    }
+
+    /// The number of buffers the device has returned to the driver
+    pub fn used_count(&self, space: &GuestAddressSpaceView) -> u16 {
+        LittleEndian::read_u16(&guest_read(space, self.used_ring + 2, 2))
+    }
+
+    /// The head of the descriptor chain and the length written by the
+    /// device of the `index`th used buffer
+    pub fn used_elem(
+        &self,
+        space: &GuestAddressSpaceView,
+        index: u16,
+    ) -> (u32, u32) {
+        let elem = self.used_ring + 4 + (index % self.size) as u64 * 8;
+        let elem = guest_read(space, elem, 8);
+        (
+            LittleEndian::read_u32(&elem[0..4]),
+            LittleEndian::read_u32(&elem[4..8]),
+        )
+    }
+}
@@ -143,4 +143,9 @@ impl EmulatedDevice for VgaController {
         }
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
 }
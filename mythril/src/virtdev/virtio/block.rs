@@ -0,0 +1,487 @@
+//! A virtio block device (see section 5.2 of the virtio 1.1 specification)
+//!
+//! The contents of the device are provided by a disk image (typically a
+//! multiboot module), which is never modified. The device is either
+//! read-only, or guest writes are kept in an in-memory copy-on-write
+//! overlay. The overlay is preserved across guest resets, but is lost when
+//! mythril itself restarts.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceView;
+use crate::virtdev::virtio::queue::{DescriptorChain, Virtqueue};
+use crate::virtdev::virtio::{read_config_bytes, VirtioDevice};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+const VIRTIO_ID_BLOCK: u16 = 2;
+
+// PCI class and subclass of a 'SCSI mass storage controller'
+const CLASS_MASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SCSI: u8 = 0x00;
+
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+/// The size of a virtio block device sector
+pub const SECTOR_SIZE: usize = 512;
+
+const QUEUE_SIZE: u16 = 128;
+const REQUEST_QUEUE: u16 = 0;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status values
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// The request header is the type (u32), a reserved field (u32) and the
+// first sector of the request (u64)
+const REQUEST_HEADER_SIZE: usize = 16;
+
+const SERIAL_SIZE: usize = 20;
+
+// The layout of the device configuration
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SEG_MAX: usize = 12;
+const CONFIG_SIZE: usize = 16;
+
+/// A virtio block device backed by a disk image
+pub struct VirtioBlock {
+    image: &'static [u8],
+    serial: [u8; SERIAL_SIZE],
+
+    // The sectors written by the guest (or None if the device is read-only)
+    overlay: Option<BTreeMap<u64, Box<[u8; SECTOR_SIZE]>>>,
+}
+
+impl VirtioBlock {
+    fn new(serial: &str, image: &'static [u8], writable: bool) -> Self {
+        let mut serial_bytes = [0u8; SERIAL_SIZE];
+        let len = serial.len().min(SERIAL_SIZE);
+        serial_bytes[..len].copy_from_slice(&serial.as_bytes()[..len]);
+        Self {
+            image,
+            serial: serial_bytes,
+            overlay: if writable {
+                Some(BTreeMap::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Create a read-only block device with the contents of `image`
+    ///
+    /// The `serial` is reported to the guest as the device ID (truncated
+    /// to 20 bytes).
+    pub fn read_only(serial: &str, image: &'static [u8]) -> Self {
+        Self::new(serial, image, false)
+    }
+
+    /// Create a writable block device initialized with the contents of
+    /// `image`, where guest writes are kept in memory
+    pub fn copy_on_write(serial: &str, image: &'static [u8]) -> Self {
+        Self::new(serial, image, true)
+    }
+
+    /// The size of the device in sectors. Any partial sector at the end of
+    /// the image is padded with zeros.
+    pub fn capacity(&self) -> u64 {
+        ((self.image.len() + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64
+    }
+
+    // Whether the `len` bytes starting at `sector` are a whole number of
+    // sectors within the device
+    fn is_valid_range(&self, sector: u64, len: usize) -> bool {
+        if len % SECTOR_SIZE != 0 {
+            return false;
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) => end <= self.capacity(),
+            None => false,
+        }
+    }
+
+    fn read_sector(&self, sector: u64, data: &mut [u8]) {
+        if let Some(written) = self
+            .overlay
+            .as_ref()
+            .and_then(|overlay| overlay.get(&sector))
+        {
+            data.copy_from_slice(&written[..]);
+            return;
+        }
+
+        let start = sector as usize * SECTOR_SIZE;
+        let end = self.image.len().min(start + SECTOR_SIZE);
+        data[..end - start].copy_from_slice(&self.image[start..end]);
+        for byte in data[end - start..].iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn read(&self, sector: u64, len: usize) -> Option<Vec<u8>> {
+        if !self.is_valid_range(sector, len) {
+            return None;
+        }
+        let mut data = vec![0u8; len];
+        for (i, chunk) in data.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            self.read_sector(sector + i as u64, chunk);
+        }
+        Some(data)
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> u8 {
+        if !self.is_valid_range(sector, data.len()) {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let overlay = match self.overlay.as_mut() {
+            Some(overlay) => overlay,
+            None => return VIRTIO_BLK_S_IOERR,
+        };
+        for (i, chunk) in data.chunks_exact(SECTOR_SIZE).enumerate() {
+            let mut written = Box::new([0u8; SECTOR_SIZE]);
+            written.copy_from_slice(chunk);
+            overlay.insert(sector + i as u64, written);
+        }
+        VIRTIO_BLK_S_OK
+    }
+
+    // Perform a single request, returning the number of bytes written to
+    // the request buffers (including the status byte)
+    fn process_request(
+        &mut self,
+        chain: &DescriptorChain,
+        space: &GuestAddressSpaceView,
+    ) -> Result<u32> {
+        let request = chain.read_all(space)?;
+        if request.len() < REQUEST_HEADER_SIZE || chain.writable_len() == 0 {
+            return Err(Error::InvalidValue(
+                "Malformed virtio block request".into(),
+            ));
+        }
+
+        let kind = LittleEndian::read_u32(&request[0..4]);
+        let sector = LittleEndian::read_u64(&request[8..16]);
+
+        // The status is the last byte of the writable buffers
+        let status_offset = chain.writable_len() - 1;
+        let (status, written) = match kind {
+            VIRTIO_BLK_T_IN => match self.read(sector, status_offset) {
+                Some(data) => {
+                    (VIRTIO_BLK_S_OK, chain.write_at(space, 0, &data)?)
+                }
+                None => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_OUT => {
+                (self.write(sector, &request[REQUEST_HEADER_SIZE..]), 0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let len = status_offset.min(SERIAL_SIZE);
+                let written = chain.write_at(space, 0, &self.serial[..len])?;
+                (VIRTIO_BLK_S_OK, written)
+            }
+            _ => {
+                debug!("virtio-blk: unsupported request type {}", kind);
+                (VIRTIO_BLK_S_UNSUPP, 0)
+            }
+        };
+
+        chain.write_at(space, status_offset, &[status])?;
+        Ok(written as u32 + 1)
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn class(&self) -> (u8, u8) {
+        (CLASS_MASS_STORAGE, SUBCLASS_SCSI)
+    }
+
+    fn features(&self) -> u64 {
+        if self.overlay.is_some() {
+            VIRTIO_BLK_F_SEG_MAX
+        } else {
+            VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_RO
+        }
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u64(
+            &mut config[CONFIG_CAPACITY..],
+            self.capacity(),
+        );
+
+        // Each request needs a descriptor for its header and status
+        LittleEndian::write_u32(
+            &mut config[CONFIG_SEG_MAX..],
+            QUEUE_SIZE as u32 - 2,
+        );
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    fn on_queue_notify(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        if queue != REQUEST_QUEUE {
+            return Ok(());
+        }
+
+        let queue = &mut queues[REQUEST_QUEUE as usize];
+        while let Some(chain) = queue.pop(space)? {
+            let len = self.process_request(&chain, space)?;
+            queue.add_used(space, chain.head(), len)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{
+        define_mapped_test_view, guest_read, guest_write, TestVirtqueue,
+        VIRTQUEUE_TEST_PAGES, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    };
+
+    const TEST_QUEUE_SIZE: u16 = 8;
+
+    fn test_image() -> &'static [u8] {
+        // Two and a half sectors, each filled with its sector number
+        let image: Vec<u8> = (0..SECTOR_SIZE * 5 / 2)
+            .map(|i| (i / SECTOR_SIZE) as u8)
+            .collect();
+        Box::leak(image.into_boxed_slice())
+    }
+
+    fn test_queue() -> (TestVirtqueue, Vec<Virtqueue>) {
+        let (mut layout, queues) = TestVirtqueue::queues(1, TEST_QUEUE_SIZE);
+        (layout.remove(0), queues)
+    }
+
+    // The buffers of a request: the header, an optional data buffer and
+    // the status byte
+    struct TestRequest {
+        header: u64,
+        data: u64,
+        status: u64,
+    }
+
+    // Make a request available as the `index`th request of the queue. For
+    // reads, `data` is only used for its length.
+    fn add_request(
+        space: &GuestAddressSpaceView,
+        queue: &TestVirtqueue,
+        index: u16,
+        kind: u32,
+        sector: u64,
+        data: &[u8],
+    ) -> TestRequest {
+        let base = queue.buffer_addr(index);
+        let request = TestRequest {
+            header: base,
+            data: base + 0x100,
+            status: base + 0xf00,
+        };
+
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], kind);
+        LittleEndian::write_u64(&mut header[8..16], sector);
+        guest_write(space, request.header, &header);
+
+        let data_flags = if kind == VIRTIO_BLK_T_OUT {
+            guest_write(space, request.data, data);
+            VIRTQ_DESC_F_NEXT
+        } else {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        };
+
+        let mut buffers = vec![(
+            request.header,
+            REQUEST_HEADER_SIZE as u32,
+            VIRTQ_DESC_F_NEXT,
+        )];
+        if !data.is_empty() {
+            buffers.push((request.data, data.len() as u32, data_flags));
+        }
+        buffers.push((request.status, 1, VIRTQ_DESC_F_WRITE));
+
+        let first = index * 3;
+        for (i, (addr, len, flags)) in buffers.iter().enumerate() {
+            let desc = first + i as u16;
+            queue.write_desc(space, desc, *addr, *len, *flags, desc + 1);
+        }
+        queue.make_available(space, index, first);
+        request
+    }
+
+    fn process(
+        device: &mut VirtioBlock,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) {
+        device
+            .on_queue_notify(REQUEST_QUEUE, queues, space)
+            .unwrap();
+    }
+
+    fn status(space: &GuestAddressSpaceView, request: &TestRequest) -> u8 {
+        guest_read(space, request.status, 1)[0]
+    }
+
+    #[test]
+    fn test_config() {
+        let device = VirtioBlock::read_only("disk", test_image());
+        assert_eq!(device.capacity(), 3);
+        assert_ne!(device.features() & VIRTIO_BLK_F_RO, 0);
+
+        let mut data = [0u8; 8];
+        device.read_config(CONFIG_CAPACITY as u64, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 3);
+
+        let device = VirtioBlock::copy_on_write("disk", test_image());
+        assert_eq!(device.features() & VIRTIO_BLK_F_RO, 0);
+    }
+
+    #[test]
+    fn test_read() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device = VirtioBlock::read_only("disk", test_image());
+        let (layout, mut queues) = test_queue();
+
+        let buffer = [0xffu8; SECTOR_SIZE * 2];
+        let request =
+            add_request(&space, &layout, 0, VIRTIO_BLK_T_IN, 1, &buffer);
+        process(&mut device, &mut queues, &space);
+
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_OK);
+        let data = guest_read(&space, request.data, SECTOR_SIZE * 2);
+        assert!(data[..SECTOR_SIZE].iter().all(|byte| *byte == 1));
+
+        // The end of the image is padded with zeros
+        assert!(data[SECTOR_SIZE..SECTOR_SIZE * 3 / 2]
+            .iter()
+            .all(|byte| *byte == 2));
+        assert!(data[SECTOR_SIZE * 3 / 2..].iter().all(|byte| *byte == 0));
+
+        let (_, used_len) = layout.used_elem(&space, 0);
+        assert_eq!(used_len, SECTOR_SIZE as u32 * 2 + 1);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device = VirtioBlock::read_only("disk", test_image());
+        let (layout, mut queues) = test_queue();
+
+        let data = [0xaau8; SECTOR_SIZE];
+        let request =
+            add_request(&space, &layout, 0, VIRTIO_BLK_T_OUT, 0, &data);
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let image = test_image();
+        let mut device = VirtioBlock::copy_on_write("disk", image);
+        let (layout, mut queues) = test_queue();
+
+        let data = [0xaau8; SECTOR_SIZE];
+        let request =
+            add_request(&space, &layout, 0, VIRTIO_BLK_T_OUT, 2, &data);
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_OK);
+
+        // Writes survive a reset, and never modify the image
+        device.reset().unwrap();
+        let request = add_request(
+            &space,
+            &layout,
+            1,
+            VIRTIO_BLK_T_IN,
+            2,
+            &[0u8; SECTOR_SIZE],
+        );
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_OK);
+        assert_eq!(guest_read(&space, request.data, SECTOR_SIZE), &data[..]);
+        assert_eq!(image[SECTOR_SIZE * 2], 2);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device = VirtioBlock::copy_on_write("disk", test_image());
+        let (layout, mut queues) = test_queue();
+
+        // Past the end of the device
+        let request = add_request(
+            &space,
+            &layout,
+            0,
+            VIRTIO_BLK_T_IN,
+            3,
+            &[0u8; SECTOR_SIZE],
+        );
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_IOERR);
+
+        // Not a whole number of sectors
+        let request =
+            add_request(&space, &layout, 1, VIRTIO_BLK_T_OUT, 0, &[0u8; 16]);
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_IOERR);
+
+        let request = add_request(&space, &layout, 2, 0x1234, 0, &[]);
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_UNSUPP);
+    }
+
+    #[test]
+    fn test_get_id() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device =
+            VirtioBlock::read_only("a-very-long-disk-serial", test_image());
+        let (layout, mut queues) = test_queue();
+
+        let request = add_request(
+            &space,
+            &layout,
+            0,
+            VIRTIO_BLK_T_GET_ID,
+            0,
+            &[0u8; SERIAL_SIZE],
+        );
+        process(&mut device, &mut queues, &space);
+        assert_eq!(status(&space, &request), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            guest_read(&space, request.data, SERIAL_SIZE),
+            b"a-very-long-disk-ser"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+//! A virtio console device (see section 5.3 of the virtio 1.1 specification)
+//!
+//! Output written to any port is sent to the host console (along with the
+//! output of the emulated UART), and input from the host console is
+//! delivered to the first port, which the guest uses as its console (e.g.,
+//! `console=hvc0` for linux).
+
+use crate::console;
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceView;
+use crate::virtdev::virtio::queue::Virtqueue;
+use crate::virtdev::virtio::{read_config_bytes, VirtioDevice};
+use crate::virtdev::DeviceEvent;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+const VIRTIO_ID_CONSOLE: u16 = 3;
+
+// PCI class and subclass of a 'simple communication controller'
+const CLASS_COMMUNICATION: u8 = 0x07;
+const SUBCLASS_OTHER: u8 = 0x80;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// The maximum number of ports of a console
+pub const MAX_CONSOLE_PORTS: u32 = 31;
+
+const QUEUE_SIZE: u16 = 128;
+
+// The queues of the first port and the control queues. The queues of the
+// other ports follow the control queues.
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const CONTROL_RECEIVE_QUEUE: u16 = 2;
+const CONTROL_TRANSMIT_QUEUE: u16 = 3;
+
+// Control message events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+const CONTROL_MESSAGE_SIZE: usize = 8;
+
+// The layout of the device configuration
+const CONFIG_MAX_NR_PORTS: usize = 4;
+const CONFIG_EMERG_WR: u64 = 8;
+const CONFIG_SIZE: usize = 12;
+
+// The amount of host input that is retained until the guest receives it
+const MAX_PENDING_INPUT: usize = 4096;
+
+/// A virtio console with one or more ports
+pub struct VirtioConsole {
+    vm_id: u32,
+    ports: u32,
+    queue_sizes: Vec<u16>,
+
+    // Host input that has not been received by the guest
+    input: VecDeque<u8>,
+
+    // Control messages that have not been received by the guest
+    control: VecDeque<[u8; CONTROL_MESSAGE_SIZE]>,
+}
+
+impl VirtioConsole {
+    /// Create a new console for the given VM
+    ///
+    /// If there is more than one port, the guest must support the
+    /// multiport feature to use anything but the first.
+    pub fn new(vm_id: u32, ports: u32) -> Result<Self> {
+        if ports == 0 || ports > MAX_CONSOLE_PORTS {
+            return Err(Error::InvalidValue(format!(
+                "Invalid virtio console port count: {}",
+                ports
+            )));
+        }
+
+        // The control queues only exist with the multiport feature
+        let queues = if ports > 1 { ports * 2 + 2 } else { 2 };
+        Ok(Self {
+            vm_id,
+            ports,
+            queue_sizes: vec![QUEUE_SIZE; queues as usize],
+            input: VecDeque::new(),
+            control: VecDeque::new(),
+        })
+    }
+
+    fn is_transmit_queue(queue: u16) -> bool {
+        queue == TRANSMIT_QUEUE
+            || (queue > CONTROL_TRANSMIT_QUEUE && queue % 2 == 1)
+    }
+
+    fn write_output(&self, data: &[u8]) {
+        console::write_guest_output_bytes(self.vm_id, data);
+    }
+
+    // Send any output the guest has written to the given queue
+    fn transmit(
+        &mut self,
+        queue: &mut Virtqueue,
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        while let Some(chain) = queue.pop(space)? {
+            let data = chain.read_all(space)?;
+            self.write_output(&data);
+            queue.add_used(space, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    // Copy pending input in to the receive buffers of the first port
+    fn receive(
+        &mut self,
+        queue: &mut Virtqueue,
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        while !self.input.is_empty() {
+            let chain = match queue.pop(space)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = chain.writable_len().min(self.input.len());
+            let data: Vec<u8> = self.input.drain(..len).collect();
+            chain.write_all(space, &data)?;
+            queue.add_used(space, chain.head(), len as u32)?;
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, port: u32, event: u16, value: u16) {
+        let mut message = [0u8; CONTROL_MESSAGE_SIZE];
+        LittleEndian::write_u32(&mut message[0..4], port);
+        LittleEndian::write_u16(&mut message[4..6], event);
+        LittleEndian::write_u16(&mut message[6..8], value);
+        self.control.push_back(message);
+    }
+
+    // Copy pending control messages in to the control receive buffers
+    fn flush_control(
+        &mut self,
+        queue: &mut Virtqueue,
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        while let Some(message) = self.control.front() {
+            let chain = match queue.pop(space)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = chain.write_all(space, message)?;
+            queue.add_used(space, chain.head(), len as u32)?;
+            self.control.pop_front();
+        }
+        Ok(())
+    }
+
+    // Handle the control messages sent by the guest
+    fn process_control(
+        &mut self,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        let queue = &mut queues[CONTROL_TRANSMIT_QUEUE as usize];
+        while let Some(chain) = queue.pop(space)? {
+            let message = chain.read_all(space)?;
+            queue.add_used(space, chain.head(), 0)?;
+            if message.len() < CONTROL_MESSAGE_SIZE {
+                return Err(Error::InvalidValue(
+                    "Virtio console control message is too short".into(),
+                ));
+            }
+
+            let port = LittleEndian::read_u32(&message[0..4]);
+            let event = LittleEndian::read_u16(&message[4..6]);
+            let value = LittleEndian::read_u16(&message[6..8]);
+            match event {
+                // The driver is ready to learn about the ports
+                VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                    for port in 0..self.ports {
+                        self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0);
+                    }
+                }
+                // The host side of every port is always open
+                VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                    if port == 0 {
+                        self.send_control(port, VIRTIO_CONSOLE_CONSOLE_PORT, 1);
+                    }
+                    self.send_control(port, VIRTIO_CONSOLE_PORT_OPEN, 1);
+                }
+                _ => {
+                    debug!(
+                        "virtio-console: control event {} for port {} (value={})",
+                        event, port, value
+                    );
+                }
+            }
+        }
+
+        self.flush_control(&mut queues[CONTROL_RECEIVE_QUEUE as usize], space)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn class(&self) -> (u8, u8) {
+        (CLASS_COMMUNICATION, SUBCLASS_OTHER)
+    }
+
+    fn features(&self) -> u64 {
+        if self.ports > 1 {
+            VIRTIO_CONSOLE_F_EMERG_WRITE | VIRTIO_CONSOLE_F_MULTIPORT
+        } else {
+            VIRTIO_CONSOLE_F_EMERG_WRITE
+        }
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u32(&mut config[CONFIG_MAX_NR_PORTS..], self.ports);
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Emergency writes can be used before the queues are set up. The
+        // character is the low-order byte of the field.
+        if offset == CONFIG_EMERG_WR && !data.is_empty() {
+            self.write_output(&[data[0]]);
+        }
+    }
+
+    fn on_queue_notify(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        match queue {
+            RECEIVE_QUEUE => {
+                self.receive(&mut queues[RECEIVE_QUEUE as usize], space)
+            }
+            CONTROL_RECEIVE_QUEUE => self.flush_control(
+                &mut queues[CONTROL_RECEIVE_QUEUE as usize],
+                space,
+            ),
+            CONTROL_TRANSMIT_QUEUE => self.process_control(queues, space),
+            queue if Self::is_transmit_queue(queue) => {
+                self.transmit(&mut queues[queue as usize], space)
+            }
+            // Input is only delivered to the first port
+            _ => Ok(()),
+        }
+    }
+
+    fn on_host_event(
+        &mut self,
+        kind: &DeviceEvent,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        match kind {
+            DeviceEvent::HostUartReceived(key) => {
+                if self.input.len() < MAX_PENDING_INPUT {
+                    self.input.push_back(*key);
+                }
+                self.receive(&mut queues[RECEIVE_QUEUE as usize], space)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.input.clear();
+        self.control.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{
+        define_mapped_test_view, guest_read, TestVirtqueue,
+        VIRTQUEUE_TEST_PAGES,
+    };
+
+    const TEST_QUEUE_SIZE: u16 = 8;
+
+    fn test_queues(
+        console: &VirtioConsole,
+    ) -> (Vec<TestVirtqueue>, Vec<Virtqueue>) {
+        let count = console.queue_sizes().len() as u16;
+        TestVirtqueue::queues(count, TEST_QUEUE_SIZE)
+    }
+
+    fn control_message(port: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = vec![0u8; CONTROL_MESSAGE_SIZE];
+        LittleEndian::write_u32(&mut message[0..4], port);
+        LittleEndian::write_u16(&mut message[4..6], event);
+        LittleEndian::write_u16(&mut message[6..8], value);
+        message
+    }
+
+    #[test]
+    fn test_queue_layout() {
+        let console = VirtioConsole::new(0, 1).unwrap();
+        assert_eq!(console.queue_sizes().len(), 2);
+        assert_eq!(console.features() & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+
+        let console = VirtioConsole::new(0, 3).unwrap();
+        assert_eq!(console.queue_sizes().len(), 8);
+        assert_ne!(console.features() & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+
+        let mut data = [0u8; 4];
+        console.read_config(CONFIG_MAX_NR_PORTS as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 3);
+
+        assert!(VirtioConsole::new(0, 0).is_err());
+        assert!(VirtioConsole::new(0, MAX_CONSOLE_PORTS + 1).is_err());
+    }
+
+    #[test]
+    fn test_transmit() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut console = VirtioConsole::new(0, 2).unwrap();
+        let (layout, mut queues) = test_queues(&console);
+        let transmit = &layout[TRANSMIT_QUEUE as usize];
+
+        transmit.add_buffer(&space, 0, b"hello", 0);
+        console
+            .on_queue_notify(TRANSMIT_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(transmit.used_count(&space), 1);
+        assert!(queues[TRANSMIT_QUEUE as usize].take_interrupt());
+
+        // The transmit queue of the second port
+        layout[5].add_buffer(&space, 0, b"world", 0);
+        console.on_queue_notify(5, &mut queues, &space).unwrap();
+        assert_eq!(layout[5].used_count(&space), 1);
+    }
+
+    #[test]
+    fn test_receive() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut console = VirtioConsole::new(0, 1).unwrap();
+        let (layout, mut queues) = test_queues(&console);
+        let receive = &layout[RECEIVE_QUEUE as usize];
+
+        // Input is retained until the guest provides a buffer
+        for key in b"ls\n" {
+            let event = DeviceEvent::HostUartReceived(*key);
+            console.on_host_event(&event, &mut queues, &space).unwrap();
+        }
+        assert_eq!(receive.used_count(&space), 0);
+
+        let addr = receive.add_buffer(&space, 0, &[], 2);
+        console
+            .on_queue_notify(RECEIVE_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(receive.used_count(&space), 1);
+        assert_eq!(guest_read(&space, addr, 2), b"ls");
+
+        let addr = receive.add_buffer(&space, 1, &[], 16);
+        console
+            .on_queue_notify(RECEIVE_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(receive.used_count(&space), 2);
+        assert_eq!(guest_read(&space, addr, 1), b"\n");
+    }
+
+    #[test]
+    fn test_multiport_control() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut console = VirtioConsole::new(0, 2).unwrap();
+        let (layout, mut queues) = test_queues(&console);
+        let control_receive = &layout[CONTROL_RECEIVE_QUEUE as usize];
+        let control_transmit = &layout[CONTROL_TRANSMIT_QUEUE as usize];
+        for slot in 0..4 {
+            control_receive.add_buffer(&space, slot, &[], 8);
+        }
+
+        let ready = control_message(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        control_transmit.add_buffer(&space, 0, &ready, 0);
+        console
+            .on_queue_notify(CONTROL_TRANSMIT_QUEUE, &mut queues, &space)
+            .unwrap();
+
+        // Each port is added
+        assert_eq!(control_receive.used_count(&space), 2);
+        for port in 0..2 {
+            assert_eq!(
+                guest_read(
+                    &space,
+                    control_receive.buffer_addr(port as u16),
+                    CONTROL_MESSAGE_SIZE
+                ),
+                control_message(port, VIRTIO_CONSOLE_DEVICE_ADD, 0)
+            );
+        }
+
+        // The first port is the console
+        let ready = control_message(0, VIRTIO_CONSOLE_PORT_READY, 1);
+        control_transmit.add_buffer(&space, 1, &ready, 0);
+        console
+            .on_queue_notify(CONTROL_TRANSMIT_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(control_receive.used_count(&space), 4);
+        assert_eq!(
+            guest_read(
+                &space,
+                control_receive.buffer_addr(2),
+                CONTROL_MESSAGE_SIZE
+            ),
+            control_message(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1)
+        );
+        assert_eq!(
+            guest_read(
+                &space,
+                control_receive.buffer_addr(3),
+                CONTROL_MESSAGE_SIZE
+            ),
+            control_message(0, VIRTIO_CONSOLE_PORT_OPEN, 1)
+        );
+    }
+}
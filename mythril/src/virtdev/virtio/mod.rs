@@ -0,0 +1,1081 @@
+//! Paravirtual devices using the virtio 1.x PCI transport
+//!
+//! See the virtio 1.1 specification for details. A concrete device
+//! implements `VirtioDevice`, and is wrapped in a `VirtioPciDevice` to be
+//! attached to the `PciRootComplex`. Only the modern (non-transitional)
+//! interface is supported.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceView;
+use crate::virtdev::msi::MsixCapability;
+use crate::virtdev::pci::{PciBar, PciConfigSpace, PciDevice};
+use crate::virtdev::{DeviceEvent, Event, ResponseEventArray};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+use queue::{Virtqueue, VIRTIO_MSI_NO_VECTOR};
+
+/// The PCI vendor id of all virtio devices
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
+// Modern devices use a PCI device id of 0x1040 plus the virtio device id
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+// Non-transitional devices have a revision of at least 1
+const VIRTIO_PCI_REVISION_ID: u8 = 1;
+const PCI_REVISION_ID_OFFSET: u16 = 0x08;
+
+/// The device complies with version 1 of the specification. This is offered
+/// by all devices, in addition to the device specific features.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+
+// Interrupt status bits
+const ISR_QUEUE: u8 = 1;
+const ISR_CONFIG: u8 = 2;
+
+// The virtio structures are described by vendor specific PCI capabilities
+const VIRTIO_PCI_CAPABILITY_ID: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// The layout of the BAR holding the virtio structures. Each structure is
+// in its own page.
+const VIRTIO_BAR: usize = 0;
+const VIRTIO_BAR_SIZE: u32 = 0x4000;
+const COMMON_CFG_OFFSET: u64 = 0x0000;
+const COMMON_CFG_SIZE: usize = 0x38;
+const ISR_CFG_OFFSET: u64 = 0x1000;
+const DEVICE_CFG_OFFSET: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_CFG_OFFSET: u64 = 0x3000;
+const NOTIFY_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+/// The maximum number of queues of a virtio device
+pub const MAX_VIRTQUEUE_COUNT: usize =
+    (NOTIFY_CFG_SIZE / NOTIFY_OFF_MULTIPLIER as u64) as usize;
+
+// The layout of the BAR holding the MSI-X table and PBA
+const MSIX_BAR: usize = 1;
+const MSIX_BAR_SIZE: u32 = 0x1000;
+const MSIX_TABLE_OFFSET: u32 = 0x000;
+const MSIX_PBA_OFFSET: u32 = 0x800;
+const MAX_MSIX_VECTORS: usize = MSIX_PBA_OFFSET as usize / 16;
+
+/// The offsets of the fields of the common configuration structure
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0c;
+    pub const MSIX_CONFIG: usize = 0x10;
+    pub const NUM_QUEUES: usize = 0x12;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const CONFIG_GENERATION: usize = 0x15;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR: usize = 0x1a;
+    pub const QUEUE_ENABLE: usize = 0x1c;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1e;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+}
+
+/// A device that is exposed to the guest through the virtio PCI transport
+pub trait VirtioDevice: Send + Sync {
+    /// The virtio device id (e.g., 3 for a console)
+    fn device_type(&self) -> u16;
+
+    /// The PCI class and subclass of the device
+    fn class(&self) -> (u8, u8) {
+        (0xff, 0x00)
+    }
+
+    /// The device specific feature bits offered to the driver
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// The maximum size of each of the queues of the device
+    fn queue_sizes(&self) -> &[u16];
+
+    /// Read from the device specific configuration structure
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Write to the device specific configuration structure (`data` is
+    /// in little endian order)
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Called once the driver has finished initializing the device, with
+    /// the features it accepted
+    fn activate(&mut self, _features: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Process the buffers made available by the driver on the given queue
+    fn on_queue_notify(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()>;
+
+    /// Handle an event from the host (e.g., console input). Note that this
+    /// may be called before the driver has enabled the queues.
+    fn on_host_event(
+        &mut self,
+        _kind: &DeviceEvent,
+        _queues: &mut [Virtqueue],
+        _space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Return the device to its initial state (when the driver resets it,
+    /// or as part of a guest restart)
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Copy a device configuration structure in to a guest read at `offset`.
+/// Bytes past the end of the structure read as zero.
+pub fn read_config_bytes(config: &[u8], offset: u64, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+    }
+}
+
+/// A virtio device attached to the PCI bus
+pub struct VirtioPciDevice {
+    config: PciConfigSpace,
+    msix: MsixCapability,
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    msix_config: u16,
+    status: u8,
+    config_generation: u8,
+    queue_select: u16,
+    isr: u8,
+}
+
+impl VirtioPciDevice {
+    /// Create the PCI function for the given virtio device
+    pub fn new(device: Box<dyn VirtioDevice>) -> Result<Self> {
+        let queue_count = device.queue_sizes().len();
+        if queue_count == 0 || queue_count > MAX_VIRTQUEUE_COUNT {
+            return Err(Error::InvalidValue(format!(
+                "Invalid virtio queue count: {}",
+                queue_count
+            )));
+        }
+        if let Some(size) = device.queue_sizes().iter().find(|size| {
+            !size.is_power_of_two() || **size > queue::MAX_QUEUE_SIZE
+        }) {
+            return Err(Error::InvalidValue(format!(
+                "Invalid virtio queue size: {}",
+                size
+            )));
+        }
+
+        let (class, subclass) = device.class();
+        let mut config = PciConfigSpace::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device.device_type(),
+            class,
+            subclass,
+        );
+        config.set_u8(PCI_REVISION_ID_OFFSET, VIRTIO_PCI_REVISION_ID);
+        config.set_interrupt_pin(1);
+        config.set_bar(
+            VIRTIO_BAR,
+            PciBar::Memory32 {
+                size: VIRTIO_BAR_SIZE,
+                prefetchable: false,
+            },
+        )?;
+        config.set_bar(
+            MSIX_BAR,
+            PciBar::Memory32 {
+                size: MSIX_BAR_SIZE,
+                prefetchable: false,
+            },
+        )?;
+
+        Self::add_virtio_capability(
+            &mut config,
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            COMMON_CFG_OFFSET,
+            COMMON_CFG_SIZE as u32,
+            &[],
+        )?;
+        Self::add_virtio_capability(
+            &mut config,
+            VIRTIO_PCI_CAP_NOTIFY_CFG,
+            NOTIFY_CFG_OFFSET,
+            queue_count as u32 * NOTIFY_OFF_MULTIPLIER,
+            &NOTIFY_OFF_MULTIPLIER.to_le_bytes(),
+        )?;
+        Self::add_virtio_capability(
+            &mut config,
+            VIRTIO_PCI_CAP_ISR_CFG,
+            ISR_CFG_OFFSET,
+            1,
+            &[],
+        )?;
+        Self::add_virtio_capability(
+            &mut config,
+            VIRTIO_PCI_CAP_DEVICE_CFG,
+            DEVICE_CFG_OFFSET,
+            DEVICE_CFG_SIZE as u32,
+            &[],
+        )?;
+
+        // One vector per queue, plus one for configuration changes
+        let vectors = (queue_count + 1).min(MAX_MSIX_VECTORS);
+        let msix = MsixCapability::new(
+            &mut config,
+            vectors as u16,
+            MSIX_BAR,
+            MSIX_TABLE_OFFSET,
+            MSIX_PBA_OFFSET,
+        )?;
+
+        let queues = device
+            .queue_sizes()
+            .iter()
+            .map(|size| Virtqueue::new(*size))
+            .collect();
+
+        Ok(Self {
+            config,
+            msix,
+            device,
+            queues,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            msix_config: VIRTIO_MSI_NO_VECTOR,
+            status: 0,
+            config_generation: 0,
+            queue_select: 0,
+            isr: 0,
+        })
+    }
+
+    // Describe the location of one of the virtio structures in the BAR
+    fn add_virtio_capability(
+        config: &mut PciConfigSpace,
+        cfg_type: u8,
+        offset: u64,
+        length: u32,
+        extra: &[u8],
+    ) -> Result<u16> {
+        let mut data = vec![0u8; 14];
+        data[0] = (data.len() + extra.len() + 2) as u8;
+        data[1] = cfg_type;
+        data[2] = VIRTIO_BAR as u8;
+        data[6..10].copy_from_slice(&(offset as u32).to_le_bytes());
+        data[10..14].copy_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(extra);
+        config.add_capability(VIRTIO_PCI_CAPABILITY_ID, &data, &[])
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    // Only vectors that exist in the MSI-X table can be assigned
+    fn valid_vector(&self, vector: u16) -> u16 {
+        if (vector as usize) < (self.queues.len() + 1).min(MAX_MSIX_VECTORS) {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn common_config(&self) -> [u8; COMMON_CFG_SIZE] {
+        let mut bytes = [0u8; COMMON_CFG_SIZE];
+        let feature_word = |features: u64, select: u32| match select {
+            0 => features as u32,
+            1 => (features >> 32) as u32,
+            _ => 0,
+        };
+
+        LittleEndian::write_u32(
+            &mut bytes[common::DEVICE_FEATURE_SELECT..],
+            self.device_feature_select,
+        );
+        LittleEndian::write_u32(
+            &mut bytes[common::DEVICE_FEATURE..],
+            feature_word(self.device_features(), self.device_feature_select),
+        );
+        LittleEndian::write_u32(
+            &mut bytes[common::DRIVER_FEATURE_SELECT..],
+            self.driver_feature_select,
+        );
+        LittleEndian::write_u32(
+            &mut bytes[common::DRIVER_FEATURE..],
+            feature_word(self.driver_features, self.driver_feature_select),
+        );
+        LittleEndian::write_u16(
+            &mut bytes[common::MSIX_CONFIG..],
+            self.msix_config,
+        );
+        LittleEndian::write_u16(
+            &mut bytes[common::NUM_QUEUES..],
+            self.queues.len() as u16,
+        );
+        bytes[common::DEVICE_STATUS] = self.status;
+        bytes[common::CONFIG_GENERATION] = self.config_generation;
+        LittleEndian::write_u16(
+            &mut bytes[common::QUEUE_SELECT..],
+            self.queue_select,
+        );
+
+        // The queue fields read as zero if no queue is selected
+        if let Some(queue) = self.queues.get(self.queue_select as usize) {
+            LittleEndian::write_u16(
+                &mut bytes[common::QUEUE_SIZE..],
+                queue.size,
+            );
+            LittleEndian::write_u16(
+                &mut bytes[common::QUEUE_MSIX_VECTOR..],
+                queue.msix_vector,
+            );
+            LittleEndian::write_u16(
+                &mut bytes[common::QUEUE_ENABLE..],
+                queue.ready as u16,
+            );
+            LittleEndian::write_u16(
+                &mut bytes[common::QUEUE_NOTIFY_OFF..],
+                self.queue_select,
+            );
+            LittleEndian::write_u64(
+                &mut bytes[common::QUEUE_DESC..],
+                queue.desc_table,
+            );
+            LittleEndian::write_u64(
+                &mut bytes[common::QUEUE_DRIVER..],
+                queue.avail_ring,
+            );
+            LittleEndian::write_u64(
+                &mut bytes[common::QUEUE_DEVICE..],
+                queue.used_ring,
+            );
+        }
+        bytes
+    }
+
+    fn write_common_config(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let end = offset + data.len();
+        if end > COMMON_CFG_SIZE {
+            return Ok(());
+        }
+
+        // Merge the write with the current values, so the driver can use
+        // any access width (e.g., dword writes to the 64 bit fields)
+        let mut bytes = self.common_config();
+        bytes[offset..end].copy_from_slice(data);
+        let written =
+            |field: usize, len: usize| offset < field + len && field < end;
+
+        if written(common::DEVICE_FEATURE_SELECT, 4) {
+            self.device_feature_select =
+                LittleEndian::read_u32(&bytes[common::DEVICE_FEATURE_SELECT..]);
+        }
+        if written(common::DRIVER_FEATURE_SELECT, 4) {
+            self.driver_feature_select =
+                LittleEndian::read_u32(&bytes[common::DRIVER_FEATURE_SELECT..]);
+        }
+        if written(common::DRIVER_FEATURE, 4) && self.driver_feature_select < 2
+        {
+            let shift = self.driver_feature_select * 32;
+            let value =
+                LittleEndian::read_u32(&bytes[common::DRIVER_FEATURE..]);
+            self.driver_features = (self.driver_features
+                & !(0xffffffff << shift))
+                | (value as u64) << shift;
+        }
+        if written(common::MSIX_CONFIG, 2) {
+            self.msix_config = self.valid_vector(LittleEndian::read_u16(
+                &bytes[common::MSIX_CONFIG..],
+            ));
+        }
+        if written(common::DEVICE_STATUS, 1) {
+            self.set_status(bytes[common::DEVICE_STATUS], responses)?;
+        }
+        if written(common::QUEUE_SELECT, 2) {
+            self.queue_select =
+                LittleEndian::read_u16(&bytes[common::QUEUE_SELECT..]);
+        }
+
+        let vector = self.valid_vector(LittleEndian::read_u16(
+            &bytes[common::QUEUE_MSIX_VECTOR..],
+        ));
+        let queue = match self.queues.get_mut(self.queue_select as usize) {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+        if written(common::QUEUE_SIZE, 2) {
+            // The driver may only reduce the size (to a power of two)
+            let size = LittleEndian::read_u16(&bytes[common::QUEUE_SIZE..]);
+            if size.is_power_of_two() && size <= queue.max_size() {
+                queue.size = size;
+            }
+        }
+        if written(common::QUEUE_MSIX_VECTOR, 2) {
+            queue.msix_vector = vector;
+        }
+        if written(common::QUEUE_ENABLE, 2)
+            && LittleEndian::read_u16(&bytes[common::QUEUE_ENABLE..]) == 1
+        {
+            queue.ready = true;
+        }
+        if written(common::QUEUE_DESC, 8) {
+            queue.desc_table =
+                LittleEndian::read_u64(&bytes[common::QUEUE_DESC..]);
+        }
+        if written(common::QUEUE_DRIVER, 8) {
+            queue.avail_ring =
+                LittleEndian::read_u64(&bytes[common::QUEUE_DRIVER..]);
+        }
+        if written(common::QUEUE_DEVICE, 8) {
+            queue.used_ring =
+                LittleEndian::read_u64(&bytes[common::QUEUE_DEVICE..]);
+        }
+        Ok(())
+    }
+
+    fn set_status(
+        &mut self,
+        mut status: u8,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if status == 0 {
+            self.config.set_interrupt_status(false, responses);
+            return self.reset_device();
+        }
+
+        // The driver may only accept features that were offered. If it
+        // accepted anything else, FEATURES_OK will not read back as set.
+        if status & STATUS_FEATURES_OK != 0
+            && self.status & STATUS_FEATURES_OK == 0
+            && self.driver_features & !self.device_features() != 0
+        {
+            status &= !STATUS_FEATURES_OK;
+        }
+
+        if status & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0
+        {
+            self.device.activate(self.driver_features)?;
+        }
+
+        // The device is the only one that can request a reset
+        self.status = status | (self.status & STATUS_DEVICE_NEEDS_RESET);
+        Ok(())
+    }
+
+    // Return the transport and device to their initial state (this does not
+    // affect the PCI configuration space)
+    fn reset_device(&mut self) -> Result<()> {
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.msix_config = VIRTIO_MSI_NO_VECTOR;
+        self.status = 0;
+        self.queue_select = 0;
+        self.isr = 0;
+        self.device.reset()
+    }
+
+    // Send an interrupt with the given MSI-X vector (if MSI-X is enabled)
+    // or set the given ISR bits and assert INTx
+    fn interrupt(
+        &mut self,
+        isr: u8,
+        vector: u16,
+        responses: &mut ResponseEventArray,
+    ) {
+        if self.msix.is_enabled(&self.config) {
+            if vector != VIRTIO_MSI_NO_VECTOR {
+                self.msix.signal(&self.config, vector, responses);
+            }
+        } else {
+            self.isr |= isr;
+            self.config.set_interrupt_status(true, responses);
+        }
+    }
+
+    /// Notify the driver that the device configuration has changed
+    pub fn signal_config_change(&mut self, responses: &mut ResponseEventArray) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt(ISR_CONFIG, self.msix_config, responses);
+    }
+
+    // Interrupt the driver for each queue that has used buffers
+    fn signal_used_queues(&mut self, responses: &mut ResponseEventArray) {
+        for index in 0..self.queues.len() {
+            if self.queues[index].take_interrupt() {
+                let vector = self.queues[index].msix_vector;
+                self.interrupt(ISR_QUEUE, vector, responses);
+            }
+        }
+    }
+
+    fn notify_queue(
+        &mut self,
+        queue: u16,
+        space: &GuestAddressSpaceView,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if self.status & STATUS_DRIVER_OK == 0
+            || self.status & STATUS_DEVICE_NEEDS_RESET != 0
+            || queue as usize >= self.queues.len()
+        {
+            return Ok(());
+        }
+
+        let result =
+            self.device.on_queue_notify(queue, &mut self.queues, space);
+        self.finish_processing(result, responses);
+        Ok(())
+    }
+
+    // Interrupt the driver for any buffers used by the device
+    fn finish_processing(
+        &mut self,
+        result: Result<()>,
+        responses: &mut ResponseEventArray,
+    ) {
+        // A misbehaving driver should not take down the whole VM, so errors
+        // processing the queues just put the device in to an error state
+        if let Err(e) = result {
+            warn!("virtio: error processing queues: {:?}", e);
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.signal_config_change(responses);
+        }
+
+        self.signal_used_queues(responses);
+    }
+
+    fn read_virtio_bar(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        responses: &mut ResponseEventArray,
+    ) {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+
+        if offset >= DEVICE_CFG_OFFSET && offset < NOTIFY_CFG_OFFSET {
+            self.device.read_config(offset - DEVICE_CFG_OFFSET, data);
+        } else if offset >= ISR_CFG_OFFSET && offset < DEVICE_CFG_OFFSET {
+            // Reading the ISR clears it (and deasserts INTx)
+            if offset == ISR_CFG_OFFSET {
+                data[0] = self.isr;
+                self.isr = 0;
+                self.config.set_interrupt_status(false, responses);
+            }
+        } else if offset < ISR_CFG_OFFSET {
+            read_config_bytes(&self.common_config(), offset, data);
+        }
+    }
+
+    fn write_virtio_bar(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        space: &GuestAddressSpaceView,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if offset >= NOTIFY_CFG_OFFSET {
+            let queue =
+                (offset - NOTIFY_CFG_OFFSET) / NOTIFY_OFF_MULTIPLIER as u64;
+            self.notify_queue(queue as u16, space, responses)
+        } else if offset >= DEVICE_CFG_OFFSET {
+            self.device.write_config(offset - DEVICE_CFG_OFFSET, data);
+            Ok(())
+        } else if offset < ISR_CFG_OFFSET {
+            self.write_common_config(offset as usize, data, responses)
+        } else {
+            // The ISR is read-only
+            Ok(())
+        }
+    }
+}
+
+impl PciDevice for VirtioPciDevice {
+    fn config_space(&self) -> &PciConfigSpace {
+        &self.config
+    }
+
+    fn config_space_mut(&mut self) -> &mut PciConfigSpace {
+        &mut self.config
+    }
+
+    fn on_bar_event(
+        &mut self,
+        bar: usize,
+        offset: u64,
+        event: Event,
+    ) -> Result<()> {
+        if bar == MSIX_BAR {
+            if self.msix.contains(bar, offset) {
+                self.msix.on_bar_event(&self.config, offset, event)?;
+            }
+            return Ok(());
+        }
+
+        match event.kind {
+            DeviceEvent::MemRead(_, mut req) => {
+                self.read_virtio_bar(
+                    offset,
+                    req.as_mut_slice(),
+                    event.responses,
+                );
+                Ok(())
+            }
+            DeviceEvent::MemWrite(_, req) => {
+                // The virtio structures are little endian, but the value
+                // is reported with the most significant byte first
+                let len = req.as_slice().len();
+                let bytes = req.value().to_le_bytes();
+                self.write_virtio_bar(
+                    offset,
+                    &bytes[..len],
+                    &event.space,
+                    event.responses,
+                )
+            }
+            _ => Err(Error::InvalidValue(format!(
+                "Invalid virtio event: {:?}",
+                event.kind
+            ))),
+        }
+    }
+
+    fn on_config_write(
+        &mut self,
+        _offset: u16,
+        _len: usize,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        self.msix.on_config_write(&self.config, responses);
+        Ok(())
+    }
+
+    fn on_host_event(
+        &mut self,
+        kind: &DeviceEvent,
+        space: &GuestAddressSpaceView,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return Ok(());
+        }
+
+        let result = self.device.on_host_event(kind, &mut self.queues, space);
+        self.finish_processing(result, responses);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.config.reset();
+        self.msix.reset();
+        self.reset_device()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{GuestAddressSpace, GuestPhysAddr};
+    use crate::virtdev::testing::{
+        define_test_space, guest_read, guest_write, handle_event, mem_read,
+        mem_write, view_of, TestVirtqueue, VIRTQUEUE_TEST_PAGES,
+        VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    };
+    use crate::virtdev::{DeviceEventResponse, MemWriteRequest};
+
+    const STATUS_ACKNOWLEDGE: u8 = 1;
+    const STATUS_DRIVER: u8 = 2;
+
+    fn test_queue() -> TestVirtqueue {
+        TestVirtqueue::new(0, 4)
+    }
+
+    // A device that reverses the data it is sent
+    struct ReverseDevice {
+        features: u64,
+    }
+
+    impl VirtioDevice for ReverseDevice {
+        fn device_type(&self) -> u16 {
+            0x3f
+        }
+
+        fn features(&self) -> u64 {
+            self.features
+        }
+
+        fn queue_sizes(&self) -> &[u16] {
+            &[8]
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            read_config_bytes(&[0xaa, 0xbb], offset, data);
+        }
+
+        fn on_queue_notify(
+            &mut self,
+            queue: u16,
+            queues: &mut [Virtqueue],
+            space: &GuestAddressSpaceView,
+        ) -> Result<()> {
+            let queue = &mut queues[queue as usize];
+            while let Some(chain) = queue.pop(space)? {
+                let mut data = chain.read_all(space)?;
+                data.reverse();
+                let len = chain.write_all(space, &data)?;
+                queue.add_used(space, chain.head(), len as u32)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn test_device() -> VirtioPciDevice {
+        let device = Box::new(ReverseDevice { features: 1 << 5 });
+        let mut device = VirtioPciDevice::new(device).unwrap();
+
+        // Enable memory decoding and bus mastering
+        device.config_space_mut().write(0x04, 0b110, 2);
+        device
+    }
+
+    fn bar_write(
+        device: &mut VirtioPciDevice,
+        memory: &'static GuestAddressSpace,
+        offset: u64,
+        data: &[u8],
+    ) -> ResponseEventArray {
+        let addr = GuestPhysAddr::new(0xc0000000 + offset);
+        let kind = DeviceEvent::MemWrite(addr, MemWriteRequest::new(data));
+        handle_event(kind, view_of(memory), |event| {
+            device.on_bar_event(VIRTIO_BAR, offset, event)
+        })
+    }
+
+    fn bar_read(
+        device: &mut VirtioPciDevice,
+        memory: &'static GuestAddressSpace,
+        offset: u64,
+        len: usize,
+    ) -> (u64, ResponseEventArray) {
+        let addr = 0xc0000000 + offset;
+        mem_read(view_of(memory), addr, len, |event| {
+            device.on_bar_event(VIRTIO_BAR, offset, event)
+        })
+    }
+
+    fn common_write(
+        device: &mut VirtioPciDevice,
+        memory: &'static GuestAddressSpace,
+        field: usize,
+        value: u64,
+        len: usize,
+    ) {
+        let offset = field as u64;
+        mem_write(view_of(memory), 0xc0000000 + offset, value, len, |event| {
+            device.on_bar_event(VIRTIO_BAR, offset, event)
+        });
+    }
+
+    fn common_read(
+        device: &mut VirtioPciDevice,
+        memory: &'static GuestAddressSpace,
+        field: usize,
+        len: usize,
+    ) -> u64 {
+        bar_read(device, memory, field as u64, len).0
+    }
+
+    // Initialize the device like a driver, with a single queue of size 4
+    fn initialize(
+        device: &mut VirtioPciDevice,
+        memory: &'static GuestAddressSpace,
+    ) {
+        let queue = test_queue();
+        let status = (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u64;
+        common_write(device, memory, common::DEVICE_STATUS, status, 1);
+        common_write(device, memory, common::DRIVER_FEATURE_SELECT, 1, 4);
+        common_write(device, memory, common::DRIVER_FEATURE, 1, 4);
+        let status = status | STATUS_FEATURES_OK as u64;
+        common_write(device, memory, common::DEVICE_STATUS, status, 1);
+
+        common_write(device, memory, common::QUEUE_SELECT, 0, 2);
+        let size = queue.size as u64;
+        common_write(device, memory, common::QUEUE_SIZE, size, 2);
+        common_write(device, memory, common::QUEUE_DESC, queue.desc_table, 4);
+        common_write(device, memory, common::QUEUE_DRIVER, queue.avail_ring, 4);
+        common_write(device, memory, common::QUEUE_DRIVER + 4, 0, 4);
+        common_write(device, memory, common::QUEUE_DEVICE, queue.used_ring, 8);
+        common_write(device, memory, common::QUEUE_ENABLE, 1, 2);
+
+        let status = status | STATUS_DRIVER_OK as u64;
+        common_write(device, memory, common::DEVICE_STATUS, status, 1);
+    }
+
+    // Make a request with the given data and a writable buffer available,
+    // where the data buffer continues in the given descriptor
+    fn make_request(memory: &GuestAddressSpace, data: &[u8], next: u16) {
+        let (space, queue) = (view_of(memory), test_queue());
+        let (input, output) = (queue.buffer_addr(0), queue.buffer_addr(1));
+        guest_write(&space, input, data);
+        let len = data.len() as u32;
+        queue.write_desc(&space, 0, input, len, VIRTQ_DESC_F_NEXT, next);
+        queue.write_desc(&space, 1, output, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        queue.make_available(&space, 0, 0);
+    }
+
+    #[test]
+    fn test_pci_identity() {
+        let device = test_device();
+        let config = device.config_space();
+        assert_eq!(config.read(0x00, 2), VIRTIO_PCI_VENDOR_ID as u32);
+        assert_eq!(config.read(0x02, 2), 0x107f);
+        assert_eq!(config.read(0x08, 1), 1);
+
+        let capabilities: Vec<_> = config.capabilities().collect();
+        assert_eq!(capabilities.len(), 5);
+        let cfg_types: Vec<_> = capabilities
+            .iter()
+            .filter(|(id, _)| *id == VIRTIO_PCI_CAPABILITY_ID)
+            .map(|(_, offset)| config.read(offset + 3, 1) as u8)
+            .collect();
+        assert_eq!(
+            cfg_types,
+            [
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                VIRTIO_PCI_CAP_ISR_CFG,
+                VIRTIO_PCI_CAP_DEVICE_CFG
+            ]
+        );
+
+        // The notify capability also has the offset multiplier
+        let (_, notify) = capabilities[1];
+        assert_eq!(config.read(notify + 2, 1), 20);
+        assert_eq!(config.read(notify + 16, 4), NOTIFY_OFF_MULTIPLIER);
+    }
+
+    #[test]
+    fn test_feature_negotiation() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+
+        assert_eq!(
+            common_read(&mut device, memory, common::DEVICE_FEATURE, 4),
+            1 << 5
+        );
+        common_write(&mut device, memory, common::DEVICE_FEATURE_SELECT, 1, 4);
+        assert_eq!(
+            common_read(&mut device, memory, common::DEVICE_FEATURE, 4),
+            1
+        );
+
+        // Accepting a feature that was not offered fails
+        common_write(&mut device, memory, common::DRIVER_FEATURE, 1 << 6, 4);
+        let status =
+            (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK) as u64;
+        common_write(&mut device, memory, common::DEVICE_STATUS, status, 1);
+        assert_eq!(
+            common_read(&mut device, memory, common::DEVICE_STATUS, 1),
+            (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u64
+        );
+
+        // Writing zero to the status resets the device
+        common_write(&mut device, memory, common::DEVICE_STATUS, 0, 1);
+        assert_eq!(
+            common_read(&mut device, memory, common::DRIVER_FEATURE, 4),
+            0
+        );
+
+        initialize(&mut device, memory);
+        assert_eq!(device.driver_features, VIRTIO_F_VERSION_1);
+        assert_eq!(
+            common_read(&mut device, memory, common::DEVICE_STATUS, 1),
+            (STATUS_ACKNOWLEDGE
+                | STATUS_DRIVER
+                | STATUS_FEATURES_OK
+                | STATUS_DRIVER_OK) as u64
+        );
+    }
+
+    #[test]
+    fn test_queue_configuration() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        assert_eq!(common_read(&mut device, memory, common::NUM_QUEUES, 2), 1);
+        assert_eq!(common_read(&mut device, memory, common::QUEUE_SIZE, 2), 8);
+
+        // The size must be a power of two no larger than the maximum
+        common_write(&mut device, memory, common::QUEUE_SIZE, 6, 2);
+        assert_eq!(common_read(&mut device, memory, common::QUEUE_SIZE, 2), 8);
+        common_write(&mut device, memory, common::QUEUE_SIZE, 16, 2);
+        assert_eq!(common_read(&mut device, memory, common::QUEUE_SIZE, 2), 8);
+
+        // Only two MSI-X vectors exist
+        common_write(&mut device, memory, common::QUEUE_MSIX_VECTOR, 2, 2);
+        assert_eq!(
+            common_read(&mut device, memory, common::QUEUE_MSIX_VECTOR, 2),
+            VIRTIO_MSI_NO_VECTOR as u64
+        );
+        common_write(&mut device, memory, common::QUEUE_MSIX_VECTOR, 1, 2);
+        assert_eq!(
+            common_read(&mut device, memory, common::QUEUE_MSIX_VECTOR, 2),
+            1
+        );
+
+        initialize(&mut device, memory);
+        assert_eq!(common_read(&mut device, memory, common::QUEUE_SIZE, 2), 4);
+        assert_eq!(
+            common_read(&mut device, memory, common::QUEUE_ENABLE, 2),
+            1
+        );
+        assert_eq!(
+            common_read(&mut device, memory, common::QUEUE_DRIVER, 8),
+            test_queue().avail_ring
+        );
+
+        // There is no second queue
+        common_write(&mut device, memory, common::QUEUE_SELECT, 1, 2);
+        assert_eq!(common_read(&mut device, memory, common::QUEUE_SIZE, 2), 0);
+    }
+
+    #[test]
+    fn test_common_config_write_order() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+
+        // A qword write, as emulate::memio reports it
+        let value = 0x11223344_55667788u64;
+        bar_write(
+            &mut device,
+            memory,
+            common::QUEUE_DESC as u64,
+            &value.to_be_bytes(),
+        );
+        assert_eq!(device.queues[0].desc_table, value);
+
+        // Each dword of a 64 bit field can be written separately
+        bar_write(
+            &mut device,
+            memory,
+            common::QUEUE_DRIVER as u64,
+            &0x55667788u32.to_be_bytes(),
+        );
+        bar_write(
+            &mut device,
+            memory,
+            common::QUEUE_DRIVER as u64 + 4,
+            &0x11223344u32.to_be_bytes(),
+        );
+        assert_eq!(device.queues[0].avail_ring, value);
+
+        bar_write(
+            &mut device,
+            memory,
+            common::QUEUE_SIZE as u64,
+            &4u16.to_be_bytes(),
+        );
+        assert_eq!(device.queues[0].size, 4);
+    }
+
+    #[test]
+    fn test_queue_notify() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        device.config_space_mut().write(0x3c, 11, 1);
+        initialize(&mut device, memory);
+
+        make_request(memory, b"olleh", 1);
+        let responses =
+            bar_write(&mut device, memory, NOTIFY_CFG_OFFSET, &[0, 0]);
+        let (space, queue) = (view_of(memory), test_queue());
+        assert_eq!(guest_read(&space, queue.buffer_addr(1), 5), b"hello");
+        assert_eq!(queue.used_count(&space), 1);
+        assert_eq!(queue.used_elem(&space, 0), (0, 5));
+
+        // Without MSI-X, the device uses INTx and the ISR
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(11, true)]
+        ));
+        let (isr, responses) = bar_read(&mut device, memory, ISR_CFG_OFFSET, 1);
+        assert_eq!(isr, ISR_QUEUE as u64);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::GSILevel(11, false)]
+        ));
+        assert_eq!(bar_read(&mut device, memory, ISR_CFG_OFFSET, 1).0, 0);
+    }
+
+    #[test]
+    fn test_device_config() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        assert_eq!(
+            bar_read(&mut device, memory, DEVICE_CFG_OFFSET, 4).0,
+            0xbbaa
+        );
+        assert_eq!(
+            bar_read(&mut device, memory, DEVICE_CFG_OFFSET + 1, 1).0,
+            0xbb
+        );
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let memory = define_test_space(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        initialize(&mut device, memory);
+
+        // A descriptor that points outside of the queue
+        make_request(memory, b"abc", 7);
+        bar_write(&mut device, memory, NOTIFY_CFG_OFFSET, &[0, 0]);
+        assert_ne!(
+            common_read(&mut device, memory, common::DEVICE_STATUS, 1) as u8
+                & STATUS_DEVICE_NEEDS_RESET,
+            0
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! A virtio network device (see section 5.1 of the virtio 1.1 specification)
+//!
+//! Each device is connected to a port of an inter-VM switch (see the
+//! `switch` module). Frames transmitted by the guest are forwarded by the
+//! switch, and frames received by the port are copied in to the receive
+//! queue. No offloads are supported, so every frame has an empty header.
+
+use crate::error::Result;
+use crate::memory::GuestAddressSpaceView;
+use crate::switch::{self, MacAddress, PortId};
+use crate::virtdev::virtio::queue::Virtqueue;
+use crate::virtdev::virtio::{read_config_bytes, VirtioDevice};
+use crate::virtdev::DeviceEvent;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+const VIRTIO_ID_NET: u16 = 1;
+
+// PCI class and subclass of an 'ethernet controller'
+const CLASS_NETWORK: u8 = 0x02;
+const SUBCLASS_ETHERNET: u8 = 0x00;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const QUEUE_SIZE: u16 = 256;
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// Every frame is preceded by a 'struct virtio_net_hdr'. For a version 1
+// device, this includes the 'num_buffers' field (which is always 1 for
+// received frames, as mergeable buffers are not supported).
+const NET_HEADER_SIZE: usize = 12;
+const NET_HEADER_NUM_BUFFERS: usize = 10;
+
+// The layout of the device configuration
+const CONFIG_MAC: usize = 0;
+const CONFIG_SIZE: usize = 8;
+
+// The number of received frames retained until the guest provides buffers
+const MAX_PENDING_FRAMES: usize = 64;
+
+/// A virtio network device connected to an inter-VM switch
+pub struct VirtioNet {
+    port: PortId,
+    mac: MacAddress,
+
+    // Frames received by the port that have not been received by the guest
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl VirtioNet {
+    /// Create a new device connected to the given switch port
+    pub fn new(port: PortId, mac: MacAddress) -> Self {
+        Self {
+            port,
+            mac,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Forward any frames the guest has written to the transmit queue
+    fn transmit(
+        &mut self,
+        queue: &mut Virtqueue,
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        while let Some(chain) = queue.pop(space)? {
+            let data = chain.read_all(space)?;
+            queue.add_used(space, chain.head(), 0)?;
+            if data.len() < NET_HEADER_SIZE {
+                debug!("virtio-net: dropping transmit without a header");
+                continue;
+            }
+            switch::transmit(self.port, &data[NET_HEADER_SIZE..]);
+        }
+        Ok(())
+    }
+
+    // Copy pending frames in to the receive buffers
+    fn receive(
+        &mut self,
+        queue: &mut Virtqueue,
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        while let Some(frame) = self.pending.front() {
+            let chain = match queue.pop(space)? {
+                Some(chain) => chain,
+                None => break,
+            };
+
+            // A buffer that can not hold the frame is returned empty, which
+            // the driver treats as a dropped frame
+            let len = if chain.writable_len() < NET_HEADER_SIZE + frame.len() {
+                debug!(
+                    "virtio-net: dropping frame of length {} (buffer={})",
+                    frame.len(),
+                    chain.writable_len()
+                );
+                0
+            } else {
+                let mut data = vec![0u8; NET_HEADER_SIZE];
+                data[NET_HEADER_NUM_BUFFERS] = 1;
+                data.extend_from_slice(frame);
+                chain.write_all(space, &data)?
+            };
+            queue.add_used(space, chain.head(), len as u32)?;
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_NET
+    }
+
+    fn class(&self) -> (u8, u8) {
+        (CLASS_NETWORK, SUBCLASS_ETHERNET)
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        config[CONFIG_MAC..CONFIG_MAC + 6].copy_from_slice(&self.mac.0);
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    fn on_queue_notify(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        match queue {
+            RECEIVE_QUEUE => {
+                self.receive(&mut queues[RECEIVE_QUEUE as usize], space)
+            }
+            TRANSMIT_QUEUE => {
+                self.transmit(&mut queues[TRANSMIT_QUEUE as usize], space)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_host_event(
+        &mut self,
+        kind: &DeviceEvent,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        match kind {
+            DeviceEvent::NetworkFrameReceived(port, frame)
+                if *port == self.port =>
+            {
+                // Frames are dropped until the driver is ready for them
+                if !queues[RECEIVE_QUEUE as usize].is_ready() {
+                    return Ok(());
+                }
+                if self.pending.len() < MAX_PENDING_FRAMES {
+                    self.pending.push_back(frame.to_vec());
+                }
+                self.receive(&mut queues[RECEIVE_QUEUE as usize], space)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{
+        define_mapped_test_view, guest_read, TestVirtqueue,
+        VIRTQUEUE_TEST_PAGES,
+    };
+
+    const TEST_QUEUE_SIZE: u16 = 8;
+    const TEST_PORT: PortId = 3;
+
+    fn test_device() -> VirtioNet {
+        VirtioNet::new(TEST_PORT, MacAddress([0x52, 0x54, 0, 0, 0, 1]))
+    }
+
+    fn test_queues() -> (Vec<TestVirtqueue>, Vec<Virtqueue>) {
+        TestVirtqueue::queues(2, TEST_QUEUE_SIZE)
+    }
+
+    #[test]
+    fn test_config() {
+        let device = test_device();
+        assert_ne!(device.features() & VIRTIO_NET_F_MAC, 0);
+        let mut mac = [0u8; 6];
+        device.read_config(CONFIG_MAC as u64, &mut mac);
+        assert_eq!(mac, [0x52, 0x54, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_receive() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        let (layout, mut queues) = test_queues();
+        let receive = &layout[RECEIVE_QUEUE as usize];
+
+        // Frames for other ports are ignored, and frames are retained until
+        // the guest provides a buffer
+        let frame = [0xabu8; 60];
+        for port in &[TEST_PORT + 1, TEST_PORT] {
+            let event = DeviceEvent::NetworkFrameReceived(*port, &frame);
+            device.on_host_event(&event, &mut queues, &space).unwrap();
+        }
+        assert_eq!(receive.used_count(&space), 0);
+
+        let buffer = receive.add_buffer(&space, 0, &[], 0x200);
+        device
+            .on_queue_notify(RECEIVE_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(receive.used_count(&space), 1);
+        assert_eq!(
+            receive.used_elem(&space, 0).1,
+            (NET_HEADER_SIZE + frame.len()) as u32
+        );
+
+        let data = guest_read(&space, buffer, NET_HEADER_SIZE + frame.len());
+        assert_eq!(data[NET_HEADER_NUM_BUFFERS], 1);
+        assert_eq!(&data[NET_HEADER_SIZE..], &frame[..]);
+
+        // Buffers that are too small are returned empty
+        let event = DeviceEvent::NetworkFrameReceived(TEST_PORT, &frame);
+        receive.add_buffer(&space, 1, &[], 32);
+        device.on_host_event(&event, &mut queues, &space).unwrap();
+        assert_eq!(receive.used_count(&space), 2);
+        assert_eq!(receive.used_elem(&space, 1).1, 0);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device = test_device();
+        let (layout, mut queues) = test_queues();
+        let transmit = &layout[TRANSMIT_QUEUE as usize];
+
+        let mut data = vec![0u8; NET_HEADER_SIZE];
+        data.extend_from_slice(&[0xffu8; 60]);
+        transmit.add_buffer(&space, 0, &data, 0);
+        transmit.add_buffer(&space, 1, &[0u8; 4], 0);
+        device
+            .on_queue_notify(TRANSMIT_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(transmit.used_count(&space), 2);
+        assert!(queues[TRANSMIT_QUEUE as usize].take_interrupt());
+    }
+}
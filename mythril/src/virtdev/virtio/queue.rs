@@ -0,0 +1,459 @@
+//! Split virtqueues (see section 2.6 of the virtio 1.1 specification)
+
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceView, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::mem;
+
+/// The largest queue size allowed by the specification
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// Indicates that a queue (or configuration change) has no MSI-X vector
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+// The offsets of the fields of the available and used rings
+const RING_FLAGS: u64 = 0;
+const RING_IDX: u64 = 2;
+const RING_ENTRIES: u64 = 4;
+
+fn read_guest(
+    space: &GuestAddressSpaceView,
+    addr: u64,
+    length: usize,
+) -> Result<Vec<u8>> {
+    space.read_bytes(
+        GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+        length,
+        GuestAccess::Read(PrivilegeLevel(0)),
+    )
+}
+
+fn write_guest(
+    space: &GuestAddressSpaceView,
+    addr: u64,
+    bytes: &[u8],
+) -> Result<()> {
+    space.write_bytes(
+        GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+        bytes,
+        GuestAccess::Write(PrivilegeLevel(0)),
+    )
+}
+
+/// A single guest buffer of a descriptor chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    /// The guest physical address of the buffer
+    pub addr: u64,
+    /// The length of the buffer in bytes
+    pub len: u32,
+    /// Whether the buffer is written by the device (otherwise it is read)
+    pub writable: bool,
+}
+
+/// The buffers of a request made available by the driver
+///
+/// All device readable buffers precede the device writable buffers.
+#[derive(Debug)]
+pub struct DescriptorChain {
+    head: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// The index of the first descriptor of the chain, which identifies the
+    /// chain when it is returned to the driver
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// The buffers that are read by the device
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| !desc.writable)
+    }
+
+    /// The buffers that are written by the device
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|desc| desc.writable)
+    }
+
+    /// The total length of the device writable buffers
+    pub fn writable_len(&self) -> usize {
+        self.writable().map(|desc| desc.len as usize).sum()
+    }
+
+    /// Read the contents of all of the device readable buffers
+    pub fn read_all(&self, space: &GuestAddressSpaceView) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        for desc in self.readable() {
+            data.extend(read_guest(space, desc.addr, desc.len as usize)?);
+        }
+        Ok(data)
+    }
+
+    /// Copy `data` in to the device writable buffers
+    ///
+    /// Returns the number of bytes written, which is less than the length
+    /// of `data` if the buffers are too small.
+    pub fn write_all(
+        &self,
+        space: &GuestAddressSpaceView,
+        data: &[u8],
+    ) -> Result<usize> {
+        self.write_at(space, 0, data)
+    }
+
+    /// Copy `data` in to the device writable buffers, starting `offset`
+    /// bytes from the start of the first buffer
+    ///
+    /// Returns the number of bytes written, which is less than the length
+    /// of `data` if the buffers are too small.
+    pub fn write_at(
+        &self,
+        space: &GuestAddressSpaceView,
+        mut offset: usize,
+        mut data: &[u8],
+    ) -> Result<usize> {
+        let mut written = 0;
+        for desc in self.writable() {
+            if data.is_empty() {
+                break;
+            }
+            let desc_len = desc.len as usize;
+            if offset >= desc_len {
+                offset -= desc_len;
+                continue;
+            }
+            let len = data.len().min(desc_len - offset);
+            write_guest(space, desc.addr + offset as u64, &data[..len])?;
+            data = &data[len..];
+            written += len;
+            offset = 0;
+        }
+        Ok(written)
+    }
+}
+
+/// A split virtqueue shared with the guest driver
+///
+/// The rings live in guest memory, at the addresses configured by the
+/// driver through the transport.
+pub struct Virtqueue {
+    max_size: u16,
+    pub(super) size: u16,
+    pub(super) ready: bool,
+    pub(super) msix_vector: u16,
+    pub(super) desc_table: u64,
+    pub(super) avail_ring: u64,
+    pub(super) used_ring: u64,
+
+    next_avail: u16,
+    next_used: u16,
+    interrupt_pending: bool,
+}
+
+impl Virtqueue {
+    /// Create a new queue with at most `max_size` entries
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            msix_vector: VIRTIO_MSI_NO_VECTOR,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: 0,
+            next_used: 0,
+            interrupt_pending: false,
+        }
+    }
+
+    /// Create a queue with the given layout, as if the driver had
+    /// configured and enabled it
+    #[cfg(test)]
+    pub fn configured(
+        size: u16,
+        desc_table: u64,
+        avail_ring: u64,
+        used_ring: u64,
+    ) -> Self {
+        let mut queue = Self::new(size);
+        queue.desc_table = desc_table;
+        queue.avail_ring = avail_ring;
+        queue.used_ring = used_ring;
+        queue.ready = true;
+        queue
+    }
+
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Whether the driver has enabled the queue
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    fn read_u16(
+        &self,
+        space: &GuestAddressSpaceView,
+        addr: u64,
+    ) -> Result<u16> {
+        let bytes = read_guest(space, addr, 2)?;
+        Ok(LittleEndian::read_u16(&bytes))
+    }
+
+    /// Whether the driver has made any buffers available
+    pub fn has_available(&self, space: &GuestAddressSpaceView) -> Result<bool> {
+        if !self.ready {
+            return Ok(false);
+        }
+        let avail_idx = self.read_u16(space, self.avail_ring + RING_IDX)?;
+        Ok(avail_idx != self.next_avail)
+    }
+
+    /// Take the next descriptor chain made available by the driver
+    pub fn pop(
+        &mut self,
+        space: &GuestAddressSpaceView,
+    ) -> Result<Option<DescriptorChain>> {
+        if !self.ready {
+            return Ok(None);
+        }
+
+        let avail_idx = self.read_u16(space, self.avail_ring + RING_IDX)?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        if avail_idx.wrapping_sub(self.next_avail) > self.size {
+            return Err(Error::InvalidValue(format!(
+                "Invalid virtqueue available index: {}",
+                avail_idx
+            )));
+        }
+
+        let slot = (self.next_avail % self.size) as u64;
+        let head =
+            self.read_u16(space, self.avail_ring + RING_ENTRIES + slot * 2)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        self.read_chain(space, head).map(Some)
+    }
+
+    fn read_chain(
+        &self,
+        space: &GuestAddressSpaceView,
+        head: u16,
+    ) -> Result<DescriptorChain> {
+        let mut descriptors: Vec<Descriptor> = vec![];
+        let mut index = head;
+        loop {
+            if index >= self.size {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid virtqueue descriptor index: {}",
+                    index
+                )));
+            }
+            // A chain can't be longer than the queue (so it must loop)
+            if descriptors.len() >= self.size as usize {
+                return Err(Error::InvalidValue(
+                    "Virtqueue descriptor chain is too long".into(),
+                ));
+            }
+
+            let bytes = read_guest(
+                space,
+                self.desc_table + index as u64 * DESCRIPTOR_SIZE,
+                DESCRIPTOR_SIZE as usize,
+            )?;
+            let flags = LittleEndian::read_u16(&bytes[12..14]);
+
+            // VIRTIO_F_INDIRECT_DESC is never offered
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(Error::InvalidValue(
+                    "Unexpected indirect virtqueue descriptor".into(),
+                ));
+            }
+            let writable = flags & VIRTQ_DESC_F_WRITE != 0;
+            if !writable && descriptors.iter().any(|desc| desc.writable) {
+                return Err(Error::InvalidValue(
+                    "Readable virtqueue descriptor after a writable one".into(),
+                ));
+            }
+
+            descriptors.push(Descriptor {
+                addr: LittleEndian::read_u64(&bytes[0..8]),
+                len: LittleEndian::read_u32(&bytes[8..12]),
+                writable,
+            });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = LittleEndian::read_u16(&bytes[14..16]);
+        }
+
+        Ok(DescriptorChain { head, descriptors })
+    }
+
+    /// Return a descriptor chain to the driver, after `len` bytes have been
+    /// written to its writable buffers
+    pub fn add_used(
+        &mut self,
+        space: &GuestAddressSpaceView,
+        head: u16,
+        len: u32,
+    ) -> Result<()> {
+        let mut element = [0u8; USED_ELEMENT_SIZE as usize];
+        LittleEndian::write_u32(&mut element[0..4], head as u32);
+        LittleEndian::write_u32(&mut element[4..8], len);
+
+        let slot = (self.next_used % self.size) as u64;
+        write_guest(
+            space,
+            self.used_ring + RING_ENTRIES + slot * USED_ELEMENT_SIZE,
+            &element,
+        )?;
+
+        self.next_used = self.next_used.wrapping_add(1);
+        let mut idx = [0u8; 2];
+        LittleEndian::write_u16(&mut idx, self.next_used);
+        write_guest(space, self.used_ring + RING_IDX, &idx)?;
+
+        let flags = self.read_u16(space, self.avail_ring + RING_FLAGS)?;
+        if flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt_pending = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the driver should be interrupted because buffers have been
+    /// used since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        mem::replace(&mut self.interrupt_pending, false)
+    }
+
+    /// Return the queue to its initial (disabled) state
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::testing::{
+        define_mapped_test_view, guest_read, guest_write, TestVirtqueue,
+        VIRTQUEUE_TEST_PAGES,
+    };
+
+    const QUEUE_SIZE: u16 = 4;
+
+    #[test]
+    fn test_empty_queue() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let layout = TestVirtqueue::new(0, QUEUE_SIZE);
+        let mut queue = layout.virtqueue();
+        assert!(!queue.has_available(&space).unwrap());
+        assert!(queue.pop(&space).unwrap().is_none());
+
+        // Nothing is processed until the driver enables the queue
+        layout.make_available(&space, 0, 0);
+        queue.ready = false;
+        assert!(queue.pop(&space).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_descriptor_chain() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let layout = TestVirtqueue::new(0, QUEUE_SIZE);
+        let mut queue = layout.virtqueue();
+        let buffers = layout.buffer_addr(0);
+
+        guest_write(&space, buffers, b"hello ");
+        guest_write(&space, buffers + 0x100, b"world");
+        layout.write_desc(&space, 2, buffers, 6, VIRTQ_DESC_F_NEXT, 0);
+        layout.write_desc(&space, 0, buffers + 0x100, 5, VIRTQ_DESC_F_NEXT, 3);
+        layout.write_desc(&space, 3, buffers + 0x200, 4, VIRTQ_DESC_F_WRITE, 0);
+        layout.make_available(&space, 0, 2);
+
+        let chain = queue.pop(&space).unwrap().unwrap();
+        assert_eq!(chain.head(), 2);
+        assert_eq!(chain.descriptors().len(), 3);
+        assert_eq!(chain.writable_len(), 4);
+        assert_eq!(chain.read_all(&space).unwrap(), b"hello world");
+
+        // Writes are truncated to the size of the writable buffers
+        assert_eq!(chain.write_all(&space, b"thanks").unwrap(), 4);
+        assert_eq!(guest_read(&space, buffers + 0x200, 4), b"than");
+        assert!(queue.pop(&space).unwrap().is_none());
+
+        queue.add_used(&space, chain.head(), 4).unwrap();
+        assert_eq!(layout.used_count(&space), 1);
+        assert_eq!(layout.used_elem(&space, 0), (2, 4));
+        assert!(queue.take_interrupt());
+        assert!(!queue.take_interrupt());
+    }
+
+    #[test]
+    fn test_invalid_chains() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let layout = TestVirtqueue::new(0, QUEUE_SIZE);
+        let mut queue = layout.virtqueue();
+        let buffer = layout.buffer_addr(0);
+
+        // A chain that loops back on itself
+        layout.write_desc(&space, 0, buffer, 4, VIRTQ_DESC_F_NEXT, 1);
+        layout.write_desc(&space, 1, buffer, 4, VIRTQ_DESC_F_NEXT, 0);
+        layout.make_available(&space, 0, 0);
+        assert!(queue.pop(&space).is_err());
+
+        // A readable buffer after a writable one
+        let flags = VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT;
+        layout.write_desc(&space, 2, buffer, 4, flags, 3);
+        layout.write_desc(&space, 3, buffer, 4, 0, 0);
+        layout.make_available(&space, 1, 2);
+        assert!(queue.pop(&space).is_err());
+
+        // A descriptor outside of the table
+        let flags = VIRTQ_DESC_F_NEXT;
+        layout.write_desc(&space, 3, buffer, 4, flags, QUEUE_SIZE);
+        layout.make_available(&space, 2, 3);
+        assert!(queue.pop(&space).is_err());
+    }
+
+    #[test]
+    fn test_suppressed_interrupt() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let layout = TestVirtqueue::new(0, QUEUE_SIZE);
+        let mut queue = layout.virtqueue();
+
+        layout.add_buffer(&space, 0, &[0u8; 4], 0);
+        layout.set_avail_flags(&space, VIRTQ_AVAIL_F_NO_INTERRUPT);
+
+        let chain = queue.pop(&space).unwrap().unwrap();
+        queue.add_used(&space, chain.head(), 0).unwrap();
+        assert!(!queue.take_interrupt());
+    }
+}
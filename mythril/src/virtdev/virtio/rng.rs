@@ -0,0 +1,118 @@
+//! A virtio entropy device (see section 5.4 of the virtio 1.1 specification)
+//!
+//! Guest buffers are filled from the host entropy source (see `random`),
+//! so guests that block waiting for entropy early in boot (e.g., from a
+//! minimal initramfs) can make progress.
+
+use crate::error::Result;
+use crate::memory::GuestAddressSpaceView;
+use crate::random::HostEntropy;
+use crate::virtdev::virtio::queue::Virtqueue;
+use crate::virtdev::virtio::VirtioDevice;
+
+const VIRTIO_ID_ENTROPY: u16 = 4;
+
+// PCI class and subclass of an 'unassigned class' device
+const CLASS_OTHER: u8 = 0xff;
+const SUBCLASS_OTHER: u8 = 0x00;
+
+const QUEUE_SIZE: u16 = 64;
+const REQUEST_QUEUE: u16 = 0;
+
+// The most entropy provided for a single request, which bounds the time
+// spent handling a notification when the host has no hardware source
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// A virtio entropy device backed by the host entropy source
+pub struct VirtioRng {
+    entropy: HostEntropy,
+}
+
+impl VirtioRng {
+    /// Create an entropy device using the given host source
+    pub fn new(entropy: HostEntropy) -> Self {
+        Self { entropy }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn class(&self) -> (u8, u8) {
+        (CLASS_OTHER, SUBCLASS_OTHER)
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    // The device has no configuration
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn on_queue_notify(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpaceView,
+    ) -> Result<()> {
+        if queue != REQUEST_QUEUE {
+            return Ok(());
+        }
+
+        let queue = &mut queues[REQUEST_QUEUE as usize];
+        while let Some(chain) = queue.pop(space)? {
+            let mut data =
+                vec![0u8; chain.writable_len().min(MAX_REQUEST_SIZE)];
+            self.entropy.fill_bytes(&mut data);
+            let written = chain.write_all(space, &data)?;
+            queue.add_used(space, chain.head(), written as u32)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::random::EntropySource;
+    use crate::virtdev::testing::{
+        define_mapped_test_view, guest_read, TestVirtqueue,
+        VIRTQUEUE_TEST_PAGES,
+    };
+    use alloc::vec::Vec;
+
+    const TEST_QUEUE_SIZE: u16 = 8;
+
+    fn test_queue() -> (TestVirtqueue, Vec<Virtqueue>) {
+        let (mut layout, queues) = TestVirtqueue::queues(1, TEST_QUEUE_SIZE);
+        (layout.remove(0), queues)
+    }
+
+    #[test]
+    fn test_fill_buffers() {
+        let space = define_mapped_test_view(VIRTQUEUE_TEST_PAGES);
+        let mut device =
+            VirtioRng::new(HostEntropy::with_source(EntropySource::TscJitter));
+        let (queue, mut queues) = test_queue();
+
+        let addr = queue.add_buffer(&space, 0, &[], 64);
+        device
+            .on_queue_notify(REQUEST_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(queue.used_elem(&space, 0).1, 64);
+        assert!(guest_read(&space, addr, 64).iter().any(|byte| *byte != 0));
+
+        // Large requests are only partially filled
+        queue.add_buffer(&space, 1, &[], 0x2000);
+        device
+            .on_queue_notify(REQUEST_QUEUE, &mut queues, &space)
+            .unwrap();
+        assert_eq!(queue.used_elem(&space, 1).1, MAX_REQUEST_SIZE as u32);
+    }
+}
@@ -2,28 +2,35 @@
 
 use crate::apic;
 use crate::boot_info::BootInfo;
+use crate::config;
 use crate::error::{Error, Result};
 use crate::interrupt;
+use crate::ioapic;
 use crate::memory::{
     self, GuestAddressSpace, GuestPhysAddr, HostPhysAddr, HostPhysFrame,
     Raw4kPage,
 };
 use crate::percore;
 use crate::physdev;
+use crate::shmem;
+use crate::switch;
 use crate::time;
 use crate::vcpu;
 use crate::virtdev::{
-    self, DeviceEvent, DeviceInteraction, DeviceMap, Event, ResponseEventArray,
+    self, DeviceEvent, DeviceInteraction, DeviceMap, EmulatedDevice, Event,
+    ResponseEventArray,
 };
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec::Vec;
 use arraydeque::ArrayDeque;
 use arrayvec::ArrayVec;
+use core::convert::TryFrom;
 use core::default::Default;
 use core::mem;
 use core::pin::Pin;
-use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, AtomicU32};
 use spin::RwLock;
 
 static BIOS_BLOB: &'static [u8] = include_bytes!("blob/bios.bin");
@@ -41,11 +48,19 @@ pub const MAX_PER_VM_CORE_COUNT: usize = 32;
 /// The maximum number of VCpus that can be defined
 pub const MAX_VCPU_COUNT: usize = MAX_VM_COUNT * MAX_PER_VM_CORE_COUNT;
 
+/// The cores, vector and type of an interrupt that should be delivered
+pub type InterruptDestination = (
+    ArrayVec<[percore::CoreId; MAX_PER_VM_CORE_COUNT]>,
+    u8,
+    vcpu::InjectedInterruptType,
+);
+
 static mut VIRTUAL_MACHINE_SET: VirtualMachineSet = VirtualMachineSet::new();
 
 const MAX_DYNAMIC_VIRTUAL_DEVICES: usize = 32;
 
-const MAX_PENDING_MSG: usize = 100;
+/// The number of messages that may be queued for each core
+pub const MAX_PENDING_MSG: usize = 100;
 
 const MAX_IMAGE_MAPPING_PER_VM: usize = 16;
 
@@ -72,15 +87,16 @@ pub fn virtual_machines() -> &'static VirtualMachineSet {
 /// VirtualMachineSet type, accessible via the 'virtual_machines'
 /// method after startup
 pub enum VirtualMachineMsg {
-    /// Transfer control of a physical serial console to another VM
-    GrantConsole(physdev::com::Uart8250),
-
     /// Cancel a the given timer
     CancelTimer(time::TimerId),
 
     /// Start a core at the given physical address
     StartVcpu(GuestPhysAddr),
 
+    /// Deliver an INIT signal to a core (which resets it and, unless it is
+    /// the BSP, puts it in the wait-for-SIPI state)
+    InitVcpu,
+
     /// Inject a guest interrupt with the given vector
     GuestInterrupt {
         /// The type of the injected interrupt
@@ -89,6 +105,78 @@ pub enum VirtualMachineMsg {
         /// The injected interrupt vector
         vector: u8,
     },
+
+    /// Perform the given action on a core (as part of a change to the power
+    /// state of its VM)
+    PowerAction(config::PowerAction),
+
+    /// Press the power button of a VM
+    PowerButton,
+
+    /// An ethernet frame forwarded by an inter-VM switch
+    NetworkFrame {
+        /// The switch port that should receive the frame
+        port: switch::PortId,
+
+        /// The frame (without the frame check sequence)
+        frame: Vec<u8>,
+    },
+
+    /// A doorbell rung by another peer of a shared memory region
+    SharedMemoryDoorbell {
+        /// The region whose doorbell was rung
+        region: shmem::RegionId,
+
+        /// The interrupt vector requested by the peer
+        vector: u16,
+    },
+}
+
+/// A guest initiated change to the power state of a virtual machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerEvent {
+    /// The guest reset the machine (e.g., through port 0xcf9 or the 8042)
+    Reboot,
+
+    /// The guest powered off the machine (e.g., by entering ACPI S5)
+    PowerOff,
+
+    /// The guest crashed (e.g., by triple faulting)
+    Crash,
+}
+
+/// The actions taken in response to each `PowerEvent`
+#[derive(Clone, Copy, Debug)]
+pub struct PowerPolicy {
+    /// The action taken for a `PowerEvent::Reboot`
+    pub on_reboot: config::PowerAction,
+
+    /// The action taken for a `PowerEvent::PowerOff`
+    pub on_poweroff: config::PowerAction,
+
+    /// The action taken for a `PowerEvent::Crash`
+    pub on_crash: config::PowerAction,
+}
+
+impl PowerPolicy {
+    /// Returns the action taken for the given event
+    pub fn action(&self, event: PowerEvent) -> config::PowerAction {
+        match event {
+            PowerEvent::Reboot => self.on_reboot,
+            PowerEvent::PowerOff => self.on_poweroff,
+            PowerEvent::Crash => self.on_crash,
+        }
+    }
+}
+
+impl Default for PowerPolicy {
+    fn default() -> Self {
+        Self {
+            on_reboot: config::PowerAction::Restart,
+            on_poweroff: config::PowerAction::Destroy,
+            on_crash: config::PowerAction::Destroy,
+        }
+    }
 }
 
 struct VirtualMachineContext {
@@ -274,6 +362,13 @@ impl VirtualMachineSet {
         self.send_msg_core(msg, vm_bsp, notify)
     }
 
+    /// Press the power button of the given virtual machine
+    ///
+    /// This allows the guest to shut down gracefully (if it is using ACPI).
+    pub fn press_power_button(&self, vm_id: u32) -> Result<()> {
+        self.send_msg(VirtualMachineMsg::PowerButton, vm_id, true)
+    }
+
     /// Receive any pending message for the current core
     pub fn recv_msg(&self) -> Option<VirtualMachineMsg> {
         let context = self
@@ -297,26 +392,47 @@ pub struct StaticVirtualDevices {
     acpi_runtime: RwLock<virtdev::acpi::AcpiRuntime>,
     vga_controller: RwLock<virtdev::vga::VgaController>,
     pci_root: RwLock<virtdev::pci::PciRootComplex>,
-    pic: RwLock<virtdev::pic::Pic8259>,
+
+    /// The legacy programmable interrupt controllers
+    pub pic: RwLock<virtdev::pic::Pic8259>,
+
     keyboard: RwLock<virtdev::keyboard::Keyboard8042>,
+    reset_control: RwLock<virtdev::reset::ResetControl>,
     pit: RwLock<virtdev::pit::Pit8254>,
     rtc: RwLock<virtdev::rtc::CmosRtc>,
+    hpet: RwLock<virtdev::hpet::Hpet>,
 
+    /// The guest I/O APIC
     // TODO(alschwalm): In reality the number of ioapics is variable,
     // but for now just have one in here
-    io_apic: RwLock<virtdev::ioapic::IoApic>,
+    pub io_apic: RwLock<virtdev::ioapic::IoApic>,
 }
 
 impl StaticVirtualDevices {
-    fn new(config: &VirtualMachineConfig) -> Result<Self> {
+    fn new(config: &VirtualMachineConfig, info: &BootInfo) -> Result<Self> {
+        let mut rtc = virtdev::rtc::CmosRtc::new(config.memory)?;
+        if let Some(image) = &config.nvram_image {
+            let data = info
+                .find_module(image)
+                .ok_or_else(|| {
+                    Error::InvalidValue(format!("No such module '{}'", image))
+                })?
+                .data();
+            rtc.load_nvram(data)?;
+        }
+
         Ok(Self {
-            acpi_runtime: RwLock::new(virtdev::acpi::AcpiRuntime::new(0x600)?),
+            acpi_runtime: RwLock::new(virtdev::acpi::AcpiRuntime::new(
+                virtdev::acpi::PM_BASE,
+            )?),
             vga_controller: RwLock::new(virtdev::vga::VgaController::new()?),
             pci_root: RwLock::new(virtdev::pci::PciRootComplex::new()?),
             pic: RwLock::new(virtdev::pic::Pic8259::new()?),
             keyboard: RwLock::new(virtdev::keyboard::Keyboard8042::new()?),
+            reset_control: RwLock::new(virtdev::reset::ResetControl::new()?),
             pit: RwLock::new(virtdev::pit::Pit8254::new()?),
-            rtc: RwLock::new(virtdev::rtc::CmosRtc::new(config.memory)?),
+            rtc: RwLock::new(rtc),
+            hpet: RwLock::new(virtdev::hpet::Hpet::new()?),
             io_apic: RwLock::new(virtdev::ioapic::IoApic::new()?),
         })
     }
@@ -330,8 +446,10 @@ impl StaticVirtualDevices {
             &self.pci_root as &RwLock<dyn virtdev::EmulatedDevice>,
             &self.pic as &RwLock<dyn virtdev::EmulatedDevice>,
             &self.keyboard as &RwLock<dyn virtdev::EmulatedDevice>,
+            &self.reset_control as &RwLock<dyn virtdev::EmulatedDevice>,
             &self.pit as &RwLock<dyn virtdev::EmulatedDevice>,
             &self.rtc as &RwLock<dyn virtdev::EmulatedDevice>,
+            &self.hpet as &RwLock<dyn virtdev::EmulatedDevice>,
             &self.io_apic as &RwLock<dyn virtdev::EmulatedDevice>,
         ])
     }
@@ -340,9 +458,6 @@ impl StaticVirtualDevices {
 /// A set of physical hardware that may be attached to a VM
 #[derive(Default)]
 pub struct HostPhysicalDevices {
-    /// The physical serial connection for this VM (if any).
-    pub serial: RwLock<Option<physdev::com::Uart8250>>,
-
     /// The physical ps2 keyboard connection for this VM (if any).
     pub ps2_keyboard: RwLock<Option<physdev::keyboard::Ps2Controller>>,
 }
@@ -365,6 +480,23 @@ pub struct VirtualMachineConfig {
 
     /// The size of this machines physical address space in MiB
     pub memory: u64,
+
+    /// The image used to initialize the CMOS NVRAM (if any)
+    pub nvram_image: Option<String>,
+
+    /// The actions taken when the guest changes its power state
+    pub power_policy: PowerPolicy,
+
+    /// The handling of guest RDRAND and RDSEED instructions
+    pub random_policy: config::RandomInstructionPolicy,
+
+    /// The devices attached to the PCI bus (in addition to the chipset)
+    pub pci_devices:
+        Vec<(virtdev::pci::PciBdf, Box<dyn virtdev::pci::PciDevice>)>,
+
+    /// The host frames shared with other virtual machines, the address
+    /// they are mapped at, and whether they are read-only
+    pub shared_memory: Vec<(GuestPhysAddr, &'static [HostPhysFrame], bool)>,
 }
 
 impl VirtualMachineConfig {
@@ -372,7 +504,7 @@ impl VirtualMachineConfig {
     ///
     /// # Arguments
     ///
-    /// * `cpus` - A list of the cores used by the VM (by APIC id)
+    /// * `cpus` - A list of the cores used by the VM (the first is the BSP)
     /// * `memory` - The amount of VM memory (in MB)
     pub fn new(
         cpus: &[percore::CoreId],
@@ -387,6 +519,11 @@ impl VirtualMachineConfig {
             virtual_devices: ArrayVec::new(),
             host_devices: physical_devices,
             memory: memory,
+            nvram_image: None,
+            power_policy: PowerPolicy::default(),
+            random_policy: config::RandomInstructionPolicy::Passthrough,
+            pci_devices: vec![],
+            shared_memory: vec![],
         })
     }
 
@@ -402,6 +539,48 @@ impl VirtualMachineConfig {
         self.images.push((image, addr));
         Ok(())
     }
+
+    /// Specify that the given image should be used to initialize the
+    /// CMOS NVRAM
+    ///
+    /// The NVRAM is preserved across guest resets, so this only affects
+    /// the initial contents.
+    pub fn set_nvram_image(&mut self, image: String) {
+        self.nvram_image = Some(image);
+    }
+
+    /// Specify the actions taken when the guest changes its power state
+    pub fn set_power_policy(&mut self, policy: PowerPolicy) {
+        self.power_policy = policy;
+    }
+
+    /// Specify how the guest may use the RDRAND and RDSEED instructions
+    pub fn set_random_instruction_policy(
+        &mut self,
+        policy: config::RandomInstructionPolicy,
+    ) {
+        self.random_policy = policy;
+    }
+
+    /// Attach a device to the PCI bus at the given address
+    pub fn add_pci_device(
+        &mut self,
+        bdf: virtdev::pci::PciBdf,
+        device: Box<dyn virtdev::pci::PciDevice>,
+    ) {
+        self.pci_devices.push((bdf, device));
+    }
+
+    /// Specify that the given host frames (which are shared with other
+    /// virtual machines) should be mapped at the given address
+    pub fn map_shared_memory(
+        &mut self,
+        addr: GuestPhysAddr,
+        frames: &'static [HostPhysFrame],
+        readonly: bool,
+    ) {
+        self.shared_memory.push((addr, frames, readonly));
+    }
 }
 
 /// A virtual machine
@@ -415,6 +594,16 @@ pub struct VirtualMachine {
     /// Size of guest physical memory in MB
     pub memory: u64,
 
+    /// The actions taken when the guest changes its power state
+    pub power_policy: PowerPolicy,
+
+    /// The handling of guest RDRAND and RDSEED instructions
+    pub random_policy: config::RandomInstructionPolicy,
+
+    /// The contents of the images mapped into the guest address space
+    images:
+        ArrayVec<[(&'static [u8], GuestPhysAddr); MAX_IMAGE_MAPPING_PER_VM]>,
+
     /// The set of host physical devices available to this guest
     pub host_devices: HostPhysicalDevices,
 
@@ -445,6 +634,12 @@ pub struct VirtualMachine {
 
     /// The number of vcpus that are up and waiting to start
     cpus_ready: AtomicU32,
+
+    /// Whether a change to the power state of the VM is in progress
+    power_transition: AtomicBool,
+
+    /// The number of vcpus (other than the BSP) reset as part of a restart
+    cpus_reset: AtomicU32,
 }
 
 impl VirtualMachine {
@@ -457,7 +652,27 @@ impl VirtualMachine {
         config: VirtualMachineConfig,
         info: &BootInfo,
     ) -> Result<Self> {
-        let guest_space = Self::setup_ept(&config, info)?;
+        let mut images = ArrayVec::new();
+        for (image, addr) in config.images.iter() {
+            let data = info
+                .find_module(image)
+                .ok_or_else(|| {
+                    Error::InvalidValue(format!("No such module '{}'", image))
+                })?
+                .data();
+            images.push((data, *addr));
+        }
+
+        let guest_space = Self::setup_ept(config.memory, &images)?;
+        for (addr, frames, readonly) in config.shared_memory.iter() {
+            for (i, frame) in frames.iter().enumerate() {
+                guest_space.map_frame(
+                    *addr + i * HostPhysFrame::SIZE,
+                    *frame,
+                    *readonly,
+                )?;
+            }
+        }
 
         // Prepare the portion of per-core local apic state that is stored at the
         // VM level (as needed for logical addressing)
@@ -469,13 +684,19 @@ impl VirtualMachine {
             );
         }
 
-        let static_devices = StaticVirtualDevices::new(&config)?;
+        let static_devices = StaticVirtualDevices::new(&config, info)?;
+        for (bdf, device) in config.pci_devices {
+            static_devices.pci_root.write().add_device(bdf, device)?;
+        }
 
         Ok(Self {
             id: id,
             host_devices: config.host_devices,
             cpus: config.cpus,
             memory: config.memory,
+            power_policy: config.power_policy,
+            random_policy: config.random_policy,
+            images: images,
             dynamic_virtual_devices: config.virtual_devices,
             static_virtual_devices: static_devices,
             virtual_device_map: virtdev::DeviceMap::default(),
@@ -483,6 +704,8 @@ impl VirtualMachine {
             apic_access_page: Raw4kPage([0u8; 4096]),
             logical_apic_state: logical_apic_states,
             cpus_ready: AtomicU32::new(0),
+            power_transition: AtomicBool::new(false),
+            cpus_reset: AtomicU32::new(0),
         })
     }
 
@@ -521,6 +744,60 @@ impl VirtualMachine {
             == self.cpus.len() as u32
     }
 
+    /// Begin a change to the power state of this VirtualMachine
+    ///
+    /// Returns false if a change is already in progress (in which case the
+    /// new change should be ignored).
+    pub fn begin_power_transition(&self) -> bool {
+        self.power_transition
+            .compare_exchange(
+                false,
+                true,
+                core::sync::atomic::Ordering::SeqCst,
+                core::sync::atomic::Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    /// Notify this VirtualMachine that the current core has been reset as
+    /// part of a restart
+    ///
+    /// Each core other than the BSP must call this method before the BSP
+    /// can complete the restart with `restart`.
+    pub fn notify_reset(&self) {
+        self.cpus_reset
+            .fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Return this VirtualMachine to its initial state
+    ///
+    /// This must only be called by the BSP, after it has been reset. Once
+    /// all other cores have been reset, the guest memory is restored (so the
+    /// guest images and firmware are reloaded) and all virtual devices are
+    /// returned to their power-on state. This completes the power transition.
+    pub fn restart(&self) -> Result<()> {
+        while self.cpus_reset.load(core::sync::atomic::Ordering::SeqCst) + 1
+            < self.cpus.len() as u32
+        {
+            crate::lock::relax_cpu();
+        }
+        self.cpus_reset
+            .store(0, core::sync::atomic::Ordering::SeqCst);
+
+        self.reset_memory()?;
+
+        for dev in self.static_virtual_devices.devices() {
+            dev.write().reset()?;
+        }
+        for dev in self.dynamic_virtual_devices.iter() {
+            dev.write().reset()?;
+        }
+
+        self.power_transition
+            .store(false, core::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
     /// Process the given DeviceEvent on the virtual hardware matching 'ident'
     ///
     /// # Arguments
@@ -569,6 +846,24 @@ impl VirtualMachine {
         dev.write().on_event(event)
     }
 
+    /// Offer an event from the host (e.g., console input or a frame from
+    /// an inter-VM switch) to the virtual PCI devices
+    pub fn dispatch_pci_host_event(
+        &self,
+        kind: DeviceEvent,
+        vcpu: &crate::vcpu::VCpu,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let space = crate::memory::GuestAddressSpaceView::from_vmcs(
+            &vcpu.vmcs,
+            &self.guest_space,
+        )?;
+        self.static_virtual_devices
+            .pci_root
+            .write()
+            .on_host_event(&kind, &space, responses)
+    }
+
     /// Returns an iterator of the CoreIds that are logically addressed by the given mask
     ///
     /// # Arguments
@@ -579,9 +874,8 @@ impl VirtualMachine {
         &self,
         mask: u32,
     ) -> Result<impl Iterator<Item = &percore::CoreId>> {
-        // FIXME: currently we only support the 'Flat Model' logical mode
-        // (so we just ignore the destination format register). See 10.6.2.2
-        // of Volume 3A of the Intel software developer's manual
+        // See 10.6.2.2 of Volume 3A of the Intel software developer's manual
+        // for the flat and cluster models
         Ok(self.cpus.iter().filter(move |core| {
             let apic_state = self
                 .logical_apic_state
@@ -591,7 +885,25 @@ impl VirtualMachine {
             let destination = apic_state
                 .logical_destination
                 .load(core::sync::atomic::Ordering::SeqCst);
-            destination & mask != 0
+            let format = apic_state
+                .destination_format
+                .load(core::sync::atomic::Ordering::SeqCst);
+
+            if apic_state.x2apic.load(core::sync::atomic::Ordering::SeqCst) {
+                // x2APIC logical destinations are always in the cluster
+                // model, with the cluster in the upper 16 bits
+                mask == 0xffffffff
+                    || (destination >> 16 == mask >> 16
+                        && destination & mask & 0xffff != 0)
+            } else if format >> 28 == 0 {
+                // Cluster model, with the cluster in the upper nibble
+                let (destination, mask) = (destination >> 24, mask >> 24);
+                mask == 0xff
+                    || (destination >> 4 == mask >> 4
+                        && destination & mask & 0xf != 0)
+            } else {
+                destination & mask != 0
+            }
         }))
     }
 
@@ -607,29 +919,201 @@ impl VirtualMachine {
             .store(dest, core::sync::atomic::Ordering::SeqCst);
     }
 
-    /// Resolve a guest GSI to a specific CoreId, vector and interrupt type
+    /// Returns the virtual local APIC id of the given core
+    ///
+    /// Each VM has a dense APIC id space: the virtual APIC id of a core is
+    /// its index in the VM's core list (so the BSP always has APIC id 0).
+    pub fn core_to_apic_id(&self, core: percore::CoreId) -> Option<u32> {
+        self.cpus
+            .iter()
+            .position(|cpu| *cpu == core)
+            .map(|idx| idx as u32)
+    }
+
+    /// Returns the CoreId of the core with the given virtual local APIC id
+    pub fn apic_id_to_core(&self, apic_id: u32) -> Option<percore::CoreId> {
+        self.cpus.get(apic_id as usize).copied()
+    }
+
+    /// Notify the VirtualMachine of a change in the Destination Format
+    /// register for the current core
+    pub fn update_core_destination_format(&self, format: u32) {
+        let apic_state = self
+            .logical_apic_state
+            .get(&percore::read_core_id())
+            .expect("Missing logical state for core");
+        apic_state
+            .destination_format
+            .store(format, core::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Notify the VirtualMachine that the current core has entered or left
+    /// x2APIC mode, which changes the logical destination of the core
+    pub fn update_core_x2apic_mode(&self, enabled: bool, dest: u32) {
+        let apic_state = self
+            .logical_apic_state
+            .get(&percore::read_core_id())
+            .expect("Missing logical state for core");
+        apic_state
+            .x2apic
+            .store(enabled, core::sync::atomic::Ordering::SeqCst);
+        apic_state
+            .logical_destination
+            .store(dest, core::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Resolve a guest GSI to the CoreIds, vector and interrupt type
+    /// described by the guest I/O APIC redirection table
+    ///
+    /// Returns `None` if the entry can not currently be delivered.
     pub fn gsi_destination(
         &self,
         gsi: u32,
-    ) -> Result<(percore::CoreId, u8, vcpu::InjectedInterruptType)> {
-        //TODO(alschwalm): For now just route the UART interrupts to the BSP,
-        // but this should ulimately do actual interrupt routing based on the
-        // guest IO APICs. For now just blindly translate GSI to vector based
-        // on this basic formula.
-        let vector = (gsi + 48) as u8;
-        if gsi == interrupt::gsi::UART {
-            Ok((
-                self.bsp_id(),
-                vector,
-                vcpu::InjectedInterruptType::ExternalInterrupt,
-            ))
+    ) -> Result<Option<InterruptDestination>> {
+        let entry = match self
+            .static_virtual_devices
+            .io_apic
+            .read()
+            .redirection_entry(gsi)
+        {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Invalid I/O APIC entry for GSI {}: {:?}", gsi, e);
+                return Ok(None);
+            }
+        };
+
+        if entry.is_masked() {
+            return Ok(None);
+        }
+
+        self.interrupt_destination(
+            entry.destination() as u32,
+            entry.destination_mode(),
+            entry.delivery_mode(),
+            entry.vector(),
+        )
+    }
+
+    /// Resolve the address and data of a guest message signaled interrupt
+    /// to the CoreIds, vector and interrupt type it describes
+    ///
+    /// Returns `None` if the message can not be delivered.
+    pub fn msi_destination(
+        &self,
+        address: u64,
+        data: u32,
+    ) -> Result<Option<InterruptDestination>> {
+        // See 10.11 of Volume 3A of the Intel software developer's manual
+        // for the format of the address and data
+        if address & 0xfff00000 != GUEST_LOCAL_APIC_ADDR.as_u64() {
+            warn!("Invalid MSI address 0x{:x}", address);
+            return Ok(None);
+        }
+
+        let mode = if address & (1 << 2) != 0 {
+            ioapic::DestinationMode::Logical
         } else {
-            Ok((
-                percore::read_core_id(),
-                vector,
-                vcpu::InjectedInterruptType::ExternalInterrupt,
-            ))
+            ioapic::DestinationMode::Physical
+        };
+        let delivery =
+            match ioapic::DeliveryMode::try_from(((data >> 8) & 0b111) as u8) {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    warn!("Invalid MSI data 0x{:x}: {:?}", data, e);
+                    return Ok(None);
+                }
+            };
+
+        self.interrupt_destination(
+            ((address >> 12) & 0xff) as u32,
+            mode,
+            delivery,
+            data as u8,
+        )
+    }
+
+    // Resolve an interrupt message (from the I/O APIC or an MSI) to the
+    // cores it should be delivered to
+    fn interrupt_destination(
+        &self,
+        destination: u32,
+        mode: ioapic::DestinationMode,
+        delivery: ioapic::DeliveryMode,
+        vector: u8,
+    ) -> Result<Option<InterruptDestination>> {
+        // The 8 bit logical destination is in the xAPIC format
+        let destination = match mode {
+            ioapic::DestinationMode::Physical => destination,
+            ioapic::DestinationMode::Logical => destination << 24,
+        };
+        let destinations = self.apic_destination(destination, mode, 0xff)?;
+        Ok(interrupt_delivery(destinations, delivery, vector))
+    }
+
+    /// Returns the CoreIds addressed by an interrupt with the given
+    /// destination
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The APIC id of a physically addressed interrupt, or
+    ///                   the ICR address contents of a logically addressed one
+    /// * `mode` - How the destination should be interpreted
+    /// * `broadcast` - The physical destination that addresses every core
+    pub fn apic_destination(
+        &self,
+        destination: u32,
+        mode: ioapic::DestinationMode,
+        broadcast: u32,
+    ) -> Result<ArrayVec<[percore::CoreId; MAX_PER_VM_CORE_COUNT]>> {
+        let mut destinations = ArrayVec::new();
+        match mode {
+            ioapic::DestinationMode::Physical => {
+                if destination == broadcast {
+                    destinations.extend(self.cpus.iter().copied());
+                } else if let Some(core) = self.apic_id_to_core(destination) {
+                    destinations.push(core);
+                } else {
+                    warn!(
+                        "Interrupt sent to unknown local apic id 0x{:x}",
+                        destination
+                    );
+                }
+            }
+            ioapic::DestinationMode::Logical => {
+                destinations.extend(
+                    self.logical_apic_destination(destination)?.copied(),
+                );
+            }
         }
+        Ok(destinations)
+    }
+
+    /// Broadcast an EOI for the given vector to the guest I/O APIC
+    ///
+    /// Any level triggered lines that are still asserted will be delivered
+    /// again.
+    pub fn broadcast_eoi(&self, vector: u8) -> Result<()> {
+        let redeliver = self
+            .static_virtual_devices
+            .io_apic
+            .write()
+            .end_of_interrupt(vector);
+
+        for gsi in redeliver {
+            if let Some((destinations, vector, kind)) =
+                self.gsi_destination(gsi)?
+            {
+                for core in destinations {
+                    virtual_machines().send_msg_core(
+                        VirtualMachineMsg::GuestInterrupt { kind, vector },
+                        core,
+                        true,
+                    )?;
+                }
+            }
+        }
+        Ok(())
     }
 
     fn map_data(
@@ -665,38 +1149,25 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn map_image(
-        image: &str,
-        addr: &GuestPhysAddr,
-        space: &mut GuestAddressSpace,
-        info: &BootInfo,
-    ) -> Result<()> {
-        let data = info
-            .find_module(image)
-            .ok_or_else(|| {
-                Error::InvalidValue(format!("No such module '{}'", image))
-            })?
-            .data();
-        Self::map_data(data, addr, space)
+    fn bios_addresses() -> [GuestPhysAddr; 2] {
+        // The bios is mapped just below 1MB and 4GB
+        let bios_size = BIOS_BLOB.len() as u64;
+        [
+            memory::GuestPhysAddr::new((1024 * 1024) - bios_size),
+            memory::GuestPhysAddr::new((4 * 1024 * 1024 * 1024) - bios_size),
+        ]
     }
 
     fn map_bios(space: &mut GuestAddressSpace) -> Result<()> {
-        let bios_size = BIOS_BLOB.len() as u64;
-        Self::map_data(
-            BIOS_BLOB,
-            &memory::GuestPhysAddr::new((1024 * 1024) - bios_size),
-            space,
-        )?;
-        Self::map_data(
-            BIOS_BLOB,
-            &memory::GuestPhysAddr::new((4 * 1024 * 1024 * 1024) - bios_size),
-            space,
-        )
+        for addr in Self::bios_addresses().iter() {
+            Self::map_data(BIOS_BLOB, addr, space)?;
+        }
+        Ok(())
     }
 
     fn setup_ept(
-        config: &VirtualMachineConfig,
-        info: &BootInfo,
+        memory: u64,
+        images: &[(&'static [u8], GuestPhysAddr)],
     ) -> Result<GuestAddressSpace> {
         let mut guest_space = GuestAddressSpace::new()?;
 
@@ -704,12 +1175,12 @@ impl VirtualMachine {
         Self::map_bios(&mut guest_space)?;
 
         // Now map any guest iamges
-        for image in config.images.iter() {
-            Self::map_image(&image.0, &image.1, &mut guest_space, info)?;
+        for (data, addr) in images.iter() {
+            Self::map_data(data, addr, &mut guest_space)?;
         }
 
         // Iterate over each page
-        for i in 0..(config.memory << 8) {
+        for i in 0..(memory << 8) {
             match guest_space.map_new_frame(
                 memory::GuestPhysAddr::new(
                     i as u64 * mem::size_of::<Raw4kPage>() as u64,
@@ -723,6 +1194,84 @@ impl VirtualMachine {
 
         Ok(guest_space)
     }
+
+    // Copy the given data to the (already mapped) guest memory at 'addr',
+    // clearing the remainder of the last page
+    fn load_data(&self, data: &[u8], addr: &GuestPhysAddr) -> Result<()> {
+        for (i, chunk) in data.chunks(mem::size_of::<Raw4kPage>()).enumerate() {
+            let mut frame = self.guest_space.find_host_frame(
+                memory::GuestPhysAddr::new(
+                    addr.as_u64()
+                        + (i as u64 * mem::size_of::<Raw4kPage>() as u64),
+                ),
+            )?;
+            let array = unsafe { frame.as_mut_array() };
+            array[..chunk.len()].copy_from_slice(chunk);
+            for byte in array[chunk.len()..].iter_mut() {
+                *byte = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // Return the guest memory to the state it had when the VirtualMachine
+    // was created
+    fn reset_memory(&self) -> Result<()> {
+        for i in 0..(self.memory << 8) {
+            let mut frame = self.guest_space.find_host_frame(
+                memory::GuestPhysAddr::new(
+                    i as u64 * mem::size_of::<Raw4kPage>() as u64,
+                ),
+            )?;
+            for byte in unsafe { frame.as_mut_array() }.iter_mut() {
+                *byte = 0;
+            }
+        }
+
+        for addr in Self::bios_addresses().iter() {
+            self.load_data(BIOS_BLOB, addr)?;
+        }
+        for (data, addr) in self.images.iter() {
+            self.load_data(data, addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Select the cores, vector and interrupt type used to deliver an
+/// interrupt with the given delivery mode to `destinations`
+///
+/// Returns `None` if the interrupt can not be delivered (including for
+/// delivery modes other than Fixed, LowestPriority and NMI).
+pub fn interrupt_delivery(
+    mut destinations: ArrayVec<[percore::CoreId; MAX_PER_VM_CORE_COUNT]>,
+    delivery: ioapic::DeliveryMode,
+    vector: u8,
+) -> Option<InterruptDestination> {
+    let (vector, kind) = match delivery {
+        ioapic::DeliveryMode::Fixed => {
+            (vector, vcpu::InjectedInterruptType::ExternalInterrupt)
+        }
+        ioapic::DeliveryMode::LowestPriority => {
+            // There is no task priority emulation, so just pick the
+            // first core that matches the destination
+            destinations.truncate(1);
+            (vector, vcpu::InjectedInterruptType::ExternalInterrupt)
+        }
+        ioapic::DeliveryMode::NMI => {
+            (2, vcpu::InjectedInterruptType::NonMaskableInterrupt)
+        }
+        mode => {
+            warn!("Unsupported interrupt delivery mode {:?}", mode);
+            return None;
+        }
+    };
+
+    if destinations.is_empty() {
+        return None;
+    }
+
+    Some((destinations, vector, kind))
 }
 
 #[cfg(test)]
@@ -266,9 +266,11 @@ bitflags! {
         const APIC_REGISTER_VIRT =       0x00000100;
         const VIRTUAL_INTR_DELIVERY =    0x00000200;
         const PAUSE_LOOP_EXITING =       0x00000400;
+        const RDRAND_EXITING =           0x00000800;
         const ENABLE_INVPCID =           0x00001000;
         const ENABLE_VM_FUNCTIONS =      0x00002000;
         const ENABLE_VMCS_SHADOWING =    0x00004000;
+        const RDSEED_EXITING =           0x00010000;
         const ENABLE_PML =               0x00020000;
         const ENABLE_VIRT_EXCEPTIONS =   0x00040000;
         const XSAVES =                   0x00100000;
@@ -128,11 +128,11 @@ pub enum ExitInformation {
     Wbinvd,
     Xsetbv,
     ApicWrite,
-    RdRand,
+    RdRand(RandomInstructionInformation),
     Invpcid,
     VmFunc,
     Encls,
-    RdSeed,
+    RdSeed(RandomInstructionInformation),
     PageModificationLogFull,
     Xsaves,
     Xrstors,
@@ -212,11 +212,15 @@ impl ExitReason {
             54 => ExitInformation::Wbinvd,
             55 => ExitInformation::Xsetbv,
             56 => ExitInformation::ApicWrite,
-            57 => ExitInformation::RdRand,
+            57 => ExitInformation::RdRand(
+                RandomInstructionInformation::from_active_vmcs(vmcs)?,
+            ),
             58 => ExitInformation::Invpcid,
             59 => ExitInformation::VmFunc,
             60 => ExitInformation::Encls,
-            61 => ExitInformation::RdSeed,
+            61 => ExitInformation::RdSeed(
+                RandomInstructionInformation::from_active_vmcs(vmcs)?,
+            ),
             62 => ExitInformation::PageModificationLogFull,
             63 => ExitInformation::Xsaves,
             64 => ExitInformation::Xrstors,
@@ -301,6 +305,33 @@ impl ExtendedExitInformation for IoInstructionInformation {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct RandomInstructionInformation {
+    pub register: MovCrRegister,
+    pub size: u8,
+}
+
+impl ExtendedExitInformation for RandomInstructionInformation {
+    fn from_active_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let info = vmcs.read_field(vmcs::VmcsField::VmxInstructionInfo)?;
+        let size = match (info >> 11) & 0b11 {
+            0 => 2,
+            1 => 4,
+            2 => 8,
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid RDRAND/RDSEED instruction information: 0x{:x}",
+                    info
+                )))
+            }
+        };
+        Ok(RandomInstructionInformation {
+            register: MovCrRegister::try_from(((info >> 3) & 0b1111) as u8)?,
+            size: size,
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug, TryFromPrimitive)]
 #[repr(u8)]
 pub enum InterruptType {